@@ -0,0 +1,194 @@
+use crate::config::agent_config;
+use crate::disk::vm_storage_dir;
+use crate::endpoints;
+use crate::ip_discovery::{normalize_mac, vm_mac, ARP_TABLE};
+use crate::ssh::SshSettings;
+use log::{info, warn};
+use std::process::Stdio;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+/// Marks failures where the address found for a VM belongs to something else
+pub const NETWORK_CONFLICT: &str = "network conflict";
+/// Pause between SSH port probes
+const PROBE_INTERVAL_SECS: u64 = 2;
+/// Upper bound for reading the ARP table
+const ARP_COMMAND_TIMEOUT_SECS: u64 = 5;
+
+/// (IP, MAC) pairs of complete entries in the host's ARP table
+async fn arp_entries() -> Vec<(String, String)> {
+    if let Ok(content) = std::fs::read_to_string(ARP_TABLE) {
+        return parse_proc_arp(&content);
+    }
+    // macOS has no /proc; `arp -an` lists the same table
+    let output = timeout(
+        Duration::from_secs(ARP_COMMAND_TIMEOUT_SECS),
+        Command::new("arp")
+            .arg("-an")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await;
+    match output {
+        Ok(Ok(output)) if output.status.success() => {
+            parse_arp_command(&String::from_utf8_lossy(&output.stdout))
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// `/proc/net/arp`: `IP address  HW type  Flags  HW address  Mask  Device`
+fn parse_proc_arp(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // Flags 0x0 is an incomplete entry
+            if fields.get(2).is_some_and(|flags| *flags == "0x0") {
+                return None;
+            }
+            Some((fields.first()?.to_string(), normalize_mac(fields.get(3)?)?))
+        })
+        .collect()
+}
+
+/// `arp -an`: `? (192.168.64.5) at 5e:3a:1:2b:c:9 on bridge100 ifscope [bridge]`
+fn parse_arp_command(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let ip = line.split_once('(')?.1.split_once(')')?.0;
+            let mac = line.split_once(" at ")?.1.split_whitespace().next()?;
+            Some((ip.to_string(), normalize_mac(mac)?))
+        })
+        .collect()
+}
+
+/// Why `ip_address` cannot be the VM with MAC `expected`, judging by the ARP table
+fn arp_conflict(
+    ip_address: &str,
+    expected: Option<&str>,
+    entries: &[(String, String)],
+) -> Option<String> {
+    let mut macs: Vec<&str> = entries
+        .iter()
+        .filter(|(ip, _)| ip == ip_address)
+        .map(|(_, mac)| mac.as_str())
+        .collect();
+    macs.sort_unstable();
+    macs.dedup();
+    if macs.len() > 1 {
+        return Some(format!(
+            "{} is claimed by several MAC addresses: {}",
+            ip_address,
+            macs.join(", ")
+        ));
+    }
+    match (macs.first(), expected) {
+        (Some(mac), Some(expected)) if *mac != expected => Some(format!(
+            "{} answers from MAC {}, but the VM has MAC {}",
+            ip_address, mac, expected
+        )),
+        _ => None,
+    }
+}
+
+/// Wait until `ip_address:port` accepts TCP connections, up to `window`
+async fn wait_for_port(ip_address: &str, port: u16, connect_secs: u64, window: Duration) -> bool {
+    let start = Instant::now();
+    loop {
+        let connect = timeout(
+            Duration::from_secs(connect_secs),
+            TcpStream::connect((ip_address, port)),
+        )
+        .await;
+        if matches!(connect, Ok(Ok(_))) {
+            return true;
+        }
+        if start.elapsed() >= window {
+            return false;
+        }
+        sleep(Duration::from_secs(PROBE_INTERVAL_SECS)).await;
+    }
+}
+
+/// Before SSH is attempted, check that `ip_address` really is `vm_name`: the SSH port must
+/// answer, and on a local provider the ARP table must map the address to the VM's MAC and
+/// nothing else. Failures name the cause instead of ending in a generic SSH timeout.
+pub async fn verify(vm_name: &str, ip_address: &str, ssh: &SshSettings) -> Result<(), String> {
+    if !agent_config().ip_discovery.verify_address {
+        return Ok(());
+    }
+    // Behind a jump host the VM's network is not reachable from here
+    let reachable = ssh.jump_host.is_some()
+        || wait_for_port(
+            ip_address,
+            ssh.port,
+            ssh.connect_timeout_secs,
+            Duration::from_secs(agent_config().timeouts.ssh_ready_secs),
+        )
+        .await;
+
+    // The ARP table only describes VMs on this host
+    if endpoints::current().is_none() {
+        let expected = vm_mac(&vm_storage_dir(vm_name));
+        if let Some(conflict) = arp_conflict(ip_address, expected.as_deref(), &arp_entries().await)
+        {
+            warn!("VM '{}': {}", vm_name, conflict);
+            return Err(format!("{}: {}", NETWORK_CONFLICT, conflict));
+        }
+    }
+    if !reachable {
+        return Err(format!(
+            "SSH port {} on {} is unreachable for VM '{}'",
+            ssh.port, ip_address, vm_name
+        ));
+    }
+    info!(
+        "Verified address {} of VM '{}' (SSH port {} open)",
+        ip_address, vm_name, ssh.port
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arp_parsing_and_conflicts() {
+        let proc_arp = "IP address       HW type     Flags       HW address            Mask     Device\n\
+                        192.168.122.13   0x1         0x0         52:54:00:ab:cd:01     *        virbr0\n\
+                        192.168.122.14   0x1         0x2         52:54:00:AB:CD:02     *        virbr0\n";
+        assert_eq!(
+            parse_proc_arp(proc_arp),
+            vec![(
+                "192.168.122.14".to_string(),
+                "52:54:00:ab:cd:02".to_string()
+            )]
+        );
+        let entries = parse_arp_command(
+            "? (192.168.64.5) at 5e:3a:1:2b:c:9 on bridge100 ifscope [bridge]\n\
+             ? (192.168.64.6) at (incomplete) on bridge100 ifscope [bridge]\n\
+             ? (192.168.64.5) at 5e:3a:1:2b:c:a on en0 ifscope [ethernet]\n",
+        );
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].1, "5e:3a:01:2b:0c:09");
+
+        assert!(arp_conflict("192.168.64.5", None, &entries[..1]).is_none());
+        assert!(arp_conflict("192.168.64.5", Some("5e:3a:01:2b:0c:09"), &entries[..1]).is_none());
+        assert!(arp_conflict("192.168.64.7", Some("5e:3a:01:2b:0c:09"), &entries).is_none());
+        assert!(
+            arp_conflict("192.168.64.5", Some("52:54:00:00:00:01"), &entries[..1])
+                .unwrap()
+                .contains("answers from MAC 5e:3a:01:2b:0c:09")
+        );
+        assert!(arp_conflict("192.168.64.5", None, &entries)
+            .unwrap()
+            .contains("several MAC addresses"));
+    }
+}
@@ -0,0 +1,92 @@
+use crate::config::BackpressureConfig;
+use serde::Serialize;
+
+/// Why the agent asks the API to slow down assigning runners
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// More runners are provisioning or waiting than `[backpressure] queue_threshold`
+    QueueDepth,
+    /// Every VM slot (`--max-vms`) is taken
+    NoVmSlots,
+    /// Runners are waiting for overcommit headroom
+    Capacity,
+}
+
+/// Sent with every poll, so the API can hold back runners the agent would only queue
+/// until they time out
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Backpressure {
+    pub active: bool,
+    pub reasons: Vec<Reason>,
+    /// Runners provisioning or waiting for room after the last poll
+    pub queue_depth: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_threshold: Option<usize>,
+}
+
+/// Work the agent held after the last poll
+#[derive(Debug, Default)]
+pub struct Load {
+    pub provisioning: usize,
+    /// Requested runners that found no free VM slot
+    pub waiting_for_slot: usize,
+    /// Requested runners queued for overcommit headroom
+    pub waiting_for_capacity: usize,
+}
+
+pub fn assess(load: &Load, config: &BackpressureConfig) -> Backpressure {
+    let queue_depth = load.provisioning + load.waiting_for_slot + load.waiting_for_capacity;
+    let queue_threshold = Some(config.queue_threshold).filter(|threshold| *threshold > 0);
+    let mut reasons = Vec::new();
+    if queue_threshold.is_some_and(|threshold| queue_depth > threshold) {
+        reasons.push(Reason::QueueDepth);
+    }
+    if load.waiting_for_slot > 0 {
+        reasons.push(Reason::NoVmSlots);
+    }
+    if load.waiting_for_capacity > 0 {
+        reasons.push(Reason::Capacity);
+    }
+    Backpressure {
+        active: !reasons.is_empty(),
+        reasons,
+        queue_depth,
+        queue_threshold,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assess() {
+        let config = BackpressureConfig { queue_threshold: 3 };
+        let idle = assess(&Load::default(), &config);
+        assert!(!idle.active);
+
+        let busy = Load {
+            provisioning: 3,
+            ..Load::default()
+        };
+        assert!(!assess(&busy, &config).active);
+
+        let overloaded = Load {
+            provisioning: 3,
+            waiting_for_slot: 2,
+            ..Load::default()
+        };
+        let pressure = assess(&overloaded, &config);
+        assert_eq!(
+            pressure.reasons,
+            vec![Reason::QueueDepth, Reason::NoVmSlots]
+        );
+        assert_eq!(pressure.queue_depth, 5);
+
+        // Without a threshold only exhausted resources count
+        let pressure = assess(&overloaded, &BackpressureConfig::default());
+        assert_eq!(pressure.reasons, vec![Reason::NoVmSlots]);
+        assert_eq!(pressure.queue_threshold, None);
+    }
+}
@@ -0,0 +1,285 @@
+use crate::config::agent_config;
+use crate::host_keys;
+use crate::ip_discovery::wait_for_meda_ip;
+use crate::lume::client::LumeClient;
+use crate::lume::models::RunConfig;
+use crate::meda::client::MedaClient;
+use crate::meda::models::VmRunRequest;
+use crate::naming;
+use crate::ssh;
+use crate::timing::PhaseTimings;
+use crate::vm_provision::{run_ssh_command, wait_for_vm_ip};
+use crate::{use_meda, RunnerLogin};
+use log::{info, warn};
+use serde::Serialize;
+use std::time::Instant;
+use tokio::time::{sleep, Duration};
+
+/// Size of the file written inside the guest to measure disk throughput
+const DISK_TEST_MB: u64 = 512;
+
+/// Clone + boot-to-SSH time (seconds) that scores 50 points
+const BASELINE_STARTUP_SECS: f64 = 30.0;
+/// Disk throughput (MB/s) that scores 50 points
+const BASELINE_DISK_MB_PER_SEC: f64 = 500.0;
+
+/// Measurements from a single benchmark run
+#[derive(Debug, Serialize)]
+pub struct BenchmarkResult {
+    pub image: String,
+    pub clone_secs: f64,
+    pub boot_to_ssh_secs: f64,
+    pub disk_mb_per_sec: f64,
+    /// Relative score where 100 matches the baseline host
+    pub score: f64,
+}
+
+/// Score a host relative to the baseline: half for startup latency, half for disk throughput
+pub fn performance_score(clone_secs: f64, boot_to_ssh_secs: f64, disk_mb_per_sec: f64) -> f64 {
+    let startup = (clone_secs + boot_to_ssh_secs).max(0.1);
+    let score = 50.0 * (BASELINE_STARTUP_SECS / startup)
+        + 50.0 * (disk_mb_per_sec / BASELINE_DISK_MB_PER_SEC);
+    (score * 10.0).round() / 10.0
+}
+
+/// Create the benchmark VM from the reference image (meda) or by cloning it (lume)
+async fn create_bench_vm(image: &str, vm_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if use_meda() {
+        let meda = MedaClient::new()?;
+        meda.run_vm(VmRunRequest {
+            image: image.to_string(),
+            name: Some(vm_name.to_string()),
+            memory: None,
+            cpus: None,
+            disk_size: None,
+            cpu_affinity: None,
+            numa_node: None,
+            qos: None,
+        })
+        .await?;
+    } else {
+        let lume = LumeClient::new()?;
+        lume.clone_vm(image, vm_name).await?;
+    }
+    Ok(())
+}
+
+/// Boot the benchmark VM if needed and wait until it has an IP address
+async fn boot_bench_vm(vm_name: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if use_meda() {
+        let meda = MedaClient::new()?;
+        Ok(wait_for_meda_ip(&meda, vm_name, agent_config().timeouts.ip_wait_secs).await?)
+    } else {
+        let lume = LumeClient::new()?;
+        let run_config = RunConfig {
+            no_display: Some(true),
+            shared_directories: None,
+            recovery_mode: None,
+        };
+        lume.run_vm(vm_name, Some(run_config)).await?;
+        wait_for_vm_ip(&lume, vm_name, agent_config().timeouts.ip_wait_secs).await
+    }
+}
+
+async fn delete_bench_vm(vm_name: &str) {
+    host_keys::forget(vm_name);
+    ssh::close_connections(vm_name).await;
+    let result: Result<(), Box<dyn std::error::Error>> = if use_meda() {
+        match MedaClient::new() {
+            Ok(meda) => meda.delete_vm(vm_name).await.map_err(Into::into),
+            Err(e) => Err(e.into()),
+        }
+    } else {
+        match LumeClient::new() {
+            Ok(lume) => lume.delete_vm(vm_name).await.map_err(Into::into),
+            Err(e) => Err(e.into()),
+        }
+    };
+    if let Err(e) = result {
+        warn!("Failed to delete benchmark VM '{}': {}", vm_name, e);
+    }
+}
+
+async fn measure(
+    image: &str,
+    vm_name: &str,
+    login: &RunnerLogin,
+) -> Result<BenchmarkResult, Box<dyn std::error::Error>> {
+    info!("Creating benchmark VM '{}' from '{}'", vm_name, image);
+    let clone_start = Instant::now();
+    create_bench_vm(image, vm_name).await?;
+    let clone_secs = clone_start.elapsed().as_secs_f64();
+    info!("Clone took {:.1}s", clone_secs);
+
+    let timeouts = &agent_config().timeouts;
+    let boot_start = Instant::now();
+    let ip_address = boot_bench_vm(vm_name).await?;
+    let ssh_deadline = Instant::now() + Duration::from_secs(timeouts.ssh_ready_secs);
+    loop {
+        match run_ssh_command(
+            vm_name,
+            &ip_address,
+            login,
+            "echo ready",
+            timeouts.ssh_attempt_secs,
+        )
+        .await
+        {
+            Ok(output) if output.status.success() => break,
+            _ if Instant::now() > ssh_deadline => {
+                return Err(format!(
+                    "SSH did not become ready within {}s",
+                    timeouts.ssh_ready_secs
+                )
+                .into());
+            }
+            _ => sleep(Duration::from_secs(2)).await,
+        }
+    }
+    let boot_to_ssh_secs = boot_start.elapsed().as_secs_f64();
+    info!("Boot to SSH took {:.1}s", boot_to_ssh_secs);
+
+    let disk_command = format!(
+        "dd if=/dev/zero of=/tmp/cirun-bench bs=1048576 count={} 2>/dev/null && sync && rm -f /tmp/cirun-bench",
+        DISK_TEST_MB
+    );
+    let disk_start = Instant::now();
+    let output = run_ssh_command(vm_name, &ip_address, login, &disk_command, 600).await?;
+    if !output.status.success() {
+        return Err(format!(
+            "Disk throughput test failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+    let disk_mb_per_sec = DISK_TEST_MB as f64 / disk_start.elapsed().as_secs_f64().max(0.001);
+    info!("Disk throughput: {:.1} MB/s", disk_mb_per_sec);
+
+    Ok(BenchmarkResult {
+        image: image.to_string(),
+        clone_secs,
+        boot_to_ssh_secs,
+        disk_mb_per_sec,
+        score: performance_score(clone_secs, boot_to_ssh_secs, disk_mb_per_sec),
+    })
+}
+
+/// Run the host benchmark against a reference image, always removing the benchmark VM afterwards.
+/// On Linux `image` is a meda image; on macOS it is an existing Lume VM or template to clone.
+pub async fn run_benchmark(
+    image: &str,
+    login: &RunnerLogin,
+) -> Result<BenchmarkResult, Box<dyn std::error::Error>> {
+    let vm_name = naming::vm_name("bench-", &uuid::Uuid::new_v4().simple().to_string()[..8]);
+    let result = measure(image, &vm_name, login)
+        .await
+        .map_err(|e| e.to_string());
+    delete_bench_vm(&vm_name).await;
+    Ok(result?)
+}
+
+/// One pass through the full provisioning pipeline, from template lookup to deletion
+#[derive(Debug, Clone)]
+pub struct ProvisionRun {
+    pub timings: PhaseTimings,
+    pub delete_secs: Option<f64>,
+    /// Provisioning and deletion together
+    pub total_secs: f64,
+    pub error: Option<String>,
+}
+
+/// Latency of one phase across the runs it took part in
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PhaseStats {
+    pub phase: &'static str,
+    pub runs: usize,
+    pub p50: f64,
+    pub p90: f64,
+    pub max: f64,
+}
+
+/// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Percentiles per phase, plus deletion and the whole run. Phases that never ran are left out.
+pub fn phase_stats(runs: &[ProvisionRun]) -> Vec<PhaseStats> {
+    let mut phases: Vec<(&'static str, Vec<f64>)> = PhaseTimings::default()
+        .entries()
+        .iter()
+        .map(|(name, _)| (*name, Vec::new()))
+        .collect();
+    phases.push(("delete", Vec::new()));
+    phases.push(("total", Vec::new()));
+    for run in runs {
+        let mut secs: Vec<Option<f64>> = run.timings.entries().iter().map(|(_, s)| *s).collect();
+        secs.push(run.delete_secs);
+        secs.push(Some(run.total_secs));
+        for ((_, values), secs) in phases.iter_mut().zip(secs) {
+            values.extend(secs);
+        }
+    }
+    phases
+        .into_iter()
+        .filter(|(_, values)| !values.is_empty())
+        .map(|(phase, mut values)| {
+            values.sort_by(f64::total_cmp);
+            PhaseStats {
+                phase,
+                runs: values.len(),
+                p50: percentile(&values, 50.0),
+                p90: percentile(&values, 90.0),
+                max: values[values.len() - 1],
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_performance_score_baseline() {
+        // A host matching the baseline scores 100
+        assert_eq!(performance_score(10.0, 20.0, 500.0), 100.0);
+        // Faster startup and disk score higher
+        assert!(performance_score(5.0, 10.0, 1000.0) > 100.0);
+        assert!(performance_score(30.0, 60.0, 100.0) < 100.0);
+    }
+
+    #[test]
+    fn test_phase_stats() {
+        let run = |clone: f64, total: f64, error: Option<&str>| ProvisionRun {
+            timings: PhaseTimings {
+                clone: Some(clone),
+                ..Default::default()
+            },
+            delete_secs: error.is_none().then_some(2.0),
+            total_secs: total,
+            error: error.map(str::to_string),
+        };
+        let runs: Vec<ProvisionRun> = (1..=10)
+            .map(|i| run(i as f64, 10.0 * i as f64, None))
+            .chain([run(50.0, 60.0, Some("SSH timed out"))])
+            .collect();
+
+        let stats = phase_stats(&runs);
+        let phases: Vec<&str> = stats.iter().map(|s| s.phase).collect();
+        assert_eq!(phases, ["clone", "delete", "total"]);
+        assert_eq!(
+            stats[0],
+            PhaseStats {
+                phase: "clone",
+                runs: 11,
+                p50: 6.0,
+                p90: 10.0,
+                max: 50.0,
+            }
+        );
+        assert_eq!(stats[1].runs, 10);
+        assert_eq!(percentile(&[4.0], 90.0), 4.0);
+    }
+}
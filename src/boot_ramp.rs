@@ -0,0 +1,102 @@
+use crate::config::agent_config;
+use crate::endpoints;
+use log::info;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Recent boot starts per host; `None` is this host
+static BOOTS: Mutex<Option<HashMap<Option<String>, VecDeque<Instant>>>> = Mutex::new(None);
+
+/// Take a boot slot if fewer than `max_boots` of `starts` lie within `window` of `now`;
+/// otherwise how long until the oldest of them leaves the window
+fn take_slot(
+    starts: &mut VecDeque<Instant>,
+    now: Instant,
+    max_boots: usize,
+    window: Duration,
+) -> Option<Duration> {
+    while starts
+        .front()
+        .is_some_and(|start| now.duration_since(*start) >= window)
+    {
+        starts.pop_front();
+    }
+    if starts.len() < max_boots {
+        starts.push_back(now);
+        return None;
+    }
+    starts
+        .front()
+        .map(|oldest| window.saturating_sub(now.duration_since(*oldest)))
+}
+
+/// Wait until runner VM `vm_name` may boot on the current host, so a burst of runners
+/// boots a few at a time instead of all at once. Without `[boot_ramp] max_boots` this
+/// returns right away.
+pub async fn wait_for_slot(vm_name: &str) {
+    let config = &agent_config().boot_ramp;
+    if config.max_boots == 0 {
+        return;
+    }
+    let host = endpoints::current().map(|endpoint| endpoint.name.clone());
+    let window = Duration::from_secs(config.window_secs);
+    let mut waited = false;
+    loop {
+        let wait = take_slot(
+            BOOTS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .get_or_insert_with(HashMap::new)
+                .entry(host.clone())
+                .or_default(),
+            Instant::now(),
+            config.max_boots,
+            window,
+        );
+        let Some(wait) = wait else {
+            if waited {
+                info!("Boot slot free for VM '{}'", vm_name);
+            }
+            return;
+        };
+        if !waited {
+            info!(
+                "{} VMs booted in the last {}s; VM '{}' boots in {}s",
+                config.max_boots,
+                config.window_secs,
+                vm_name,
+                wait.as_secs().max(1)
+            );
+            waited = true;
+        }
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_slot() {
+        let window = Duration::from_secs(30);
+        let start = Instant::now();
+        let mut starts = VecDeque::new();
+        assert_eq!(take_slot(&mut starts, start, 2, window), None);
+        assert_eq!(
+            take_slot(&mut starts, start + Duration::from_secs(5), 2, window),
+            None
+        );
+        assert_eq!(
+            take_slot(&mut starts, start + Duration::from_secs(10), 2, window),
+            Some(Duration::from_secs(20))
+        );
+        // The first boot has left the window
+        assert_eq!(
+            take_slot(&mut starts, start + Duration::from_secs(30), 2, window),
+            None
+        );
+        assert_eq!(starts.len(), 2);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::provider_health::{self, ProviderHealth};
+use crate::use_meda;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Serializer};
+use std::sync::{OnceLock, RwLock};
+use std::time::Instant;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short commit hash, empty when built outside a git checkout
+const GIT_SHA: &str = env!("CIRUN_AGENT_GIT_SHA");
+/// Unix time of the build
+const BUILT_AT: &str = env!("CIRUN_AGENT_BUILT_AT");
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static PROVIDER_VERSION: RwLock<Option<String>> = RwLock::new(None);
+
+/// Commit and time the agent was built from
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BuildInfo {
+    pub git_sha: Option<&'static str>,
+    pub built_at: Option<DateTime<Utc>>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        BuildInfo {
+            git_sha: Some(GIT_SHA).filter(|sha| !sha.is_empty()),
+            built_at: BUILT_AT
+                .parse()
+                .ok()
+                .and_then(|secs| DateTime::from_timestamp(secs, 0)),
+        }
+    }
+}
+
+/// Mark the agent as started; uptime is counted from the first call
+pub fn mark_started() {
+    STARTED.get_or_init(Instant::now);
+}
+
+/// Agent uptime, serialized as whole seconds at the time the payload is built
+#[derive(Debug, Clone, Default)]
+pub struct Uptime;
+
+impl Serialize for Uptime {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let secs = STARTED
+            .get()
+            .map_or(0, |started| started.elapsed().as_secs());
+        serializer.serialize_u64(secs)
+    }
+}
+
+/// Look up the installed provider's version again, e.g. after an upgrade. Runs the
+/// provider binary, so call it off the async runtime.
+pub fn refresh_provider_version() {
+    let version = installed_provider_version();
+    if let Some(version) = &version {
+        crate::identity::record_provider_version(provider_name(), version);
+    }
+    *PROVIDER_VERSION.write().unwrap_or_else(|e| e.into_inner()) = version;
+}
+
+/// Version of the installed provider binary. Runs it, so call it off the async runtime.
+pub fn installed_provider_version() -> Option<String> {
+    if use_meda() {
+        crate::meda::setup::installed_meda_version()
+    } else {
+        crate::lume::setup::installed_lume_version()
+    }
+}
+
+pub fn provider_name() -> &'static str {
+    if use_meda() {
+        "meda"
+    } else {
+        "lume"
+    }
+}
+
+/// The VM provider, its version as last looked up and its health, serialized as
+/// `{"name", "version", "reachable", "last_success_at", ...}`
+#[derive(Debug, Clone, Default)]
+pub struct Provider;
+
+impl Serialize for Provider {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct ProviderInfo {
+            name: &'static str,
+            version: Option<String>,
+            #[serde(flatten)]
+            health: Option<ProviderHealth>,
+        }
+        ProviderInfo {
+            name: provider_name(),
+            version: PROVIDER_VERSION
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+            health: provider_health::current(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info_and_uptime() {
+        let build = BuildInfo::current();
+        assert!(build.built_at.is_some());
+
+        mark_started();
+        let uptime = serde_json::to_value(Uptime).unwrap();
+        assert!(uptime.is_u64());
+
+        let provider = serde_json::to_value(Provider).unwrap();
+        assert!(provider["name"] == "meda" || provider["name"] == "lume");
+    }
+}
@@ -0,0 +1,237 @@
+use crate::config::CadenceConfig;
+use tokio::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Empty calls in a row before the wait starts growing
+const IDLE_GRACE_CALLS: u32 = 3;
+/// Growth of the wait per further empty call
+const IDLE_GROWTH: f64 = 1.5;
+
+/// Schedule of a recurring API call: a base interval with random jitter, so many agents
+/// started together do not hit the API in lockstep, and exponential backoff while the call
+/// keeps failing. Calls that keep bringing no work can slow it down to an idle maximum.
+#[derive(Debug)]
+pub struct Cadence {
+    /// Interval from the command line
+    configured: Duration,
+    interval: Duration,
+    jitter_percent: u32,
+    max_backoff: Duration,
+    max_idle: Duration,
+    failures: u32,
+    idle_calls: u32,
+    due_at: Instant,
+    last_run: Option<Instant>,
+}
+
+impl Cadence {
+    /// A cadence that is due straight away
+    pub fn new(interval: Duration, config: &CadenceConfig) -> Self {
+        Cadence {
+            configured: interval,
+            interval,
+            jitter_percent: config.jitter_percent.min(100),
+            max_backoff: Duration::from_secs(config.max_backoff_secs).max(interval),
+            max_idle: config
+                .idle_max_secs
+                .map_or(interval, Duration::from_secs)
+                .max(interval),
+            failures: 0,
+            idle_calls: 0,
+            due_at: Instant::now(),
+            last_run: None,
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.due_at
+    }
+
+    pub fn due_at(&self) -> Instant {
+        self.due_at
+    }
+
+    /// Make the call due soon because something worth reporting happened. Waits `debounce`
+    /// so a burst of changes is sent as one call, and keeps calls at least `debounce` apart.
+    /// Has no effect while backing off after failures.
+    pub fn trigger(&mut self, debounce: Duration) {
+        if self.failures > 0 {
+            return;
+        }
+        let mut due = Instant::now() + debounce;
+        if let Some(last_run) = self.last_run {
+            due = due.max(last_run + debounce);
+        }
+        self.due_at = self.due_at.min(due);
+    }
+
+    /// The call went through and brought work; the next one is one interval away
+    pub fn succeeded(&mut self) {
+        self.idle_calls = 0;
+        self.went_through();
+    }
+
+    /// The call went through without any work. After a few of these in a row each wait is
+    /// longer than the last, up to the idle maximum.
+    pub fn idle(&mut self) {
+        self.idle_calls = self.idle_calls.saturating_add(1);
+        self.went_through();
+    }
+
+    /// Use the interval the server asked for, or the configured one when it asks for none.
+    /// A server interval also ends an idle slowdown.
+    pub fn server_interval(&mut self, interval: Option<Duration>) {
+        if self.failures > 0 {
+            return;
+        }
+        match interval {
+            Some(interval) => {
+                self.interval = interval;
+                self.max_idle = self.max_idle.max(interval);
+                self.idle_calls = 0;
+            }
+            None => self.interval = self.configured,
+        }
+        let last_run = self.last_run.unwrap_or_else(Instant::now);
+        self.due_at = last_run + self.delay(random_fraction());
+    }
+
+    /// Make the next call wait exactly `delay` from now, as the server asked
+    pub fn defer(&mut self, delay: Duration) {
+        self.due_at = Instant::now() + delay;
+    }
+
+    fn went_through(&mut self) {
+        self.last_run = Some(Instant::now());
+        self.failures = 0;
+        self.due_at = Instant::now() + self.delay(random_fraction());
+    }
+
+    /// The call failed; wait twice as long as last time, up to the maximum backoff
+    pub fn failed(&mut self) {
+        self.last_run = Some(Instant::now());
+        self.failures = self.failures.saturating_add(1);
+        self.due_at = Instant::now() + self.delay(random_fraction());
+    }
+
+    /// Delay before the next call, `fraction` (0..1) picking a point in the jitter range
+    fn delay(&self, fraction: f64) -> Duration {
+        let base = match self.failures {
+            0 if self.idle_calls > IDLE_GRACE_CALLS => {
+                let growth = IDLE_GROWTH.powi((self.idle_calls - IDLE_GRACE_CALLS).min(64) as i32);
+                self.interval.mul_f64(growth).min(self.max_idle)
+            }
+            0 => self.interval,
+            failures => self
+                .interval
+                .saturating_mul(1 << failures.min(16))
+                .min(self.max_backoff),
+        };
+        let jitter = base.as_secs_f64() * self.jitter_percent as f64 / 100.0;
+        Duration::from_secs_f64((base.as_secs_f64() + jitter * (2.0 * fraction - 1.0)).max(0.0))
+    }
+}
+
+/// A random number in 0..1
+fn random_fraction() -> f64 {
+    (Uuid::new_v4().as_u128() >> 75) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cadence(interval_secs: u64, jitter_percent: u32, max_backoff_secs: u64) -> Cadence {
+        Cadence::new(
+            Duration::from_secs(interval_secs),
+            &CadenceConfig {
+                jitter_percent,
+                max_backoff_secs,
+                idle_max_secs: Some(100),
+            },
+        )
+    }
+
+    #[test]
+    fn test_delay_jitter_bounds() {
+        let cadence = cadence(10, 20, 300);
+        assert_eq!(cadence.delay(0.0), Duration::from_secs(8));
+        assert_eq!(cadence.delay(0.5), Duration::from_secs(10));
+        assert_eq!(cadence.delay(1.0), Duration::from_secs(12));
+        let fraction = random_fraction();
+        assert!((0.0..1.0).contains(&fraction));
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_maximum() {
+        let mut cadence = cadence(10, 0, 60);
+        assert!(cadence.is_due());
+        cadence.failed();
+        assert_eq!(cadence.delay(0.5), Duration::from_secs(20));
+        cadence.failed();
+        assert_eq!(cadence.delay(0.5), Duration::from_secs(40));
+        cadence.failed();
+        assert_eq!(cadence.delay(0.5), Duration::from_secs(60));
+        assert!(!cadence.is_due());
+
+        // Changes do not cut a backoff short
+        cadence.trigger(Duration::ZERO);
+        assert!(!cadence.is_due());
+
+        cadence.succeeded();
+        assert_eq!(cadence.delay(0.5), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_trigger_coalesces_changes() {
+        let mut cadence = cadence(30, 0, 300);
+        cadence.succeeded();
+        let debounce = Duration::from_secs(2);
+
+        cadence.trigger(debounce);
+        let due = cadence.due_at();
+        assert!(due <= Instant::now() + debounce);
+        assert!(due > Instant::now());
+        // Later changes in the burst ride along with the first
+        cadence.trigger(debounce);
+        assert_eq!(cadence.due_at(), due);
+
+        cadence.trigger(Duration::ZERO);
+        assert!(cadence.is_due());
+    }
+
+    #[test]
+    fn test_idle_calls_slow_down_until_work_appears() {
+        let mut cadence = cadence(10, 0, 300);
+        for _ in 0..IDLE_GRACE_CALLS {
+            cadence.idle();
+        }
+        assert_eq!(cadence.delay(0.5), Duration::from_secs(10));
+        cadence.idle();
+        assert_eq!(cadence.delay(0.5), Duration::from_secs(15));
+        cadence.idle();
+        assert_eq!(cadence.delay(0.5), Duration::from_millis(22500));
+        for _ in 0..20 {
+            cadence.idle();
+        }
+        assert_eq!(cadence.delay(0.5), Duration::from_secs(100));
+
+        cadence.succeeded();
+        assert_eq!(cadence.delay(0.5), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_server_interval_and_defer() {
+        let mut cadence = cadence(10, 0, 300);
+        for _ in 0..IDLE_GRACE_CALLS + 2 {
+            cadence.idle();
+        }
+        cadence.server_interval(Some(Duration::from_secs(5)));
+        assert_eq!(cadence.delay(0.5), Duration::from_secs(5));
+        cadence.server_interval(None);
+        assert_eq!(cadence.delay(0.5), Duration::from_secs(10));
+
+        cadence.defer(Duration::from_secs(60));
+        assert!(cadence.due_at() > Instant::now() + Duration::from_secs(55));
+    }
+}
@@ -0,0 +1,125 @@
+use log::info;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+// Cancellation flows down agent -> provider -> runner: shutting the agent down cancels
+// everything, cancelling a runner stops only its own provisioning. Cancelled work is
+// dropped at its next await, which stops image pulls, IP waits and SSH retries
+// mid-wait and kills their child processes.
+static AGENT: OnceLock<CancellationToken> = OnceLock::new();
+static PROVIDER: OnceLock<CancellationToken> = OnceLock::new();
+static RUNNERS: Mutex<Option<HashMap<String, (u64, CancellationToken)>>> = Mutex::new(None);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+fn with_runners<T>(f: impl FnOnce(&mut HashMap<String, (u64, CancellationToken)>) -> T) -> T {
+    f(RUNNERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new))
+}
+
+/// Cancelled when the agent shuts down
+pub fn agent() -> &'static CancellationToken {
+    AGENT.get_or_init(CancellationToken::new)
+}
+
+/// Cancelled with the agent; parent of every runner's token and of background provider work
+pub fn provider() -> &'static CancellationToken {
+    PROVIDER.get_or_init(|| agent().child_token())
+}
+
+/// Whether work stopped because the agent is shutting down, rather than being cancelled on
+/// its own
+pub fn shutting_down() -> bool {
+    agent().is_cancelled()
+}
+
+/// Token for one runner's provisioning, registered until the returned guard is dropped
+pub fn runner(runner_name: &str) -> RunnerToken {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let token = provider().child_token();
+    with_runners(|runners| runners.insert(runner_name.to_string(), (id, token.clone())));
+    RunnerToken {
+        id,
+        runner_name: runner_name.to_string(),
+        token,
+    }
+}
+
+/// Cancel a runner's provisioning, if it is running. Returns whether it was.
+pub fn cancel_runner(runner_name: &str) -> bool {
+    match with_runners(|runners| runners.get(runner_name).map(|(_, token)| token.clone())) {
+        Some(token) => {
+            info!("Cancelling provisioning of runner '{}'", runner_name);
+            token.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+/// A runner's cancellation token, unregistered when dropped
+pub struct RunnerToken {
+    id: u64,
+    runner_name: String,
+    token: CancellationToken,
+}
+
+impl RunnerToken {
+    /// Run `future` until it finishes or the runner is cancelled
+    pub async fn run<F: Future>(&self, future: F) -> Option<F::Output> {
+        self.token.run_until_cancelled(future).await
+    }
+}
+
+impl Drop for RunnerToken {
+    fn drop(&mut self) {
+        with_runners(|runners| {
+            // A newer provisioning of the same name may have registered its own token
+            if runners
+                .get(&self.runner_name)
+                .is_some_and(|(id, _)| *id == self.id)
+            {
+                runners.remove(&self.runner_name);
+            }
+        });
+    }
+}
+
+/// Spawn background provider work, such as refilling the pool, that stops when the agent
+/// shuts down
+pub fn spawn_provider_task<F>(future: F) -> JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        provider().run_until_cancelled(future).await;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_cancel_runner_stops_its_work_only() {
+        let first = runner("cancel-test-1");
+        let second = runner("cancel-test-2");
+        assert!(cancel_runner("cancel-test-1"));
+        assert!(!cancel_runner("cancel-test-unknown"));
+
+        let slow = tokio::time::sleep(Duration::from_secs(60));
+        assert_eq!(first.run(slow).await, None);
+        assert_eq!(second.run(async { 42 }).await, Some(42));
+
+        drop(first);
+        assert!(!cancel_runner("cancel-test-1"));
+        drop(second);
+        assert!(!cancel_runner("cancel-test-2"));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::config::agent_config;
+use crate::state::StateStore;
+use crate::units::Memory;
+use std::process::Command;
+
+/// Allocation limits from the host size and the `[capacity]` overcommit policy, and what
+/// runners created by this agent currently hold. A dimension without an overcommit ratio
+/// has no limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capacity {
+    pub cpu_limit: Option<u32>,
+    pub cpu_allocated: u32,
+    pub memory_limit_mb: Option<u64>,
+    pub memory_allocated_mb: u64,
+}
+
+impl Capacity {
+    /// Whether a runner of this size fits in the remaining headroom
+    pub fn fits(&self, cpu: u32, memory: Memory) -> bool {
+        self.cpu_limit
+            .is_none_or(|limit| self.cpu_allocated + cpu <= limit)
+            && self
+                .memory_limit_mb
+                .is_none_or(|limit| self.memory_allocated_mb + memory.as_mb() <= limit)
+    }
+
+    /// Whether a runner of this size could ever fit on this host
+    pub fn could_fit(&self, cpu: u32, memory: Memory) -> bool {
+        self.cpu_limit.is_none_or(|limit| cpu <= limit)
+            && self
+                .memory_limit_mb
+                .is_none_or(|limit| memory.as_mb() <= limit)
+    }
+
+    /// Count a runner against the headroom
+    pub fn reserve(&mut self, cpu: u32, memory: Memory) {
+        self.cpu_allocated += cpu;
+        self.memory_allocated_mb += memory.as_mb();
+    }
+
+    pub fn cpu_headroom(&self) -> Option<u32> {
+        self.cpu_limit
+            .map(|limit| limit.saturating_sub(self.cpu_allocated))
+    }
+
+    pub fn memory_headroom_mb(&self) -> Option<u64> {
+        self.memory_limit_mb
+            .map(|limit| limit.saturating_sub(self.memory_allocated_mb))
+    }
+
+    /// Limits, allocations and remaining headroom as reported to the API
+    pub fn report(&self) -> serde_json::Value {
+        serde_json::json!({
+            "cpu_limit": self.cpu_limit,
+            "cpu_allocated": self.cpu_allocated,
+            "cpu_headroom": self.cpu_headroom(),
+            "memory_limit_mb": self.memory_limit_mb,
+            "memory_allocated_mb": self.memory_allocated_mb,
+            "memory_headroom_mb": self.memory_headroom_mb(),
+        })
+    }
+}
+
+fn host_cpus() -> Option<u32> {
+    std::thread::available_parallelism()
+        .ok()
+        .map(|n| n.get() as u32)
+}
+
+fn host_memory() -> Option<Memory> {
+    if cfg!(target_os = "linux") {
+        let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+        parse_meminfo_total(&meminfo)
+    } else {
+        let output = Command::new("sysctl")
+            .args(["-n", "hw.memsize"])
+            .output()
+            .ok()?;
+        let bytes: u64 = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .ok()?;
+        Some(Memory::from_mb(bytes / 1024 / 1024))
+    }
+}
+
+/// `MemTotal:       16318480 kB` from /proc/meminfo
+fn parse_meminfo_total(meminfo: &str) -> Option<Memory> {
+    let line = meminfo.lines().find(|l| l.starts_with("MemTotal:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(Memory::from_mb(kb / 1024))
+}
+
+/// Current capacity under the overcommit policy, or None when no policy is configured
+pub fn current_capacity() -> Option<Capacity> {
+    let config = &agent_config().capacity;
+    if config.cpu_overcommit.is_none() && config.memory_overcommit.is_none() {
+        return None;
+    }
+    let (cpu_allocated, memory_allocated_mb) = StateStore::new().read(|state| {
+        state
+            .runners
+            .iter()
+            .filter(|(name, _)| !state.placements.contains_key(*name))
+            .fold((0, 0), |(cpu, memory), (_, r)| {
+                (cpu + r.cpu, memory + r.memory.as_mb())
+            })
+    });
+    Some(Capacity {
+        cpu_limit: config
+            .cpu_overcommit
+            .zip(host_cpus())
+            .map(|(ratio, cpus)| (cpus as f64 * ratio).floor() as u32),
+        cpu_allocated,
+        memory_limit_mb: config
+            .memory_overcommit
+            .zip(host_memory())
+            .map(|(ratio, memory)| (memory.as_mb() as f64 * ratio).floor() as u64),
+        memory_allocated_mb,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admission_under_overcommit() {
+        assert_eq!(
+            parse_meminfo_total("MemTotal:       16318480 kB\nMemFree: 1 kB\n"),
+            Some(Memory::from_mb(15936))
+        );
+
+        // 8 cores at 2x vCPU overcommit, 16GB at 1x
+        let mut capacity = Capacity {
+            cpu_limit: Some(16),
+            cpu_allocated: 8,
+            memory_limit_mb: Some(16 * 1024),
+            memory_allocated_mb: 8 * 1024,
+        };
+        assert!(capacity.fits(8, Memory::from_gb(8)));
+        capacity.reserve(8, Memory::from_gb(8));
+        assert_eq!(capacity.cpu_headroom(), Some(0));
+        assert!(!capacity.fits(1, Memory::from_gb(1)));
+        assert!(capacity.could_fit(16, Memory::from_gb(16)));
+        assert!(!capacity.could_fit(4, Memory::from_gb(32)));
+
+        let unlimited_cpu = Capacity {
+            cpu_limit: None,
+            ..capacity
+        };
+        assert!(unlimited_cpu.fits(64, Memory::from_gb(0)));
+    }
+}
@@ -0,0 +1,183 @@
+use log::warn;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Faults injected with `--chaos`, to exercise retries, fallbacks and reconciliation in
+/// CI and staging. Never enabled in normal operation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    /// Seed of the fault sequence; the same seed and call order give the same faults
+    pub seed: u64,
+    /// Chance (0..1) that a provider API call fails
+    pub provider_error: f64,
+    /// Chance that an SSH or SFTP command times out
+    pub ssh_timeout: f64,
+    /// Chance that an image pull is slowed down
+    pub slow_pull: f64,
+    /// Delay added to a slowed pull
+    pub pull_delay_secs: u64,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            seed: 0,
+            provider_error: 0.1,
+            ssh_timeout: 0.1,
+            slow_pull: 0.2,
+            pull_delay_secs: 30,
+        }
+    }
+}
+
+/// Parse a `--chaos` profile, e.g. `seed=7,provider_error=0.2,ssh_timeout=0,slow_pull=0.5`.
+/// Unset chances keep their defaults.
+pub fn parse_profile(value: &str) -> Result<Profile, String> {
+    let mut profile = Profile::default();
+    for setting in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (name, value) = setting
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=VALUE, got '{}'", setting))?;
+        let chance = || match value.parse::<f64>() {
+            Ok(chance) if (0.0..=1.0).contains(&chance) => Ok(chance),
+            _ => Err(format!("{} must be between 0 and 1, got '{}'", name, value)),
+        };
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("{} must be a whole number, got '{}'", name, value))
+        };
+        match name {
+            "seed" => profile.seed = number()?,
+            "provider_error" => profile.provider_error = chance()?,
+            "ssh_timeout" => profile.ssh_timeout = chance()?,
+            "slow_pull" => profile.slow_pull = chance()?,
+            "pull_delay_secs" => profile.pull_delay_secs = number()?,
+            _ => return Err(format!("unknown chaos setting '{}'", name)),
+        }
+    }
+    Ok(profile)
+}
+
+/// Marks errors that chaos mode made up
+pub const INJECTED: &str = "injected fault";
+
+struct Chaos {
+    profile: Profile,
+    rng: u64,
+}
+
+static CHAOS: Mutex<Option<Chaos>> = Mutex::new(None);
+
+/// Start injecting faults
+pub fn enable(profile: Profile) {
+    warn!(
+        "Chaos mode enabled: injecting faults with {:?}. Do not use this in production.",
+        profile
+    );
+    *CHAOS.lock().unwrap_or_else(|e| e.into_inner()) = Some(Chaos {
+        rng: profile.seed,
+        profile,
+    });
+}
+
+/// splitmix64: small, seedable and good enough to pick faults
+fn next_random(state: &mut u64) -> f64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Whether to inject the fault whose chance `chance` picks from the profile
+fn roll(chance: impl Fn(&Profile) -> f64) -> bool {
+    let mut chaos = CHAOS.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(chaos) = chaos.as_mut() else {
+        return false;
+    };
+    next_random(&mut chaos.rng) < chance(&chaos.profile)
+}
+
+/// An injected fault
+#[derive(Debug)]
+pub struct Fault(String);
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", INJECTED, self.0)
+    }
+}
+
+impl std::error::Error for Fault {}
+
+/// Called before each provider API request; fails some of them in chaos mode
+pub fn provider_call(url: &str) -> Result<(), Fault> {
+    if roll(|profile| profile.provider_error) {
+        warn!("Chaos: failing provider API call to {}", url);
+        return Err(Fault(format!("provider API call to {} failed", url)));
+    }
+    Ok(())
+}
+
+/// Whether an SSH command should time out instead of running
+pub fn ssh_timeout(program: &str) -> bool {
+    let timeout = roll(|profile| profile.ssh_timeout);
+    if timeout {
+        warn!("Chaos: timing out {} command", program);
+    }
+    timeout
+}
+
+/// Called before an image pull; delays some of them in chaos mode
+pub async fn slow_pull(image: &str) {
+    if !roll(|profile| profile.slow_pull) {
+        return;
+    }
+    let delay = CHAOS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map_or(0, |chaos| chaos.profile.pull_delay_secs);
+    warn!("Chaos: slowing pull of '{}' by {}s", image, delay);
+    tokio::time::sleep(Duration::from_secs(delay)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profile() {
+        let profile = parse_profile("seed=7, provider_error=0.5,slow_pull=0").unwrap();
+        assert_eq!(
+            profile,
+            Profile {
+                seed: 7,
+                provider_error: 0.5,
+                slow_pull: 0.0,
+                ..Profile::default()
+            }
+        );
+        assert_eq!(parse_profile("").unwrap(), Profile::default());
+        assert!(parse_profile("provider_error=2").is_err());
+        assert!(parse_profile("seed").is_err());
+        assert!(parse_profile("network_split=0.1").is_err());
+    }
+
+    #[test]
+    fn test_same_seed_same_faults() {
+        let sequence = |seed: u64| {
+            let mut state = seed;
+            (0..20)
+                .map(|_| next_random(&mut state) < 0.3)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sequence(42), sequence(42));
+        assert_ne!(sequence(42), sequence(43));
+        let mut state = 1;
+        assert!((0..1000).all(|_| (0.0..1.0).contains(&next_random(&mut state))));
+    }
+}
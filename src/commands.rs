@@ -0,0 +1,287 @@
+use crate::config::{agent_config, CommandsConfig};
+use crate::health::get_vm_state_and_ip;
+use crate::pool;
+use crate::vm_provision::run_ssh_command;
+use crate::RunnerLogin;
+use chrono::Utc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashSet, VecDeque};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Output, Stdio};
+use tokio::process::Command;
+use tokio::time::{timeout, Duration};
+
+const AUDIT_LOG: &str = ".cirun-agent/commands.log";
+/// Output kept per stream; the rest is cut off
+const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+/// Command IDs remembered as started; the API stops sending a command once it has its result
+const MAX_STARTED_IDS: usize = 1000;
+/// Results kept until the API accepts them; the oldest are dropped beyond this
+pub const MAX_UNACKED_RESULTS: usize = 50;
+
+/// IDs of commands already started, so repeats are not run twice. Only the most recent
+/// `MAX_STARTED_IDS` are remembered.
+#[derive(Debug, Default)]
+pub struct StartedCommands {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl StartedCommands {
+    /// Remember `id`; false if it was already started
+    pub fn insert(&mut self, id: &str) -> bool {
+        if !self.ids.insert(id.to_string()) {
+            return false;
+        }
+        self.order.push_back(id.to_string());
+        while self.order.len() > MAX_STARTED_IDS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// An ad-hoc command the API asks the agent to run, on the host or inside a runner VM
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CommandRequest {
+    pub id: String,
+    pub command: String,
+    /// Runner to run the command in; unset runs it on the host
+    #[serde(default)]
+    pub runner: Option<String>,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+/// What a command did, sent back to the API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandResponse {
+    pub id: String,
+    pub command: String,
+    pub runner: Option<String>,
+    pub exit_code: Option<i32>,
+    pub output: String,
+    pub error: String,
+}
+
+impl CommandResponse {
+    /// A command that did not run, or did not finish
+    fn refused(request: &CommandRequest, error: String) -> Self {
+        CommandResponse {
+            id: request.id.clone(),
+            command: request.command.clone(),
+            runner: request.runner.clone(),
+            exit_code: None,
+            output: String::new(),
+            error,
+        }
+    }
+
+    fn finished(request: &CommandRequest, output: &Output) -> Self {
+        CommandResponse {
+            exit_code: output.status.code(),
+            output: truncated(&output.stdout),
+            ..CommandResponse::refused(request, truncated(&output.stderr))
+        }
+    }
+}
+
+/// `bytes` as text, cut to `MAX_OUTPUT_BYTES`
+fn truncated(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(&bytes[..bytes.len().min(MAX_OUTPUT_BYTES)]).to_string();
+    if bytes.len() > MAX_OUTPUT_BYTES {
+        format!(
+            "{}\n[truncated {} bytes]",
+            text,
+            bytes.len() - MAX_OUTPUT_BYTES
+        )
+    } else {
+        text
+    }
+}
+
+/// Program and arguments of a host command, if the allowlist permits the program. Host
+/// commands run without a shell, so arguments are split on whitespace and never expanded.
+fn host_argv(command: &str, config: &CommandsConfig) -> Result<Vec<String>, String> {
+    let argv: Vec<String> = command.split_whitespace().map(str::to_string).collect();
+    let program = argv.first().ok_or("Empty command")?;
+    if !config
+        .host_allowlist
+        .iter()
+        .any(|allowed| allowed == program)
+    {
+        return Err(format!("'{}' is not in commands.host_allowlist", program));
+    }
+    Ok(argv)
+}
+
+async fn run_on_host(request: &CommandRequest, timeout_secs: u64) -> CommandResponse {
+    let argv = match host_argv(&request.command, &agent_config().commands) {
+        Ok(argv) => argv,
+        Err(e) => return CommandResponse::refused(request, e),
+    };
+    let output = timeout(
+        Duration::from_secs(timeout_secs),
+        Command::new(&argv[0])
+            .args(&argv[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await;
+    match output {
+        Ok(Ok(output)) => CommandResponse::finished(request, &output),
+        Ok(Err(e)) => CommandResponse::refused(request, format!("Failed to run: {}", e)),
+        Err(_) => CommandResponse::refused(request, format!("Timed out after {}s", timeout_secs)),
+    }
+}
+
+async fn run_in_runner(
+    request: &CommandRequest,
+    runner_name: &str,
+    login: Option<&RunnerLogin>,
+    timeout_secs: u64,
+) -> CommandResponse {
+    if !agent_config().commands.runner_commands {
+        return CommandResponse::refused(
+            request,
+            "Runner commands are disabled (commands.runner_commands)".to_string(),
+        );
+    }
+    let Some(login) = login else {
+        return CommandResponse::refused(
+            request,
+            format!("No login known for runner '{}'", runner_name),
+        );
+    };
+    let ip_address = match get_vm_state_and_ip(runner_name).await {
+        Ok((state, Some(ip))) if state == "running" && !ip.is_empty() => ip,
+        Ok((state, _)) => {
+            return CommandResponse::refused(
+                request,
+                format!(
+                    "Runner '{}' is not reachable (state: {})",
+                    runner_name, state
+                ),
+            )
+        }
+        Err(e) => {
+            return CommandResponse::refused(
+                request,
+                format!("Runner '{}' not found: {}", runner_name, e),
+            )
+        }
+    };
+    let vm_name = pool::vm_name(runner_name);
+    match run_ssh_command(&vm_name, &ip_address, login, &request.command, timeout_secs).await {
+        Ok(output) => CommandResponse::finished(request, &output),
+        Err(e) => CommandResponse::refused(request, e.to_string()),
+    }
+}
+
+fn audit_log_path() -> PathBuf {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home_dir).join(AUDIT_LOG)
+}
+
+/// Log the command and append it to the audit log, one JSON object per line
+fn audit(response: &CommandResponse) {
+    let target = response.runner.as_deref().unwrap_or("host");
+    info!(
+        "Command {} on {} finished (exit code {:?}): {}",
+        response.id, target, response.exit_code, response.command
+    );
+    let entry = json!({
+        "time": Utc::now(),
+        "id": response.id,
+        "target": target,
+        "command": response.command,
+        "exit_code": response.exit_code,
+        "output_bytes": response.output.len(),
+        "error": response.error,
+    });
+    let path = audit_log_path();
+    let written = std::fs::create_dir_all(path.parent().unwrap_or(&path)).and_then(|_| {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        writeln!(file, "{}", entry)
+    });
+    if let Err(e) = written {
+        warn!("Failed to write command audit log {:?}: {}", path, e);
+    }
+}
+
+/// Run a command from the API, on the host or in its runner, and audit it. `login` is the
+/// runner's login when the command targets one.
+pub async fn execute(request: CommandRequest, login: Option<RunnerLogin>) -> CommandResponse {
+    let config = &agent_config().commands;
+    let target = request.runner.as_deref().unwrap_or("host");
+    info!(
+        "Running command {} from the API on {}: {}",
+        request.id, target, request.command
+    );
+    let timeout_secs = request
+        .timeout_secs
+        .unwrap_or(config.max_timeout_secs)
+        .min(config.max_timeout_secs);
+    let response = if !config.enabled {
+        CommandResponse::refused(&request, "Commands are disabled on this agent".to_string())
+    } else {
+        match &request.runner {
+            Some(runner_name) => {
+                run_in_runner(&request, runner_name, login.as_ref(), timeout_secs).await
+            }
+            None => run_on_host(&request, timeout_secs).await,
+        }
+    };
+    audit(&response);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_started_commands_are_bounded() {
+        let mut started = StartedCommands::default();
+        assert!(started.insert("cmd-0"));
+        assert!(!started.insert("cmd-0"));
+        for n in 1..=MAX_STARTED_IDS {
+            assert!(started.insert(&format!("cmd-{}", n)));
+        }
+        assert_eq!(started.ids.len(), MAX_STARTED_IDS);
+        // The oldest ID was forgotten, the newest are still known
+        assert!(started.insert("cmd-0"));
+        assert!(!started.insert(&format!("cmd-{}", MAX_STARTED_IDS)));
+    }
+
+    #[test]
+    fn test_host_allowlist() {
+        let config = CommandsConfig {
+            host_allowlist: vec!["uptime".to_string(), "/usr/bin/df".to_string()],
+            ..CommandsConfig::default()
+        };
+        assert_eq!(
+            host_argv("/usr/bin/df  -h /", &config).unwrap(),
+            vec!["/usr/bin/df", "-h", "/"]
+        );
+        assert!(host_argv("uptime", &config).is_ok());
+        assert!(host_argv("df -h", &config).is_err());
+        assert!(host_argv("rm -rf /", &config).is_err());
+        assert!(host_argv("  ", &config).is_err());
+        assert!(host_argv("uptime", &CommandsConfig::default()).is_err());
+
+        let long = vec![b'x'; MAX_OUTPUT_BYTES + 10];
+        assert!(truncated(&long).ends_with("[truncated 10 bytes]"));
+    }
+}
@@ -0,0 +1,1497 @@
+use crate::cpu_pinning::parse_cpu_list;
+use crate::os_detect::normalize_os;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use url::Url;
+
+const DEFAULT_CONFIG_FILE: &str = ".cirun-agent/config.toml";
+
+/// Shown instead of secrets when the configuration is printed
+const REDACTED: &str = "<redacted>";
+/// Script variables whose names contain any of these are treated as secrets
+const SECRET_NAME_MARKERS: &[&str] = &["token", "secret", "password", "key", "credential"];
+
+// Configuration loaded once at startup and shared by all provisioning tasks
+static CONFIG: OnceLock<AgentConfig> = OnceLock::new();
+
+/// Per-image settings: provider-specific aliases and where meda should get the image from
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ImageConfig {
+    /// Image to run on Linux hosts (meda)
+    pub meda: Option<String>,
+    /// Image or existing template/VM to clone on macOS hosts (lume)
+    pub lume: Option<String>,
+    /// Where meda fetches the image from, for hosts that cannot reach the default registries
+    pub source: Option<ImageSource>,
+    /// Host directories mounted into every macOS runner of this image (lume only)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shared_directories: Vec<SharedDirectoryConfig>,
+    /// I/O and network limits for runners of this image, over the `[qos]` defaults
+    #[serde(default, skip_serializing_if = "QosLimits::is_unlimited")]
+    pub qos: QosLimits,
+}
+
+/// Host cache (Homebrew, DerivedData, npm...) shared into lume runners
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SharedDirectoryConfig {
+    pub host_path: PathBuf,
+    #[serde(default)]
+    pub read_only: bool,
+    /// Guest path linked to the mounted directory, e.g. `~/Library/Caches/Homebrew`
+    pub guest_path: Option<String>,
+}
+
+/// Private or offline source for a meda image
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ImageSource {
+    /// Disk image downloaded from a local HTTP mirror
+    Http { url: String },
+    /// OCI registry that may require credentials
+    Registry {
+        registry: String,
+        /// Repository and tag on that registry; defaults to the image name
+        image: Option<String>,
+        username: Option<String>,
+        /// Environment variable holding the registry password or token
+        password_env: Option<String>,
+    },
+    /// Disk image already present on this host
+    File { path: PathBuf },
+}
+
+/// How the host tool cache reaches a new runner
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolCacheMethod {
+    /// Copy over SSH with rsync (works on both providers)
+    #[default]
+    Rsync,
+    /// Mount the directory into the VM (lume only) and copy it in place
+    SharedDirectory,
+}
+
+/// Host directory synced into every new runner before the provision script runs
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ToolCacheConfig {
+    pub host_path: PathBuf,
+    /// Destination in the guest; defaults to /opt/hostedtoolcache on Linux and
+    /// ~/hostedtoolcache on macOS
+    pub guest_path: Option<String>,
+    #[serde(default)]
+    pub method: ToolCacheMethod,
+}
+
+/// GitHub Actions runner tarballs cached on the host and pushed into each runner
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunnerCacheConfig {
+    pub enabled: bool,
+    /// Runner version to push, e.g. "2.319.1"; unset follows the latest release
+    pub version: Option<String>,
+    /// How often the latest release is looked up
+    pub refresh_hours: u64,
+    /// Where the tarball is placed on the guest
+    pub guest_path: String,
+}
+
+impl Default for RunnerCacheConfig {
+    fn default() -> Self {
+        RunnerCacheConfig {
+            enabled: false,
+            version: None,
+            refresh_hours: 24,
+            guest_path: "/tmp/actions-runner.tar.gz".to_string(),
+        }
+    }
+}
+
+/// Ad-hoc commands the API can ask the agent to run
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandsConfig {
+    pub enabled: bool,
+    /// Programs the API may run on the host; empty allows no host commands
+    pub host_allowlist: Vec<String>,
+    /// Allow commands inside runner VMs
+    pub runner_commands: bool,
+    /// Longest a command may run, whatever the API asks for
+    pub max_timeout_secs: u64,
+}
+
+impl Default for CommandsConfig {
+    fn default() -> Self {
+        CommandsConfig {
+            enabled: true,
+            host_allowlist: Vec::new(),
+            runner_commands: true,
+            max_timeout_secs: 300,
+        }
+    }
+}
+
+/// What to do when a supervised provider server exits
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    #[default]
+    Always,
+    OnFailure,
+    Never,
+}
+
+/// Certificate and key `serve` uses for HTTPS on the local provider API
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ProviderTls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA the agent trusts for the provider API; defaults to `cert` (self-signed)
+    pub ca: Option<PathBuf>,
+}
+
+/// How the agent runs `meda serve` / `lume serve`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProviderConfig {
+    /// Run the server as a child process of the agent instead of detaching it
+    pub supervise: bool,
+    pub restart: RestartPolicy,
+    /// Stop the supervised server when the agent exits. Off by default: lume VMs run inside
+    /// `lume serve`, so stopping it would kill every running runner.
+    pub stop_on_exit: bool,
+    /// The server runs as its own systemd/launchd service (installed with
+    /// `--install-service --provider-as-service`); the agent only makes sure it is up
+    pub service: bool,
+    /// Require a shared bearer token on the provider API (needs a provider that supports it)
+    pub auth_token: bool,
+    /// Serve the provider API over HTTPS (needs a provider that supports it)
+    pub tls: Option<ProviderTls>,
+}
+
+impl Default for ProviderConfig {
+    fn default() -> Self {
+        ProviderConfig {
+            supervise: true,
+            restart: RestartPolicy::Always,
+            stop_on_exit: false,
+            service: false,
+            auth_token: false,
+            tls: None,
+        }
+    }
+}
+
+/// Defaults for SSH connections to runner VMs; the API can override them per runner
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshConfig {
+    pub port: u16,
+    pub connect_timeout_secs: u64,
+    /// Connection attempts after the first while waiting for SSH after boot
+    /// (unset: backoff for lume, the whole `ssh_ready` timeout for meda)
+    pub retries: Option<u32>,
+    pub ciphers: Option<String>,
+    pub kex_algorithms: Option<String>,
+    /// Extra `-o` options, e.g. "ServerAliveInterval=15"
+    pub options: Vec<String>,
+    /// Bastion to reach runner VMs through (`ProxyJump`), e.g. "ops@bastion.internal:2222"
+    pub jump_host: Option<String>,
+    pub host_keys: HostKeyMode,
+    /// How long a shared master connection to a VM stays open when idle (0: no reuse)
+    pub control_persist_secs: u64,
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        SshConfig {
+            port: 22,
+            connect_timeout_secs: 10,
+            retries: None,
+            ciphers: None,
+            kex_algorithms: None,
+            options: Vec::new(),
+            jump_host: None,
+            host_keys: HostKeyMode::Off,
+            control_persist_secs: 60,
+        }
+    }
+}
+
+/// How the host keys of runner VMs are verified
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HostKeyMode {
+    /// Accept any key (VMs are recreated constantly and their keys are unknown)
+    #[default]
+    Off,
+    /// Trust the first key a VM presents and reject a different one later
+    Tofu,
+    /// Only trust keys read from the guest agent before connecting
+    Strict,
+}
+
+/// Default interpreter for provision scripts; the API can set one per runner
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptConfig {
+    /// bash, sh, zsh, pwsh or python3 (unset: bash on meda, the script's shebang on lume)
+    pub interpreter: Option<String>,
+    /// Arguments before the script path, replacing the interpreter's defaults
+    pub interpreter_args: Vec<String>,
+}
+
+/// Faster IP discovery for meda VMs from the host's DHCP leases and ARP table, and checks
+/// that a discovered address really is the VM
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpDiscoveryConfig {
+    /// Watch the host tables for the VM's MAC address while polling the API
+    pub fast_path: bool,
+    /// dnsmasq lease files or libvirt `.status` files to search
+    pub lease_files: Vec<PathBuf>,
+    /// Check the address against the ARP table and probe the SSH port before SSH
+    pub verify_address: bool,
+}
+
+impl Default for IpDiscoveryConfig {
+    fn default() -> Self {
+        IpDiscoveryConfig {
+            fast_path: false,
+            lease_files: vec![
+                PathBuf::from("/var/lib/misc/dnsmasq.leases"),
+                PathBuf::from("/var/lib/libvirt/dnsmasq/virbr0.status"),
+            ],
+            verify_address: true,
+        }
+    }
+}
+
+/// Overcommit policy used to admit runners, relative to the host's cores and RAM
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CapacityConfig {
+    /// vCPUs allowed per host core, e.g. 2.0; unset means vCPUs are not limited
+    pub cpu_overcommit: Option<f64>,
+    /// Runner memory allowed per byte of host RAM, e.g. 1.0; unset means not limited
+    pub memory_overcommit: Option<f64>,
+}
+
+/// Limit on how many runner VMs boot per host in a sliding window, so a burst of runners
+/// does not saturate the host and time out together
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootRampConfig {
+    /// Boots allowed per window; 0 boots every VM right away
+    pub max_boots: usize,
+    pub window_secs: u64,
+}
+
+impl Default for BootRampConfig {
+    fn default() -> Self {
+        BootRampConfig {
+            max_boots: 0,
+            window_secs: 30,
+        }
+    }
+}
+
+/// When the agent asks the API to slow down assigning runners. Exhausted VM slots or
+/// overcommit headroom always count; the queue only with a threshold.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackpressureConfig {
+    /// Runners provisioning or waiting for room above which the agent is overloaded; 0
+    /// turns the queue check off
+    pub queue_threshold: usize,
+}
+
+/// Templates runners are provisioned from when the template or image for their own image
+/// cannot be prepared, per runner OS. An unset OS has no fallback, so its runners fail.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FallbackTemplateConfig {
+    pub linux: Option<String>,
+    pub macos: Option<String>,
+    pub windows: Option<String>,
+}
+
+impl FallbackTemplateConfig {
+    /// Fallback for runners of `os`, as the API or an image name spells it, e.g. "macOS"
+    pub fn for_os(&self, os: &str) -> Option<&str> {
+        match normalize_os(os)? {
+            "linux" => self.linux.as_deref(),
+            "macos" => self.macos.as_deref(),
+            "windows" => self.windows.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Dedicated host cores for runner VMs on meda. Runners the API marks `dedicated_cpus` are
+/// pinned even when `enabled` is off.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CpuPinningConfig {
+    /// Pin every runner
+    pub enabled: bool,
+    /// Host cores runners may be pinned to, e.g. "2-15"; unset allows every online core
+    pub cores: Option<String>,
+    /// Keep each runner's cores and memory on a single NUMA node
+    pub numa: bool,
+}
+
+/// Disk and network throttling of a runner VM (meda only), so one noisy build cannot
+/// starve the other runners on the host. Unset limits are unlimited.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct QosLimits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_read_iops: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_write_iops: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_read_mbps: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_write_mbps: Option<u32>,
+    /// Network rate into the guest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub net_rx_mbps: Option<u32>,
+    /// Network rate out of the guest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub net_tx_mbps: Option<u32>,
+}
+
+impl QosLimits {
+    pub fn is_unlimited(&self) -> bool {
+        *self == QosLimits::default()
+    }
+
+    /// These limits, with any unset one taken from `fallback`
+    pub fn or(self, fallback: QosLimits) -> QosLimits {
+        QosLimits {
+            disk_read_iops: self.disk_read_iops.or(fallback.disk_read_iops),
+            disk_write_iops: self.disk_write_iops.or(fallback.disk_write_iops),
+            disk_read_mbps: self.disk_read_mbps.or(fallback.disk_read_mbps),
+            disk_write_mbps: self.disk_write_mbps.or(fallback.disk_write_mbps),
+            net_rx_mbps: self.net_rx_mbps.or(fallback.net_rx_mbps),
+            net_tx_mbps: self.net_tx_mbps.or(fallback.net_tx_mbps),
+        }
+    }
+
+    /// Each limit and its setting name
+    fn entries(&self) -> [(&'static str, Option<u32>); 6] {
+        [
+            ("disk_read_iops", self.disk_read_iops),
+            ("disk_write_iops", self.disk_write_iops),
+            ("disk_read_mbps", self.disk_read_mbps),
+            ("disk_write_mbps", self.disk_write_mbps),
+            ("net_rx_mbps", self.net_rx_mbps),
+            ("net_tx_mbps", self.net_tx_mbps),
+        ]
+    }
+}
+
+/// A provider API on another hypervisor host, of the same kind (meda or lume) as the
+/// local one. Runners are placed on the endpoint with the most free capacity.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EndpointConfig {
+    /// Name used in logs, placements and reports
+    pub name: String,
+    /// Base API URL, e.g. `https://hv-2.internal:7777/api/v1`
+    pub url: String,
+    /// Bearer token for the remote API
+    pub token: Option<String>,
+    /// Environment variable holding the token, instead of `token`
+    pub token_env: Option<String>,
+    /// CA certificate to trust for an HTTPS endpoint
+    pub ca: Option<PathBuf>,
+    /// Most runner VMs to keep on this endpoint; unset means no limit
+    pub max_vms: Option<u32>,
+    /// SSH jump host used to reach runners on this endpoint
+    pub ssh_jump_host: Option<String>,
+}
+
+/// How the agent detects that a runner VM finished booting
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadinessMethod {
+    /// Retry SSH until it connects
+    #[default]
+    Ssh,
+    /// Ping the QEMU guest agent over its host socket
+    GuestAgent,
+    /// Wait for the guest to call `GET /ready/<vm-name>` on the agent
+    Beacon,
+}
+
+/// An HTTP endpoint that receives runner events as JSON
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Event names to send, e.g. `["vm_ready", "provision_failed"]`; empty sends all
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+/// Chat service a notifier posts to
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatService {
+    Slack,
+    Teams,
+}
+
+/// Which events a notifier posts
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyOn {
+    #[default]
+    Failures,
+    All,
+}
+
+/// Human-readable event messages posted to a Slack or Teams incoming webhook
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NotifierConfig {
+    pub service: ChatService,
+    pub url: String,
+    #[serde(default)]
+    pub on: NotifyOn,
+}
+
+/// Subscribers to the agent's runner events
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    /// File every event is appended to as a JSON line
+    pub audit_log: Option<PathBuf>,
+    pub webhooks: Vec<WebhookConfig>,
+    pub notifiers: Vec<NotifierConfig>,
+}
+
+/// Names of the VMs and templates the agent creates. Only VMs under the prefix are
+/// reported and reaped, so manually managed VMs on the same hypervisor are left alone.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamingConfig {
+    /// Start of every VM and template name, e.g. "cirun-prod-"
+    pub prefix: String,
+}
+
+impl Default for NamingConfig {
+    fn default() -> Self {
+        NamingConfig {
+            prefix: "cirun-".to_string(),
+        }
+    }
+}
+
+/// Reuse pool of pre-cloned VMs, refilled as runners are deleted
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    pub enabled: bool,
+    /// Most idle VMs kept per template and size
+    pub max_per_spec: usize,
+    /// Idle VMs older than this are deleted
+    pub max_idle_secs: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            enabled: false,
+            max_per_spec: 2,
+            max_idle_secs: 6 * 3600,
+        }
+    }
+}
+
+/// Retries of runners whose provisioning keeps failing, and of the single steps in between
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Consecutive failures after which the agent gives up on a runner, unless the API
+    /// sets `max_retries`
+    pub budget: u32,
+    /// Delay before the first retry; it doubles after every further failure
+    pub base_delay_secs: u64,
+    /// Upper bound on the delay between retries
+    pub max_delay_secs: u64,
+    /// Calls to meda or lume
+    pub provider_api: RetryPolicy,
+    /// Polls of the Cirun API
+    pub cirun_api: RetryPolicy,
+    /// SSH connections and script uploads
+    pub ssh: RetryPolicy,
+    /// Provision script runs; only lost connections are retried
+    pub script: RetryPolicy,
+    /// Files fetched for guests, e.g. the Actions runner
+    pub downloads: RetryPolicy,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            budget: 3,
+            base_delay_secs: 30,
+            max_delay_secs: 30 * 60,
+            provider_api: RetryPolicy {
+                max_retries: 5,
+                base_delay_ms: 500,
+                max_delay_secs: 30,
+            },
+            cirun_api: RetryPolicy {
+                max_retries: 2,
+                base_delay_ms: 1000,
+                max_delay_secs: 10,
+            },
+            ssh: RetryPolicy {
+                max_retries: 10,
+                ..RetryPolicy::default()
+            },
+            script: RetryPolicy {
+                max_retries: 2,
+                ..RetryPolicy::default()
+            },
+            downloads: RetryPolicy {
+                max_retries: 3,
+                ..RetryPolicy::default()
+            },
+        }
+    }
+}
+
+/// Exponential backoff of one class of operations. Only failures that look transient are
+/// retried.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Attempts after the first
+    pub max_retries: usize,
+    /// Delay before the first retry; it doubles after every further failure
+    pub base_delay_ms: u64,
+    /// Upper bound on the delay between retries
+    pub max_delay_secs: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            base_delay_ms: 1000,
+            max_delay_secs: 60,
+        }
+    }
+}
+
+/// Jitter and backoff of a recurring API call; the interval itself is a command line option
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CadenceConfig {
+    /// Each wait is randomly lengthened or shortened by up to this share of the interval
+    pub jitter_percent: u32,
+    /// Longest wait after repeated failures; the wait doubles after each one
+    pub max_backoff_secs: u64,
+    /// Longest wait while calls keep returning no work; unset keeps the interval
+    pub idle_max_secs: Option<u64>,
+}
+
+impl Default for CadenceConfig {
+    fn default() -> Self {
+        CadenceConfig {
+            jitter_percent: 10,
+            max_backoff_secs: 300,
+            idle_max_secs: None,
+        }
+    }
+}
+
+/// Diagnostics bundles assembled when provisioning fails
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiagnosticsConfig {
+    pub enabled: bool,
+    /// Also send each bundle to the API
+    pub upload: bool,
+    /// Bundles kept on disk; the oldest are removed first
+    pub max_bundles: usize,
+    /// Most lines of each log included in a bundle
+    pub log_lines: usize,
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> Self {
+        DiagnosticsConfig {
+            enabled: true,
+            upload: false,
+            max_bundles: 20,
+            log_lines: 500,
+        }
+    }
+}
+
+/// Temporary debug tunnels from the host to a runner VM's SSH port
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelConfig {
+    /// Off by default: a tunnel exposes a runner's SSH port on the host
+    pub enabled: bool,
+    /// Host address tunnels listen on
+    pub bind_address: String,
+    /// Lifetime of a tunnel whose request sets none
+    pub default_ttl_secs: u64,
+    /// Longest lifetime a request may ask for
+    pub max_ttl_secs: u64,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        TunnelConfig {
+            enabled: false,
+            bind_address: "127.0.0.1".to_string(),
+            default_ttl_secs: 15 * 60,
+            max_ttl_secs: 4 * 3600,
+        }
+    }
+}
+
+/// CPU, memory and disk usage sampled inside running runners
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    /// Seconds between sampling passes over all runners
+    pub interval_secs: u64,
+    /// Spare capacity added to peak usage when recommending smaller runner sizes
+    pub headroom_percent: u32,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: true,
+            interval_secs: 120,
+            headroom_percent: 25,
+        }
+    }
+}
+
+/// Behaviour while the Cirun API is unreachable
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OfflineConfig {
+    /// How long the last fetched desired state keeps being served
+    pub desired_state_max_age_secs: u64,
+    /// Most reports kept queued; the oldest are dropped first
+    pub max_queued_reports: usize,
+    /// Queued reports older than this are dropped
+    pub report_retention_secs: u64,
+}
+
+impl Default for OfflineConfig {
+    fn default() -> Self {
+        OfflineConfig {
+            desired_state_max_age_secs: 3600,
+            max_queued_reports: 1000,
+            report_retention_secs: 24 * 3600,
+        }
+    }
+}
+
+/// Coordination with other agents serving the same account
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoordinationConfig {
+    /// Take an API-issued lease on each runner name before provisioning it
+    pub leases: bool,
+    /// Requested lease lifetime; leases are renewed while provisioning runs
+    pub lease_ttl_secs: u64,
+}
+
+impl Default for CoordinationConfig {
+    fn default() -> Self {
+        CoordinationConfig {
+            leases: false,
+            lease_ttl_secs: 600,
+        }
+    }
+}
+
+/// Boot readiness detection, checked before the SSH wait
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadinessConfig {
+    pub method: ReadinessMethod,
+    /// Guest agent socket; `{name}` is replaced by the VM name
+    pub guest_agent_socket: String,
+    /// Port the agent listens on for boot beacons
+    pub beacon_port: u16,
+}
+
+impl Default for ReadinessConfig {
+    fn default() -> Self {
+        ReadinessConfig {
+            method: ReadinessMethod::Ssh,
+            guest_agent_socket: "~/.meda/vms/{name}/qga.sock".to_string(),
+            beacon_port: 7780,
+        }
+    }
+}
+
+/// Timeouts (in seconds) for every wait loop in provisioning, template handling and benchmarks
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// Waiting for a VM to get an IP address (meda runners, templates, benchmarks)
+    pub ip_wait_secs: u64,
+    /// IP wait for lume runners, which are started with retries before waiting
+    pub lume_runner_ip_wait_secs: u64,
+    /// A single SSH connection attempt
+    pub ssh_attempt_secs: u64,
+    /// Overall window for SSH to become reachable after boot
+    pub ssh_ready_secs: u64,
+    /// Copying the provision script to the VM
+    pub transfer_secs: u64,
+    /// Downloading an Actions runner release into the runner cache
+    pub runner_download_secs: u64,
+    /// Launching a detached provision script
+    pub script_launch_secs: u64,
+    /// Running a provision script to completion
+    pub script_secs: u64,
+    /// Pulling a lume image into a new template
+    pub image_pull_secs: u64,
+    /// Applying CPU/memory/disk settings to a new template
+    pub template_configure_secs: u64,
+    /// Waiting for a VM to stop
+    pub vm_stop_secs: u64,
+    /// Waiting for a deleted VM and its storage to disappear
+    pub vm_delete_secs: u64,
+    /// Waiting for an existing runner VM to leave a transitional state, e.g. `starting`
+    pub vm_settle_secs: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            ip_wait_secs: 300,
+            lume_runner_ip_wait_secs: 20,
+            ssh_attempt_secs: 30,
+            ssh_ready_secs: 300,
+            transfer_secs: 60,
+            runner_download_secs: 900,
+            script_launch_secs: 60,
+            script_secs: 600,
+            image_pull_secs: 1800,
+            template_configure_secs: 600,
+            vm_stop_secs: 120,
+            vm_delete_secs: 120,
+            vm_settle_secs: 120,
+        }
+    }
+}
+
+impl Timeouts {
+    /// Every timeout by name, without the `_secs` suffix
+    pub fn entries(&self) -> [(&'static str, u64); 13] {
+        [
+            ("ip_wait", self.ip_wait_secs),
+            ("lume_runner_ip_wait", self.lume_runner_ip_wait_secs),
+            ("ssh_attempt", self.ssh_attempt_secs),
+            ("ssh_ready", self.ssh_ready_secs),
+            ("transfer", self.transfer_secs),
+            ("runner_download", self.runner_download_secs),
+            ("script_launch", self.script_launch_secs),
+            ("script", self.script_secs),
+            ("image_pull", self.image_pull_secs),
+            ("template_configure", self.template_configure_secs),
+            ("vm_stop", self.vm_stop_secs),
+            ("vm_delete", self.vm_delete_secs),
+            ("vm_settle", self.vm_settle_secs),
+        ]
+    }
+
+    /// Override a single timeout by name (with or without the `_secs` suffix)
+    pub fn set(&mut self, name: &str, secs: u64) -> Result<(), String> {
+        let slot = match name.trim_end_matches("_secs") {
+            "ip_wait" => &mut self.ip_wait_secs,
+            "lume_runner_ip_wait" => &mut self.lume_runner_ip_wait_secs,
+            "ssh_attempt" => &mut self.ssh_attempt_secs,
+            "ssh_ready" => &mut self.ssh_ready_secs,
+            "transfer" => &mut self.transfer_secs,
+            "runner_download" => &mut self.runner_download_secs,
+            "script_launch" => &mut self.script_launch_secs,
+            "script" => &mut self.script_secs,
+            "image_pull" => &mut self.image_pull_secs,
+            "template_configure" => &mut self.template_configure_secs,
+            "vm_stop" => &mut self.vm_stop_secs,
+            "vm_settle" => &mut self.vm_settle_secs,
+            "vm_delete" => &mut self.vm_delete_secs,
+            _ => return Err(format!("unknown timeout '{}'", name)),
+        };
+        *slot = secs;
+        Ok(())
+    }
+}
+
+/// Parse a `NAME=SECS` timeout override from the command line
+pub fn parse_timeout_override(value: &str) -> Result<(String, u64), String> {
+    let (name, secs) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=SECS, got '{}'", value))?;
+    let secs: u64 = secs
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid number of seconds '{}'", secs))?;
+    if secs == 0 {
+        return Err("timeouts must be at least 1 second".to_string());
+    }
+    // Reject unknown names at parse time rather than at startup
+    Timeouts::default().set(name.trim(), secs)?;
+    Ok((name.trim().to_string(), secs))
+}
+
+/// macOS runners (lume)
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LumeConfig {
+    /// After growing a runner's disk beyond its template's, expand the guest's APFS
+    /// container so the space is usable
+    pub resize_guest_filesystem: bool,
+    /// Reuse image layers lume already downloaded when pulling a new template
+    pub pull_cache: bool,
+    pub template_setup: TemplateSetupConfig,
+    pub freshness: TemplateFreshnessConfig,
+}
+
+impl Default for LumeConfig {
+    fn default() -> Self {
+        LumeConfig {
+            resize_guest_filesystem: true,
+            pull_cache: true,
+            template_setup: TemplateSetupConfig::default(),
+            freshness: TemplateFreshnessConfig::default(),
+        }
+    }
+}
+
+/// Setup baked into each new template, so runners cloned from it only configure and
+/// register the Actions runner
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemplateSetupConfig {
+    /// Install the Actions runner and its dependencies
+    pub install_runner: bool,
+    /// Where the runner is unpacked, relative to the login user's home
+    pub runner_dir: String,
+    /// Host script run once in each new template, e.g. to install toolchains
+    pub script: Option<PathBuf>,
+}
+
+impl Default for TemplateSetupConfig {
+    fn default() -> Self {
+        TemplateSetupConfig {
+            install_runner: false,
+            runner_dir: "actions-runner".to_string(),
+            script: None,
+        }
+    }
+}
+
+impl TemplateSetupConfig {
+    pub fn enabled(&self) -> bool {
+        self.install_runner || self.script.is_some()
+    }
+}
+
+/// Checks of templates against the registry, so updates of their base image are noticed
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TemplateFreshnessConfig {
+    /// Hours between checks; 0 disables them
+    pub check_hours: u64,
+    /// Rebuild stale templates in the background
+    pub rebuild: bool,
+}
+
+impl Default for TemplateFreshnessConfig {
+    fn default() -> Self {
+        TemplateFreshnessConfig {
+            check_hours: 24,
+            rebuild: false,
+        }
+    }
+}
+
+/// Optional agent configuration file (TOML)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AgentConfig {
+    /// Map of API-provided image names to local images, e.g.
+    /// `[images."ubuntu-24.04"] meda = "cirunlabs/ubuntu:24.04"`
+    #[serde(default)]
+    pub images: HashMap<String, ImageConfig>,
+    /// Host-provided values available to provision scripts as `{{ name }}`
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    /// Tool cache preseeded into each runner
+    pub tool_cache: Option<ToolCacheConfig>,
+    #[serde(default)]
+    pub runner_cache: RunnerCacheConfig,
+    #[serde(default)]
+    pub commands: CommandsConfig,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub provider: ProviderConfig,
+    #[serde(default)]
+    pub ssh: SshConfig,
+    #[serde(default)]
+    pub script: ScriptConfig,
+    #[serde(default)]
+    pub ip_discovery: IpDiscoveryConfig,
+    #[serde(default)]
+    pub readiness: ReadinessConfig,
+    #[serde(default)]
+    pub capacity: CapacityConfig,
+    #[serde(default)]
+    pub boot_ramp: BootRampConfig,
+    #[serde(default)]
+    pub backpressure: BackpressureConfig,
+    #[serde(default)]
+    pub fallback_templates: FallbackTemplateConfig,
+    #[serde(default)]
+    pub cpu_pinning: CpuPinningConfig,
+    /// I/O and network limits applied to every runner
+    #[serde(default)]
+    pub qos: QosLimits,
+    #[serde(default)]
+    pub coordination: CoordinationConfig,
+    #[serde(default)]
+    pub offline: OfflineConfig,
+    #[serde(default)]
+    pub events: EventsConfig,
+    #[serde(default)]
+    pub naming: NamingConfig,
+    #[serde(default)]
+    pub pool: PoolConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub diagnostics: DiagnosticsConfig,
+    #[serde(default)]
+    pub tunnels: TunnelConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub lume: LumeConfig,
+    /// Polls for runners to provision and delete
+    #[serde(default)]
+    pub poll: CadenceConfig,
+    /// Reports of the agent's VMs
+    #[serde(default)]
+    pub report: CadenceConfig,
+    /// Provider APIs on other hosts that runners can be placed on
+    #[serde(default)]
+    pub endpoints: Vec<EndpointConfig>,
+}
+
+impl AgentConfig {
+    /// Load the configuration from an explicit path, or from `~/.cirun-agent/config.toml`
+    /// when it exists. A missing default file yields an empty configuration.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = match path {
+            Some(p) => p.to_path_buf(),
+            None => {
+                let default_path = default_config_path();
+                if !default_path.exists() {
+                    return Ok(Self::default());
+                }
+                default_path
+            }
+        };
+
+        let content = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read config file {:?}: {}", path, e))?;
+        let config: AgentConfig = toml::from_str(&content)
+            .map_err(|e| format!("Failed to parse config file {:?}: {}", path, e))?;
+        info!("Loaded configuration from {:?}", path);
+        Ok(config)
+    }
+
+    /// Resolve an API-provided image name through the alias table for the given provider
+    pub fn resolve_image(&self, image: &str, use_meda: bool) -> String {
+        let alias = self.images.get(image).and_then(|alias| {
+            if use_meda {
+                alias.meda.clone()
+            } else {
+                alias.lume.clone()
+            }
+        });
+
+        match alias {
+            Some(mapped) => {
+                info!("Image '{}' is aliased to '{}'", image, mapped);
+                mapped
+            }
+            None => image.to_string(),
+        }
+    }
+
+    /// Configured meda source for an API-provided image name, if any
+    pub fn image_source(&self, image: &str) -> Option<&ImageSource> {
+        self.images.get(image).and_then(|c| c.source.as_ref())
+    }
+
+    /// Host directories shared into lume runners of `image`
+    pub fn shared_directories(&self, image: &str) -> &[SharedDirectoryConfig] {
+        self.images
+            .get(image)
+            .map_or(&[], |c| c.shared_directories.as_slice())
+    }
+
+    /// Limits for a runner of `image`: those the API requested, then the image's, then `[qos]`
+    pub fn qos_limits(&self, image: &str, requested: QosLimits) -> QosLimits {
+        let image_limits = self.images.get(image).map(|c| c.qos).unwrap_or_default();
+        requested.or(image_limits).or(self.qos)
+    }
+
+    /// Settings read from environment variables: what reads them, the variable, and
+    /// whether it is set
+    pub fn env_references(&self) -> Vec<(String, String, bool)> {
+        let mut references = Vec::new();
+        let mut reference = |setting: String, var: &str| {
+            references.push((setting, var.to_string(), std::env::var_os(var).is_some()));
+        };
+        let mut images: Vec<_> = self.images.iter().collect();
+        images.sort_by_key(|(name, _)| *name);
+        for (name, image) in images {
+            if let Some(ImageSource::Registry {
+                password_env: Some(var),
+                ..
+            }) = &image.source
+            {
+                reference(format!("images.\"{}\".source.password_env", name), var);
+            }
+        }
+        for endpoint in &self.endpoints {
+            if let Some(var) = &endpoint.token_env {
+                reference(format!("endpoints.{}.token_env", endpoint.name), var);
+            }
+        }
+        references
+    }
+
+    /// Problems the file's schema cannot express: out-of-range values, conflicting
+    /// settings, missing files and unset environment variables
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        for (setting, var, set) in self.env_references() {
+            if !set {
+                errors.push(format!(
+                    "{}: environment variable {} is not set",
+                    setting, var
+                ));
+            }
+        }
+        let mut images: Vec<_> = self.images.iter().collect();
+        images.sort_by_key(|(name, _)| *name);
+        for (name, image) in images {
+            match &image.source {
+                Some(ImageSource::Http { url }) if Url::parse(url).is_err() => errors.push(
+                    format!("images.\"{}\".source.url: invalid URL '{}'", name, url),
+                ),
+                Some(ImageSource::File { path }) if !path.exists() => errors.push(format!(
+                    "images.\"{}\".source.path: {} does not exist",
+                    name,
+                    path.display()
+                )),
+                _ => {}
+            }
+            for (limit, value) in image.qos.entries() {
+                if value == Some(0) {
+                    errors.push(format!(
+                        "images.\"{}\".qos.{}: must be positive",
+                        name, limit
+                    ));
+                }
+            }
+            for (i, shared) in image.shared_directories.iter().enumerate() {
+                if !shared.host_path.is_dir() {
+                    errors.push(format!(
+                        "images.\"{}\".shared_directories[{}].host_path: {} is not a directory",
+                        name,
+                        i,
+                        shared.host_path.display()
+                    ));
+                }
+            }
+        }
+        if let Some(tool_cache) = &self.tool_cache {
+            if !tool_cache.host_path.is_dir() {
+                errors.push(format!(
+                    "tool_cache.host_path: {} is not a directory",
+                    tool_cache.host_path.display()
+                ));
+            }
+        }
+        if let Some(script) = &self.lume.template_setup.script {
+            if !script.is_file() {
+                errors.push(format!(
+                    "lume.template_setup.script: {} does not exist",
+                    script.display()
+                ));
+            }
+        }
+        for os in ["linux", "macos", "windows"] {
+            if self
+                .fallback_templates
+                .for_os(os)
+                .is_some_and(|template| template.trim().is_empty())
+            {
+                errors.push(format!("fallback_templates.{}: must not be empty", os));
+            }
+        }
+        if self.runner_cache.refresh_hours == 0 {
+            errors.push("runner_cache.refresh_hours: must be at least 1".to_string());
+        }
+        if !self.runner_cache.guest_path.starts_with('/') {
+            errors.push(format!(
+                "runner_cache.guest_path: '{}' is not an absolute path",
+                self.runner_cache.guest_path
+            ));
+        }
+        if let Some(tls) = &self.provider.tls {
+            for (setting, path) in [
+                ("cert", Some(&tls.cert)),
+                ("key", Some(&tls.key)),
+                ("ca", tls.ca.as_ref()),
+            ] {
+                if let Some(path) = path.filter(|path| !path.exists()) {
+                    errors.push(format!(
+                        "provider.tls.{}: {} does not exist",
+                        setting,
+                        path.display()
+                    ));
+                }
+            }
+        }
+        for (name, secs) in self.timeouts.entries() {
+            if secs == 0 {
+                errors.push(format!("timeouts.{}: must be at least 1 second", name));
+            }
+        }
+        for (section, cadence) in [("poll", &self.poll), ("report", &self.report)] {
+            if cadence.jitter_percent > 100 {
+                errors.push(format!("{}.jitter_percent: must be at most 100", section));
+            }
+        }
+        if self.retry.base_delay_secs > self.retry.max_delay_secs {
+            errors.push("retry.base_delay_secs: must not exceed retry.max_delay_secs".to_string());
+        }
+        if let Some(cores) = &self.cpu_pinning.cores {
+            if parse_cpu_list(cores).is_none_or(|cores| cores.is_empty()) {
+                errors.push(format!(
+                    "cpu_pinning.cores: '{}' is not a CPU list like \"2-15\"",
+                    cores
+                ));
+            }
+        }
+        for (limit, value) in self.qos.entries() {
+            if value == Some(0) {
+                errors.push(format!("qos.{}: must be positive", limit));
+            }
+        }
+        if self.naming.prefix.is_empty()
+            || !self
+                .naming
+                .prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            errors.push(format!(
+                "naming.prefix: '{}' must be non-empty letters, digits, '-' or '_'",
+                self.naming.prefix
+            ));
+        }
+        if self.boot_ramp.max_boots > 0 && self.boot_ramp.window_secs == 0 {
+            errors.push("boot_ramp.window_secs: must be positive".to_string());
+        }
+        for (class, policy) in [
+            ("provider_api", &self.retry.provider_api),
+            ("cirun_api", &self.retry.cirun_api),
+            ("ssh", &self.retry.ssh),
+            ("script", &self.retry.script),
+            ("downloads", &self.retry.downloads),
+        ] {
+            if policy.base_delay_ms == 0 {
+                errors.push(format!("retry.{}.base_delay_ms: must be positive", class));
+            }
+            if policy.base_delay_ms > policy.max_delay_secs.saturating_mul(1000) {
+                errors.push(format!(
+                    "retry.{}.base_delay_ms: must not exceed retry.{}.max_delay_secs",
+                    class, class
+                ));
+            }
+        }
+        for (setting, overcommit) in [
+            ("cpu_overcommit", self.capacity.cpu_overcommit),
+            ("memory_overcommit", self.capacity.memory_overcommit),
+        ] {
+            if overcommit.is_some_and(|ratio| ratio <= 0.0) {
+                errors.push(format!("capacity.{}: must be greater than 0", setting));
+            }
+        }
+        if self.tunnels.bind_address.parse::<IpAddr>().is_err() {
+            errors.push(format!(
+                "tunnels.bind_address: '{}' is not an IP address",
+                self.tunnels.bind_address
+            ));
+        }
+        if self.tunnels.default_ttl_secs > self.tunnels.max_ttl_secs {
+            errors
+                .push("tunnels.default_ttl_secs: must not exceed tunnels.max_ttl_secs".to_string());
+        }
+        if self.metrics.enabled && self.metrics.interval_secs == 0 {
+            errors.push("metrics.interval_secs: must be at least 1 second".to_string());
+        }
+        for (i, webhook) in self.events.webhooks.iter().enumerate() {
+            if Url::parse(&webhook.url).is_err() {
+                errors.push(format!("events.webhooks[{}].url: invalid URL", i));
+            }
+        }
+        for (i, notifier) in self.events.notifiers.iter().enumerate() {
+            if Url::parse(&notifier.url).is_err() {
+                errors.push(format!("events.notifiers[{}].url: invalid URL", i));
+            }
+        }
+        let mut endpoint_names = HashSet::new();
+        for endpoint in &self.endpoints {
+            if !endpoint_names.insert(endpoint.name.as_str()) {
+                errors.push(format!(
+                    "endpoints.{}: duplicate endpoint name",
+                    endpoint.name
+                ));
+            }
+            if Url::parse(&endpoint.url).is_err() {
+                errors.push(format!(
+                    "endpoints.{}.url: invalid URL '{}'",
+                    endpoint.name, endpoint.url
+                ));
+            }
+            if endpoint.token.is_some() && endpoint.token_env.is_some() {
+                errors.push(format!(
+                    "endpoints.{}: set either token or token_env, not both",
+                    endpoint.name
+                ));
+            }
+            if let Some(ca) = endpoint.ca.as_ref().filter(|ca| !ca.exists()) {
+                errors.push(format!(
+                    "endpoints.{}.ca: {} does not exist",
+                    endpoint.name,
+                    ca.display()
+                ));
+            }
+        }
+        errors
+    }
+
+    /// A copy safe to print: tokens, webhook URLs and secret-looking variables are masked
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        for (name, value) in config.variables.iter_mut() {
+            let name = name.to_lowercase();
+            if SECRET_NAME_MARKERS
+                .iter()
+                .any(|marker| name.contains(marker))
+            {
+                *value = REDACTED.to_string();
+            }
+        }
+        for endpoint in &mut config.endpoints {
+            if endpoint.token.is_some() {
+                endpoint.token = Some(REDACTED.to_string());
+            }
+        }
+        for webhook in &mut config.events.webhooks {
+            webhook.url = REDACTED.to_string();
+        }
+        for notifier in &mut config.events.notifiers {
+            notifier.url = REDACTED.to_string();
+        }
+        config
+    }
+}
+
+/// Install the configuration loaded at startup. Later calls are ignored.
+pub fn set_agent_config(config: AgentConfig) {
+    let _ = CONFIG.set(config);
+}
+
+/// The startup configuration, or an empty one if none was installed
+pub fn agent_config() -> &'static AgentConfig {
+    CONFIG.get_or_init(AgentConfig::default)
+}
+
+pub fn default_config_path() -> PathBuf {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home_dir).join(DEFAULT_CONFIG_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_alias_resolution() {
+        let config: AgentConfig = toml::from_str(
+            r#"
+            [images."ubuntu-24.04"]
+            meda = "cirunlabs/ubuntu:24.04"
+
+            [images."macos-15"]
+            lume = "cirun-template-macos-15"
+
+            [images."ubuntu-offline"]
+            source = { type = "file", path = "/srv/images/ubuntu.qcow2" }
+            qos = { disk_write_mbps = 100 }
+
+            [qos]
+            disk_write_mbps = 200
+            net_tx_mbps = 50
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.resolve_image("ubuntu-24.04", true),
+            "cirunlabs/ubuntu:24.04"
+        );
+        // No lume mapping: the original name is kept
+        assert_eq!(config.resolve_image("ubuntu-24.04", false), "ubuntu-24.04");
+        assert_eq!(
+            config.resolve_image("macos-15", false),
+            "cirun-template-macos-15"
+        );
+        assert_eq!(config.resolve_image("unmapped", true), "unmapped");
+
+        assert_eq!(
+            config.image_source("ubuntu-offline"),
+            Some(&ImageSource::File {
+                path: PathBuf::from("/srv/images/ubuntu.qcow2")
+            })
+        );
+        assert_eq!(config.image_source("ubuntu-24.04"), None);
+        // The request overrides the image, which overrides `[qos]`
+        let requested = QosLimits {
+            net_tx_mbps: Some(10),
+            ..QosLimits::default()
+        };
+        assert_eq!(
+            config.qos_limits("ubuntu-offline", requested),
+            QosLimits {
+                disk_write_mbps: Some(100),
+                net_tx_mbps: Some(10),
+                ..QosLimits::default()
+            }
+        );
+        assert_eq!(
+            config
+                .qos_limits("ubuntu-24.04", QosLimits::default())
+                .disk_write_mbps,
+            Some(200)
+        );
+        assert_eq!(config.timeouts, Timeouts::default());
+        assert_eq!(config.provider, ProviderConfig::default());
+        assert_eq!(config.ssh, SshConfig::default());
+    }
+
+    #[test]
+    fn test_timeout_overrides() {
+        let config: AgentConfig = toml::from_str("[timeouts]\nip_wait_secs = 600").unwrap();
+        assert_eq!(config.timeouts.ip_wait_secs, 600);
+        assert_eq!(config.timeouts.script_secs, 600);
+
+        assert_eq!(
+            parse_timeout_override("ssh_ready=90"),
+            Ok(("ssh_ready".to_string(), 90))
+        );
+        assert!(parse_timeout_override("ssh_ready").is_err());
+        assert!(parse_timeout_override("bogus=10").is_err());
+        assert!(parse_timeout_override("script=0").is_err());
+    }
+
+    #[test]
+    fn test_validate_and_redact() {
+        let config: AgentConfig = toml::from_str(
+            r#"
+            variables = { region = "eu", api_token = "hunter2" }
+
+            [timeouts]
+            script_secs = 0
+
+            [poll]
+            jitter_percent = 150
+
+            [fallback_templates]
+            macos = "cirun-runner-template"
+            linux = " "
+
+            [[endpoints]]
+            name = "hv-2"
+            url = "not a url"
+            token = "secret"
+            token_env = "CIRUN_TEST_UNSET_TOKEN"
+            "#,
+        )
+        .unwrap();
+
+        let errors = config.validate();
+        for expected in [
+            "endpoints.hv-2.token_env: environment variable CIRUN_TEST_UNSET_TOKEN is not set",
+            "timeouts.script: must be at least 1 second",
+            "poll.jitter_percent: must be at most 100",
+            "endpoints.hv-2.url: invalid URL 'not a url'",
+            "endpoints.hv-2: set either token or token_env, not both",
+            "fallback_templates.linux: must not be empty",
+        ] {
+            assert!(
+                errors.iter().any(|e| e == expected),
+                "missing: {}",
+                expected
+            );
+        }
+        assert_eq!(errors.len(), 6);
+        assert!(AgentConfig::default().validate().is_empty());
+        assert_eq!(
+            config.fallback_templates.for_os("macos"),
+            Some("cirun-runner-template")
+        );
+        assert_eq!(config.fallback_templates.for_os("windows"), None);
+        // The API spells the OS its own way
+        assert_eq!(
+            config.fallback_templates.for_os("macOS"),
+            Some("cirun-runner-template")
+        );
+
+        let redacted = config.redacted();
+        assert_eq!(redacted.variables["region"], "eu");
+        assert_eq!(redacted.variables["api_token"], REDACTED);
+        assert_eq!(redacted.endpoints[0].token.as_deref(), Some(REDACTED));
+        let printed = toml::to_string_pretty(&redacted).unwrap();
+        assert!(!printed.contains("hunter2") && !printed.contains("secret"));
+    }
+}
@@ -0,0 +1,291 @@
+use crate::config::{agent_config, AgentConfig, ImageSource};
+use crate::os_detect::split_image_reference;
+use crate::use_meda;
+use chrono::{DateTime, Utc};
+use reqwest::redirect::Policy;
+use reqwest::Client;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+use url::Url;
+
+const PROBE_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_REGISTRY: &str = "ghcr.io";
+/// Where ghcr.io redirects image layer downloads
+const GHCR_BLOBS: &str = "https://pkg-containers.githubusercontent.com";
+
+/// A host the agent connects to, and what for
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub purpose: &'static str,
+    pub url: String,
+    /// Whether the agent cannot do its job without it
+    pub required: bool,
+    /// Only the origin is reported, e.g. for webhooks whose path holds a secret
+    pub secret_path: bool,
+}
+
+impl Endpoint {
+    fn new(purpose: &'static str, url: String, required: bool) -> Self {
+        Endpoint {
+            purpose,
+            url,
+            required,
+            secret_path: false,
+        }
+    }
+}
+
+/// What probing an endpoint found
+#[derive(Debug, Serialize)]
+pub struct ProbeResult {
+    pub purpose: &'static str,
+    pub url: String,
+    pub host: String,
+    pub port: u16,
+    pub required: bool,
+    /// Whether the endpoint answered over HTTP at all; any status counts
+    pub reachable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Egress the agent needs from this host, as printed by `connectivity-check`
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub checked_at: DateTime<Utc>,
+    /// Whether every required endpoint answered
+    pub ok: bool,
+    /// `host:port` of every required endpoint, for firewall rules
+    pub required_egress: Vec<String>,
+    pub endpoints: Vec<ProbeResult>,
+}
+
+/// Registries the configured images are pulled from, plus the default one
+fn registries(config: &AgentConfig) -> BTreeSet<String> {
+    let mut registries = BTreeSet::from([DEFAULT_REGISTRY.to_string()]);
+    for image in config.images.values() {
+        let reference = if use_meda() { &image.meda } else { &image.lume };
+        if let Some(reference) = reference {
+            registries.insert(split_image_reference(reference).0);
+        }
+        if let Some(ImageSource::Registry { registry, .. }) = &image.source {
+            registries.insert(registry.clone());
+        }
+    }
+    registries
+}
+
+/// Every endpoint the agent may connect to with this configuration
+pub fn endpoints(api_url: &str, config: &AgentConfig) -> Vec<Endpoint> {
+    let mut endpoints = vec![Endpoint::new("cirun_api", api_url.to_string(), true)];
+
+    // Provider releases are only downloaded when no local binary is provided
+    let (binary_env, releases) = if use_meda() {
+        ("MEDA_BINARY", "https://github.com/cirunlabs/meda/releases")
+    } else {
+        ("LUME_BINARY", "https://github.com/trycua/cua/releases")
+    };
+    let download_provider = std::env::var_os(binary_env).is_none();
+    endpoints.push(Endpoint::new(
+        "provider_download",
+        releases.to_string(),
+        download_provider,
+    ));
+    if use_meda() {
+        endpoints.push(Endpoint::new(
+            "provider_download",
+            "https://raw.githubusercontent.com".to_string(),
+            download_provider,
+        ));
+    }
+    // GitHub serves release assets from these hosts
+    for host in [
+        "https://objects.githubusercontent.com",
+        "https://release-assets.githubusercontent.com",
+    ] {
+        endpoints.push(Endpoint::new(
+            "release_assets",
+            host.to_string(),
+            download_provider || config.runner_cache.enabled,
+        ));
+    }
+
+    if config.runner_cache.enabled {
+        if config.runner_cache.version.is_none() {
+            endpoints.push(Endpoint::new(
+                "actions_runner_release",
+                "https://api.github.com".to_string(),
+                true,
+            ));
+        }
+        endpoints.push(Endpoint::new(
+            "actions_runner_download",
+            "https://github.com/actions/runner/releases".to_string(),
+            true,
+        ));
+    }
+
+    for registry in registries(config) {
+        endpoints.push(Endpoint::new(
+            "image_registry",
+            format!("https://{}/v2/", registry),
+            true,
+        ));
+        if registry == DEFAULT_REGISTRY {
+            endpoints.push(Endpoint::new("image_layers", GHCR_BLOBS.to_string(), true));
+        }
+    }
+    for image in config.images.values() {
+        if let Some(ImageSource::Http { url }) = &image.source {
+            endpoints.push(Endpoint::new("image_mirror", url.clone(), true));
+        }
+    }
+
+    for endpoint in &config.endpoints {
+        endpoints.push(Endpoint::new("remote_endpoint", endpoint.url.clone(), true));
+    }
+    let hooks = config
+        .events
+        .webhooks
+        .iter()
+        .map(|webhook| &webhook.url)
+        .chain(config.events.notifiers.iter().map(|notifier| &notifier.url));
+    for url in hooks {
+        endpoints.push(Endpoint {
+            secret_path: true,
+            ..Endpoint::new("event_webhook", url.clone(), false)
+        });
+    }
+    endpoints
+}
+
+/// Connect to an endpoint. Any HTTP answer, even an error status, shows the host is
+/// reachable; redirects are not followed, since their targets are listed separately.
+async fn probe(client: &Client, endpoint: &Endpoint) -> ProbeResult {
+    let parsed = Url::parse(&endpoint.url).ok();
+    let host = parsed
+        .as_ref()
+        .and_then(|url| url.host_str())
+        .unwrap_or_default()
+        .to_string();
+    let port = parsed
+        .as_ref()
+        .and_then(Url::port_or_known_default)
+        .unwrap_or(443);
+    let url = match (&parsed, endpoint.secret_path) {
+        (Some(parsed), true) => parsed.origin().ascii_serialization(),
+        _ => endpoint.url.clone(),
+    };
+    let mut result = ProbeResult {
+        purpose: endpoint.purpose,
+        url,
+        host,
+        port,
+        required: endpoint.required,
+        reachable: false,
+        status: None,
+        latency_ms: None,
+        error: None,
+    };
+    if parsed.is_none() {
+        result.error = Some("invalid URL".to_string());
+        return result;
+    }
+    let start = Instant::now();
+    match client.get(&endpoint.url).send().await {
+        Ok(response) => {
+            result.reachable = true;
+            result.status = Some(response.status().as_u16());
+            result.latency_ms = Some(start.elapsed().as_millis() as u64);
+        }
+        Err(e) => {
+            // The full error chain names the failing step (DNS, connect, TLS)
+            let mut message = e.to_string();
+            let mut source = std::error::Error::source(&e);
+            while let Some(cause) = source {
+                message = format!("{}: {}", message, cause);
+                source = cause.source();
+            }
+            result.error = Some(message);
+        }
+    }
+    result
+}
+
+/// Probe every endpoint the agent needs, all at once
+pub async fn check(api_url: &str) -> Report {
+    let client = Client::builder()
+        .timeout(Duration::from_secs(PROBE_TIMEOUT_SECS))
+        .redirect(Policy::none())
+        .build()
+        .expect("Failed to build HTTP client");
+    let probes: Vec<_> = endpoints(api_url, agent_config())
+        .into_iter()
+        .map(|endpoint| {
+            let client = client.clone();
+            tokio::spawn(async move { probe(&client, &endpoint).await })
+        })
+        .collect();
+    let mut results = Vec::new();
+    for probe in probes {
+        if let Ok(result) = probe.await {
+            results.push(result);
+        }
+    }
+    let required_egress: BTreeSet<String> = results
+        .iter()
+        .filter(|result| result.required && !result.host.is_empty())
+        .map(|result| format!("{}:{}", result.host, result.port))
+        .collect();
+    Report {
+        checked_at: Utc::now(),
+        ok: results
+            .iter()
+            .all(|result| !result.required || result.reachable),
+        required_egress: required_egress.into_iter().collect(),
+        endpoints: results,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ImageConfig, WebhookConfig};
+
+    #[test]
+    fn test_endpoints() {
+        let mut config = AgentConfig::default();
+        config.images.insert(
+            "private".to_string(),
+            ImageConfig {
+                meda: Some("registry.example.com/ci/ubuntu:24.04".to_string()),
+                lume: Some("registry.example.com/ci/macos:15".to_string()),
+                ..ImageConfig::default()
+            },
+        );
+        config.events.webhooks.push(WebhookConfig {
+            url: "https://hooks.example.com/T0/secret".to_string(),
+            events: Vec::new(),
+        });
+        let endpoints = endpoints("https://api.cirun.io/api/v1", &config);
+
+        assert_eq!(endpoints[0].purpose, "cirun_api");
+        assert!(endpoints
+            .iter()
+            .any(|e| e.url == "https://registry.example.com/v2/" && e.required));
+        assert!(endpoints.iter().any(|e| e.url == GHCR_BLOBS));
+        let webhook = endpoints
+            .iter()
+            .find(|e| e.purpose == "event_webhook")
+            .unwrap();
+        assert!(webhook.secret_path && !webhook.required);
+        assert!(!endpoints
+            .iter()
+            .any(|e| e.purpose == "actions_runner_download"));
+    }
+}
@@ -0,0 +1,84 @@
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+/// Set the console output mode from `--quiet` and `--plain`
+pub fn configure(quiet: bool, plain: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+    PLAIN.store(plain, Ordering::Relaxed);
+}
+
+/// Routine per-request lines are logged at debug level instead of info
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// No banner, emoji or colors, for journald and log pipelines
+pub fn plain() -> bool {
+    PLAIN.load(Ordering::Relaxed)
+}
+
+/// Whether the startup banner is printed
+pub fn show_banner() -> bool {
+    !quiet() && !plain()
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(c as u32,
+        0x2600..=0x27BF      // symbols and dingbats, e.g. ✅ ❌ ⚠
+        | 0x1F000..=0x1FAFF  // pictographs, e.g. 🚀
+        | 0xFE0F             // emoji presentation selector
+    )
+}
+
+/// `text` without emoji (and the space following one) in plain mode
+pub fn text(text: &str) -> Cow<'_, str> {
+    if !plain() || !text.chars().any(is_emoji) {
+        return Cow::Borrowed(text);
+    }
+    Cow::Owned(strip_emoji(text))
+}
+
+fn strip_emoji(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if is_emoji(c) {
+            if chars.peek() == Some(&' ') {
+                chars.next();
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// Log routine, per-request detail: at info level normally, at debug level with `--quiet`
+macro_rules! chatty {
+    ($($arg:tt)*) => {
+        if $crate::console::quiet() {
+            log::debug!($($arg)*)
+        } else {
+            log::info!($($arg)*)
+        }
+    };
+}
+pub(crate) use chatty;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_emoji() {
+        assert_eq!(
+            strip_emoji("✅ Deleted expired runner: r-1"),
+            "Deleted expired runner: r-1"
+        );
+        assert_eq!(strip_emoji("❌ Failed ⚠️ twice"), "Failed twice");
+        assert_eq!(strip_emoji("plain line"), "plain line");
+    }
+}
@@ -0,0 +1,202 @@
+use crate::config::agent_config;
+use crate::endpoints;
+use crate::state::StateStore;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+const NODES_DIR: &str = "/sys/devices/system/node";
+const ONLINE_CPUS: &str = "/sys/devices/system/cpu/online";
+
+/// Host cores a runner VM's vCPUs are pinned to, one vCPU per core
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CpuPinning {
+    pub cores: Vec<u32>,
+    /// NUMA node holding every core, and the VM's memory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub numa_node: Option<u32>,
+}
+
+/// Cores of a Linux CPU list, e.g. `0-3,8,10-11`
+pub fn parse_cpu_list(list: &str) -> Option<Vec<u32>> {
+    let mut cores = Vec::new();
+    for part in list.trim().split(',').filter(|part| !part.is_empty()) {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last): (u32, u32) = (first.parse().ok()?, last.parse().ok()?);
+                if first > last {
+                    return None;
+                }
+                cores.extend(first..=last);
+            }
+            None => cores.push(part.parse().ok()?),
+        }
+    }
+    Some(cores)
+}
+
+/// Online cores per NUMA node; hosts without NUMA information form a single unnamed node
+fn host_nodes() -> Vec<(Option<u32>, Vec<u32>)> {
+    let mut nodes: Vec<(Option<u32>, Vec<u32>)> = std::fs::read_dir(NODES_DIR)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let node = name.strip_prefix("node")?.parse().ok()?;
+            let cpulist = std::fs::read_to_string(entry.path().join("cpulist")).ok()?;
+            Some((Some(node), parse_cpu_list(&cpulist)?))
+        })
+        .collect();
+    if nodes.is_empty() {
+        let online = std::fs::read_to_string(ONLINE_CPUS)
+            .ok()
+            .and_then(|list| parse_cpu_list(&list))
+            .unwrap_or_default();
+        nodes.push((None, online));
+    }
+    nodes.sort();
+    nodes
+}
+
+/// Pick `count` free cores. A single NUMA node that fits is preferred, the fullest one
+/// first so larger gaps stay free; with `single_node` nothing else is accepted.
+fn choose(
+    nodes: &[(Option<u32>, Vec<u32>)],
+    allowed: Option<&[u32]>,
+    used: &HashSet<u32>,
+    count: usize,
+    single_node: bool,
+) -> Option<CpuPinning> {
+    let free: Vec<(Option<u32>, Vec<u32>)> = nodes
+        .iter()
+        .map(|(node, cores)| {
+            let cores = cores
+                .iter()
+                .copied()
+                .filter(|core| !used.contains(core))
+                .filter(|core| allowed.is_none_or(|allowed| allowed.contains(core)))
+                .collect();
+            (*node, cores)
+        })
+        .collect();
+    let fitting = free
+        .iter()
+        .filter(|(_, cores)| cores.len() >= count)
+        .min_by_key(|(_, cores)| cores.len());
+    if let Some((node, cores)) = fitting {
+        return Some(CpuPinning {
+            cores: cores[..count].to_vec(),
+            numa_node: *node,
+        });
+    }
+    if single_node {
+        return None;
+    }
+    let mut spread: Vec<u32> = free.into_iter().flat_map(|(_, cores)| cores).collect();
+    spread.sort_unstable();
+    (spread.len() >= count).then(|| CpuPinning {
+        cores: spread[..count].to_vec(),
+        numa_node: None,
+    })
+}
+
+/// Dedicated cores for a new runner VM with `vcpus` vCPUs, when `[cpu_pinning]` pins every
+/// runner or the API asked for it. Cores stay taken until the runner is forgotten, and a
+/// runner that is provisioned again keeps its cores.
+pub fn allocate(
+    runner_name: &str,
+    vcpus: u32,
+    requested: bool,
+) -> Result<Option<CpuPinning>, String> {
+    let config = &agent_config().cpu_pinning;
+    if !config.enabled && !requested {
+        return Ok(None);
+    }
+    // Only this host's topology is known, and its cores are the ones tracked in the state
+    if let Some(endpoint) = endpoints::current() {
+        warn!(
+            "Not pinning runner '{}': CPU pinning is not supported on remote endpoint '{}'",
+            runner_name, endpoint.name
+        );
+        return Ok(None);
+    }
+    let allowed = config.cores.as_deref().and_then(parse_cpu_list);
+    let nodes = host_nodes();
+    let pinning = StateStore::new().update(|state| {
+        if let Some(existing) = state
+            .runners
+            .get(runner_name)
+            .and_then(|record| record.cpu_pinning.clone())
+            .filter(|existing| existing.cores.len() == vcpus as usize)
+        {
+            return Some(existing);
+        }
+        let used: HashSet<u32> = state
+            .runners
+            .iter()
+            .filter(|(name, _)| name.as_str() != runner_name)
+            .filter_map(|(_, record)| record.cpu_pinning.as_ref())
+            .flat_map(|pinning| pinning.cores.iter().copied())
+            .collect();
+        let pinning = choose(
+            &nodes,
+            allowed.as_deref(),
+            &used,
+            vcpus as usize,
+            config.numa,
+        )?;
+        if let Some(record) = state.runners.get_mut(runner_name) {
+            record.cpu_pinning = Some(pinning.clone());
+        }
+        Some(pinning)
+    });
+    match pinning {
+        Some(pinning) => {
+            info!(
+                "Pinning runner '{}' to cores {:?} (NUMA node {:?})",
+                runner_name, pinning.cores, pinning.numa_node
+            );
+            Ok(Some(pinning))
+        }
+        None => Err(format!(
+            "Not enough free host cores to pin {} vCPUs{}",
+            vcpus,
+            if config.numa { " on one NUMA node" } else { "" }
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose_cores() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11\n"),
+            Some(vec![0, 1, 2, 3, 8, 10, 11])
+        );
+        assert_eq!(parse_cpu_list("3-1"), None);
+
+        let nodes = vec![(Some(0), vec![0, 1, 2, 3]), (Some(1), vec![4, 5, 6, 7])];
+        let used = HashSet::from([0, 1]);
+        // Node 0 has just enough room left, so node 1 stays whole
+        assert_eq!(
+            choose(&nodes, None, &used, 2, true),
+            Some(CpuPinning {
+                cores: vec![2, 3],
+                numa_node: Some(0)
+            })
+        );
+        assert_eq!(choose(&nodes, None, &used, 5, true), None);
+        assert_eq!(
+            choose(&nodes, None, &used, 5, false).map(|p| p.cores),
+            Some(vec![2, 3, 4, 5, 6])
+        );
+        assert_eq!(
+            choose(&nodes, Some(&[5, 6, 7]), &used, 2, true).map(|p| p.cores),
+            Some(vec![5, 6])
+        );
+    }
+}
@@ -0,0 +1,281 @@
+use crate::lifecycle::RunnerState;
+use crate::offline::enqueue_report;
+use crate::state::StateStore;
+use crate::temp_guard;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::backtrace::Backtrace;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::panic::PanicHookInfo;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+const CRASH_DIR: &str = ".cirun-agent/crashes";
+/// Crash reports kept on disk; the oldest are removed first
+const MAX_CRASH_REPORTS: usize = 20;
+/// API request IDs remembered for the next crash report
+const RECENT_REQUESTS: usize = 10;
+const UPLOAD_TIMEOUT_SECS: u64 = 10;
+/// Exit code after a panic, so the service manager restarts the agent
+const CRASH_EXIT_CODE: i32 = 101;
+
+/// Where crash reports are uploaded
+pub struct CrashUpload {
+    pub url: String,
+    pub api_token: String,
+    pub agent_id: String,
+    /// The agent's identity as sent in every API request, built when the report is sent
+    pub agent: Box<dyn Fn() -> Value + Send + Sync>,
+}
+
+static UPLOAD: OnceLock<CrashUpload> = OnceLock::new();
+static RECENT_REQUEST_IDS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+// Set by the first panic; panics on other threads while it is reported are ignored
+static CRASHING: AtomicBool = AtomicBool::new(false);
+
+/// Everything known about a panic, written to `~/.cirun-agent/crashes/`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashReport {
+    pub at: DateTime<Utc>,
+    pub version: String,
+    pub message: String,
+    pub location: Option<String>,
+    pub thread: Option<String>,
+    pub backtrace: String,
+    /// Runners that were being provisioned or deleted, and their lifecycle state
+    pub in_flight: BTreeMap<String, RunnerState>,
+    /// IDs of the last requests sent to the API, oldest first
+    pub recent_request_ids: Vec<String>,
+    #[serde(default)]
+    pub uploaded: bool,
+}
+
+/// Remember the ID of a request sent to the API, for crash reports
+pub fn record_request_id(request_id: &str) {
+    let mut ids = RECENT_REQUEST_IDS.lock().unwrap_or_else(|e| e.into_inner());
+    if ids.len() == RECENT_REQUESTS {
+        ids.pop_front();
+    }
+    ids.push_back(request_id.to_string());
+}
+
+fn crash_dir() -> PathBuf {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home_dir).join(CRASH_DIR)
+}
+
+fn panic_message(info: &PanicHookInfo) -> String {
+    let payload = info.payload();
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic payload".to_string())
+}
+
+/// Runners in an active lifecycle state
+fn in_flight(
+    lifecycle: impl Iterator<Item = (String, RunnerState)>,
+) -> BTreeMap<String, RunnerState> {
+    lifecycle
+        .filter(|(_, state)| {
+            !matches!(
+                state,
+                RunnerState::Ready | RunnerState::Deleted | RunnerState::Failed { .. }
+            )
+        })
+        .collect()
+}
+
+impl CrashReport {
+    fn new(info: &PanicHookInfo) -> Self {
+        // The panicking thread may hold the state lock
+        let lifecycle = StateStore::new().read_unlocked(|state| {
+            state
+                .lifecycle
+                .iter()
+                .map(|(name, record)| (name.clone(), record.state))
+                .collect::<Vec<_>>()
+        });
+        let recent_request_ids = RECENT_REQUEST_IDS
+            .lock()
+            .map(|ids| ids.iter().cloned().collect())
+            .unwrap_or_default();
+        CrashReport {
+            at: Utc::now(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            message: panic_message(info),
+            location: info.location().map(|l| l.to_string()),
+            thread: std::thread::current().name().map(str::to_string),
+            backtrace: Backtrace::force_capture().to_string(),
+            in_flight: in_flight(lifecycle.into_iter()),
+            recent_request_ids,
+            uploaded: false,
+        }
+    }
+}
+
+fn write_report(path: &Path, report: &CrashReport) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(report)?)
+}
+
+/// Remove all but the newest `MAX_CRASH_REPORTS` reports
+fn prune(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut paths: Vec<PathBuf> = entries.flatten().map(|e| e.path()).collect();
+    // Names start with the crash time, so they sort oldest first
+    paths.sort();
+    let excess = paths.len().saturating_sub(MAX_CRASH_REPORTS);
+    for path in paths.into_iter().take(excess) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+fn payload(upload: &CrashUpload, report: &CrashReport) -> Value {
+    json!({
+        "agent": (upload.agent)(),
+        "crash_report": report,
+    })
+}
+
+async fn send(upload: &CrashUpload, report: &CrashReport) -> Result<(), String> {
+    let client = Client::builder()
+        .timeout(Duration::from_secs(UPLOAD_TIMEOUT_SECS))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let response = client
+        .post(&upload.url)
+        .header("Authorization", format!("Bearer {}", upload.api_token))
+        .header("X-Agent-ID", &upload.agent_id)
+        .json(&payload(upload, report))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("API returned {}", response.status()))
+    }
+}
+
+/// Upload from inside the panic hook: the panicking thread may be a runtime worker, so the
+/// request runs on a thread of its own
+fn upload_blocking(report: &CrashReport) -> bool {
+    let Some(upload) = UPLOAD.get() else {
+        return false;
+    };
+    let report = report.clone();
+    let sent = std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| e.to_string())
+            .and_then(|runtime| runtime.block_on(send(upload, &report)))
+    })
+    .join();
+    match sent {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            eprintln!("Failed to upload crash report: {}", e);
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+/// Install a panic hook that writes a crash report, tries to upload it, and exits so the
+/// service manager restarts the agent, which then recovers from the state store
+pub fn install(upload: CrashUpload) {
+    let _ = UPLOAD.set(upload);
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        if CRASHING.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let mut report = CrashReport::new(info);
+        let dir = crash_dir();
+        let path = dir.join(format!("{}.json", report.at.format("%Y%m%dT%H%M%S%.3fZ")));
+        if let Err(e) = write_report(&path, &report) {
+            eprintln!("Failed to write crash report {:?}: {}", path, e);
+        }
+        if upload_blocking(&report) {
+            report.uploaded = true;
+            let _ = write_report(&path, &report);
+        }
+        prune(&dir);
+        // Exiting skips destructors, so guarded credentials and downloads go now
+        temp_guard::remove_all();
+        error!("Agent crashed; crash report written to {:?}", path);
+        std::process::exit(CRASH_EXIT_CODE);
+    }));
+}
+
+/// Queue crash reports that could not be uploaded when they were written, to be sent with
+/// the other queued reports
+pub fn queue_unsent_reports() {
+    let Some(upload) = UPLOAD.get() else {
+        return;
+    };
+    let Ok(entries) = fs::read_dir(crash_dir()) else {
+        return;
+    };
+    for path in entries.flatten().map(|e| e.path()) {
+        let report = fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<CrashReport>(&content).ok());
+        let Some(mut report) = report.filter(|r| !r.uploaded) else {
+            continue;
+        };
+        info!("Queueing crash report from {} for upload", report.at);
+        enqueue_report(payload(upload, &report));
+        report.uploaded = true;
+        if let Err(e) = write_report(&path, &report) {
+            warn!("Failed to update crash report {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lifecycle::Stage;
+
+    #[test]
+    fn test_in_flight_and_request_ids() {
+        let lifecycle = vec![
+            ("cirun-1".to_string(), RunnerState::Booting),
+            ("cirun-2".to_string(), RunnerState::Ready),
+            (
+                "cirun-3".to_string(),
+                RunnerState::Failed {
+                    stage: Stage::Cloning,
+                },
+            ),
+            ("cirun-4".to_string(), RunnerState::Deleting),
+        ];
+        let in_flight = in_flight(lifecycle.into_iter());
+        assert_eq!(
+            in_flight.keys().collect::<Vec<_>>(),
+            vec!["cirun-1", "cirun-4"]
+        );
+
+        for i in 0..=RECENT_REQUESTS {
+            record_request_id(&format!("request-{}", i));
+        }
+        let ids = RECENT_REQUEST_IDS.lock().unwrap();
+        assert_eq!(ids.len(), RECENT_REQUESTS);
+        assert_eq!(ids.back().unwrap(), &format!("request-{}", RECENT_REQUESTS));
+    }
+}
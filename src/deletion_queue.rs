@@ -0,0 +1,89 @@
+use crate::state::StateStore;
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+/// Delay before the first retry of a failed deletion
+const BASE_RETRY_SECS: i64 = 30;
+/// Upper bound on the delay between retries
+const MAX_RETRY_SECS: i64 = 15 * 60;
+
+/// A deletion that failed (typically because the provider was unreachable) and is
+/// retried in the background until it is confirmed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingDeletion {
+    pub queued_at: DateTime<Utc>,
+    pub attempts: u32,
+    pub last_error: String,
+    pub next_attempt_at: DateTime<Utc>,
+}
+
+/// Exponential backoff between deletion retries, capped at `MAX_RETRY_SECS`
+pub fn retry_delay(attempts: u32) -> chrono::Duration {
+    let secs = BASE_RETRY_SECS.saturating_mul(1 << attempts.saturating_sub(1).min(10));
+    chrono::Duration::seconds(secs.min(MAX_RETRY_SECS))
+}
+
+/// Queue a failed deletion for retry, or bump the attempt count if it is already queued
+pub fn queue_deletion(runner_name: &str, error: &str) {
+    StateStore::new().update(|state| {
+        let now = Utc::now();
+        let entry = state
+            .pending_deletions
+            .entry(runner_name.to_string())
+            .or_insert_with(|| PendingDeletion {
+                queued_at: now,
+                attempts: 0,
+                last_error: String::new(),
+                next_attempt_at: now,
+            });
+        entry.attempts += 1;
+        entry.last_error = error.to_string();
+        entry.next_attempt_at = now + retry_delay(entry.attempts);
+        info!(
+            "Queued deletion of '{}' for retry at {} (attempt {})",
+            runner_name,
+            entry.next_attempt_at.format("%H:%M:%S"),
+            entry.attempts
+        );
+    });
+}
+
+/// Drop a runner from the queue once its deletion is confirmed
+pub fn clear_deletion(runner_name: &str) {
+    StateStore::new().update(|state| {
+        state.pending_deletions.remove(runner_name);
+    });
+}
+
+/// Queued deletions whose next retry is due
+pub fn due_deletions() -> Vec<String> {
+    let now = Utc::now();
+    StateStore::new().read(|state| {
+        state
+            .pending_deletions
+            .iter()
+            .filter(|(_, pending)| pending.next_attempt_at <= now)
+            .map(|(name, _)| name.clone())
+            .collect()
+    })
+}
+
+/// Whether a runner has a deletion waiting to be retried
+pub fn is_pending_deletion(runner_name: &str) -> bool {
+    StateStore::new().read(|state| state.pending_deletions.contains_key(runner_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay_backs_off_and_caps() {
+        assert_eq!(retry_delay(1).num_seconds(), 30);
+        assert_eq!(retry_delay(2).num_seconds(), 60);
+        assert_eq!(retry_delay(4).num_seconds(), 240);
+        assert_eq!(retry_delay(6).num_seconds(), MAX_RETRY_SECS);
+        assert_eq!(retry_delay(100).num_seconds(), MAX_RETRY_SECS);
+    }
+}
@@ -0,0 +1,318 @@
+use crate::build_info;
+use crate::config::agent_config;
+use crate::console;
+use crate::lifecycle::Stage;
+use crate::log_target::{LogTarget, Sink};
+use crate::pool;
+use crate::runner_logs::{diagnostic_files, saved_script};
+use crate::state::script_hash;
+use crate::timing::PhaseTimings;
+use crate::use_meda;
+use chrono::Utc;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{info, warn, Log, Metadata, Record};
+use serde_json::json;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const DIAGNOSTICS_DIR: &str = ".cirun-agent/diagnostics";
+/// Agent log lines kept in memory for bundles
+const LOG_BUFFER_LINES: usize = 5000;
+/// Bytes read from the end of a provider log to find its last lines
+const PROVIDER_LOG_TAIL_BYTES: u64 = 256 * 1024;
+
+static RECENT_LOGS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// env_logger, also keeping the latest lines in memory for diagnostics bundles. Records go
+/// to syslog or the journal instead of stderr when a sink is set.
+struct RecordingLogger {
+    inner: env_logger::Logger,
+    sink: Option<Sink>,
+}
+
+impl Log for RecordingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        if console::plain() {
+            let message = record.args().to_string();
+            if let Cow::Owned(message) = console::text(&message) {
+                return self.log(
+                    &Record::builder()
+                        .args(format_args!("{}", message))
+                        .metadata(record.metadata().clone())
+                        .module_path(record.module_path())
+                        .file(record.file())
+                        .line(record.line())
+                        .build(),
+                );
+            }
+        }
+        let line = format!(
+            "[{} {} {}] {}",
+            Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        {
+            let mut logs = RECENT_LOGS.lock().unwrap_or_else(|e| e.into_inner());
+            if logs.len() == LOG_BUFFER_LINES {
+                logs.pop_front();
+            }
+            logs.push_back(line);
+        }
+        if self
+            .sink
+            .as_ref()
+            .is_some_and(|sink| sink.send(record).is_ok())
+        {
+            return;
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Set up logging from `RUST_LOG`, like `env_logger::init`, writing to `target`. Plain
+/// console output has no colors or emoji. If syslog or the journal cannot be reached, logs
+/// go to stderr.
+pub fn init_logging(target: LogTarget) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if console::plain() {
+        builder.write_style(env_logger::WriteStyle::Never);
+    }
+    let inner = builder.build();
+    let max_level = inner.filter();
+    let (sink, sink_error) = match Sink::connect(target) {
+        Ok(sink) => (sink, None),
+        Err(e) => (None, Some(e)),
+    };
+    if log::set_boxed_logger(Box::new(RecordingLogger { inner, sink })).is_ok() {
+        log::set_max_level(max_level);
+    }
+    if let Some(e) = sink_error {
+        warn!("Cannot log to {:?}, logging to stderr: {}", target, e);
+    }
+}
+
+/// The last `limit` buffered agent log lines that mention any of `names`
+fn agent_log_excerpt(names: &[&str], limit: usize) -> String {
+    let logs = RECENT_LOGS.lock().unwrap_or_else(|e| e.into_inner());
+    let matching: Vec<&String> = logs
+        .iter()
+        .filter(|line| names.iter().any(|name| line.contains(name)))
+        .collect();
+    let skip = matching.len().saturating_sub(limit);
+    matching
+        .into_iter()
+        .skip(skip)
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+/// The last `limit` lines of a file, reading only its end
+fn tail_lines(path: &Path, limit: usize) -> Option<String> {
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    let start = len.saturating_sub(PROVIDER_LOG_TAIL_BYTES);
+    file.seek(SeekFrom::Start(start)).ok()?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).ok()?;
+    let text = String::from_utf8_lossy(&bytes);
+    let lines: Vec<&str> = text.lines().collect();
+    // The first line may have been cut by the seek
+    let first = usize::from(start > 0);
+    let skip = lines.len().saturating_sub(limit).max(first);
+    Some(lines[skip.min(lines.len())..].join("\n") + "\n")
+}
+
+fn provider_logs() -> Vec<PathBuf> {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    let (dir, name) = if use_meda() {
+        (".meda/logs", "meda")
+    } else {
+        (".lume/logs", "lume")
+    };
+    ["stdout", "stderr"]
+        .iter()
+        .map(|stream| {
+            PathBuf::from(&home_dir)
+                .join(dir)
+                .join(format!("{}-{}.log", name, stream))
+        })
+        .collect()
+}
+
+fn diagnostics_dir() -> PathBuf {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home_dir).join(DIAGNOSTICS_DIR)
+}
+
+fn append(
+    archive: &mut tar::Builder<GzEncoder<File>>,
+    name: &str,
+    contents: &[u8],
+) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+    header.set_cksum();
+    archive.append_data(&mut header, name, contents)
+}
+
+/// Remove all but the newest `keep` bundles
+fn prune(dir: &Path, keep: usize) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut bundles: Vec<(std::time::SystemTime, PathBuf)> = entries
+        .flatten()
+        .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+        .collect();
+    bundles.sort();
+    let excess = bundles.len().saturating_sub(keep);
+    for (_, path) in bundles.into_iter().take(excess) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Assemble everything known about a failed provisioning into one `.tar.gz` under
+/// `~/.cirun-agent/diagnostics/`: a summary with the error, stage, timings and script hash,
+/// the agent log lines about the runner, the provider log tails, and the runner's
+/// transcript, result and last VM details
+pub fn write_bundle(
+    runner_name: &str,
+    error: &str,
+    stage: Stage,
+    timings: &PhaseTimings,
+    hostname: &str,
+) -> Result<PathBuf, String> {
+    let config = &agent_config().diagnostics;
+    let vm_name = pool::vm_name(runner_name);
+    let summary = json!({
+        "runner_name": runner_name,
+        "vm_name": vm_name,
+        "error": error,
+        "stage": stage,
+        "phases": timings,
+        "script_hash": saved_script(runner_name).map(|script| script_hash(&script)),
+        "agent_version": build_info::VERSION,
+        "build": build_info::BuildInfo::current(),
+        "hostname": hostname,
+        "created_at": Utc::now(),
+    });
+
+    let dir = diagnostics_dir();
+    fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = fs::set_permissions(&dir, fs::Permissions::from_mode(0o700));
+    }
+    let safe_name: String = runner_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let path = dir.join(format!(
+        "{}-{}.tar.gz",
+        safe_name,
+        Utc::now().format("%Y%m%dT%H%M%SZ")
+    ));
+
+    let write = || -> std::io::Result<()> {
+        let file = File::create(&path)?;
+        let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        append(
+            &mut archive,
+            "summary.json",
+            &serde_json::to_vec_pretty(&summary).unwrap_or_default(),
+        )?;
+        let names = [runner_name, vm_name.as_str()];
+        append(
+            &mut archive,
+            "agent.log",
+            agent_log_excerpt(&names, config.log_lines).as_bytes(),
+        )?;
+        for log in provider_logs() {
+            if let Some(tail) = tail_lines(&log, config.log_lines) {
+                let name = log.file_name().unwrap_or_default().to_string_lossy();
+                append(&mut archive, &format!("provider/{}", name), tail.as_bytes())?;
+            }
+        }
+        for (name, contents) in diagnostic_files(runner_name) {
+            append(&mut archive, &format!("runner/{}", name), &contents)?;
+        }
+        archive.into_inner()?.finish()?;
+        Ok(())
+    };
+    if let Err(e) = write() {
+        let _ = fs::remove_file(&path);
+        return Err(format!("Failed to write diagnostics bundle: {}", e));
+    }
+    prune(&dir, config.max_bundles);
+    info!(
+        "Wrote diagnostics bundle for '{}' to {:?}",
+        runner_name, path
+    );
+    Ok(path)
+}
+
+/// Write a bundle if enabled, logging rather than failing
+pub fn bundle_failure(
+    runner_name: &str,
+    error: &str,
+    stage: Stage,
+    timings: &PhaseTimings,
+    hostname: &str,
+) -> Option<PathBuf> {
+    if !agent_config().diagnostics.enabled {
+        return None;
+    }
+    write_bundle(runner_name, error, stage, timings, hostname)
+        .map_err(|e| warn!("{}", e))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_excerpt_and_tail() {
+        {
+            let mut logs = RECENT_LOGS.lock().unwrap();
+            logs.push_back("[INFO] Cloning VM for diag-runner-1".to_string());
+            logs.push_back("[INFO] Unrelated line".to_string());
+            logs.push_back("[ERROR] diag-runner-1 failed to boot".to_string());
+        }
+        let excerpt = agent_log_excerpt(&["diag-runner-1"], 1);
+        assert_eq!(excerpt, "[ERROR] diag-runner-1 failed to boot\n");
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("meda-stderr.log");
+        fs::write(&log, "one\ntwo\nthree\n").unwrap();
+        assert_eq!(tail_lines(&log, 2).unwrap(), "two\nthree\n");
+        assert_eq!(tail_lines(&log, 10).unwrap(), "one\ntwo\nthree\n");
+    }
+}
@@ -0,0 +1,173 @@
+use crate::config::agent_config;
+use crate::units::DiskSize;
+use crate::vm_provision::run_ssh_command;
+use crate::{use_meda, RunnerLogin};
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::process::Command;
+
+/// Expands the APFS container holding / to fill its (grown) disk. The disk is repaired
+/// first so macOS moves the recovery partition out of the way.
+const GROW_APFS_SCRIPT: &str = r#"store=$(diskutil info / | awk -F': *' '/APFS Physical Store/ {print $2}' | tr -d ' ')
+[ -n "$store" ] || { echo "no APFS physical store for /" >&2; exit 1; }
+yes | sudo -n diskutil repairDisk "${store%s*}" >/dev/null 2>&1
+sudo -n diskutil apfs resizeContainer "$store" 0"#;
+
+// VMs whose disk was grown and whose guest filesystem still has to follow
+static PENDING_GROWTH: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// Directory holding a runner VM's disk and configuration for the active provider
+pub fn vm_storage_dir(vm_name: &str) -> PathBuf {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    if use_meda() {
+        PathBuf::from(home_dir).join(".meda/vms").join(vm_name)
+    } else {
+        PathBuf::from(home_dir).join(".lume").join(vm_name)
+    }
+}
+
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+/// Disk of a VM: the space its files take on the host, and the size the guest sees
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct DiskUsage {
+    pub allocated: DiskSize,
+    pub total: DiskSize,
+}
+
+/// Usage of the files under a VM's storage directory: the blocks they take on the host,
+/// and their apparent size, which for sparse raw disk images is the size the guest sees.
+/// `None` if the directory does not exist, e.g. for VMs on remote endpoints.
+pub fn storage_usage(dir: &Path) -> Option<DiskUsage> {
+    fn walk(dir: &Path, allocated: &mut u64, apparent: &mut u64) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_dir() {
+                walk(&entry.path(), allocated, apparent)?;
+            } else if meta.is_file() {
+                *allocated += allocated_bytes(&meta);
+                *apparent += meta.len();
+            }
+        }
+        Ok(())
+    }
+    let (mut allocated, mut apparent) = (0, 0);
+    walk(dir, &mut allocated, &mut apparent).ok()?;
+    Some(DiskUsage {
+        allocated: DiskSize::from_mb(allocated.div_ceil(BYTES_PER_MB)),
+        total: DiskSize::from_mb(apparent.div_ceil(BYTES_PER_MB)),
+    })
+}
+
+#[cfg(unix)]
+fn allocated_bytes(meta: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    // st_blocks is always in 512-byte units
+    meta.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn allocated_bytes(meta: &std::fs::Metadata) -> u64 {
+    meta.len()
+}
+
+/// Free space in bytes on the filesystem containing `path`, as reported by `df`
+pub async fn available_bytes(path: &Path) -> Option<u64> {
+    // Walk up to an existing ancestor so this also works once the VM directory is gone
+    let existing = path.ancestors().find(|p| p.exists())?;
+    let output = Command::new("df")
+        .arg("-Pk")
+        .arg(existing)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_df_available(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Remember to expand the guest filesystem once `vm_name` is reachable over SSH
+pub fn grow_filesystem_on_boot(vm_name: &str) {
+    if agent_config().lume.resize_guest_filesystem {
+        PENDING_GROWTH
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(HashSet::new)
+            .insert(vm_name.to_string());
+    }
+}
+
+/// Expand the guest filesystem of a VM whose disk was grown. Failures are logged: the disk
+/// is bigger either way, and the provision script can still grow the filesystem itself.
+pub async fn grow_pending_filesystem(vm_name: &str, ip_address: &str, login: &RunnerLogin) {
+    let pending = PENDING_GROWTH
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_mut()
+        .is_some_and(|pending| pending.remove(vm_name));
+    if !pending {
+        return;
+    }
+    info!("Expanding the guest filesystem of VM '{}'", vm_name);
+    let timeout = agent_config().timeouts.script_launch_secs;
+    match run_ssh_command(vm_name, ip_address, login, GROW_APFS_SCRIPT, timeout).await {
+        Ok(output) if output.status.success() => {
+            info!("Guest filesystem of VM '{}' expanded", vm_name)
+        }
+        Ok(output) => warn!(
+            "Failed to expand the guest filesystem of VM '{}': {}",
+            vm_name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => warn!(
+            "Failed to expand the guest filesystem of VM '{}': {}",
+            vm_name, e
+        ),
+    }
+}
+
+/// Extract the available bytes from POSIX `df -Pk` output
+fn parse_df_available(output: &str) -> Option<u64> {
+    let fields: Vec<&str> = output.lines().nth(1)?.split_whitespace().collect();
+    // Filesystem, 1024-blocks, Used, Available, Capacity, Mounted on
+    let available_kb: u64 = fields.get(3)?.parse().ok()?;
+    Some(available_kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_df_available() {
+        let output = "Filesystem 1024-blocks Used Available Capacity Mounted on\n\
+                      /dev/sda1 102400 51200 51200 50% /\n";
+        assert_eq!(parse_df_available(output), Some(51200 * 1024));
+        assert_eq!(parse_df_available("Filesystem\n"), None);
+    }
+
+    #[test]
+    fn test_storage_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("disks")).unwrap();
+        // A sparse 64 MB disk image with 1 MB written
+        let image = std::fs::File::create(dir.path().join("disks/rootfs.raw")).unwrap();
+        image.set_len(64 * BYTES_PER_MB).unwrap();
+        std::fs::write(
+            dir.path().join("config.json"),
+            vec![0u8; BYTES_PER_MB as usize],
+        )
+        .unwrap();
+
+        let usage = storage_usage(dir.path()).unwrap();
+        assert_eq!(usage.total, DiskSize::from_mb(65));
+        assert!(usage.allocated < usage.total);
+        assert!(storage_usage(&dir.path().join("missing")).is_none());
+    }
+}
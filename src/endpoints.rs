@@ -0,0 +1,187 @@
+use crate::config::{agent_config, EndpointConfig};
+use crate::state::StateStore;
+use crate::{get_running_vm_count, RunnerToProvision};
+use log::{info, warn};
+use std::fmt::Debug;
+use std::future::Future;
+
+tokio::task_local! {
+    // Remote endpoint the provider clients in the current task talk to (None: this host)
+    static CURRENT: Option<&'static EndpointConfig>;
+}
+
+/// Run a future with every provider client it creates pointed at `endpoint`
+pub async fn on_endpoint<F: Future>(
+    endpoint: Option<&'static EndpointConfig>,
+    future: F,
+) -> F::Output {
+    CURRENT.scope(endpoint, future).await
+}
+
+/// Remote endpoint of the current task, if any
+pub fn current() -> Option<&'static EndpointConfig> {
+    CURRENT.try_with(|endpoint| *endpoint).ok().flatten()
+}
+
+fn find(name: &str) -> Option<&'static EndpointConfig> {
+    agent_config().endpoints.iter().find(|e| e.name == name)
+}
+
+/// Endpoint a runner was placed on (None: this host)
+pub fn endpoint_for_runner(runner_name: &str) -> Option<&'static EndpointConfig> {
+    let name = StateStore::new().read(|state| state.placements.get(runner_name).cloned())?;
+    let endpoint = find(&name);
+    if endpoint.is_none() {
+        warn!(
+            "Runner '{}' was placed on endpoint '{}', which is no longer configured",
+            runner_name, name
+        );
+    }
+    endpoint
+}
+
+/// Forget where a runner lives once its VM is gone
+pub fn clear_placement(runner_name: &str) {
+    StateStore::new().update(|state| state.placements.remove(runner_name));
+}
+
+/// Running VM count and slot limit of every reachable endpoint, this host first
+async fn endpoint_load(
+    local_max_vms: Option<u32>,
+) -> Vec<(Option<&'static EndpointConfig>, usize, Option<u32>)> {
+    let mut load = Vec::new();
+    if let Ok(running) = get_running_vm_count().await {
+        load.push((None, running, local_max_vms));
+    }
+    for endpoint in &agent_config().endpoints {
+        match on_endpoint(Some(endpoint), get_running_vm_count()).await {
+            Ok(running) => load.push((Some(endpoint), running, endpoint.max_vms)),
+            Err(e) => warn!("Endpoint '{}' is not reachable: {}", endpoint.name, e),
+        }
+    }
+    load
+}
+
+/// Free VM slots on the remote endpoints; endpoints without a limit count as `unlimited`
+pub async fn remote_free_slots(unlimited: usize) -> usize {
+    if agent_config().endpoints.is_empty() {
+        return 0;
+    }
+    endpoint_load(None)
+        .await
+        .into_iter()
+        .filter(|(endpoint, _, _)| endpoint.is_some())
+        .map(|(_, running, max_vms)| {
+            max_vms.map_or(unlimited, |max| (max as usize).saturating_sub(running))
+        })
+        .sum()
+}
+
+/// Index of the endpoint with a free slot and the fewest running VMs; earlier entries
+/// (this host first) win ties
+fn least_loaded(load: &[(Option<&'static EndpointConfig>, usize, Option<u32>)]) -> Option<usize> {
+    load.iter()
+        .enumerate()
+        .filter(|(_, (_, running, max_vms))| max_vms.is_none_or(|max| *running < max as usize))
+        .min_by_key(|(i, (_, running, _))| (*running, *i))
+        .map(|(i, _)| i)
+}
+
+/// Place each runner on the endpoint with a free slot and the fewest VMs, recording
+/// remote placements in the state file. Runners placed before (e.g. being re-provisioned)
+/// stay where they are. Runners that fit nowhere are dropped and picked up by a later poll.
+pub async fn place(
+    runners: Vec<RunnerToProvision>,
+    local_max_vms: Option<u32>,
+) -> Vec<RunnerToProvision> {
+    if agent_config().endpoints.is_empty() {
+        return runners;
+    }
+    let mut load = endpoint_load(local_max_vms).await;
+    let state = StateStore::new();
+    let mut placed = Vec::new();
+
+    for runner in runners {
+        if state.read(|s| s.placements.contains_key(&runner.name)) {
+            placed.push(runner);
+            continue;
+        }
+        let Some(index) = least_loaded(&load) else {
+            info!(
+                "No endpoint has a free slot for runner '{}'. It will be picked up on next poll.",
+                runner.name
+            );
+            continue;
+        };
+        load[index].1 += 1;
+        if let Some(endpoint) = load[index].0 {
+            info!(
+                "Placing runner '{}' on endpoint '{}'",
+                runner.name, endpoint.name
+            );
+            state.update(|s| {
+                s.placements
+                    .insert(runner.name.clone(), endpoint.name.clone())
+            });
+        }
+        placed.push(runner);
+    }
+    placed
+}
+
+/// Run a listing against every remote endpoint and combine the results, skipping
+/// endpoints that can't be reached
+pub async fn list_remote<T, E, F, Fut>(list: F) -> Vec<T>
+where
+    E: Debug,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Vec<T>, E>>,
+{
+    let mut items = Vec::new();
+    for endpoint in &agent_config().endpoints {
+        match on_endpoint(Some(endpoint), list()).await {
+            Ok(remote) => items.extend(remote),
+            Err(e) => warn!(
+                "Failed to list VMs on endpoint '{}': {:?}",
+                endpoint.name, e
+            ),
+        }
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_endpoint_scope_and_placement() {
+        let endpoint: &'static EndpointConfig = Box::leak(Box::new(EndpointConfig {
+            name: "hv-2".to_string(),
+            url: "http://10.0.0.12:7777/api/v1".to_string(),
+            token: None,
+            token_env: None,
+            ca: None,
+            max_vms: Some(4),
+            ssh_jump_host: None,
+        }));
+        assert!(current().is_none());
+        let inner = on_endpoint(Some(endpoint), async { current().map(|e| e.name.clone()) }).await;
+        assert_eq!(inner.as_deref(), Some("hv-2"));
+        assert!(current().is_none());
+
+        // This host wins ties, full endpoints are skipped
+        assert_eq!(
+            least_loaded(&[(None, 2, None), (Some(endpoint), 2, Some(4))]),
+            Some(0)
+        );
+        assert_eq!(
+            least_loaded(&[(None, 3, Some(3)), (Some(endpoint), 2, Some(4))]),
+            Some(1)
+        );
+        assert_eq!(
+            least_loaded(&[(None, 3, Some(3)), (Some(endpoint), 4, Some(4))]),
+            None
+        );
+    }
+}
@@ -0,0 +1,324 @@
+use crate::config::{agent_config, WebhookConfig};
+use crate::lifecycle::{RunnerState, Stage};
+use crate::notifiers;
+use crate::script_monitor::ScriptStatus;
+use crate::template_failure::TemplateStage;
+use chrono::{DateTime, Utc};
+use log::{debug, error, warn};
+use reqwest::Client;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio::time::Duration;
+
+/// Events a slow subscriber may fall behind by before it starts missing some
+const BUS_CAPACITY: usize = 1024;
+const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+
+/// Something that happened to a runner, published by the task that did it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AgentEvent {
+    ProvisionStarted {
+        runner_name: String,
+    },
+    VmReady {
+        runner_name: String,
+    },
+    ProvisionFailed {
+        runner_name: String,
+        stage: Stage,
+        error: String,
+    },
+    VmDeleted {
+        runner_name: String,
+    },
+    DeleteFailed {
+        runner_name: String,
+        error: String,
+    },
+    RunnerExpired {
+        runner_name: String,
+        lifetime_secs: i64,
+    },
+    StateChanged {
+        runner_name: String,
+        from: Option<RunnerState>,
+        to: RunnerState,
+    },
+    ScriptFinished {
+        runner_name: String,
+        #[serde(flatten)]
+        status: ScriptStatus,
+    },
+    TemplateFallback {
+        runner_name: String,
+        image: String,
+        template: String,
+        error: String,
+    },
+    TemplateCreationFailed {
+        runner_name: String,
+        image: String,
+        template: String,
+        stage: TemplateStage,
+        error: String,
+    },
+}
+
+impl AgentEvent {
+    /// Event name as serialized, used for webhook filters and counters
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AgentEvent::ProvisionStarted { .. } => "provision_started",
+            AgentEvent::VmReady { .. } => "vm_ready",
+            AgentEvent::ProvisionFailed { .. } => "provision_failed",
+            AgentEvent::VmDeleted { .. } => "vm_deleted",
+            AgentEvent::DeleteFailed { .. } => "delete_failed",
+            AgentEvent::RunnerExpired { .. } => "runner_expired",
+            AgentEvent::StateChanged { .. } => "state_changed",
+            AgentEvent::ScriptFinished { .. } => "script_finished",
+            AgentEvent::TemplateFallback { .. } => "template_fallback",
+            AgentEvent::TemplateCreationFailed { .. } => "template_creation_failed",
+        }
+    }
+
+    /// Whether the set of VMs or their reported status changed, so the API should get a
+    /// fresh status report
+    pub fn changes_vms(&self) -> bool {
+        matches!(
+            self,
+            AgentEvent::VmReady { .. }
+                | AgentEvent::VmDeleted { .. }
+                | AgentEvent::ScriptFinished { .. }
+        )
+    }
+}
+
+/// An event with the time it was published
+#[derive(Debug, Clone, Serialize)]
+pub struct EventRecord {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: AgentEvent,
+}
+
+static BUS: OnceLock<Sender<EventRecord>> = OnceLock::new();
+// Events published since startup, by kind
+static COUNTS: Mutex<Option<HashMap<&'static str, u64>>> = Mutex::new(None);
+
+fn bus() -> &'static Sender<EventRecord> {
+    BUS.get_or_init(|| broadcast::channel(BUS_CAPACITY).0)
+}
+
+/// Publish an event to every subscriber; events published before anyone subscribed are lost
+pub fn publish(event: AgentEvent) {
+    debug!("Event: {:?}", event);
+    let _ = bus().send(EventRecord {
+        at: Utc::now(),
+        event,
+    });
+}
+
+/// Receive every event published from now on
+pub fn subscribe() -> Receiver<EventRecord> {
+    bus().subscribe()
+}
+
+/// Next event for a subscriber, skipping over any it fell too far behind to see
+async fn next_event(receiver: &mut Receiver<EventRecord>, subscriber: &str) -> Option<EventRecord> {
+    loop {
+        match receiver.recv().await {
+            Ok(record) => return Some(record),
+            Err(RecvError::Lagged(missed)) => {
+                warn!("Event subscriber '{}' missed {} events", subscriber, missed)
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Whether any event received since the last call changed the set of VMs
+pub fn vms_changed(receiver: &mut Receiver<EventRecord>) -> bool {
+    let mut changed = false;
+    loop {
+        match receiver.try_recv() {
+            Ok(record) => changed |= record.event.changes_vms(),
+            // Missed events may have been VM changes
+            Err(TryRecvError::Lagged(_)) => changed = true,
+            Err(_) => return changed,
+        }
+    }
+}
+
+/// Template creation failures received since the last call
+pub fn template_failures(receiver: &mut Receiver<EventRecord>) -> Vec<EventRecord> {
+    let mut failures = Vec::new();
+    loop {
+        match receiver.try_recv() {
+            Ok(record) if matches!(record.event, AgentEvent::TemplateCreationFailed { .. }) => {
+                failures.push(record)
+            }
+            Ok(_) => {}
+            Err(TryRecvError::Lagged(missed)) => {
+                warn!("Template failure reporting missed {} events", missed)
+            }
+            Err(_) => return failures,
+        }
+    }
+}
+
+/// Wait for the next event that changes the set of VMs
+pub async fn next_vm_change(receiver: &mut Receiver<EventRecord>) {
+    loop {
+        match receiver.recv().await {
+            Ok(record) if record.event.changes_vms() => return,
+            Ok(_) => {}
+            Err(RecvError::Lagged(_)) => return,
+            // The bus lives as long as the agent, so this never resolves
+            Err(RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
+/// Event counts since startup, included in status reports
+pub fn counts() -> HashMap<&'static str, u64> {
+    COUNTS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_default()
+}
+
+fn count(kind: &'static str) {
+    let mut counts = COUNTS.lock().unwrap_or_else(|e| e.into_inner());
+    *counts
+        .get_or_insert_with(HashMap::new)
+        .entry(kind)
+        .or_insert(0) += 1;
+}
+
+fn wants(webhook: &WebhookConfig, kind: &str) -> bool {
+    webhook.events.is_empty() || webhook.events.iter().any(|e| e == kind)
+}
+
+/// Start the metrics, audit log, webhook and chat notifier subscribers. `hostname` names
+/// this agent in chat messages. Must be called from within the tokio runtime.
+pub fn start_subscribers(hostname: &str) {
+    let mut metrics = subscribe();
+    tokio::spawn(async move {
+        while let Some(record) = next_event(&mut metrics, "metrics").await {
+            count(record.event.kind());
+        }
+    });
+
+    let config = &agent_config().events;
+    if let Some(path) = &config.audit_log {
+        let mut audit = subscribe();
+        tokio::spawn(async move {
+            while let Some(record) = next_event(&mut audit, "audit log").await {
+                let line = serde_json::to_string(&record).unwrap_or_default();
+                let written = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| writeln!(file, "{}", line));
+                if let Err(e) = written {
+                    error!("Failed to write audit log {:?}: {}", path, e);
+                }
+            }
+        });
+    }
+
+    if !config.webhooks.is_empty() {
+        let mut webhooks = subscribe();
+        let client = Client::builder()
+            .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+            .build()
+            .expect("Failed to build HTTP client");
+        tokio::spawn(async move {
+            while let Some(record) = next_event(&mut webhooks, "webhooks").await {
+                let kind = record.event.kind();
+                for webhook in config.webhooks.iter().filter(|w| wants(w, kind)) {
+                    let sent = client.post(&webhook.url).json(&record).send().await;
+                    match sent {
+                        Ok(response) if !response.status().is_success() => warn!(
+                            "Webhook {} returned {} for {} event",
+                            webhook.url,
+                            response.status(),
+                            kind
+                        ),
+                        Ok(_) => {}
+                        Err(e) => {
+                            warn!("Failed to deliver {} event to {}: {}", kind, webhook.url, e)
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    if !config.notifiers.is_empty() {
+        let mut chat = subscribe();
+        let hostname = hostname.to_string();
+        let client = Client::builder()
+            .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+            .build()
+            .expect("Failed to build HTTP client");
+        tokio::spawn(async move {
+            while let Some(record) = next_event(&mut chat, "notifiers").await {
+                let notifiers = config
+                    .notifiers
+                    .iter()
+                    .filter(|n| notifiers::wants(n, &record.event));
+                for notifier in notifiers {
+                    if let Err(e) = notifiers::notify(&client, notifier, &record, &hostname).await {
+                        warn!(
+                            "Failed to post {} event to {:?}: {}",
+                            record.event.kind(),
+                            notifier.service,
+                            e
+                        );
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_events_reach_subscribers() {
+        let mut receiver = subscribe();
+        publish(AgentEvent::DeleteFailed {
+            runner_name: "cirun-runner-1".to_string(),
+            error: "meda is unreachable".to_string(),
+        });
+        // Other tests may publish concurrently
+        let record = loop {
+            let record = receiver.recv().await.unwrap();
+            if matches!(record.event, AgentEvent::DeleteFailed { .. }) {
+                break record;
+            }
+        };
+        assert_eq!(record.event.kind(), "delete_failed");
+        assert!(!record.event.changes_vms());
+
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["event"], "delete_failed");
+        assert_eq!(json["runner_name"], "cirun-runner-1");
+
+        let webhook = WebhookConfig {
+            url: "https://hooks.example.com/cirun".to_string(),
+            events: vec!["vm_ready".to_string()],
+        };
+        assert!(wants(&webhook, "vm_ready"));
+        assert!(!wants(&webhook, "delete_failed"));
+    }
+}
@@ -0,0 +1,128 @@
+use crate::RunnerToProvision;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Images being pulled or built into a template right now, and since when
+static PULLS: Mutex<Option<HashMap<String, Instant>>> = Mutex::new(None);
+
+/// Marks an image as being pulled until dropped
+pub struct PullGuard {
+    image: String,
+}
+
+impl Drop for PullGuard {
+    fn drop(&mut self) {
+        if let Some(pulls) = PULLS.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            pulls.remove(&self.image);
+        }
+    }
+}
+
+/// Mark `image` as being pulled, so the scheduler holds back its other runners instead
+/// of letting them take slots while they wait
+pub fn pulling(image: &str) -> PullGuard {
+    PULLS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new)
+        .insert(image.to_string(), Instant::now());
+    PullGuard {
+        image: image.to_string(),
+    }
+}
+
+/// Images being pulled right now, and for how long
+pub fn pulls() -> HashMap<String, Duration> {
+    PULLS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|pulls| {
+            pulls
+                .iter()
+                .map(|(image, started)| (image.clone(), started.elapsed()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Order runners so every image gets a turn: one runner per image per round, images in
+/// the order they were first requested. Runners whose image (`image_of`) is in `pulling`
+/// are held back, since they would only wait for the pull while holding a slot; they are
+/// returned separately.
+pub fn schedule(
+    runners: Vec<RunnerToProvision>,
+    pulling: &HashSet<String>,
+    image_of: impl Fn(&RunnerToProvision) -> String,
+) -> (Vec<RunnerToProvision>, Vec<RunnerToProvision>) {
+    let mut order = Vec::new();
+    let mut queues: HashMap<String, VecDeque<RunnerToProvision>> = HashMap::new();
+    let mut held = Vec::new();
+    for runner in runners {
+        let image = image_of(&runner);
+        if pulling.contains(&image) {
+            held.push(runner);
+            continue;
+        }
+        if !queues.contains_key(&image) {
+            order.push(image.clone());
+        }
+        queues.entry(image).or_default().push_back(runner);
+    }
+
+    let mut scheduled = Vec::new();
+    while !queues.is_empty() {
+        for image in &order {
+            if let Some(queue) = queues.get_mut(image) {
+                scheduled.extend(queue.pop_front());
+                if queue.is_empty() {
+                    queues.remove(image);
+                }
+            }
+        }
+    }
+    (scheduled, held)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule_round_robin() {
+        let runner = |name: &str, image: &str| -> RunnerToProvision {
+            serde_json::from_value(serde_json::json!({
+                "name": name,
+                "image": image,
+                "provision_script": "",
+                "login": {"username": "runner", "password": "secret"},
+                "cpu": 2,
+                "memory": 4096,
+                "disk": 20,
+            }))
+            .unwrap()
+        };
+        let runners = vec![
+            runner("a1", "ubuntu-24"),
+            runner("a2", "ubuntu-24"),
+            runner("a3", "ubuntu-24"),
+            runner("b1", "macos-15"),
+            runner("c1", "ubuntu-22"),
+            runner("c2", "ubuntu-22"),
+        ];
+        let image_of = |runner: &RunnerToProvision| runner.image.clone();
+
+        let (scheduled, held) = schedule(runners.clone(), &HashSet::new(), image_of);
+        let names: Vec<&str> = scheduled.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["a1", "b1", "c1", "a2", "c2", "a3"]);
+        assert!(held.is_empty());
+
+        // While ubuntu-24 is pulled, the other images go ahead
+        let pulling = HashSet::from(["ubuntu-24".to_string()]);
+        let (scheduled, held) = schedule(runners, &pulling, image_of);
+        let names: Vec<&str> = scheduled.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["b1", "c1", "c2"]);
+        assert_eq!(held.len(), 3);
+    }
+}
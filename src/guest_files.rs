@@ -0,0 +1,248 @@
+use crate::config::agent_config;
+use crate::retry::with_retries;
+use crate::runner_cache;
+use crate::temp_guard::TempGuard;
+use crate::vm_provision::{run_sftp_batch, run_ssh_command, shell_quote};
+use crate::{use_meda, RunnerLogin};
+use log::info;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::time::Duration;
+
+/// Guest directory files are uploaded to before being installed in place
+const STAGING_DIR: &str = "/tmp/cirun-files";
+
+fn default_mode() -> String {
+    "0644".to_string()
+}
+
+/// A file the API asks to place on the runner before the provision script runs, such as
+/// a runner binary, a CA certificate or a config file
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct GuestFile {
+    /// Absolute path on the guest
+    pub path: String,
+    /// Inline contents
+    #[serde(default)]
+    pub content: Option<String>,
+    /// URL the agent downloads the contents from
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Expected SHA-256 of the downloaded contents
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Octal permissions
+    #[serde(default = "default_mode")]
+    pub mode: String,
+}
+
+impl GuestFile {
+    fn validate(&self) -> Result<(), String> {
+        // sftp batches are line based, so a path may not span lines
+        if !self.path.starts_with('/') || self.path.contains(['\n', '\r', '"']) {
+            return Err(format!("Invalid guest file path '{}'", self.path));
+        }
+        let mode = self.mode.strip_prefix('0').unwrap_or(&self.mode);
+        if !(3..=4).contains(&mode.len()) || !mode.chars().all(|c| ('0'..='7').contains(&c)) {
+            return Err(format!("Invalid mode '{}' for {}", self.mode, self.path));
+        }
+        match (&self.content, &self.url) {
+            (Some(_), None) | (None, Some(_)) => Ok(()),
+            _ => Err(format!(
+                "Guest file {} needs exactly one of content or url",
+                self.path
+            )),
+        }
+    }
+
+    /// The file's contents, downloading them if needed
+    async fn contents(&self, client: &Client) -> Result<Vec<u8>, String> {
+        let (content, url) = (&self.content, &self.url);
+        let bytes = match (content, url) {
+            (Some(content), _) => content.as_bytes().to_vec(),
+            (None, Some(url)) => match runner_cache::cached_download(url).await {
+                Some(cached) => {
+                    let path = cached?;
+                    tokio::fs::read(&path)
+                        .await
+                        .map_err(|e| format!("Failed to read {:?}: {}", path, e))?
+                }
+                None => {
+                    let download = || async {
+                        client
+                            .get(url)
+                            .send()
+                            .await?
+                            .error_for_status()?
+                            .bytes()
+                            .await
+                    };
+                    with_retries("download", agent_config().retry.downloads, download)
+                        .await
+                        .map_err(|e| format!("Failed to download {}: {}", url, e))?
+                        .to_vec()
+                }
+            },
+            (None, None) => Vec::new(),
+        };
+        if let Some(expected) = &self.sha256 {
+            let actual = format!("{:x}", Sha256::digest(&bytes));
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(format!(
+                    "Checksum mismatch for {}: expected {}, got {}",
+                    self.path, expected, actual
+                ));
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+/// sftp batch uploading the numbered local files into the staging directory
+fn sftp_batch(local_dir: &Path, count: usize) -> String {
+    let mut batch = format!("-mkdir {}\nchmod 700 {}\n", STAGING_DIR, STAGING_DIR);
+    for i in 0..count {
+        batch.push_str(&format!(
+            "put \"{}\" {}/{}\n",
+            local_dir.join(i.to_string()).to_string_lossy(),
+            STAGING_DIR,
+            i
+        ));
+    }
+    batch
+}
+
+/// Shell command moving the staged files into place with their modes, as root when
+/// `elevate` is set and owned by `owner` if given, then removing the staging directory
+fn install_command(files: &[GuestFile], elevate: bool, owner: Option<&str>) -> String {
+    let owner = owner
+        .map(|owner| format!("-o {} ", shell_quote(owner)))
+        .unwrap_or_default();
+    let steps: Vec<String> = files
+        .iter()
+        .enumerate()
+        .map(|(i, file)| {
+            let path = shell_quote(&file.path);
+            format!(
+                "mkdir -p \"$(dirname {path})\" && install {owner}-m {mode} {staging}/{i} {path}",
+                path = path,
+                owner = owner,
+                mode = file.mode,
+                staging = STAGING_DIR,
+                i = i
+            )
+        })
+        .collect();
+    let install = steps.join(" && ");
+    let install = if elevate {
+        format!("sudo -n sh -c {}", shell_quote(&install))
+    } else {
+        install
+    };
+    format!(
+        "{}; status=$?; rm -rf {}; exit $status",
+        install, STAGING_DIR
+    )
+}
+
+/// Place the runner's files on the guest over SFTP. Files go in as root when the provision
+/// script runs with sudo or as another user, and belong to that user if one is set.
+pub async fn push_files(
+    vm_name: &str,
+    ip_address: &str,
+    login: &RunnerLogin,
+    files: &[GuestFile],
+) -> Result<(), String> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    for file in files {
+        file.validate()?;
+    }
+    let timeout_secs = agent_config().timeouts.transfer_secs;
+    let client = Client::builder()
+        .timeout(Duration::from_secs(timeout_secs))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let local_dir = TempGuard::dir("cirun-files-").map_err(|e| e.to_string())?;
+    for (i, file) in files.iter().enumerate() {
+        std::fs::write(
+            local_dir.path().join(i.to_string()),
+            file.contents(&client).await?,
+        )
+        .map_err(|e| format!("Failed to stage {}: {}", file.path, e))?;
+    }
+
+    let batch = sftp_batch(local_dir.path(), files.len());
+    let output = run_sftp_batch(vm_name, ip_address, login, &batch, timeout_secs)
+        .await
+        .map_err(|e| format!("File upload failed: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "File upload failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let owner = login
+        .script
+        .user
+        .as_deref()
+        .filter(|user| *user != login.username);
+    let elevate = login.script.elevates(&login.username, use_meda());
+    let command = install_command(files, elevate, owner);
+    let output = run_ssh_command(vm_name, ip_address, login, &command, timeout_secs)
+        .await
+        .map_err(|e| format!("Installing files failed: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Installing files failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    info!("✔ Placed {} files on '{}'", files.len(), vm_name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guest_file_validation_and_commands() {
+        let file: GuestFile = serde_json::from_str(
+            r#"{"path": "/etc/ssl/ca.pem", "content": "-----BEGIN CERTIFICATE-----"}"#,
+        )
+        .unwrap();
+        assert_eq!(file.mode, "0644");
+        assert!(file.validate().is_ok());
+
+        let relative = GuestFile {
+            path: "etc/ca.pem".to_string(),
+            ..file.clone()
+        };
+        assert!(relative.validate().is_err());
+        let bad_mode = GuestFile {
+            mode: "0999".to_string(),
+            ..file.clone()
+        };
+        assert!(bad_mode.validate().is_err());
+        let both = GuestFile {
+            url: Some("https://example.com/ca.pem".to_string()),
+            ..file.clone()
+        };
+        assert!(both.validate().is_err());
+
+        let batch = sftp_batch(Path::new("/tmp/local"), 2);
+        assert!(batch.ends_with("put \"/tmp/local/1\" /tmp/cirun-files/1\n"));
+
+        assert_eq!(
+            install_command(&[file], false, None),
+            "mkdir -p \"$(dirname '/etc/ssl/ca.pem')\" && \
+             install -m 0644 /tmp/cirun-files/0 '/etc/ssl/ca.pem'; \
+             status=$?; rm -rf /tmp/cirun-files; exit $status"
+        );
+    }
+}
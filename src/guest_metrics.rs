@@ -0,0 +1,233 @@
+use crate::config::agent_config;
+use crate::endpoints;
+use crate::health::get_vm_state_and_ip;
+use crate::pool;
+use crate::units::Memory;
+use crate::vm_provision::run_ssh_command;
+use crate::RunnerToProvision;
+use log::{debug, info};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Prints the share of all CPUs in use, memory used/total in MB and root disk usage.
+/// Linux guests measure CPU over one second from /proc/stat; macOS has no /proc, so it sums
+/// the recent per-process figures from ps instead.
+const PROBE: &str = r#"if [ -r /proc/stat ]; then
+  a=$(head -1 /proc/stat); sleep 1; b=$(head -1 /proc/stat)
+  echo "$a $b" | awk '{for (k = 2; k <= 11; k++) {t1 += $k; t2 += $(k + 11)}
+    if (t2 > t1) print "cpu=" 100 * (1 - ($16 + $17 - $5 - $6) / (t2 - t1))}'
+  awk '/^MemTotal:/ {t=$2} /^MemAvailable:/ {a=$2} END {print "mem=" int((t-a)/1024) "/" int(t/1024)}' /proc/meminfo
+else
+  ps -A -o %cpu= | awk -v n="$(sysctl -n hw.ncpu)" '{s += $1} END {print "cpu=" s / n}'
+  vm_stat | awk -v t="$(sysctl -n hw.memsize)" -v p="$(pagesize)" \
+    '/Pages (free|inactive|speculative)/ {sub(/\.$/, "", $NF); f += $NF} END {print "mem=" int((t-f*p)/1048576) "/" int(t/1048576)}'
+fi
+echo disk=$(df -P / | awk 'NR==2 {print $5}')"#;
+
+/// One reading taken inside a guest
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Sample {
+    /// Share of all guest CPUs in use, 0-100
+    cpu_percent: Option<f64>,
+    memory_used_mb: Option<u64>,
+    memory_total_mb: Option<u64>,
+    disk_percent: Option<u32>,
+}
+
+/// Smaller cpu/memory the API could request for a runner, given what it actually used
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Recommendation {
+    pub cpu: u32,
+    pub memory: Memory,
+}
+
+/// Usage of one runner over every sample taken since it was provisioned
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunnerMetrics {
+    pub runner_name: String,
+    pub samples: u32,
+    pub cpu_percent_avg: Option<f64>,
+    pub cpu_percent_peak: Option<f64>,
+    pub memory_used_mb_peak: Option<u64>,
+    pub memory_total_mb: Option<u64>,
+    pub disk_percent_peak: Option<u32>,
+    pub requested_cpu: u32,
+    pub requested_memory: Memory,
+    /// Only set when the runner used clearly less than it asked for
+    pub recommendation: Option<Recommendation>,
+    #[serde(skip)]
+    cpu_percent_sum: f64,
+    #[serde(skip)]
+    cpu_samples: u32,
+}
+
+static METRICS: Mutex<Option<HashMap<String, RunnerMetrics>>> = Mutex::new(None);
+
+fn with_metrics<T>(f: impl FnOnce(&mut HashMap<String, RunnerMetrics>) -> T) -> T {
+    f(METRICS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new))
+}
+
+/// Parse the output of the in-guest probe
+fn parse_sample(output: &str) -> Sample {
+    let mut sample = Sample::default();
+
+    for line in output.lines() {
+        if let Some(value) = line.strip_prefix("cpu=") {
+            sample.cpu_percent = value
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .map(|p| p.clamp(0.0, 100.0));
+        } else if let Some(value) = line.strip_prefix("mem=") {
+            if let Some((used, total)) = value.trim().split_once('/') {
+                sample.memory_used_mb = used.parse().ok();
+                sample.memory_total_mb = total.parse().ok();
+            }
+        } else if let Some(value) = line.strip_prefix("disk=") {
+            sample.disk_percent = value.trim().trim_end_matches('%').parse().ok();
+        }
+    }
+    sample
+}
+
+/// Resources covering the peak usage plus headroom, rounded up to whole CPUs and GB.
+/// `None` unless it is below what was requested.
+fn recommend(
+    requested_cpu: u32,
+    requested_memory: Memory,
+    cpu_percent_peak: Option<f64>,
+    memory_used_mb_peak: Option<u64>,
+    headroom_percent: u32,
+) -> Option<Recommendation> {
+    let factor = 1.0 + headroom_percent as f64 / 100.0;
+    let cpu = match cpu_percent_peak {
+        Some(peak) => {
+            let needed = (peak / 100.0 * requested_cpu as f64 * factor).ceil() as u32;
+            needed.clamp(1, requested_cpu.max(1))
+        }
+        None => requested_cpu,
+    };
+    let memory = match memory_used_mb_peak {
+        Some(peak) => {
+            let needed = Memory::from_gb(((peak as f64 * factor) / 1024.0).ceil().max(1.0) as u64);
+            needed.min(requested_memory)
+        }
+        None => requested_memory,
+    };
+    (cpu < requested_cpu || memory < requested_memory).then_some(Recommendation { cpu, memory })
+}
+
+fn record(runner: &RunnerToProvision, sample: Sample) -> RunnerMetrics {
+    let headroom = agent_config().metrics.headroom_percent;
+    with_metrics(|metrics| {
+        let entry = metrics
+            .entry(runner.name.clone())
+            .or_insert_with(|| RunnerMetrics {
+                runner_name: runner.name.clone(),
+                ..Default::default()
+            });
+        entry.samples += 1;
+        entry.requested_cpu = runner.cpu;
+        entry.requested_memory = runner.memory;
+        if let Some(cpu) = sample.cpu_percent {
+            entry.cpu_percent_sum += cpu;
+            entry.cpu_samples += 1;
+            entry.cpu_percent_avg = Some(entry.cpu_percent_sum / entry.cpu_samples as f64);
+            entry.cpu_percent_peak = Some(entry.cpu_percent_peak.map_or(cpu, |p| p.max(cpu)));
+        }
+        if let Some(used) = sample.memory_used_mb {
+            entry.memory_used_mb_peak =
+                Some(entry.memory_used_mb_peak.map_or(used, |p| p.max(used)));
+        }
+        entry.memory_total_mb = sample.memory_total_mb.or(entry.memory_total_mb);
+        if let Some(disk) = sample.disk_percent {
+            entry.disk_percent_peak = Some(entry.disk_percent_peak.map_or(disk, |p| p.max(disk)));
+        }
+        entry.recommendation = recommend(
+            entry.requested_cpu,
+            entry.requested_memory,
+            entry.cpu_percent_peak,
+            entry.memory_used_mb_peak,
+            headroom,
+        );
+        entry.clone()
+    })
+}
+
+/// Take one sample inside a running runner VM over SSH
+async fn sample_runner(runner: &RunnerToProvision) -> Result<Sample, String> {
+    let (state, ip) = get_vm_state_and_ip(&runner.name)
+        .await
+        .map_err(|e| e.to_string())?;
+    let Some(ip) = ip.filter(|ip| !ip.is_empty() && state == "running") else {
+        return Err(format!("VM is not running (state: {})", state));
+    };
+    let timeout = agent_config().timeouts.ssh_attempt_secs;
+    let vm_name = pool::vm_name(&runner.name);
+    let output = run_ssh_command(&vm_name, &ip, &runner.login, PROBE, timeout)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(parse_sample(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Sample every given runner sequentially and return each one's usage so far
+pub async fn sample_runners(runners: Vec<RunnerToProvision>) -> Vec<RunnerMetrics> {
+    info!("Sampling guest metrics on {} runners", runners.len());
+
+    let mut results = Vec::with_capacity(runners.len());
+    for runner in &runners {
+        let endpoint = endpoints::endpoint_for_runner(&runner.name);
+        match endpoints::on_endpoint(endpoint, sample_runner(runner)).await {
+            Ok(sample) => results.push(record(runner, sample)),
+            Err(e) => debug!("Could not sample metrics on '{}': {}", runner.name, e),
+        }
+    }
+    results
+}
+
+/// Drop the usage kept for a deleted runner
+pub fn forget(runner_name: &str) {
+    with_metrics(|metrics| metrics.remove(runner_name));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sample() {
+        let sample = parse_sample("cpu=50.5\nmem=3072/8192\ndisk=37%\n");
+        assert_eq!(sample.cpu_percent, Some(50.5));
+        assert_eq!(sample.memory_used_mb, Some(3072));
+        assert_eq!(sample.memory_total_mb, Some(8192));
+        assert_eq!(sample.disk_percent, Some(37));
+
+        let sample = parse_sample("cpu=\nmem=\ndisk=\n");
+        assert_eq!(sample, Sample::default());
+    }
+
+    #[test]
+    fn test_recommend() {
+        // 25% of 8 CPUs and 3 GB of 16 GB, with 25% headroom
+        assert_eq!(
+            recommend(8, Memory::from_gb(16), Some(25.0), Some(3072), 25),
+            Some(Recommendation {
+                cpu: 3,
+                memory: Memory::from_gb(4),
+            })
+        );
+        // Fully used runners keep what they asked for
+        assert_eq!(
+            recommend(2, Memory::from_gb(4), Some(100.0), Some(4000), 25),
+            None
+        );
+        assert_eq!(recommend(2, Memory::from_gb(4), None, None, 25), None);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::config::agent_config;
+use crate::endpoints;
+use crate::lume::client::LumeClient;
+use crate::meda::client::MedaClient;
+use crate::pool;
+use crate::vm_provision::run_ssh_command;
+use crate::{use_meda, RunnerLogin};
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Disk usage (percent) at or above which a runner is reported as unhealthy
+const DISK_FULL_THRESHOLD: u32 = 95;
+
+/// Process name of the GitHub Actions runner listener inside the guest
+const RUNNER_PROCESS: &str = "Runner.Listener";
+
+/// What the API should consider doing about an unhealthy runner
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SuggestedAction {
+    None,
+    Restart,
+    Reprovision,
+    Delete,
+}
+
+/// Result of a single health check pass over one runner
+#[derive(Debug, Serialize, Clone)]
+pub struct RunnerHealth {
+    pub runner_name: String,
+    pub vm_state: String,
+    pub ssh_reachable: Option<bool>,
+    pub runner_process_alive: Option<bool>,
+    pub disk_usage_percent: Option<u32>,
+    pub issues: Vec<String>,
+    pub suggested_action: SuggestedAction,
+}
+
+impl RunnerHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Fetch the provider state and IP address of a runner VM
+pub async fn get_vm_state_and_ip(
+    runner_name: &str,
+) -> Result<(String, Option<String>), Box<dyn std::error::Error>> {
+    let runner_name = &pool::vm_name(runner_name);
+    if use_meda() {
+        let meda = MedaClient::new()?;
+        let vm = meda.get_vm(runner_name).await?;
+        Ok((vm.state, vm.ip))
+    } else {
+        let lume = LumeClient::new()?;
+        let vm = lume.get_vm(runner_name).await?;
+        Ok((vm.state, vm.ip_address))
+    }
+}
+
+/// Parse the output of the in-guest probe command into (runner alive, disk usage)
+fn parse_probe_output(output: &str) -> (Option<bool>, Option<u32>) {
+    let mut runner_alive = None;
+    let mut disk_usage = None;
+
+    for line in output.lines() {
+        if let Some(value) = line.strip_prefix("runner=") {
+            runner_alive = Some(value.trim() == "up");
+        } else if let Some(value) = line.strip_prefix("disk=") {
+            disk_usage = value.trim().trim_end_matches('%').parse::<u32>().ok();
+        }
+    }
+
+    (runner_alive, disk_usage)
+}
+
+/// Check a single runner: VM state, SSH reachability, runner process and disk usage.
+/// Without login details only the VM state can be checked.
+pub async fn check_runner(runner_name: &str, login: Option<&RunnerLogin>) -> RunnerHealth {
+    let mut health = RunnerHealth {
+        runner_name: runner_name.to_string(),
+        vm_state: "unknown".to_string(),
+        ssh_reachable: None,
+        runner_process_alive: None,
+        disk_usage_percent: None,
+        issues: Vec::new(),
+        suggested_action: SuggestedAction::None,
+    };
+
+    let ip_address = match get_vm_state_and_ip(runner_name).await {
+        Ok((state, ip)) => {
+            health.vm_state = state;
+            ip
+        }
+        Err(e) => {
+            health
+                .issues
+                .push(format!("VM could not be found on the provider: {}", e));
+            health.suggested_action = SuggestedAction::Reprovision;
+            return health;
+        }
+    };
+
+    if health.vm_state != "running" {
+        health
+            .issues
+            .push(format!("VM is not running (state: {})", health.vm_state));
+        health.suggested_action = SuggestedAction::Restart;
+        return health;
+    }
+
+    let (Some(login), Some(ip_address)) = (login, ip_address.filter(|ip| !ip.is_empty())) else {
+        return health;
+    };
+
+    let probe = probe_command();
+
+    let timeout = agent_config().timeouts.ssh_attempt_secs;
+    let vm_name = pool::vm_name(runner_name);
+    match run_ssh_command(&vm_name, &ip_address, login, &probe, timeout).await {
+        Ok(output) if output.status.success() => {
+            health.ssh_reachable = Some(true);
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let (runner_alive, disk_usage) = parse_probe_output(&stdout);
+            health.runner_process_alive = runner_alive;
+            health.disk_usage_percent = disk_usage;
+
+            if runner_alive == Some(false) {
+                health
+                    .issues
+                    .push("Runner process is not running".to_string());
+                health.suggested_action = SuggestedAction::Reprovision;
+            }
+            if let Some(usage) = disk_usage.filter(|u| *u >= DISK_FULL_THRESHOLD) {
+                health.issues.push(format!("Disk is {}% full", usage));
+                health.suggested_action = SuggestedAction::Delete;
+            }
+        }
+        Ok(output) => {
+            health.ssh_reachable = Some(false);
+            health.issues.push(format!(
+                "SSH probe failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+            health.suggested_action = SuggestedAction::Restart;
+        }
+        Err(e) => {
+            health.ssh_reachable = Some(false);
+            health.issues.push(format!("SSH unreachable: {}", e));
+            health.suggested_action = SuggestedAction::Restart;
+        }
+    }
+
+    health
+}
+
+/// Check every given runner sequentially and return the results
+pub async fn check_runners(runners: HashMap<String, Option<RunnerLogin>>) -> Vec<RunnerHealth> {
+    info!("Running health checks on {} runners", runners.len());
+
+    let mut results = Vec::with_capacity(runners.len());
+    for (runner_name, login) in &runners {
+        let endpoint = endpoints::endpoint_for_runner(runner_name);
+        let health =
+            endpoints::on_endpoint(endpoint, check_runner(runner_name, login.as_ref())).await;
+        if health.is_healthy() {
+            info!("Runner '{}' is healthy", runner_name);
+        } else {
+            warn!(
+                "Runner '{}' is unhealthy: {} (suggested action: {:?})",
+                runner_name,
+                health.issues.join("; "),
+                health.suggested_action
+            );
+        }
+        results.push(health);
+    }
+    results
+}
+
+/// Shell command printing whether SSH works, the runner is up and how full the disk is.
+/// The pattern is bracketed (`[R]unner.Listener`) so `pgrep -f` doesn't match the shell
+/// running this very command, whose command line contains the pattern too.
+fn probe_command() -> String {
+    let (first, rest) = RUNNER_PROCESS.split_at(1);
+    format!(
+        "echo ok; (pgrep -f '[{}]{}' > /dev/null && echo runner=up) || echo runner=down; \
+         echo disk=$(df -P / | awk 'NR==2 {{print $5}}')",
+        first, rest
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_does_not_match_itself() {
+        let probe = probe_command();
+        assert!(probe.contains("pgrep -f '[R]unner.Listener'"));
+        assert!(!probe.contains(RUNNER_PROCESS));
+    }
+
+    #[test]
+    fn test_parse_probe_output() {
+        let (alive, disk) = parse_probe_output("ok\nrunner=up\ndisk=42%\n");
+        assert_eq!(alive, Some(true));
+        assert_eq!(disk, Some(42));
+
+        let (alive, disk) = parse_probe_output("ok\nrunner=down\ndisk=\n");
+        assert_eq!(alive, Some(false));
+        assert_eq!(disk, None);
+    }
+}
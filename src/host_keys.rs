@@ -0,0 +1,236 @@
+use crate::config::{agent_config, HostKeyMode};
+use crate::endpoints;
+use crate::readiness::guest_agent_socket;
+use crate::use_meda;
+use base64::prelude::*;
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::PathBuf;
+use tokio::time::{sleep, Duration, Instant};
+
+const KNOWN_HOSTS_DIR: &str = ".cirun-agent/known_hosts";
+/// A guest agent request that gets no answer within this is retried
+#[cfg(unix)]
+const GUEST_REQUEST_SECS: u64 = 5;
+/// Prints the guest's SSH host public keys
+const READ_HOST_KEYS: &str = "cat /etc/ssh/ssh_host_*_key.pub";
+
+/// Known hosts file for a VM. Keys are stored under the VM name (`HostKeyAlias`), so a
+/// new IP address after a restart keeps them, and a recycled IP never matches them.
+fn known_hosts_path(vm_name: &str) -> PathBuf {
+    // VM names come from the API; never let one escape the known hosts directory
+    let safe_name: String = vm_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home_dir)
+        .join(KNOWN_HOSTS_DIR)
+        .join(safe_name)
+}
+
+fn options_for(mode: HostKeyMode, vm_name: &str, known_hosts: &str) -> Vec<String> {
+    let checking = match mode {
+        HostKeyMode::Off => {
+            return vec![
+                "StrictHostKeyChecking=no".to_string(),
+                "UserKnownHostsFile=/dev/null".to_string(),
+            ]
+        }
+        HostKeyMode::Tofu => "accept-new",
+        HostKeyMode::Strict => "yes",
+    };
+    vec![
+        format!("StrictHostKeyChecking={}", checking),
+        format!("UserKnownHostsFile={}", known_hosts),
+        format!("HostKeyAlias={}", vm_name),
+    ]
+}
+
+/// ssh options verifying the host key of `vm_name` according to `[ssh] host_keys`
+pub fn ssh_options(vm_name: &str) -> Vec<String> {
+    let mode = agent_config().ssh.host_keys;
+    let path = known_hosts_path(vm_name);
+    if mode != HostKeyMode::Off {
+        if let Some(dir) = path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                warn!("Failed to create known hosts directory {:?}: {}", dir, e);
+            }
+        }
+    }
+    options_for(mode, vm_name, &path.to_string_lossy())
+}
+
+/// Forget the host keys of a deleted VM, so a new VM with the same name is trusted afresh
+pub fn forget(vm_name: &str) {
+    let path = known_hosts_path(vm_name);
+    if let Err(e) = fs::remove_file(&path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove known hosts file {:?}: {}", path, e);
+        }
+    }
+}
+
+/// `known_hosts` lines for the public keys in `output`, stored under `vm_name`
+fn known_hosts_lines(vm_name: &str, output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (key_type, key) = (fields.next()?, fields.next()?);
+            let known = key_type.starts_with("ssh-") || key_type.starts_with("ecdsa-");
+            known.then(|| format!("{} {} {}", vm_name, key_type, key))
+        })
+        .collect()
+}
+
+/// The guest agent socket is a Unix socket, so elsewhere there is never an answer
+#[cfg(not(unix))]
+async fn guest_agent_request(_socket: &str, _request: Value) -> Option<Value> {
+    None
+}
+
+#[cfg(unix)]
+async fn guest_agent_request(socket: &str, request: Value) -> Option<Value> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::time::timeout;
+
+    let exchange = async {
+        let mut stream = tokio::net::UnixStream::connect(socket).await.ok()?;
+        let mut line = request.to_string();
+        line.push('\n');
+        stream.write_all(line.as_bytes()).await.ok()?;
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response).await.ok()?;
+        serde_json::from_str::<Value>(&response)
+            .ok()?
+            .get("return")
+            .cloned()
+    };
+    timeout(Duration::from_secs(GUEST_REQUEST_SECS), exchange)
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Read the guest's host public keys through the QEMU guest agent
+async fn host_keys_from_guest(vm_name: &str) -> Option<String> {
+    let socket = guest_agent_socket(vm_name);
+    let started = guest_agent_request(
+        &socket,
+        json!({
+            "execute": "guest-exec",
+            "arguments": {
+                "path": "/bin/sh",
+                "arg": ["-c", READ_HOST_KEYS],
+                "capture-output": true,
+            },
+        }),
+    )
+    .await?;
+    let pid = started.get("pid")?.as_i64()?;
+    for _ in 0..10 {
+        let status = guest_agent_request(
+            &socket,
+            json!({ "execute": "guest-exec-status", "arguments": { "pid": pid } }),
+        )
+        .await?;
+        if status.get("exited").and_then(Value::as_bool) == Some(true) {
+            let data = status.get("out-data")?.as_str()?;
+            let output = BASE64_STANDARD.decode(data).ok()?;
+            return Some(String::from_utf8_lossy(&output).to_string());
+        }
+        sleep(Duration::from_millis(500)).await;
+    }
+    None
+}
+
+/// In strict mode, make sure the host keys of `vm_name` are known before connecting,
+/// reading them from the guest agent. Only meda VMs on this host have one.
+pub async fn ensure_trusted(vm_name: &str, timeout_secs: u64) -> Result<(), String> {
+    if agent_config().ssh.host_keys != HostKeyMode::Strict {
+        return Ok(());
+    }
+    let path = known_hosts_path(vm_name);
+    if fs::metadata(&path).is_ok_and(|m| m.len() > 0) {
+        return Ok(());
+    }
+    if !use_meda() || endpoints::current().is_some() {
+        return Err(format!(
+            "No trusted host key for '{}': strict host key checking reads keys from the \
+             guest agent, which only meda VMs on this host have",
+            vm_name
+        ));
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(timeout_secs);
+    while Instant::now() < deadline {
+        // sshd generates its host keys during the first boot
+        let lines = match host_keys_from_guest(vm_name).await {
+            Some(output) => known_hosts_lines(vm_name, &output),
+            None => Vec::new(),
+        };
+        if !lines.is_empty() {
+            let written = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(&path, lines.join("\n") + "\n"));
+            return match written {
+                Ok(()) => {
+                    info!(
+                        "Trusted {} host keys of '{}' from the guest agent",
+                        lines.len(),
+                        vm_name
+                    );
+                    Ok(())
+                }
+                Err(e) => Err(format!("Failed to write {:?}: {}", path, e)),
+            };
+        }
+        sleep(Duration::from_secs(2)).await;
+    }
+    Err(format!(
+        "Could not read the host keys of '{}' from the guest agent within {}s",
+        vm_name, timeout_secs
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_key_options_and_known_hosts() {
+        assert_eq!(
+            options_for(HostKeyMode::Off, "cirun-1", "/kh/cirun-1"),
+            vec!["StrictHostKeyChecking=no", "UserKnownHostsFile=/dev/null"]
+        );
+        assert_eq!(
+            options_for(HostKeyMode::Tofu, "cirun-1", "/kh/cirun-1"),
+            vec![
+                "StrictHostKeyChecking=accept-new",
+                "UserKnownHostsFile=/kh/cirun-1",
+                "HostKeyAlias=cirun-1"
+            ]
+        );
+        assert!(known_hosts_path("../etc/passwd").ends_with("___etc_passwd"));
+
+        let output = "ssh-ed25519 AAAAC3Nz root@guest\n\
+                      cat: /etc/ssh/ssh_host_dsa_key.pub: No such file or directory\n\
+                      ecdsa-sha2-nistp256 AAAAE2Vj root@guest\n";
+        assert_eq!(
+            known_hosts_lines("cirun-1", output),
+            vec![
+                "cirun-1 ssh-ed25519 AAAAC3Nz",
+                "cirun-1 ecdsa-sha2-nistp256 AAAAE2Vj"
+            ]
+        );
+    }
+}
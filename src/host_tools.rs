@@ -0,0 +1,184 @@
+use flate2::read::GzDecoder;
+use std::env;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, Signal, System, UpdateKind};
+
+// Host utilities the agent used to shell out for (`hostname`, `which`, `pgrep`, `pkill`,
+// `kill`, `curl`, `tar`), so it does not depend on a Unix userland
+
+/// Name of this host
+pub fn hostname() -> Option<String> {
+    System::host_name().filter(|name| !name.is_empty())
+}
+
+/// Path of `program` on `PATH`, like `which`. On Windows the `PATHEXT` extensions are tried.
+pub fn find_program(program: &str) -> Option<PathBuf> {
+    let extensions: Vec<OsString> = if cfg!(windows) {
+        env::var("PATHEXT")
+            .unwrap_or_else(|_| ".EXE;.CMD;.BAT;.COM".to_string())
+            .split(';')
+            .map(OsString::from)
+            .collect()
+    } else {
+        vec![OsString::new()]
+    };
+    env::split_paths(&env::var_os("PATH")?).find_map(|dir| {
+        extensions.iter().find_map(|extension| {
+            let mut name = OsString::from(program);
+            name.push(extension);
+            Some(dir.join(name)).filter(|path| is_executable(path))
+        })
+    })
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Whether a command line contains `pattern`, like `pgrep -f`
+fn command_matches(cmd: &[OsString], pattern: &str) -> bool {
+    let line = cmd
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    line.contains(pattern)
+}
+
+/// Other processes whose command line contains `pattern`
+fn matching_processes(system: &mut System, pattern: &str) -> Vec<Pid> {
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::All,
+        true,
+        ProcessRefreshKind::nothing().with_cmd(UpdateKind::Always),
+    );
+    let own = sysinfo::get_current_pid().ok();
+    system
+        .processes()
+        .iter()
+        .filter(|(pid, process)| Some(**pid) != own && command_matches(process.cmd(), pattern))
+        .map(|(pid, _)| *pid)
+        .collect()
+}
+
+/// Whether a process whose command line contains `pattern` is running
+pub fn process_running(pattern: &str) -> bool {
+    !matching_processes(&mut System::new(), pattern).is_empty()
+}
+
+/// Ask a process to exit, or kill it with `force`. Where there is no SIGTERM (Windows),
+/// the process is killed.
+fn signal(system: &System, pid: Pid, force: bool) {
+    if let Some(process) = system.process(pid) {
+        let signal = if force { Signal::Kill } else { Signal::Term };
+        if process.kill_with(signal).is_none() {
+            process.kill();
+        }
+    }
+}
+
+/// Signal every process whose command line contains `pattern`, like `pkill -f`
+pub fn signal_processes(pattern: &str, force: bool) {
+    let mut system = System::new();
+    for pid in matching_processes(&mut system, pattern) {
+        signal(&system, pid, force);
+    }
+}
+
+/// Ask the process `pid` to exit, like `kill -TERM`
+pub fn terminate(pid: u32) {
+    let pid = Pid::from_u32(pid);
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&[pid]),
+        true,
+        ProcessRefreshKind::nothing(),
+    );
+    signal(&system, pid, false);
+}
+
+/// Download `url` to `dest`, failing on HTTP errors, like `curl -fL -o`. Blocks; the
+/// request runs on a thread of its own, so this also works from within the async runtime.
+pub fn download(url: &str, dest: &Path) -> Result<(), String> {
+    let fetch = || -> Result<(), String> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| e.to_string())?;
+        runtime.block_on(async {
+            let mut response = reqwest::get(url)
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| format!("Failed to download {}: {}", url, e))?;
+            let mut file =
+                File::create(dest).map_err(|e| format!("Failed to create {:?}: {}", dest, e))?;
+            while let Some(chunk) = response
+                .chunk()
+                .await
+                .map_err(|e| format!("Failed to download {}: {}", url, e))?
+            {
+                file.write_all(&chunk)
+                    .map_err(|e| format!("Failed to write {:?}: {}", dest, e))?;
+            }
+            Ok(())
+        })
+    };
+    std::thread::scope(|scope| {
+        scope
+            .spawn(fetch)
+            .join()
+            .unwrap_or_else(|_| Err(format!("Download of {} panicked", url)))
+    })
+}
+
+/// Extract a `.tar.gz` archive into `dest`, like `tar -xzf`
+pub fn extract_tar_gz(archive: &Path, dest: &Path) -> io::Result<()> {
+    tar::Archive::new(GzDecoder::new(File::open(archive)?)).unpack(dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    #[test]
+    fn test_process_match_and_extract() {
+        let cmd: Vec<OsString> = ["/usr/local/bin/meda", "serve", "--port", "7777"]
+            .iter()
+            .map(OsString::from)
+            .collect();
+        assert!(command_matches(&cmd, "meda serve"));
+        assert!(!command_matches(&cmd, "lume serve"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("lume.tar.gz");
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            File::create(&archive).unwrap(),
+            Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "bin/lume", &b"lume"[..])
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let out = dir.path().join("out");
+        extract_tar_gz(&archive, &out).unwrap();
+        assert_eq!(std::fs::read(out.join("bin/lume")).unwrap(), b"lume");
+    }
+}
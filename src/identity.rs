@@ -0,0 +1,422 @@
+use crate::build_info;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Format version of the agent state file
+const STATE_VERSION: u32 = 1;
+
+/// Phrases the API uses when it no longer knows an agent ID
+const UNKNOWN_AGENT_MARKERS: &[&str] = &["unknown agent", "unknown_agent", "agent not found"];
+
+/// A bare 410 Gone only counts as an unknown agent after this many in a row, so a single
+/// misrouted response never rotates the identity
+const GONE_RESPONSES_BEFORE_RESET: u32 = 3;
+
+/// The last API contact is written at most this often, not on every poll
+const CONTACT_WRITE_INTERVAL: Duration = Duration::from_secs(60);
+
+static ID_FILE: OnceLock<PathBuf> = OnceLock::new();
+static LAST_CONTACT_WRITE: Mutex<Option<Instant>> = Mutex::new(None);
+static CONSECUTIVE_GONE: Mutex<u32> = Mutex::new(0);
+
+/// Who this agent is, kept in the agent ID file (`--id-file`, `~/.agent_id` by default)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AgentIdentity {
+    pub version: u32,
+    pub id: String,
+    pub first_seen: DateTime<Utc>,
+    #[serde(default)]
+    pub labels: Vec<String>,
+    #[serde(default)]
+    pub last_api_contact: Option<DateTime<Utc>>,
+    /// Agent version that last wrote the file
+    #[serde(default)]
+    pub agent_version: String,
+    /// Last known version of each VM provider, e.g. `"meda": "0.3.1"`
+    #[serde(default)]
+    pub provider_versions: BTreeMap<String, String>,
+}
+
+impl AgentIdentity {
+    fn new(id: String, first_seen: DateTime<Utc>) -> Self {
+        AgentIdentity {
+            version: STATE_VERSION,
+            id,
+            first_seen,
+            labels: Vec::new(),
+            last_api_contact: None,
+            agent_version: build_info::VERSION.to_string(),
+            provider_versions: BTreeMap::new(),
+        }
+    }
+
+    pub fn generate() -> Self {
+        Self::new(Uuid::new_v4().to_string(), Utc::now())
+    }
+}
+
+/// Remember where the agent state is kept so it can be updated while running
+pub fn set_id_file(path: &Path) {
+    let _ = ID_FILE.set(path.to_path_buf());
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Run `f` holding an exclusive lock on `<file>.lock`, so several agent processes (or a
+/// running agent and `reset-identity`) never interleave their writes
+fn locked<T>(path: &Path, f: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let lock = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(sibling(path, ".lock"))?;
+    lock.lock()?;
+    f()
+}
+
+/// Read the state file. A file holding only an ID, as older agents wrote, is migrated with
+/// its modification time as the first-seen time.
+fn read(path: &Path) -> io::Result<Option<AgentIdentity>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let contents = contents.trim();
+    if contents.is_empty() {
+        return Ok(None);
+    }
+    if contents.starts_with('{') {
+        return serde_json::from_str(contents)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+    if contents.contains(char::is_whitespace) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "agent ID file is neither an ID nor a state file",
+        ));
+    }
+    let first_seen = fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .map(DateTime::<Utc>::from)
+        .unwrap_or_else(|_| Utc::now());
+    info!("Migrating legacy agent ID file {:?} to a state file", path);
+    Ok(Some(AgentIdentity::new(contents.to_string(), first_seen)))
+}
+
+/// Write the state file through a temporary file, so it is never left half written
+fn write(path: &Path, identity: &AgentIdentity) -> io::Result<()> {
+    let tmp = sibling(path, ".tmp");
+    let json = serde_json::to_string_pretty(identity).map_err(io::Error::other)?;
+    fs::write(&tmp, json)?;
+    fs::rename(&tmp, path)
+}
+
+/// Load the agent identity, creating one on first start. Given labels replace the stored
+/// ones. A file that cannot be parsed is archived and replaced with a new identity; any other
+/// read error, e.g. a permission problem, is returned so the identity is never lost to it.
+pub fn load_or_create(path: &Path, labels: &[String]) -> io::Result<AgentIdentity> {
+    locked(path, || {
+        let mut identity = match read(path) {
+            Ok(Some(identity)) => {
+                info!("Using existing agent ID: {}", identity.id);
+                identity
+            }
+            Ok(None) => {
+                let identity = AgentIdentity::generate();
+                info!("Generated new agent ID: {}", identity.id);
+                identity
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                let archive = archive_path(path);
+                error!(
+                    "Failed to read agent state file {:?}: {}. Moving it to {:?}",
+                    path, e, archive
+                );
+                fs::rename(path, &archive)?;
+                let identity = AgentIdentity::generate();
+                info!("Generated new agent ID: {}", identity.id);
+                identity
+            }
+            Err(e) => return Err(e),
+        };
+        identity.version = STATE_VERSION;
+        identity.agent_version = build_info::VERSION.to_string();
+        if !labels.is_empty() {
+            identity.labels = labels.to_vec();
+        }
+        write(path, &identity)?;
+        Ok(identity)
+    })
+}
+
+/// Change the state file registered with `set_id_file`
+fn update(f: impl FnOnce(&mut AgentIdentity)) {
+    let Some(path) = ID_FILE.get() else {
+        return;
+    };
+    let result = locked(path, || {
+        let Some(mut identity) = read(path)? else {
+            return Ok(());
+        };
+        f(&mut identity);
+        write(path, &identity)
+    });
+    if let Err(e) = result {
+        warn!("Failed to update agent state file {:?}: {}", path, e);
+    }
+}
+
+/// Record that the API answered a poll
+pub fn record_api_contact() {
+    {
+        let mut last = LAST_CONTACT_WRITE.lock().unwrap_or_else(|e| e.into_inner());
+        if last.is_some_and(|at| at.elapsed() < CONTACT_WRITE_INTERVAL) {
+            return;
+        }
+        *last = Some(Instant::now());
+    }
+    update(|identity| identity.last_api_contact = Some(Utc::now()));
+}
+
+/// Record the installed version of a VM provider
+pub fn record_provider_version(provider: &str, version: &str) {
+    update(|identity| {
+        identity
+            .provider_versions
+            .insert(provider.to_string(), version.to_string());
+    });
+}
+
+/// Whether an API response says the backend has no agent with our ID, e.g. because it was
+/// deleted. A plain 404 is not enough, since a misconfigured API URL produces those too, and
+/// a 410 without an unknown-agent marker only counts once several arrive in a row.
+pub fn is_unknown_agent(status: StatusCode, body: &str) -> bool {
+    let mut consecutive_gone = CONSECUTIVE_GONE.lock().unwrap_or_else(|e| e.into_inner());
+    classify_response(status, body, &mut consecutive_gone)
+}
+
+fn classify_response(status: StatusCode, body: &str, consecutive_gone: &mut u32) -> bool {
+    let body = body.to_lowercase();
+    if status.is_client_error()
+        && UNKNOWN_AGENT_MARKERS
+            .iter()
+            .any(|marker| body.contains(marker))
+    {
+        *consecutive_gone = 0;
+        return true;
+    }
+    if status != StatusCode::GONE {
+        *consecutive_gone = 0;
+        return false;
+    }
+    *consecutive_gone += 1;
+    if *consecutive_gone < GONE_RESPONSES_BEFORE_RESET {
+        warn!(
+            "Cirun API answered 410 Gone without naming the agent ({} of {} before resetting the identity)",
+            consecutive_gone, GONE_RESPONSES_BEFORE_RESET
+        );
+        return false;
+    }
+    *consecutive_gone = 0;
+    true
+}
+
+/// Path the old ID file is moved to, e.g. `.agent_id.20260101T120000Z.bak`
+fn archive_path(id_file: &Path) -> PathBuf {
+    sibling(
+        id_file,
+        &format!(".{}.bak", Utc::now().format("%Y%m%dT%H%M%SZ")),
+    )
+}
+
+/// Move the ID file aside and write a newly generated identity in its place, keeping the
+/// labels. Returns the archive path, if there was a file to archive, and the new ID.
+pub fn reset(id_file: &Path) -> io::Result<(Option<PathBuf>, String)> {
+    locked(id_file, || {
+        let labels = read(id_file)
+            .ok()
+            .flatten()
+            .map(|old| old.labels)
+            .unwrap_or_default();
+        let archived = if id_file.exists() {
+            let archive = archive_path(id_file);
+            fs::rename(id_file, &archive)?;
+            Some(archive)
+        } else {
+            None
+        };
+        let mut identity = AgentIdentity::generate();
+        identity.labels = labels;
+        write(id_file, &identity)?;
+        Ok((archived, identity.id))
+    })
+}
+
+/// The identity stored in an ID file, if there is one
+pub fn load(id_file: &Path) -> io::Result<Option<AgentIdentity>> {
+    locked(id_file, || read(id_file))
+}
+
+/// Replace the ID file with `identity`, e.g. one moved over from another host. An existing
+/// file with a different ID is only replaced with `force`, and is archived first. Returns
+/// the archive path, if a file was archived.
+pub fn import(
+    id_file: &Path,
+    identity: &AgentIdentity,
+    force: bool,
+) -> io::Result<Option<PathBuf>> {
+    locked(id_file, || {
+        let existing = read(id_file).ok().flatten();
+        let archived = match existing {
+            Some(existing) if existing.id == identity.id => None,
+            Some(existing) if !force => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{:?} already holds agent ID {}; use --force to replace it",
+                        id_file, existing.id
+                    ),
+                ))
+            }
+            _ if id_file.exists() => {
+                let archive = archive_path(id_file);
+                fs::rename(id_file, &archive)?;
+                Some(archive)
+            }
+            _ => None,
+        };
+        let mut identity = identity.clone();
+        identity.version = STATE_VERSION;
+        write(id_file, &identity)?;
+        Ok(archived)
+    })
+}
+
+/// Reset the ID file registered with `set_id_file`
+pub fn reset_current() -> io::Result<(Option<PathBuf>, String)> {
+    let id_file = ID_FILE
+        .get()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "agent ID file not set"))?;
+    reset(id_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_unknown_agent() {
+        let mut gone = 0;
+        let mut check = |status, body| classify_response(status, body, &mut gone);
+        assert!(check(StatusCode::GONE, r#"{"error": "agent not found"}"#));
+        assert!(check(
+            StatusCode::NOT_FOUND,
+            r#"{"error": "Unknown agent"}"#
+        ));
+        assert!(check(StatusCode::FORBIDDEN, r#"{"code": "unknown_agent"}"#));
+        assert!(!check(StatusCode::NOT_FOUND, "Not Found"));
+        assert!(!check(StatusCode::OK, "unknown agent"));
+    }
+
+    #[test]
+    fn test_bare_gone_needs_consecutive_responses() {
+        let mut gone = 0;
+        assert!(!classify_response(StatusCode::GONE, "", &mut gone));
+        assert!(!classify_response(StatusCode::GONE, "", &mut gone));
+        // Any other response starts the count over
+        assert!(!classify_response(StatusCode::OK, "{}", &mut gone));
+        for _ in 1..GONE_RESPONSES_BEFORE_RESET {
+            assert!(!classify_response(StatusCode::GONE, "", &mut gone));
+        }
+        assert!(classify_response(StatusCode::GONE, "", &mut gone));
+        assert_eq!(gone, 0);
+    }
+
+    #[test]
+    fn test_reset_archives_old_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let id_file = dir.path().join(".agent_id");
+        let labels = vec!["gpu".to_string()];
+        let old = load_or_create(&id_file, &labels).unwrap();
+
+        let (archived, new_id) = reset(&id_file).unwrap();
+        let archived = read(&archived.unwrap()).unwrap().unwrap();
+        assert_eq!(archived.id, old.id);
+        let new = read(&id_file).unwrap().unwrap();
+        assert_eq!(new.id, new_id);
+        assert_ne!(new_id, old.id);
+        assert_eq!(new.labels, labels);
+
+        let (archived, _) = reset(&dir.path().join("missing")).unwrap();
+        assert!(archived.is_none());
+    }
+
+    #[test]
+    fn test_legacy_id_file_is_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let id_file = dir.path().join(".agent_id");
+        fs::write(&id_file, "4a7c1f9e-legacy\n").unwrap();
+
+        let identity = load_or_create(&id_file, &[]).unwrap();
+        assert_eq!(identity.id, "4a7c1f9e-legacy");
+        assert_eq!(identity.version, STATE_VERSION);
+        assert_eq!(read(&id_file).unwrap(), Some(identity.clone()));
+
+        // Loading again keeps the identity and applies new labels
+        let labels = vec!["linux".to_string(), "x64".to_string()];
+        let reloaded = load_or_create(&id_file, &labels).unwrap();
+        assert_eq!(reloaded.id, identity.id);
+        assert_eq!(reloaded.first_seen, identity.first_seen);
+        assert_eq!(reloaded.labels, labels);
+    }
+
+    #[test]
+    fn test_corrupt_state_file_is_archived() {
+        let dir = tempfile::tempdir().unwrap();
+        let id_file = dir.path().join(".agent_id");
+        fs::write(&id_file, "{ not json").unwrap();
+
+        let identity = load_or_create(&id_file, &[]).unwrap();
+        assert!(!identity.id.is_empty());
+        let archived = fs::read_dir(dir.path())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".bak"))
+            .count();
+        assert_eq!(archived, 1);
+    }
+
+    #[test]
+    fn test_unreadable_state_file_is_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        // A directory cannot be read as a file, like a file the agent has no access to
+        let id_file = dir.path().join(".agent_id");
+        fs::create_dir(&id_file).unwrap();
+
+        assert!(load_or_create(&id_file, &[]).is_err());
+        assert!(id_file.is_dir());
+        let archived = fs::read_dir(dir.path())
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.file_name().to_string_lossy().ends_with(".bak"));
+        assert!(!archived);
+    }
+}
@@ -0,0 +1,199 @@
+use crate::config::agent_config;
+use crate::disk::vm_storage_dir;
+use crate::endpoints;
+use crate::meda::client::MedaClient;
+use crate::meda::errors::MedaError;
+use log::{debug, info};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use tokio::time::{sleep, Duration};
+
+/// How often the host's lease and ARP tables are checked
+const FAST_POLL_MILLIS: u64 = 500;
+/// VM configuration files larger than this (disk images) are not scanned for the MAC
+const MAX_CONFIG_FILE_BYTES: u64 = 64 * 1024;
+pub const ARP_TABLE: &str = "/proc/net/arp";
+
+/// Wait for a meda VM's IP address. With `[ip_discovery] fast_path` enabled, the host's
+/// DHCP leases and ARP table are watched for the VM's MAC address alongside the API poll,
+/// and whichever finds the address first wins.
+pub async fn wait_for_meda_ip(
+    meda: &MedaClient,
+    vm_name: &str,
+    timeout_seconds: u64,
+) -> Result<String, MedaError> {
+    // Lease and ARP tables only describe VMs on this host
+    if !agent_config().ip_discovery.fast_path || endpoints::current().is_some() {
+        return meda.wait_for_vm_ip(vm_name, timeout_seconds).await;
+    }
+    tokio::select! {
+        result = meda.wait_for_vm_ip(vm_name, timeout_seconds) => result,
+        ip = watch_host_tables(vm_name) => {
+            info!("VM {} has IP address: {} (from host lease/ARP tables)", vm_name, ip);
+            Ok(ip)
+        }
+    }
+}
+
+async fn watch_host_tables(vm_name: &str) -> String {
+    let mut mac = None;
+    loop {
+        // The VM's configuration may only be written once it is created
+        if mac.is_none() {
+            mac = vm_mac(&vm_storage_dir(vm_name));
+            if let Some(mac) = &mac {
+                debug!("VM {} has MAC address {}", vm_name, mac);
+            }
+        }
+        if let Some(ip) = mac.as_deref().and_then(lookup_ip) {
+            return ip;
+        }
+        sleep(Duration::from_millis(FAST_POLL_MILLIS)).await;
+    }
+}
+
+/// First MAC address found in the VM's configuration files
+pub fn vm_mac(vm_dir: &Path) -> Option<String> {
+    fs::read_dir(vm_dir)
+        .ok()?
+        .flatten()
+        .filter(|entry| {
+            entry
+                .metadata()
+                .is_ok_and(|m| m.is_file() && m.len() <= MAX_CONFIG_FILE_BYTES)
+        })
+        .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+        .find_map(|content| find_mac(&content))
+}
+
+fn find_mac(content: &str) -> Option<String> {
+    content
+        .split(|c: char| !(c.is_ascii_hexdigit() || c == ':'))
+        .find(|token| is_mac(token))
+        .map(str::to_ascii_lowercase)
+}
+
+/// `mac` in lowercase with two digits per octet; macOS tools drop leading zeros
+pub fn normalize_mac(mac: &str) -> Option<String> {
+    let octets = mac
+        .split(':')
+        .map(|octet| u8::from_str_radix(octet, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    (octets.len() == 6).then(|| {
+        octets
+            .iter()
+            .map(|octet| format!("{:02x}", octet))
+            .collect::<Vec<_>>()
+            .join(":")
+    })
+}
+
+fn is_mac(token: &str) -> bool {
+    let parts: Vec<&str> = token.split(':').collect();
+    parts.len() == 6
+        && parts
+            .iter()
+            .all(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// IP leased to or seen from `mac` in the configured lease files or the ARP table
+fn lookup_ip(mac: &str) -> Option<String> {
+    let now = chrono::Utc::now().timestamp();
+    agent_config()
+        .ip_discovery
+        .lease_files
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .find_map(|content| {
+            find_in_dnsmasq_leases(&content, mac, now)
+                .or_else(|| find_in_libvirt_status(&content, mac, now))
+        })
+        .or_else(|| {
+            fs::read_to_string(ARP_TABLE)
+                .ok()
+                .and_then(|content| find_in_arp_table(&content, mac))
+        })
+}
+
+/// dnsmasq lease file: `<expiry> <mac> <ip> <hostname> <client-id>`
+fn find_in_dnsmasq_leases(content: &str, mac: &str, now: i64) -> Option<String> {
+    content.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let expiry: i64 = fields.first()?.parse().ok()?;
+        // 0 means an infinite lease
+        let current = expiry == 0 || expiry > now;
+        if current && fields.get(1)?.eq_ignore_ascii_case(mac) {
+            fields.get(2).map(|ip| ip.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+#[derive(Deserialize)]
+struct LibvirtLease {
+    #[serde(rename = "ip-address")]
+    ip_address: String,
+    #[serde(rename = "mac-address")]
+    mac_address: String,
+    #[serde(rename = "expiry-time")]
+    expiry_time: i64,
+}
+
+/// libvirt network status file (`/var/lib/libvirt/dnsmasq/<bridge>.status`)
+fn find_in_libvirt_status(content: &str, mac: &str, now: i64) -> Option<String> {
+    let leases: Vec<LibvirtLease> = serde_json::from_str(content).ok()?;
+    leases
+        .into_iter()
+        .find(|lease| lease.mac_address.eq_ignore_ascii_case(mac) && lease.expiry_time > now)
+        .map(|lease| lease.ip_address)
+}
+
+/// `/proc/net/arp`: `IP address  HW type  Flags  HW address  Mask  Device`
+fn find_in_arp_table(content: &str, mac: &str) -> Option<String> {
+    content.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // Flags 0x0 is an incomplete entry
+        let complete = fields.get(2).is_some_and(|flags| *flags != "0x0");
+        (complete && fields.get(3)?.eq_ignore_ascii_case(mac)).then(|| fields[0].to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lease_and_arp_lookup() {
+        assert_eq!(
+            find_mac("--net tap=,mac=52:54:00:AB:cd:01,ip=192.168.100.1").as_deref(),
+            Some("52:54:00:ab:cd:01")
+        );
+        assert_eq!(find_mac("created 2024-01-01T10:11:12"), None);
+
+        let mac = "52:54:00:ab:cd:01";
+        let leases = "100 52:54:00:ab:cd:01 192.168.122.10 old *\n\
+                      2000 52:54:00:AB:CD:01 192.168.122.11 runner-1 *\n";
+        assert_eq!(
+            find_in_dnsmasq_leases(leases, mac, 1000).as_deref(),
+            Some("192.168.122.11")
+        );
+        assert_eq!(find_in_dnsmasq_leases(leases, mac, 3000), None);
+
+        let status = r#"[{"ip-address": "192.168.122.12", "mac-address": "52:54:00:ab:cd:01",
+                          "hostname": "runner-1", "expiry-time": 2000}]"#;
+        assert_eq!(
+            find_in_libvirt_status(status, mac, 1000).as_deref(),
+            Some("192.168.122.12")
+        );
+
+        let arp = "IP address       HW type     Flags       HW address            Mask     Device\n\
+                   192.168.122.13   0x1         0x0         52:54:00:ab:cd:01     *        virbr0\n\
+                   192.168.122.14   0x1         0x2         52:54:00:ab:cd:01     *        virbr0\n";
+        assert_eq!(
+            find_in_arp_table(arp, mac).as_deref(),
+            Some("192.168.122.14")
+        );
+    }
+}
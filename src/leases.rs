@@ -0,0 +1,115 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// The API's answer to a lease request for a runner name
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct LeaseResponse {
+    pub granted: bool,
+    /// Agent currently holding the lease, when it was not granted
+    #[serde(default)]
+    pub holder: Option<String>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Outcome of asking the API for a runner lease
+#[derive(Debug, PartialEq)]
+pub enum LeaseOutcome {
+    /// This agent may provision the runner until the lease expires
+    Granted(DateTime<Utc>),
+    /// Another agent holds the lease
+    HeldElsewhere(Option<String>),
+    /// The API does not issue leases; provisioning is not coordinated
+    Unsupported,
+    /// The API could not be asked; the runner is left for a later poll
+    Unavailable(String),
+}
+
+impl LeaseOutcome {
+    /// Interpret a lease response. A lease without an expiry lasts `ttl_secs` from now.
+    pub fn from_response(status: u16, body: Option<LeaseResponse>, ttl_secs: u64) -> Self {
+        match (status, body) {
+            (404, _) => LeaseOutcome::Unsupported,
+            (409, body) => LeaseOutcome::HeldElsewhere(body.and_then(|b| b.holder)),
+            (200..=299, Some(body)) if body.granted => LeaseOutcome::Granted(
+                body.expires_at
+                    .unwrap_or_else(|| Utc::now() + Duration::seconds(ttl_secs as i64)),
+            ),
+            (200..=299, Some(body)) => LeaseOutcome::HeldElsewhere(body.holder),
+            (200..=299, None) => LeaseOutcome::Unavailable("unreadable lease response".into()),
+            (status, _) => LeaseOutcome::Unavailable(format!("API returned status {}", status)),
+        }
+    }
+}
+
+/// Leases this agent holds, by runner name, with their expiry
+#[derive(Debug, Default)]
+pub struct HeldLeases {
+    expiries: HashMap<String, DateTime<Utc>>,
+}
+
+impl HeldLeases {
+    pub fn insert(&mut self, runner_name: &str, expires_at: DateTime<Utc>) {
+        self.expiries.insert(runner_name.to_string(), expires_at);
+    }
+
+    pub fn remove(&mut self, runner_name: &str) -> bool {
+        self.expiries.remove(runner_name).is_some()
+    }
+
+    /// Leases within a third of their lifetime of expiring, which should be renewed
+    pub fn due_for_renewal(&self, now: DateTime<Utc>, ttl_secs: u64) -> Vec<String> {
+        let margin = Duration::seconds(ttl_secs as i64 / 3);
+        let mut due: Vec<String> = self
+            .expiries
+            .iter()
+            .filter(|(_, expires_at)| **expires_at - now <= margin)
+            .map(|(name, _)| name.clone())
+            .collect();
+        due.sort();
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lease_outcomes_and_renewal() {
+        let granted: LeaseResponse =
+            serde_json::from_str(r#"{"granted": true, "expires_at": "2026-01-01T00:10:00Z"}"#)
+                .unwrap();
+        let expires_at = granted.expires_at.unwrap();
+        assert_eq!(
+            LeaseOutcome::from_response(200, Some(granted), 600),
+            LeaseOutcome::Granted(expires_at)
+        );
+        let taken = LeaseResponse {
+            granted: false,
+            holder: Some("agent-b".to_string()),
+            expires_at: None,
+        };
+        assert_eq!(
+            LeaseOutcome::from_response(409, Some(taken), 600),
+            LeaseOutcome::HeldElsewhere(Some("agent-b".to_string()))
+        );
+        assert_eq!(
+            LeaseOutcome::from_response(404, None, 600),
+            LeaseOutcome::Unsupported
+        );
+        assert!(matches!(
+            LeaseOutcome::from_response(503, None, 600),
+            LeaseOutcome::Unavailable(_)
+        ));
+
+        let now = expires_at - Duration::seconds(600);
+        let mut held = HeldLeases::default();
+        held.insert("runner-a", expires_at);
+        held.insert("runner-b", now + Duration::seconds(60));
+        assert_eq!(held.due_for_renewal(now, 600), vec!["runner-b".to_string()]);
+        assert!(held.remove("runner-b"));
+        assert!(held.due_for_renewal(now, 600).is_empty());
+    }
+}
@@ -0,0 +1,255 @@
+use crate::events::{self, AgentEvent};
+use crate::state::StateStore;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How long Deleted runners stay in the state store before being forgotten
+const DELETED_RETENTION_HOURS: i64 = 24;
+
+/// Stage a runner was in when it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Requested,
+    Cloning,
+    Booting,
+    Provisioning,
+    Deleting,
+}
+
+/// Lifecycle of a runner VM:
+/// Requested → Cloning → Booting → Provisioning → Ready → Deleting → Deleted,
+/// with Failed(stage) reachable from every active state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunnerState {
+    Requested,
+    Cloning,
+    Booting,
+    Provisioning,
+    Ready,
+    Deleting,
+    Deleted,
+    Failed { stage: Stage },
+}
+
+impl RunnerState {
+    /// The stage a failure in this state is attributed to
+    pub fn stage(self) -> Stage {
+        match self {
+            RunnerState::Requested => Stage::Requested,
+            RunnerState::Cloning => Stage::Cloning,
+            RunnerState::Booting => Stage::Booting,
+            RunnerState::Provisioning | RunnerState::Ready => Stage::Provisioning,
+            RunnerState::Deleting | RunnerState::Deleted => Stage::Deleting,
+            RunnerState::Failed { stage } => stage,
+        }
+    }
+
+    /// Whether the agent may move a runner from this state to `next`
+    pub fn can_transition_to(self, next: RunnerState) -> bool {
+        use RunnerState::*;
+        if self == next {
+            return true;
+        }
+        match (self, next) {
+            // Deletion can interrupt anything that isn't already gone
+            (Deleted, Deleting) => false,
+            (_, Deleting) => true,
+            (Deleting, Deleted) => true,
+            // Any active state can fail
+            (Requested | Cloning | Booting | Provisioning | Deleting, Failed { .. }) => true,
+            // Provisioning starts over after a failure, a deletion or a crash of a ready runner
+            (Failed { .. } | Deleted | Ready, Requested) => true,
+            (Requested, Cloning | Booting | Ready) => true,
+            (Cloning, Booting) => true,
+            (Booting, Provisioning) => true,
+            // Restarting a ready runner only needs a boot
+            (Ready, Booting) => true,
+            (Provisioning, Ready) => true,
+            _ => false,
+        }
+    }
+
+    /// Runners being deleted or already gone must never be provisioned again by a stale
+    /// request; a failed deletion is still queued for retry, so it counts as being deleted
+    pub fn blocks_provisioning(self) -> bool {
+        matches!(
+            self,
+            RunnerState::Deleting
+                | RunnerState::Deleted
+                | RunnerState::Failed {
+                    stage: Stage::Deleting
+                }
+        )
+    }
+}
+
+/// Serialized (snake_case) name of a unit enum variant
+fn variant_name<T: Serialize>(value: &T) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", variant_name(self))
+    }
+}
+
+impl fmt::Display for RunnerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunnerState::Failed { stage } => write!(f, "failed({})", variant_name(stage)),
+            other => write!(f, "{}", variant_name(other)),
+        }
+    }
+}
+
+/// Persisted lifecycle state of a runner
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LifecycleRecord {
+    pub state: RunnerState,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Current lifecycle state of a runner, if the agent has seen it
+pub fn runner_state(runner_name: &str) -> Option<RunnerState> {
+    StateStore::new().read(|state| state.lifecycle.get(runner_name).map(|r| r.state))
+}
+
+/// Whether a runner's deletion already completed. Deleted records are kept for
+/// `DELETED_RETENTION_HOURS`, so repeated delete requests can be acknowledged without
+/// asking the provider again.
+pub fn is_deleted(runner_name: &str) -> bool {
+    runner_state(runner_name) == Some(RunnerState::Deleted)
+}
+
+/// Move a runner to `next`, persisting the transition. Invalid transitions are rejected
+/// and logged so out-of-order events can't corrupt the recorded state.
+pub fn transition(runner_name: &str, next: RunnerState) -> Result<(), String> {
+    let result = StateStore::new().update(|state| {
+        let now = Utc::now();
+        let current = state.lifecycle.get(runner_name).map(|r| r.state);
+        let allowed = match current {
+            Some(current) => current.can_transition_to(next),
+            // Runners the agent has never seen (e.g. created before an upgrade) start anywhere
+            None => true,
+        };
+        if !allowed {
+            return Err(format!(
+                "Invalid lifecycle transition for '{}': {} -> {}",
+                runner_name,
+                current.map(|s| s.to_string()).unwrap_or_default(),
+                next
+            ));
+        }
+        if current != Some(next) {
+            events::publish(AgentEvent::StateChanged {
+                runner_name: runner_name.to_string(),
+                from: current,
+                to: next,
+            });
+            info!(
+                "Runner '{}' lifecycle: {} -> {}",
+                runner_name,
+                current
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|| "new".to_string()),
+                next
+            );
+        }
+        state.lifecycle.insert(
+            runner_name.to_string(),
+            LifecycleRecord {
+                state: next,
+                updated_at: now,
+            },
+        );
+
+        let cutoff = now - chrono::Duration::hours(DELETED_RETENTION_HOURS);
+        state
+            .lifecycle
+            .retain(|_, r| r.state != RunnerState::Deleted || r.updated_at > cutoff);
+        Ok(())
+    });
+    if let Err(e) = &result {
+        warn!("{}", e);
+    }
+    result
+}
+
+/// Runners left mid-provisioning or mid-deletion by a previous run of the agent, with their
+/// state. Only meaningful at startup, before any work begins.
+pub fn interrupted() -> Vec<(String, RunnerState)> {
+    StateStore::new().read(|state| {
+        state
+            .lifecycle
+            .iter()
+            .filter(|(_, r)| {
+                matches!(
+                    r.state,
+                    RunnerState::Requested
+                        | RunnerState::Cloning
+                        | RunnerState::Booting
+                        | RunnerState::Provisioning
+                        | RunnerState::Deleting
+                )
+            })
+            .map(|(name, r)| (name.clone(), r.state))
+            .collect()
+    })
+}
+
+/// Record a failure at whatever stage the runner is currently in
+pub fn fail(runner_name: &str) -> Stage {
+    let stage = runner_state(runner_name)
+        .map(RunnerState::stage)
+        .unwrap_or(Stage::Requested);
+    let _ = transition(runner_name, RunnerState::Failed { stage });
+    stage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use RunnerState::*;
+
+    #[test]
+    fn test_lifecycle_transitions() {
+        let happy_path = [
+            Requested,
+            Cloning,
+            Booting,
+            Provisioning,
+            Ready,
+            Deleting,
+            Deleted,
+        ];
+        for pair in happy_path.windows(2) {
+            assert!(pair[0].can_transition_to(pair[1]), "{:?}", pair);
+        }
+
+        let failed = Failed {
+            stage: Booting.stage(),
+        };
+        assert!(Booting.can_transition_to(failed));
+        assert!(failed.can_transition_to(Requested));
+        assert!(!Ready.can_transition_to(failed));
+        assert!(!Deleted.can_transition_to(Deleting));
+        assert!(!Cloning.can_transition_to(Ready));
+        assert!(Deleting.blocks_provisioning());
+        assert!(Failed {
+            stage: Stage::Deleting
+        }
+        .blocks_provisioning());
+        assert!(!failed.blocks_provisioning());
+
+        assert_eq!(failed.to_string(), "failed(booting)");
+        assert_eq!(Provisioning.to_string(), "provisioning");
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::{
+    Mutex as AsyncMutex, OwnedMutexGuard, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock,
+};
+
+/// Guard held while a lifecycle operation (provision, delete, restart) runs for a runner
+pub type RunnerLockGuard = OwnedMutexGuard<()>;
+
+/// Guard held while a template is being created or validated
+pub type TemplateLockGuard = OwnedMutexGuard<()>;
+
+/// Guard held while a VM is cloned from a template, keeping the template in place
+pub type TemplateUseGuard = OwnedRwLockReadGuard<()>;
+
+/// Guard held while a rebuilt template replaces the old one
+pub type TemplateSwapGuard = OwnedRwLockWriteGuard<()>;
+
+type LockMap<L = AsyncMutex<()>> = OnceLock<Mutex<HashMap<String, Arc<L>>>>;
+
+// One async lock per runner name, shared by every code path that touches that VM
+static RUNNER_LOCKS: LockMap = OnceLock::new();
+// One async lock per template name, so concurrent runners never create the same template twice
+static TEMPLATE_LOCKS: LockMap = OnceLock::new();
+// One read-write lock per template name: clones read it, a rebuild swaps it in under the
+// write lock
+static TEMPLATE_USE_LOCKS: LockMap<RwLock<()>> = OnceLock::new();
+
+fn named_lock<L: Default>(locks: &LockMap<L>, name: &str) -> Arc<L> {
+    let mut locks = locks
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+
+    // Drop locks nobody holds or waits on so the map doesn't grow forever
+    locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+
+    locks
+        .entry(name.to_string())
+        .or_insert_with(|| Arc::new(L::default()))
+        .clone()
+}
+
+/// Wait until no other lifecycle operation is running for this runner, then take the lock
+pub async fn lock_runner(runner_name: &str) -> RunnerLockGuard {
+    named_lock(&RUNNER_LOCKS, runner_name).lock_owned().await
+}
+
+/// Take the runner lock only if it is free right now
+pub fn try_lock_runner(runner_name: &str) -> Option<RunnerLockGuard> {
+    named_lock(&RUNNER_LOCKS, runner_name).try_lock_owned().ok()
+}
+
+/// Wait for any in-progress creation or validation of this template to finish, then take the lock
+pub async fn lock_template(template_name: &str) -> TemplateLockGuard {
+    named_lock(&TEMPLATE_LOCKS, template_name)
+        .lock_owned()
+        .await
+}
+
+/// Wait for a swap of this template to finish, and keep it in place while it is cloned
+pub async fn use_template(template_name: &str) -> TemplateUseGuard {
+    named_lock(&TEMPLATE_USE_LOCKS, template_name)
+        .read_owned()
+        .await
+}
+
+/// Wait for every running clone of this template to finish, and hold off new ones while
+/// it is replaced
+pub async fn swap_template(template_name: &str) -> TemplateSwapGuard {
+    named_lock(&TEMPLATE_USE_LOCKS, template_name)
+        .write_owned()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_runner_locks_are_per_name() {
+        let guard = lock_runner("cirun-lock-a").await;
+        assert!(try_lock_runner("cirun-lock-a").is_none());
+        assert!(try_lock_runner("cirun-lock-b").is_some());
+
+        drop(guard);
+        assert!(try_lock_runner("cirun-lock-a").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_template_swap_waits_for_clones() {
+        let clone_a = use_template("cirun-template-swap").await;
+        let clone_b = use_template("cirun-template-swap").await;
+        let lock = named_lock(&TEMPLATE_USE_LOCKS, "cirun-template-swap");
+        assert!(lock.try_write().is_err());
+
+        drop(clone_a);
+        drop(clone_b);
+        let _swap = swap_template("cirun-template-swap").await;
+        assert!(lock.try_read().is_err());
+    }
+}
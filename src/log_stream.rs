@@ -0,0 +1,139 @@
+use crate::runner_logs::append_transcript;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use std::io;
+use std::process::{Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// Most lines sent to the API in a single log batch
+const MAX_LINES_PER_BATCH: usize = 500;
+/// Lines waiting for the main loop; further lines are dropped while the API falls behind
+const MAX_QUEUED_LINES: usize = 10_000;
+
+// Lines produced by provisioning tasks, forwarded to the API from the main loop
+static LOG_SINK: OnceLock<Sender<LogLine>> = OnceLock::new();
+// Lines dropped because the queue was full, since the last drain
+static DROPPED_LINES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// One line of provision output from a runner
+#[derive(Debug, Serialize)]
+pub struct LogLine {
+    pub runner_name: String,
+    pub stream: OutputStream,
+    pub line: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Start collecting runner output for the API; the receiver is drained by the main loop
+pub fn init_log_stream() -> Receiver<LogLine> {
+    let (sender, receiver) = channel(MAX_QUEUED_LINES);
+    let _ = LOG_SINK.set(sender);
+    receiver
+}
+
+/// Log a line of runner output, tagged with the runner name, append it to the runner's
+/// transcript and queue it for the API
+pub fn emit(runner_name: &str, stream: OutputStream, line: &str) {
+    match stream {
+        OutputStream::Stdout => info!("[{}] {}", runner_name, line),
+        OutputStream::Stderr => info!("[{} stderr] {}", runner_name, line),
+    }
+    append_transcript(runner_name, stream, line);
+    if let Some(sink) = LOG_SINK.get() {
+        let sent = sink.try_send(LogLine {
+            runner_name: runner_name.to_string(),
+            stream,
+            line: line.to_string(),
+            timestamp: Utc::now(),
+        });
+        if sent.is_err() {
+            DROPPED_LINES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Take the lines queued so far (up to one batch) without waiting
+pub fn drain_log_lines(receiver: &mut Receiver<LogLine>) -> Vec<LogLine> {
+    let dropped = DROPPED_LINES.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        warn!(
+            "Dropped {} runner log lines while the API fell behind; they are still in the runner transcripts",
+            dropped
+        );
+    }
+    let mut lines = Vec::new();
+    while lines.len() < MAX_LINES_PER_BATCH {
+        match receiver.try_recv() {
+            Ok(line) => lines.push(line),
+            Err(_) => break,
+        }
+    }
+    lines
+}
+
+async fn collect_lines<R: AsyncRead + Unpin>(
+    reader: R,
+    runner_name: &str,
+    stream: OutputStream,
+) -> io::Result<Vec<u8>> {
+    let mut collected = Vec::new();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        emit(runner_name, stream, &line);
+        collected.extend_from_slice(line.as_bytes());
+        collected.push(b'\n');
+    }
+    Ok(collected)
+}
+
+/// Run a command, streaming its stdout/stderr line by line as it is produced,
+/// and return the full output once it exits
+pub async fn stream_output(command: &mut Command, runner_name: &str) -> io::Result<Output> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+
+    let (stdout, stderr, status) = tokio::join!(
+        collect_lines(stdout, runner_name, OutputStream::Stdout),
+        collect_lines(stderr, runner_name, OutputStream::Stderr),
+        child.wait()
+    );
+
+    Ok(Output {
+        status: status?,
+        stdout: stdout?,
+        stderr: stderr?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stream_output_collects_both_streams() {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo one; echo two >&2; echo three");
+
+        let output = stream_output(&mut command, "cirun-test").await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "one\nthree\n");
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "two\n");
+    }
+}
@@ -0,0 +1,226 @@
+// Syslog and the journal are reached over Unix sockets; elsewhere only their formatting
+// is compiled and logs go to stderr
+#![cfg_attr(not(unix), allow(dead_code))]
+
+use crate::build_info;
+use chrono::Local;
+use clap::ValueEnum;
+use log::{Level, Record};
+use std::io;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::Mutex;
+
+const SYSLOG_IDENTIFIER: &str = "cirun-agent";
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// Local syslog sockets, Linux first
+#[cfg(unix)]
+const SYSLOG_SOCKETS: &[&str] = &["/dev/log", "/var/run/syslog"];
+/// Syslog facility for system daemons
+const FACILITY_DAEMON: u8 = 3;
+
+static AGENT_ID: Mutex<Option<String>> = Mutex::new(None);
+
+/// Where log records are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum LogTarget {
+    /// Standard error, captured by the service manager
+    #[default]
+    Stderr,
+    /// The local syslog daemon
+    Syslog,
+    /// The systemd journal, with structured fields
+    Journald,
+}
+
+impl LogTarget {
+    /// Syslog and journald need a Unix host
+    pub fn check_supported(self) -> Result<(), String> {
+        if cfg!(unix) || self == LogTarget::Stderr {
+            return Ok(());
+        }
+        Err(format!(
+            "--log-target {} is only supported on Unix hosts",
+            self.to_possible_value().unwrap().get_name()
+        ))
+    }
+}
+
+/// Include the agent ID in structured log records
+pub fn set_agent_id(agent_id: &str) {
+    *AGENT_ID.lock().unwrap_or_else(|e| e.into_inner()) = Some(agent_id.to_string());
+}
+
+/// Syslog/journald priority of a log level
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Append a field in the journal's native format. Values with newlines are sent
+/// length-prefixed, so multi-line messages stay one entry.
+fn push_field(datagram: &mut Vec<u8>, name: &str, value: &str) {
+    datagram.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+fn journald_entry(record: &Record, agent_id: Option<&str>) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    push_field(&mut datagram, "MESSAGE", &record.args().to_string());
+    push_field(
+        &mut datagram,
+        "PRIORITY",
+        &priority(record.level()).to_string(),
+    );
+    push_field(&mut datagram, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+    push_field(&mut datagram, "SYSLOG_PID", &std::process::id().to_string());
+    push_field(&mut datagram, "LOG_LEVEL", record.level().as_str());
+    push_field(&mut datagram, "LOG_TARGET", record.target());
+    if let Some(file) = record.file() {
+        push_field(&mut datagram, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        push_field(&mut datagram, "CODE_LINE", &line.to_string());
+    }
+    if let Some(module) = record.module_path() {
+        push_field(&mut datagram, "CODE_MODULE", module);
+    }
+    push_field(&mut datagram, "AGENT_VERSION", build_info::VERSION);
+    if let Some(agent_id) = agent_id {
+        push_field(&mut datagram, "AGENT_ID", agent_id);
+    }
+    datagram
+}
+
+/// An RFC 3164 message for the local syslog daemon
+fn syslog_line(record: &Record) -> String {
+    format!(
+        "<{}>{} {}[{}]: {}",
+        FACILITY_DAEMON * 8 + priority(record.level()),
+        Local::now().format("%b %e %H:%M:%S"),
+        SYSLOG_IDENTIFIER,
+        std::process::id(),
+        record.args()
+    )
+}
+
+/// Connection to syslog or the journal
+pub enum Sink {
+    #[cfg(unix)]
+    Syslog(UnixDatagram),
+    #[cfg(unix)]
+    Journald(UnixDatagram),
+}
+
+impl Sink {
+    /// Connect to `target`'s socket. `None` for stderr.
+    pub fn connect(target: LogTarget) -> io::Result<Option<Sink>> {
+        #[cfg(unix)]
+        let connect = |path: &str| {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(path)?;
+            Ok::<_, io::Error>(socket)
+        };
+        match target {
+            LogTarget::Stderr => Ok(None),
+            #[cfg(not(unix))]
+            LogTarget::Journald | LogTarget::Syslog => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "syslog and journald need a Unix host",
+            )),
+            #[cfg(unix)]
+            LogTarget::Journald => connect(JOURNALD_SOCKET).map(|s| Some(Sink::Journald(s))),
+            #[cfg(unix)]
+            LogTarget::Syslog => {
+                let path = SYSLOG_SOCKETS
+                    .iter()
+                    .find(|path| Path::new(path).exists())
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "no local syslog socket found")
+                    })?;
+                connect(path).map(|s| Some(Sink::Syslog(s)))
+            }
+        }
+    }
+
+    /// Send one record. On error the caller falls back to stderr.
+    pub fn send(&self, record: &Record) -> io::Result<()> {
+        #[cfg(not(unix))]
+        let _ = record;
+        #[cfg(not(unix))]
+        match *self {}
+        #[cfg(unix)]
+        match self {
+            Sink::Syslog(socket) => socket.send(syslog_line(record).as_bytes()).map(|_| ()),
+            Sink::Journald(socket) => {
+                let agent_id = AGENT_ID.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                socket
+                    .send(&journald_entry(record, agent_id.as_deref()))
+                    .map(|_| ())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journald_entry() {
+        let message = "first line\nsecond line";
+        let entry = journald_entry(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .level(Level::Warn)
+                .target("cirun_agent")
+                .line(Some(42))
+                .build(),
+            Some("agent-1"),
+        );
+
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&(message.len() as u64).to_le_bytes());
+        expected.extend_from_slice(b"first line\nsecond line\nPRIORITY=4\n");
+        assert!(entry.starts_with(&expected));
+        let text = String::from_utf8_lossy(&entry);
+        assert!(text.contains("\nCODE_LINE=42\n"));
+        assert!(text.contains("\nAGENT_ID=agent-1\n"));
+        assert!(text.contains("\nSYSLOG_IDENTIFIER=cirun-agent\n"));
+    }
+
+    #[test]
+    fn test_socket_targets_need_unix() {
+        assert!(LogTarget::Stderr.check_supported().is_ok());
+        for target in [LogTarget::Syslog, LogTarget::Journald] {
+            assert_eq!(target.check_supported().is_ok(), cfg!(unix));
+        }
+    }
+
+    #[test]
+    fn test_syslog_line_priority() {
+        let line = syslog_line(
+            &Record::builder()
+                .args(format_args!("VM deleted"))
+                .level(Level::Error)
+                .build(),
+        );
+        // daemon.err
+        assert!(line.starts_with("<27>"));
+        assert!(line.ends_with(&format!("cirun-agent[{}]: VM deleted", std::process::id())));
+    }
+}
@@ -1,10 +1,16 @@
-use backon::{ExponentialBuilder, Retryable};
-use log::{error, info, warn};
+use log::{error, info};
 use reqwest::Client;
+use serde_json::json;
 use std::time::Duration;
 
+use crate::chaos;
+use crate::config::agent_config;
 use crate::lume::errors::LumeError;
 use crate::lume::models::{CloneConfig, RunConfig, VmConfig, VmInfo};
+use crate::provider_auth;
+use crate::provider_health;
+use crate::retry::with_retries;
+use crate::units::DiskSize;
 
 const DEFAULT_API_URL: &str = "http://127.0.0.1:7777/lume";
 const CONNECT_TIMEOUT: u64 = 10; // 10 seconds
@@ -17,7 +23,7 @@ pub struct LumeClient {
 
 impl LumeClient {
     pub fn new() -> Result<Self, LumeError> {
-        Self::with_base_url(DEFAULT_API_URL)
+        Self::with_base_url(&provider_auth::api_url(DEFAULT_API_URL))
     }
 
     // Get the base URL of the Lume API
@@ -26,13 +32,15 @@ impl LumeClient {
     }
 
     pub fn with_base_url(base_url: &str) -> Result<Self, LumeError> {
-        let client = Client::builder()
+        let builder = Client::builder()
             .http1_only()
             .timeout(Duration::from_secs(MAX_TIMEOUT))
             .connect_timeout(Duration::from_secs(CONNECT_TIMEOUT))
             .pool_idle_timeout(Duration::from_secs(90))
             .pool_max_idle_per_host(10)
-            .tcp_keepalive(Duration::from_secs(60))
+            .tcp_keepalive(Duration::from_secs(60));
+        let client = provider_auth::configure_client(builder)
+            .map_err(LumeError::ApiError)?
             .build()
             .map_err(LumeError::from)?;
 
@@ -46,6 +54,7 @@ impl LumeClient {
     pub async fn create_vm(&self, config: VmConfig) -> Result<(), LumeError> {
         let url = format!("{}/vms", self.base_url);
 
+        chaos::provider_call(&url)?;
         let response = self.client.post(&url).json(&config).send().await?;
 
         if !response.status().is_success() {
@@ -73,6 +82,7 @@ impl LumeClient {
 
         info!("Sending request to start VM: {}", name);
 
+        chaos::provider_call(&url)?;
         let response = request.send().await?;
         let status = response.status(); // Clone status before calling .text()
         let response_text = response
@@ -97,6 +107,57 @@ impl LumeClient {
         Ok(())
     }
 
+    pub async fn stop_vm(&self, name: &str) -> Result<(), LumeError> {
+        let url = format!("{}/vms/{}/stop", self.base_url, name);
+
+        info!("Stopping VM: {}", name);
+
+        chaos::provider_call(&url)?;
+        let response = self.client.post(&url).send().await?;
+
+        if !response.status().is_success() {
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(LumeError::ApiError(format!(
+                "Failed to stop VM: {}",
+                error_text
+            )));
+        }
+
+        info!("Successfully stopped VM: {}", name);
+        Ok(())
+    }
+
+    /// Grow a stopped VM's disk. Lume cannot shrink disks.
+    pub async fn resize_disk(&self, name: &str, disk: DiskSize) -> Result<(), LumeError> {
+        let url = format!("{}/vms/{}", self.base_url, name);
+
+        info!("Resizing disk of VM {} to {}", name, disk);
+
+        chaos::provider_call(&url)?;
+        let response = self
+            .client
+            .patch(&url)
+            .json(&json!({ "diskSize": disk.to_lume() }))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(LumeError::ApiError(format!(
+                "Failed to resize disk: {}",
+                error_text
+            )));
+        }
+
+        Ok(())
+    }
+
     pub async fn clone_vm(&self, source_name: &str, new_name: &str) -> Result<(), LumeError> {
         let url = format!("{}/vms/clone", self.base_url);
 
@@ -108,39 +169,26 @@ impl LumeClient {
         info!("Cloning VM {} to {}", source_name, new_name);
 
         let send_clone_request = || async {
-            let response = self
-                .client
-                .post(&url)
-                .json(&config)
-                .send()
-                .await
-                .map_err(|e| LumeError::ApiError(format!("HTTP request failed: {:?}", e)))?;
+            chaos::provider_call(&url)?;
+            let response = self.client.post(&url).json(&config).send().await?;
 
             let status = response.status();
             info!("Clone operation response status: {}", status);
 
             if !status.is_success() {
-                let error_text = response
-                    .text()
-                    .await
-                    .unwrap_or_else(|_| "Unknown error".to_string());
-                return Err(LumeError::ApiError(format!(
-                    "Failed to clone VM: {}",
-                    error_text
-                )));
+                return Err(LumeError::from_response("Failed to clone VM", response).await);
             }
 
             Ok(())
         };
 
-        // Retry logic with proper error conversion
-        send_clone_request
-            .retry(ExponentialBuilder::default().with_max_times(5)) // Retry max 5 times
-            .sleep(tokio::time::sleep)
-            .when(|e| matches!(e, LumeError::ApiError(_))) // Retry only on API errors
-            .notify(|err, dur| warn!("Retrying VM clone after {:?}: {:?}", dur, err))
-            .await
-            .map_err(|e| LumeError::ApiError(format!("Retry exhausted: {:?}", e)))?; // Convert final error to LumeError
+        // Only transient failures are retried; e.g. a missing source VM fails right away
+        with_retries(
+            "VM clone",
+            agent_config().retry.provider_api,
+            send_clone_request,
+        )
+        .await?;
 
         info!("VM {} successfully cloned to {}", source_name, new_name);
         Ok(())
@@ -151,115 +199,70 @@ impl LumeClient {
 
         info!("Deleting VM {}", name);
 
-        let send_delete_request =
-            || async {
-                let response =
-                    self.client.delete(&url).send().await.map_err(|e| {
-                        LumeError::ApiError(format!("HTTP request failed: {:?}", e))
-                    })?;
+        let send_delete_request = || async {
+            chaos::provider_call(&url)?;
+            let response = self.client.delete(&url).send().await?;
 
-                let status = response.status();
-                let response_text = response
-                    .text()
-                    .await
-                    .unwrap_or_else(|_| "Unknown error".to_string());
+            let status = response.status();
+            info!("Delete operation response status: {}", status);
+            if !status.is_success() {
+                return Err(LumeError::from_response("Failed to delete VM", response).await);
+            }
+            Ok(())
+        };
 
-                info!("Delete operation response status: {}", status);
-                info!("Delete operation response body: {}", response_text);
-
-                if !status.is_success() {
-                    return Err(LumeError::ApiError(format!(
-                        "Failed to delete VM: {}",
-                        response_text
-                    )));
-                }
-                Ok(())
-            };
-
-        // Retry logic with proper error conversion
-        send_delete_request
-            .retry(ExponentialBuilder::default().with_max_times(5)) // Retry max 5 times
-            .sleep(tokio::time::sleep)
-            .when(|e| matches!(e, LumeError::ApiError(_))) // Retry only on API errors
-            .notify(|err, dur| warn!("Retrying VM deletion after {:?}: {:?}", dur, err))
-            .await
-            .map_err(|e| LumeError::ApiError(format!("Retry exhausted: {:?}", e)))?; // Convert final error to LumeError
+        with_retries(
+            "VM deletion",
+            agent_config().retry.provider_api,
+            send_delete_request,
+        )
+        .await?;
 
         info!("VM {} successfully deleted", name);
         Ok(())
     }
 
+    /// List all VMs. The outcome is recorded as the provider's health.
     pub async fn list_vms(&self) -> Result<Vec<VmInfo>, LumeError> {
-        let url = format!("{}/vms", self.base_url);
+        let list = async {
+            let url = format!("{}/vms", self.base_url);
 
-        let response = self.client.get(&url).send().await?;
+            chaos::provider_call(&url)?;
+            let response = self.client.get(&url).send().await?;
 
-        if !response.status().is_success() {
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(LumeError::ApiError(format!(
-                "Failed to list VMs: {}",
-                error_text
-            )));
-        }
+            if !response.status().is_success() {
+                let error_text = response
+                    .text()
+                    .await
+                    .unwrap_or_else(|_| "Unknown error".to_string());
+                return Err(LumeError::ApiError(format!(
+                    "Failed to list VMs: {}",
+                    error_text
+                )));
+            }
 
-        let vms = response.json::<Vec<VmInfo>>().await?;
-        Ok(vms)
+            let vms = response.json::<Vec<VmInfo>>().await?;
+            Ok(vms)
+        };
+        let result = list.await;
+        provider_health::record(&result);
+        result
     }
 
     pub async fn get_vm(&self, name: &str) -> Result<VmInfo, LumeError> {
         info!("Getting VM details for {}", name);
         let url = format!("{}/vms/{}", self.base_url, name);
 
-        let max_retries = 3;
-        let mut attempts = 0;
-        let retry_delay = Duration::from_millis(300);
-
-        loop {
-            attempts += 1;
-            match self.client.get(&url).send().await {
-                Ok(response) => {
-                    if response.status().is_success() {
-                        match response.json::<VmInfo>().await {
-                            Ok(vm_info) => return Ok(vm_info),
-                            Err(e) => {
-                                warn!(
-                                    "Failed to parse VM details JSON (attempt {}/{}): {:?}",
-                                    attempts, max_retries, e
-                                );
-                                if attempts >= max_retries {
-                                    return Err(LumeError::RequestError(e));
-                                }
-                            }
-                        }
-                    } else {
-                        let error_text = response
-                            .text()
-                            .await
-                            .unwrap_or_else(|_| "Unknown error".to_string());
-                        if attempts >= max_retries {
-                            return Err(LumeError::ApiError(format!(
-                                "Failed to get VM details: {}",
-                                error_text
-                            )));
-                        }
-                    }
-                }
-                Err(e) => {
-                    warn!(
-                        "Request error while getting VM details for {} (attempt {}/{}): {:?}",
-                        name, attempts, max_retries, e
-                    );
-
-                    if attempts >= max_retries {
-                        return Err(LumeError::RequestError(e));
-                    }
-                }
+        let fetch = || async {
+            chaos::provider_call(&url)?;
+            let response = self.client.get(&url).send().await?;
+            if !response.status().is_success() {
+                return Err(LumeError::from_response("Failed to get VM details", response).await);
             }
-            tokio::time::sleep(retry_delay).await;
-        }
+            Ok(response.json::<VmInfo>().await?)
+        };
+
+        with_retries("VM details", agent_config().retry.provider_api, fetch).await
     }
 
     pub async fn pull_image(
@@ -270,7 +273,7 @@ impl LumeClient {
         organization: Option<&str>,
         no_cache: bool,
     ) -> Result<(), LumeError> {
-        use serde_json::json;
+        chaos::slow_pull(image).await;
 
         info!("Pulling image '{}' for VM '{}'", image, vm_name);
 
@@ -296,6 +299,7 @@ impl LumeClient {
         // Send the pull request
         info!("Sending pull request: {}", pull_data);
 
+        chaos::provider_call(&url)?;
         let response = self.client.post(&url).json(&pull_data).send().await?;
 
         if !response.status().is_success() {
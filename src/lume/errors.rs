@@ -1,11 +1,38 @@
+use crate::chaos::Fault;
+use crate::retry::{retry_after, retryable_status, Classify};
 use reqwest::Error as ReqwestError;
+use reqwest::{Response, StatusCode};
 use serde::de::StdError;
 use std::fmt;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum LumeError {
     RequestError(ReqwestError),
     ApiError(String),
+    /// The provider answered with an error status
+    Status {
+        status: StatusCode,
+        message: String,
+        retry_after: Option<Duration>,
+    },
+}
+
+impl LumeError {
+    /// Error for a response with a failure status, e.g. "Failed to clone VM (503): busy"
+    pub async fn from_response(action: &str, response: Response) -> Self {
+        let status = response.status();
+        let retry_after = retry_after(response.headers());
+        let body = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        LumeError::Status {
+            status,
+            message: format!("{} ({}): {}", action, status.as_u16(), body),
+            retry_after,
+        }
+    }
 }
 
 impl fmt::Display for LumeError {
@@ -13,6 +40,7 @@ impl fmt::Display for LumeError {
         match self {
             LumeError::RequestError(err) => write!(f, "Request error: {}", err),
             LumeError::ApiError(msg) => write!(f, "API error: {}", msg),
+            LumeError::Status { message, .. } => write!(f, "API error: {}", message),
         }
     }
 }
@@ -21,7 +49,25 @@ impl StdError for LumeError {
     fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match self {
             LumeError::RequestError(err) => Some(err),
-            LumeError::ApiError(_) => None,
+            LumeError::ApiError(_) | LumeError::Status { .. } => None,
+        }
+    }
+}
+
+impl Classify for LumeError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            LumeError::RequestError(err) => err.is_retryable(),
+            // Errors without a status, e.g. injected faults, have always been retried
+            LumeError::ApiError(_) => true,
+            LumeError::Status { status, .. } => retryable_status(*status),
+        }
+    }
+
+    fn retry_hint(&self) -> Option<Duration> {
+        match self {
+            LumeError::Status { retry_after, .. } => *retry_after,
+            _ => None,
         }
     }
 }
@@ -31,3 +77,9 @@ impl From<ReqwestError> for LumeError {
         LumeError::RequestError(error)
     }
 }
+
+impl From<Fault> for LumeError {
+    fn from(fault: Fault) -> Self {
+        LumeError::ApiError(fault.to_string())
+    }
+}
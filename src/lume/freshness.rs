@@ -0,0 +1,416 @@
+use crate::config::agent_config;
+use crate::lume::errors::LumeError;
+use crate::lume::pull::rebuild_template;
+use crate::lume::LumeClient;
+use crate::os_detect::registry_digest;
+use crate::state::StateStore;
+use crate::{cancel, RunnerLogin, TemplateConfig};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// Login of the last runner cloned from each template, used to boot it during a rebuild.
+/// Kept in memory only, so a rebuild after a restart waits for the next runner.
+static LOGINS: Mutex<Option<HashMap<String, RunnerLogin>>> = Mutex::new(None);
+/// Templates being rebuilt right now
+static REBUILDING: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// Image a template was built from, and what the registry served for it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateSource {
+    pub config: TemplateConfig,
+    /// Registry digest of the image when the template was built; unknown for templates
+    /// built while the registry was unreachable until the first check
+    pub digest: Option<String>,
+    pub built_at: DateTime<Utc>,
+    #[serde(default)]
+    pub latest_digest: Option<String>,
+    #[serde(default)]
+    pub checked_at: Option<DateTime<Utc>>,
+    /// When the registry was first seen serving a different image
+    #[serde(default)]
+    pub stale_since: Option<DateTime<Utc>>,
+    /// Why the last rebuild failed; cleared once one succeeds
+    #[serde(default)]
+    pub last_rebuild_error: Option<String>,
+}
+
+/// Full registry reference of the image a template is pulled from
+fn image_reference(config: &TemplateConfig) -> String {
+    let registry = config.registry.as_deref().unwrap_or("ghcr.io");
+    match &config.organization {
+        Some(org) if !config.image.contains('/') => {
+            format!("{}/{}/{}", registry, org, config.image)
+        }
+        _ => format!("{}/{}", registry, config.image),
+    }
+}
+
+/// Compare the digest a template was built from with the registry's; a first known digest
+/// becomes the baseline. Returns whether the template is stale.
+fn apply_check(source: &mut TemplateSource, latest: String, now: DateTime<Utc>) -> bool {
+    source.checked_at = Some(now);
+    match &source.digest {
+        None => source.digest = Some(latest.clone()),
+        Some(built) if *built != latest => {
+            source.stale_since.get_or_insert(now);
+        }
+        Some(_) => source.stale_since = None,
+    }
+    source.latest_digest = Some(latest);
+    source.stale_since.is_some()
+}
+
+/// Remember the image a template was just built from, with the digest the registry serves now
+pub async fn record_build(template_name: &str, config: &TemplateConfig) {
+    let digest = registry_digest(&image_reference(config))
+        .await
+        .unwrap_or_else(|e| {
+            debug!(
+                "Registry digest lookup failed for '{}': {}",
+                config.image, e
+            );
+            None
+        });
+    let now = Utc::now();
+    StateStore::new().update(|state| {
+        state.templates.insert(
+            template_name.to_string(),
+            TemplateSource {
+                config: config.clone(),
+                latest_digest: digest.clone(),
+                digest,
+                built_at: now,
+                checked_at: Some(now),
+                stale_since: None,
+                last_rebuild_error: None,
+            },
+        );
+    });
+}
+
+/// A runner is about to be cloned from `template_name`. Templates the agent generated
+/// before their source was tracked are tracked from now on, and a stale template waiting
+/// for a login is rebuilt.
+pub fn template_used(template_name: &str, config: &TemplateConfig, login: &RunnerLogin) {
+    LOGINS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new)
+        .insert(template_name.to_string(), login.clone());
+    let generated = crate::lume::generate_template_name(config) == template_name;
+    let stale = StateStore::new().update(|state| {
+        if generated && !state.templates.contains_key(template_name) {
+            state.templates.insert(
+                template_name.to_string(),
+                TemplateSource {
+                    config: config.clone(),
+                    digest: None,
+                    built_at: Utc::now(),
+                    latest_digest: None,
+                    checked_at: None,
+                    stale_since: None,
+                    last_rebuild_error: None,
+                },
+            );
+        }
+        state
+            .templates
+            .get(template_name)
+            .is_some_and(|source| source.stale_since.is_some())
+    });
+    if stale && agent_config().lume.freshness.rebuild {
+        if let Err(e) = spawn_rebuild(template_name, None) {
+            info!("Template '{}' is stale: {}", template_name, e);
+        }
+    }
+}
+
+fn is_rebuilding(template_name: &str) -> bool {
+    REBUILDING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .is_some_and(|rebuilding| rebuilding.contains(template_name))
+}
+
+/// Keep why the last rebuild of a template failed, for reports
+fn record_rebuild_error(template_name: &str, error: &str) {
+    StateStore::new().update(|state| {
+        if let Some(source) = state.templates.get_mut(template_name) {
+            source.last_rebuild_error = Some(error.to_string());
+        }
+    });
+}
+
+/// Rebuild a tracked template from its image now, unless it is already being rebuilt
+pub async fn rebuild(template_name: &str, login: &RunnerLogin) -> Result<(), String> {
+    let source = StateStore::new()
+        .read(|state| state.templates.get(template_name).cloned())
+        .ok_or_else(|| {
+            format!(
+                "Template '{}' is not tracked, so its source image is unknown",
+                template_name
+            )
+        })?;
+    if !REBUILDING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashSet::new)
+        .insert(template_name.to_string())
+    {
+        return Err(format!(
+            "Template '{}' is already being rebuilt",
+            template_name
+        ));
+    }
+    info!(
+        "Rebuilding template '{}' from '{}'",
+        template_name, source.config.image
+    );
+    let result = rebuild_template(&source.config, template_name, login)
+        .await
+        .map_err(|e| e.to_string());
+    match &result {
+        Ok(()) => {
+            record_build(template_name, &source.config).await;
+            info!("Rebuilt template '{}'", template_name);
+        }
+        Err(e) => {
+            warn!("Failed to rebuild template '{}': {}", template_name, e);
+            record_rebuild_error(template_name, e);
+        }
+    }
+    if let Some(rebuilding) = REBUILDING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_mut()
+    {
+        rebuilding.remove(template_name);
+    }
+    result
+}
+
+/// Rebuild a template in the background, booting it with `login` or else the login of the
+/// last runner cloned from it
+fn spawn_rebuild(template_name: &str, login: Option<RunnerLogin>) -> Result<(), String> {
+    if is_rebuilding(template_name) {
+        return Ok(());
+    }
+    let login = login
+        .or_else(|| {
+            LOGINS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .as_ref()
+                .and_then(|logins| logins.get(template_name).cloned())
+        })
+        .ok_or_else(|| {
+            format!(
+                "No login known for template '{}' until a runner is cloned from it",
+                template_name
+            )
+        })?;
+    let template_name = template_name.to_string();
+    cancel::spawn_provider_task(async move {
+        let _ = rebuild(&template_name, &login).await;
+    });
+    Ok(())
+}
+
+/// Tracked templates named `target`, or built from the image `target`
+pub fn resolve(target: &str) -> Vec<String> {
+    let mut names: Vec<String> = StateStore::new().read(|state| {
+        state
+            .templates
+            .iter()
+            .filter(|(name, source)| {
+                name.as_str() == target
+                    || source.config.image == target
+                    || image_reference(&source.config) == target
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    });
+    names.sort();
+    names
+}
+
+/// A template rebuild the API asks for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateRebuildRequest {
+    pub id: String,
+    /// Template name or source image
+    pub template: String,
+    /// Login to boot the template with; unset uses the last runner's
+    #[serde(default)]
+    pub login: Option<RunnerLogin>,
+}
+
+/// Start the rebuilds the API asked for in the background. Failures to start are logged
+/// and, for tracked templates, reported with the template.
+pub fn request_rebuild(request: TemplateRebuildRequest) {
+    let names = resolve(&request.template);
+    if names.is_empty() {
+        warn!(
+            "Rebuild {}: no tracked template matches '{}'",
+            request.id, request.template
+        );
+        return;
+    }
+    for name in names {
+        info!("Rebuild {}: rebuilding template '{}'", request.id, name);
+        if let Err(e) = spawn_rebuild(&name, request.login.clone()) {
+            warn!("Rebuild {}: {}", request.id, e);
+            record_rebuild_error(&name, &e);
+        }
+    }
+}
+
+/// Check every tracked template against the registry and mark those whose image changed
+/// upstream as stale, rebuilding them when `[lume.freshness] rebuild` is on
+pub async fn check_all() {
+    let templates: Vec<(String, TemplateConfig)> = StateStore::new().read(|state| {
+        state
+            .templates
+            .iter()
+            .map(|(name, source)| (name.clone(), source.config.clone()))
+            .collect()
+    });
+    let lume = match LumeClient::new() {
+        Ok(lume) => lume,
+        Err(e) => {
+            warn!("Skipping template freshness checks: {}", e);
+            return;
+        }
+    };
+    for (template_name, config) in templates {
+        if is_rebuilding(&template_name) {
+            continue;
+        }
+        match lume.get_vm(&template_name).await {
+            Ok(_) => {}
+            // Unreachable lume says nothing about whether the template exists
+            Err(LumeError::RequestError(e)) => {
+                warn!("Skipping template freshness checks: {}", e);
+                return;
+            }
+            Err(_) => {
+                debug!(
+                    "Template '{}' is gone; no longer tracking it",
+                    template_name
+                );
+                StateStore::new().forget_template(&template_name);
+                continue;
+            }
+        }
+        let reference = image_reference(&config);
+        let latest = match registry_digest(&reference).await {
+            Ok(Some(latest)) => latest,
+            Ok(None) => {
+                debug!("No digest for '{}' in the registry", reference);
+                continue;
+            }
+            Err(e) => {
+                debug!("Registry digest lookup failed for '{}': {}", reference, e);
+                continue;
+            }
+        };
+        let stale = StateStore::new().update(|state| {
+            state.templates.get_mut(&template_name).map(|source| {
+                let was_stale = source.stale_since.is_some();
+                (apply_check(source, latest, Utc::now()), was_stale)
+            })
+        });
+        match stale {
+            Some((true, false)) => warn!(
+                "Template '{}' is stale: '{}' changed in the registry since it was built",
+                template_name, reference
+            ),
+            Some((false, _)) => debug!("Template '{}' is up to date", template_name),
+            _ => {}
+        }
+        if stale.is_some_and(|(stale, _)| stale) && agent_config().lume.freshness.rebuild {
+            if let Err(e) = spawn_rebuild(&template_name, None) {
+                info!("Template '{}' is stale: {}", template_name, e);
+            }
+        }
+    }
+}
+
+/// Freshness of every tracked template, for agent reports
+pub fn report() -> Vec<Value> {
+    let mut templates: Vec<Value> = StateStore::new().read(|state| {
+        state
+            .templates
+            .iter()
+            .map(|(name, source)| {
+                json!({
+                    "name": name,
+                    "image": image_reference(&source.config),
+                    "digest": source.digest,
+                    "latest_digest": source.latest_digest,
+                    "built_at": source.built_at,
+                    "checked_at": source.checked_at,
+                    "stale": source.stale_since.is_some(),
+                    "stale_since": source.stale_since,
+                    "rebuilding": is_rebuilding(name),
+                    "last_rebuild_error": source.last_rebuild_error,
+                })
+            })
+            .collect()
+    });
+    templates.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+    templates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::{DiskSize, Memory};
+
+    #[test]
+    fn test_staleness() {
+        let config = TemplateConfig {
+            image: "macos-sequoia-xcode:16".to_string(),
+            registry: None,
+            organization: Some("cirunlabs".to_string()),
+            cpu: 4,
+            memory: Memory::from_gb(8),
+            disk: DiskSize::from_gb(100),
+            os: "macos".to_string(),
+            no_cache: false,
+        };
+        assert_eq!(
+            image_reference(&config),
+            "ghcr.io/cirunlabs/macos-sequoia-xcode:16"
+        );
+
+        let built = Utc::now();
+        let mut source = TemplateSource {
+            config,
+            digest: None,
+            built_at: built,
+            latest_digest: None,
+            checked_at: None,
+            stale_since: None,
+            last_rebuild_error: None,
+        };
+        // The first digest seen becomes the baseline
+        assert!(!apply_check(&mut source, "sha256:a".to_string(), built));
+        assert_eq!(source.digest.as_deref(), Some("sha256:a"));
+        let later = built + chrono::Duration::hours(1);
+        assert!(apply_check(&mut source, "sha256:b".to_string(), later));
+        // Staleness keeps the time it was first noticed
+        assert!(apply_check(
+            &mut source,
+            "sha256:c".to_string(),
+            later + chrono::Duration::hours(1)
+        ));
+        assert_eq!(source.stale_since, Some(later));
+        assert_eq!(source.latest_digest.as_deref(), Some("sha256:c"));
+    }
+}
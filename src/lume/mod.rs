@@ -1,6 +1,7 @@
 // Re-export all public items from submodules
 pub mod client;
 pub mod errors;
+pub mod freshness;
 pub mod models;
 pub mod pull;
 pub mod setup;
@@ -12,5 +13,6 @@ pub use self::models::*;
 pub use self::setup::*;
 // Export specific functions from pull module
 pub use self::pull::{
-    check_template_exists, create_template, find_matching_template, generate_template_name,
+    check_template_exists, cleanup_incomplete_templates, create_template, find_matching_template,
+    generate_template_name, validate_template,
 };
@@ -1,3 +1,4 @@
+use crate::units::{self, DiskSize, Memory};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -39,10 +40,13 @@ pub struct CloneConfig {
     pub new_name: String,
 }
 
+/// Disk usage as lume reports it, in MB
 #[derive(Debug, Serialize, Deserialize)]
-pub struct DiskSize {
-    pub allocated: u64,
-    pub total: u64,
+pub struct DiskUsage {
+    #[serde(with = "units::mb")]
+    pub allocated: DiskSize,
+    #[serde(with = "units::mb")]
+    pub total: DiskSize,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -53,10 +57,10 @@ pub struct VmInfo {
     pub os: String,
     #[serde(rename = "cpuCount")]
     pub cpu: u32,
-    #[serde(rename = "memorySize")]
-    pub memory: u64,
+    #[serde(rename = "memorySize", with = "units::mb")]
+    pub memory: Memory,
     #[serde(rename = "diskSize")]
-    pub disk_size: DiskSize,
+    pub disk_size: DiskUsage,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub display: Option<String>,
     #[serde(rename = "ipAddress", default)]
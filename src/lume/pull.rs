@@ -1,11 +1,29 @@
+use crate::config::agent_config;
+use crate::guest_files::{push_files, GuestFile};
+use crate::host_keys;
+use crate::locks::{lock_template, swap_template};
 use crate::lume::client::LumeClient;
-use crate::TemplateConfig;
+use crate::lume::freshness;
+use crate::lume::models::RunConfig;
+use crate::naming;
+use crate::os_detect::normalize_os;
+use crate::retry::with_retries;
+use crate::runner_cache::runner_tarball;
+use crate::ssh::SshError;
+use crate::state::StateStore;
+use crate::template_failure::{AtStage, TemplateError, TemplateStage};
+use crate::vm_provision::{run_ssh_command, shell_quote, wait_for_vm_ip};
+use crate::{RunnerLogin, TemplateConfig};
 use log::{error, info, warn};
 use reqwest::Client;
 use serde_json::json;
 use std::hash::{Hash, Hasher};
 use tokio::time::{sleep, Duration};
 
+/// Where the runner tarball and setup script are uploaded in a template being set up
+const RUNNER_TARBALL: &str = "/tmp/actions-runner.tar.gz";
+const SETUP_SCRIPT: &str = "/tmp/cirun-template-setup";
+
 /// Pull an image using the Lume API
 pub async fn pull_image(
     config: &TemplateConfig,
@@ -37,20 +55,23 @@ pub async fn pull_image(
                 }
             }
 
-            // Use the LumeClient's pull_image method
+            let no_cache = config.no_cache || !agent_config().lume.pull_cache;
+            if no_cache {
+                info!("Pulling '{}' without lume's layer cache", image_name);
+            }
             lume.pull_image(
                 &image_name,
                 vm_name,
                 config.registry.as_deref(),
                 organization.as_deref(),
-                true, // noCache is true
+                no_cache,
             )
             .await?;
             info!("Waiting for VM creation - this may take up to 30 minutes for large images...");
 
             // Wait for the pull to complete with exponential backoff
             let start_time = tokio::time::Instant::now();
-            let max_timeout = Duration::from_secs(1800); // 30 minute max timeout
+            let max_timeout = Duration::from_secs(agent_config().timeouts.image_pull_secs);
 
             // Initial backoff of 10 seconds, then increasing
             let mut backoff_seconds = 10;
@@ -169,7 +190,12 @@ pub async fn check_image_exists(image: &str) -> Option<String> {
             match lume.list_vms().await {
                 Ok(vms) => {
                     // Look for template VMs with matching image
+                    let state = StateStore::new();
                     for vm in vms {
+                        if state.is_template_incomplete(&vm.name) {
+                            continue;
+                        }
+
                         // For each VM, check if the name contains the base image name and tag
                         if vm.name.contains(base_image_name) && vm.name.contains(image_tag) {
                             info!("Found existing VM with the requested image: {}", vm.name);
@@ -177,7 +203,7 @@ pub async fn check_image_exists(image: &str) -> Option<String> {
                         }
 
                         // Also check template names that might contain the image name
-                        if vm.name.starts_with("cirun-template-")
+                        if vm.name.starts_with(&naming::vm_name("template-", ""))
                             && vm.name.contains(&base_image_name.replace('-', ""))
                             && vm.name.contains(image_tag)
                         {
@@ -209,9 +235,54 @@ pub async fn check_image_exists(image: &str) -> Option<String> {
     }
 }
 
+/// Delete the leftovers of a template whose creation never completed.
+/// If deletion fails the template stays marked incomplete, so it's still never used.
+async fn discard_incomplete_template(lume: &LumeClient, template_name: &str) {
+    warn!(
+        "Template '{}' was not fully created. Removing the incomplete VM.",
+        template_name
+    );
+    match lume.get_vm(template_name).await {
+        Ok(_) => match lume.delete_vm(template_name).await {
+            Ok(_) => {
+                info!("Removed incomplete template '{}'", template_name);
+                StateStore::new().clear_template_creating(template_name);
+            }
+            Err(e) => error!(
+                "Failed to remove incomplete template '{}', quarantining it: {:?}",
+                template_name, e
+            ),
+        },
+        // Nothing was left behind
+        Err(_) => StateStore::new().clear_template_creating(template_name),
+    }
+}
+
+/// Remove every template left incomplete by an earlier crash or failed creation
+pub async fn cleanup_incomplete_templates() {
+    let incomplete: Vec<String> =
+        StateStore::new().read(|state| state.incomplete_templates.keys().cloned().collect());
+    if incomplete.is_empty() {
+        return;
+    }
+
+    match LumeClient::new() {
+        Ok(lume) => {
+            for template_name in incomplete {
+                discard_incomplete_template(&lume, &template_name).await;
+            }
+        }
+        Err(e) => error!("Failed to initialize Lume client: {:?}", e),
+    }
+}
+
 /// Check if a template exists with the given name
 pub async fn check_template_exists(template_name: &str) -> bool {
     match LumeClient::new() {
+        Ok(lume) if StateStore::new().is_template_incomplete(template_name) => {
+            discard_incomplete_template(&lume, template_name).await;
+            false
+        }
         Ok(lume) => match lume.get_vm(template_name).await {
             Ok(_) => {
                 info!("Template '{}' already exists", template_name);
@@ -237,14 +308,17 @@ pub async fn find_matching_template(config: &TemplateConfig) -> Option<String> {
             match lume.list_vms().await {
                 Ok(vms) => {
                     // Look for template VMs with matching specs
+                    let state = StateStore::new();
                     for vm in vms {
                         // Check if this is a template VM (starts with cirun-template)
-                        if vm.name.starts_with("cirun-template-") {
+                        if vm.name.starts_with(&naming::vm_name("template-", ""))
+                            && !state.is_template_incomplete(&vm.name)
+                        {
                             // Check if specs match what we need
                             if vm.cpu == config.cpu
-                                && vm.memory / 1024 == config.memory as u64
-                                && vm.disk_size.total / 1024 >= config.disk as u64
-                                && vm.os == config.os
+                                && vm.memory == config.memory
+                                && vm.disk_size.total >= config.disk
+                                && normalize_os(&vm.os) == normalize_os(&config.os)
                             {
                                 info!("Found existing template with matching specs: {}", vm.name);
                                 return Some(vm.name);
@@ -272,15 +346,48 @@ pub async fn find_matching_template(config: &TemplateConfig) -> Option<String> {
     }
 }
 
-/// Create a template VM from the image
+/// Create a template VM from the image.
+/// Creation is tracked in the state store; if it fails, the half-built template is removed
+/// (or quarantined if removal fails) so it is never picked up later.
 pub async fn create_template(
     config: &TemplateConfig,
     template_name: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+    login: &RunnerLogin,
+) -> Result<(), TemplateError> {
+    let state = StateStore::new();
+    state.mark_template_creating(template_name);
+
+    match build_template(config, template_name, login, false).await {
+        Ok(()) => {
+            state.clear_template_creating(template_name);
+            freshness::record_build(template_name, config).await;
+            Ok(())
+        }
+        Err(e) => {
+            if let Ok(lume) = LumeClient::new() {
+                discard_incomplete_template(&lume, template_name).await;
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Pull (or, unless `fresh_pull`, clone a VM that already has the image), configure and set
+/// up a new template
+async fn build_template(
+    config: &TemplateConfig,
+    template_name: &str,
+    login: &RunnerLogin,
+    fresh_pull: bool,
+) -> Result<(), TemplateError> {
     match LumeClient::new() {
         Ok(lume) => {
             // First, check if we already have a VM with this image
-            let existing_image = check_image_exists(&config.image).await;
+            let existing_image = if fresh_pull {
+                None
+            } else {
+                check_image_exists(&config.image).await
+            };
 
             if let Some(existing_vm) = existing_image {
                 info!(
@@ -308,7 +415,9 @@ pub async fn create_template(
                             );
                             // Fall back to pulling the image
                             info!("Falling back to pulling the image directly");
-                            pull_image(config, template_name).await?;
+                            pull_image(config, template_name)
+                                .await
+                                .at(TemplateStage::Pull)?;
                         }
                     }
                 } else {
@@ -327,26 +436,31 @@ pub async fn create_template(
                 info!("This process may take up to 30 minutes for large images");
 
                 // Pull the image with the template name as the VM name
-                pull_image(config, template_name).await?;
+                pull_image(config, template_name)
+                    .await
+                    .at(TemplateStage::Pull)?;
             }
 
             // Now configure the VM with the specified resources
             info!(
-                "Configuring VM resources (CPU: {}, Memory: {}GB, Disk: {}GB)",
+                "Configuring VM resources (CPU: {}, Memory: {}, Disk: {})",
                 config.cpu, config.memory, config.disk
             );
 
             let update_config = json!({
                 "cpu": config.cpu,
-                "memory": format!("{}GB", config.memory),
-                "diskSize": format!("{}GB", config.disk)
+                "memory": config.memory.to_lume(),
+                "diskSize": config.disk.to_lume()
             });
 
             let update_url = format!("{}/vms/{}", lume.get_base_url(), template_name);
 
             let client = Client::builder()
-                .timeout(Duration::from_secs(600)) // 10 minute timeout for the configuration
-                .build()?;
+                .timeout(Duration::from_secs(
+                    agent_config().timeouts.template_configure_secs,
+                ))
+                .build()
+                .at(TemplateStage::Configure)?;
 
             info!(
                 "Sending request to update VM configuration: {}",
@@ -357,27 +471,37 @@ pub async fn create_template(
                 .patch(&update_url)
                 .json(&update_config)
                 .send()
-                .await?;
+                .await
+                .at(TemplateStage::Configure)?;
 
             if !response.status().is_success() {
-                let error_text = response.text().await?;
+                let error_text = response.text().await.at(TemplateStage::Configure)?;
                 error!("Failed to update template VM configuration: {}", error_text);
-                return Err(
-                    format!("Failed to update template VM configuration: {}", error_text).into(),
-                );
+                return Err(TemplateError::new(
+                    TemplateStage::Configure,
+                    format!("Failed to update template VM configuration: {}", error_text),
+                ));
             }
 
             // Verify the configuration was applied correctly
             match lume.get_vm(template_name).await {
                 Ok(vm) => {
-                    info!("Template '{}' created and configured with: CPU: {}, Memory: {}MB, Disk: {}GB",
-                         template_name, vm.cpu, vm.memory / 1024, vm.disk_size.total / 1024);
+                    info!(
+                        "Template '{}' created and configured with: CPU: {}, Memory: {}, Disk: {}",
+                        template_name, vm.cpu, vm.memory, vm.disk_size.total
+                    );
                 }
                 Err(e) => {
                     warn!("Unable to verify template configuration: {}", e);
                 }
             }
 
+            if agent_config().lume.template_setup.enabled() {
+                setup_template(&lume, config, template_name, login)
+                    .await
+                    .at(TemplateStage::Setup)?;
+            }
+
             info!(
                 "✅ Template '{}' successfully created and ready for use",
                 template_name
@@ -386,6 +510,307 @@ pub async fn create_template(
         }
         Err(e) => {
             error!("Failed to initialize Lume client: {:?}", e);
+            Err(TemplateError::new(TemplateStage::Pull, e))
+        }
+    }
+}
+
+/// Recreate a template from a fresh pull of its image next to the old one, and swap it in
+/// once it is set up and validated. Runners keep cloning the old template until the swap,
+/// which waits for running clones and holds off new ones. The old template is copied
+/// aside first and put back if the new one can't be copied into place.
+pub async fn rebuild_template(
+    config: &TemplateConfig,
+    template_name: &str,
+    login: &RunnerLogin,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let lume = LumeClient::new()?;
+    let state = StateStore::new();
+    let staging = format!("{}-rebuild", template_name);
+    if check_template_exists(&staging).await {
+        discard_incomplete_template(&lume, &staging).await;
+    }
+
+    state.mark_template_creating(&staging);
+    let built = async {
+        build_template(config, &staging, login, true).await?;
+        validate_template(&staging, login).await
+    }
+    .await
+    .map_err(|e| e.to_string());
+    if let Err(e) = built {
+        discard_incomplete_template(&lume, &staging).await;
+        return Err(e.into());
+    }
+
+    // The staging template stays marked incomplete so no runner is cloned from it
+    let _template_lock = lock_template(template_name).await;
+    let _swap = swap_template(template_name).await;
+    let previous = format!("{}-previous", template_name);
+    let had_template = lume.get_vm(template_name).await.is_ok();
+    if had_template {
+        if check_template_exists(&previous).await {
+            discard_incomplete_template(&lume, &previous).await;
+        }
+        // Marked incomplete so a crash before the cleanup below leaves it to the
+        // startup cleanup
+        state.mark_template_creating(&previous);
+        let set_aside = async {
+            lume.clone_vm(template_name, &previous).await?;
+            lume.delete_vm(template_name).await
+        }
+        .await;
+        if let Err(e) = set_aside {
+            discard_incomplete_template(&lume, &previous).await;
+            discard_incomplete_template(&lume, &staging).await;
+            return Err(format!(
+                "Failed to set aside template '{}' for the rebuild: {:?}",
+                template_name, e
+            )
+            .into());
+        }
+    }
+
+    // Marked incomplete while it is copied, so a crash mid-swap leaves it to the startup
+    // cleanup
+    state.mark_template_creating(template_name);
+    let swapped = lume.clone_vm(&staging, template_name).await;
+    let result = match swapped {
+        Ok(()) => {
+            host_keys::forget(template_name);
+            state.clear_template_creating(template_name);
+            state.mark_template_validated(template_name);
+            Ok(())
+        }
+        Err(e) => {
+            if had_template {
+                match lume.clone_vm(&previous, template_name).await {
+                    Ok(()) => state.clear_template_creating(template_name),
+                    Err(e) => error!(
+                        "Failed to put back template '{}' after a failed rebuild: {:?}",
+                        template_name, e
+                    ),
+                }
+            }
+            Err(format!(
+                "Failed to copy rebuilt template '{}' into place: {:?}",
+                staging, e
+            ))
+        }
+    };
+
+    let mut leftovers = vec![&staging];
+    if had_template {
+        leftovers.push(&previous);
+    }
+    for leftover in leftovers {
+        if let Err(e) = lume.delete_vm(leftover).await {
+            warn!("Failed to remove template '{}': {:?}", leftover, e);
+        }
+        state.forget_template(leftover);
+    }
+    result.map_err(Into::into)
+}
+
+/// Stop a template booted for `purpose` and wait until it is stopped
+async fn stop_template(lume: &LumeClient, template_name: &str, purpose: &str) {
+    if let Err(e) = lume.stop_vm(template_name).await {
+        warn!(
+            "Failed to stop template '{}' after {}: {}",
+            template_name, purpose, e
+        );
+    }
+    let start_time = tokio::time::Instant::now();
+    while start_time.elapsed() < Duration::from_secs(agent_config().timeouts.vm_stop_secs) {
+        match lume.get_vm(template_name).await {
+            Ok(vm) if vm.state == "stopped" => break,
+            _ => sleep(Duration::from_secs(5)).await,
+        }
+    }
+}
+
+/// Run `command` in a booted template, failing with `what` and its stderr if it fails
+async fn run_setup_step(
+    template_name: &str,
+    ip_address: &str,
+    login: &RunnerLogin,
+    what: &str,
+    command: &str,
+    timeout_secs: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Template '{}': {}", template_name, what);
+    let output = run_ssh_command(template_name, ip_address, login, command, timeout_secs).await?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            what,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Boot a new template once to install the Actions runner and run the setup script, then
+/// stop it again. Any failure fails the template creation.
+async fn setup_template(
+    lume: &LumeClient,
+    config: &TemplateConfig,
+    template_name: &str,
+    login: &RunnerLogin,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let setup = &agent_config().lume.template_setup;
+    let timeouts = &agent_config().timeouts;
+    info!("Setting up template '{}'", template_name);
+    host_keys::forget(template_name);
+    let run_config = RunConfig {
+        no_display: Some(true),
+        shared_directories: None,
+        recovery_mode: None,
+    };
+    lume.run_vm(template_name, Some(run_config)).await?;
+
+    let result = async {
+        let ip_address = wait_for_vm_ip(lume, template_name, timeouts.ip_wait_secs).await?;
+        let ready = || async {
+            run_ssh_command(
+                template_name,
+                &ip_address,
+                login,
+                "true",
+                timeouts.ssh_attempt_secs,
+            )
+            .await
+            .map_err(|e| SshError::Connection(format!("SSH not ready: {}", e)))
+            .and_then(|output| {
+                output
+                    .status
+                    .success()
+                    .then_some(())
+                    .ok_or_else(|| SshError::Connection("SSH not ready".to_string()))
+            })
+        };
+        with_retries("template SSH", agent_config().retry.ssh, ready).await?;
+
+        if setup.install_runner {
+            let tarball = runner_tarball(&config.os, RUNNER_TARBALL).await?;
+            push_files(template_name, &ip_address, login, &[tarball.file]).await?;
+            let dir = shell_quote(&setup.runner_dir);
+            let install = format!(
+                "cd ~ && mkdir -p {dir} && tar xzf {tarball} -C {dir} && rm -f {tarball} && \
+                 if [ \"$(uname)\" = Linux ]; then sudo -n {dir}/bin/installdependencies.sh; fi",
+                dir = dir,
+                tarball = RUNNER_TARBALL
+            );
+            run_setup_step(
+                template_name,
+                &ip_address,
+                login,
+                &format!("installing Actions runner {}", tarball.version),
+                &install,
+                timeouts.transfer_secs,
+            )
+            .await?;
+        }
+
+        if let Some(script) = &setup.script {
+            let content = std::fs::read_to_string(script)
+                .map_err(|e| format!("Failed to read {}: {}", script.display(), e))?;
+            let file = GuestFile {
+                path: SETUP_SCRIPT.to_string(),
+                content: Some(content),
+                url: None,
+                sha256: None,
+                mode: "0755".to_string(),
+            };
+            push_files(template_name, &ip_address, login, &[file]).await?;
+            run_setup_step(
+                template_name,
+                &ip_address,
+                login,
+                &format!("running setup script {}", script.display()),
+                &format!("{} && rm -f {}", SETUP_SCRIPT, SETUP_SCRIPT),
+                timeouts.script_secs,
+            )
+            .await?;
+        }
+        Ok::<(), Box<dyn std::error::Error>>(())
+    }
+    .await
+    .map_err(|e| e.to_string());
+
+    stop_template(lume, template_name, "setup").await;
+    result?;
+    info!("Template '{}' set up", template_name);
+    Ok(())
+}
+
+/// Boot a template once, verify SSH comes up and basic commands run, then stop it again.
+/// Templates that pass are marked validated in the state store.
+pub async fn validate_template(
+    template_name: &str,
+    login: &RunnerLogin,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let lume = LumeClient::new()?;
+
+    info!("Validating template '{}' with a boot test", template_name);
+    // A rebuilt template has new host keys
+    host_keys::forget(template_name);
+    let run_config = RunConfig {
+        no_display: Some(true),
+        shared_directories: None,
+        recovery_mode: None,
+    };
+    lume.run_vm(template_name, Some(run_config)).await?;
+
+    let result = async {
+        let ip_address =
+            wait_for_vm_ip(&lume, template_name, agent_config().timeouts.ip_wait_secs).await?;
+        info!(
+            "Template '{}' booted with IP: {}",
+            template_name, ip_address
+        );
+
+        let smoke_test = || async {
+            let output = run_ssh_command(
+                template_name,
+                &ip_address,
+                login,
+                "uname -a && whoami && df -h /",
+                agent_config().timeouts.ssh_attempt_secs,
+            )
+            .await
+            .map_err(|e| SshError::Connection(format!("SSH smoke test failed: {}", e)))?;
+            if output.status.success() {
+                Ok(String::from_utf8_lossy(&output.stdout).to_string())
+            } else {
+                Err(SshError::failed("SSH smoke test", &output))
+            }
+        };
+
+        let output =
+            with_retries("template smoke test", agent_config().retry.ssh, smoke_test).await?;
+        info!(
+            "Template '{}' smoke test output: {}",
+            template_name,
+            output.trim()
+        );
+        Ok::<(), Box<dyn std::error::Error>>(())
+    }
+    .await
+    .map_err(|e| e.to_string());
+
+    // Always stop the template again so it can be cloned
+    stop_template(&lume, template_name, "validation").await;
+
+    match result {
+        Ok(()) => {
+            StateStore::new().mark_template_validated(template_name);
+            info!("✅ Template '{}' passed validation", template_name);
+            Ok(())
+        }
+        Err(e) => {
+            error!("Template '{}' failed validation: {}", template_name, e);
             Err(e.into())
         }
     }
@@ -419,13 +844,21 @@ pub fn generate_template_name(config: &TemplateConfig) -> String {
         .hash(&mut hasher);
     config.os.hash(&mut hasher);
     config.cpu.hash(&mut hasher);
-    config.memory.hash(&mut hasher);
-    config.disk.hash(&mut hasher);
+    // Hashed as whole GB so existing template names stay stable
+    (config.memory.as_gb() as u32).hash(&mut hasher);
+    (config.disk.as_gb() as u32).hash(&mut hasher);
     let config_hash = hasher.finish() % 10000; // Limit to 4 digits for readability
 
-    // Format: cirun-template-{image}-{tag}-{cpu}-{mem}-{config_hash}
-    format!(
-        "cirun-template-{}-{}-{}-{}-{:04}",
-        sanitized_image, image_tag, config.cpu, config.memory, config_hash
+    // Format: {prefix}template-{image}-{tag}-{cpu}-{mem}-{config_hash}
+    naming::vm_name(
+        "template-",
+        &format!(
+            "{}-{}-{}-{}-{:04}",
+            sanitized_image,
+            image_tag,
+            config.cpu,
+            config.memory.as_gb(),
+            config_hash
+        ),
     )
 }
@@ -4,18 +4,24 @@ use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use std::{thread, time::Duration, time::SystemTime};
 
+use crate::config::agent_config;
+use crate::host_tools;
+use crate::provider_auth;
+use crate::provider_service::{ensure_provider_service, stop_provider_service};
+use crate::supervisor::{self, ServeSpec};
+use crate::temp_guard::TempGuard;
+use crate::upgrade::{installed_version, stop_serve};
 use chrono::{DateTime, Utc};
 use std::path::Path;
 
+/// Lume release installed when `LUME_VERSION` is not set
+const DEFAULT_LUME_VERSION: &str = "0.2.22";
+/// Environment variable `serve` reads the API token from
+const LUME_TOKEN_ENV: &str = "LUME_API_TOKEN";
+
 /// Check if lume serve process is currently running
 pub fn is_lume_running() -> bool {
-    Command::new("pgrep")
-        .arg("-f")
-        .arg("lume serve")
-        .stdout(Stdio::null())
-        .status()
-        .map(|status| status.success())
-        .unwrap_or(false)
+    host_tools::process_running("lume serve")
 }
 
 pub async fn download_and_run_lume() {
@@ -124,88 +130,173 @@ pub fn cleanup_log_files(
     Ok(())
 }
 
-fn download_and_run_lume_internal() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Define constants
-    let lume_version = std::env::var("LUME_VERSION").unwrap_or_else(|_| String::from("0.2.22"));
-    let lume_url = format!(
-        "https://github.com/trycua/cua/releases/download/lume-v{}/lume-{}-darwin-arm64.tar.gz",
-        lume_version, lume_version
+/// Architecture of the host hardware in lume's release naming, even when the agent
+/// itself runs under Rosetta
+fn host_arch() -> String {
+    let apple_silicon = Command::new("sysctl")
+        .arg("-n")
+        .arg("hw.optional.arm64")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim() == "1")
+        .unwrap_or(false);
+    if apple_silicon {
+        return "arm64".to_string();
+    }
+    match std::env::consts::ARCH {
+        "aarch64" => "arm64".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Download URLs to try for a lume release, newest layout first. Older releases
+/// published a single `lume.tar.gz` without version or architecture in the name.
+fn release_asset_urls(version: &str, arch: &str) -> Vec<String> {
+    let base = format!(
+        "https://github.com/trycua/cua/releases/download/lume-v{}",
+        version
     );
-    let install_dir = PathBuf::from(format!("{}/.lume", std::env::var("HOME")?));
-    let lume_bin_path = install_dir.join("lume");
+    vec![
+        format!("{}/lume-{}-darwin-{}.tar.gz", base, version, arch),
+        format!("{}/lume-darwin-{}.tar.gz", base, arch),
+        format!("{}/lume.tar.gz", base),
+    ]
+}
 
-    // Create installation directory if it doesn't exist
-    if !install_dir.exists() {
-        fs::create_dir_all(&install_dir)?;
-        info!("Created directory: {:?}", install_dir);
-    }
+/// Lume version to install, pinned unless `LUME_VERSION` overrides it
+fn lume_version() -> String {
+    std::env::var("LUME_VERSION").unwrap_or_else(|_| String::from(DEFAULT_LUME_VERSION))
+}
 
-    // Check if lume is already downloaded
-    if !lume_bin_path.exists() {
-        info!("Lume not found, downloading version {}...", lume_version);
+/// Fail clearly on hosts lume can't run on instead of installing an incompatible binary
+fn supported_arch() -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let arch = host_arch();
+    if arch != "arm64" {
+        return Err(format!(
+            "Lume requires an Apple Silicon (arm64) Mac, but this host is {}; Intel Macs are not supported",
+            arch
+        )
+        .into());
+    }
+    Ok(arch)
+}
 
-        // Create a temporary directory for the download
-        let temp_dir = std::env::temp_dir().join("lume_download");
-        if temp_dir.exists() {
-            fs::remove_dir_all(&temp_dir)?;
+/// Download and extract a lume release into a fresh temporary directory, returning the
+/// extracted binary and the directory to clean up afterwards
+fn download_lume(
+    lume_version: &str,
+    arch: &str,
+) -> Result<(PathBuf, TempGuard), Box<dyn std::error::Error + Send + Sync>> {
+    info!("Downloading lume version {}...", lume_version);
+
+    // Download into a temporary directory that is removed with the returned guard
+    let download_dir = TempGuard::dir("lume_download")?;
+    let temp_dir = download_dir.path();
+
+    let tar_gz_path = temp_dir.join("lume.tar.gz");
+
+    // Try each known release layout until one exists
+    let mut downloaded = false;
+    for lume_url in release_asset_urls(lume_version, arch) {
+        match host_tools::download(&lume_url, &tar_gz_path) {
+            Ok(()) => {
+                info!("Downloaded lume from {}", lume_url);
+                downloaded = true;
+                break;
+            }
+            Err(e) => warn!("Lume release asset not available: {}", e),
         }
-        fs::create_dir_all(&temp_dir)?;
+    }
 
-        let tar_gz_path = temp_dir.join("lume.tar.gz");
+    if !downloaded {
+        return Err(format!(
+            "Failed to download lume v{} for darwin-{}; check LUME_VERSION",
+            lume_version, arch
+        )
+        .into());
+    }
 
-        // Use curl command to download the file (most reliable method)
-        let status = Command::new("curl")
-            .arg("-L")
-            .arg("-o")
-            .arg(&tar_gz_path)
-            .arg(&lume_url)
-            .status()?;
+    host_tools::extract_tar_gz(&tar_gz_path, temp_dir)
+        .map_err(|e| format!("Failed to extract lume archive: {}", e))?;
 
-        if !status.success() {
-            return Err("Failed to download lume archive".into());
+    // Find the lume binary
+    let mut lume_binary = None;
+    for entry in walkdir::WalkDir::new(temp_dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        let path = entry.path();
+        if path.is_file() && path.file_name().and_then(|n| n.to_str()) == Some("lume") {
+            lume_binary = Some(path.to_path_buf());
+            break;
         }
+    }
 
-        // Use tar to extract the archive
-        let status = Command::new("tar")
-            .arg("-xzf")
-            .arg(&tar_gz_path)
-            .arg("-C")
-            .arg(&temp_dir)
-            .status()?;
+    let lume_temp_path = lume_binary.ok_or("Could not find lume binary in extracted files")?;
+    Ok((lume_temp_path, download_dir))
+}
 
-        if !status.success() {
-            return Err("Failed to extract lume archive".into());
-        }
+/// Copy a downloaded lume binary into place and make it executable
+fn install_lume_binary(
+    source: &Path,
+    lume_bin_path: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    fs::copy(source, lume_bin_path)?;
+
+    // Make the binary executable
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mut perms = fs::metadata(lume_bin_path)?.permissions();
+        perms.set_mode(0o755);
+        fs::set_permissions(lume_bin_path, perms)?;
+    }
+    Ok(())
+}
 
-        // Find the lume binary
-        let mut lume_binary = None;
-        for entry in walkdir::WalkDir::new(&temp_dir)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-            let path = entry.path();
-            if path.is_file() && path.file_name().and_then(|n| n.to_str()) == Some("lume") {
-                lume_binary = Some(path.to_path_buf());
-                break;
-            }
-        }
+/// Where the agent installs lume
+fn lume_bin_path() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    let install_dir = PathBuf::from(format!("{}/.lume", std::env::var("HOME")?));
 
-        let lume_temp_path = lume_binary.ok_or("Could not find lume binary in extracted files")?;
+    // Create installation directory if it doesn't exist
+    if !install_dir.exists() {
+        fs::create_dir_all(&install_dir)?;
+        info!("Created directory: {:?}", install_dir);
+    }
+    Ok(install_dir.join("lume"))
+}
 
-        // Copy the binary to the installation directory
-        fs::copy(&lume_temp_path, &lume_bin_path)?;
+fn download_and_run_lume_internal() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    start_lume_serve(&resolve_lume_binary()?)
+}
+
+/// Install lume (if needed) and run `lume serve` as its own launchd service instead of
+/// a process owned by the agent
+pub fn install_lume_service() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    ensure_provider_service(&lume_serve_spec(&resolve_lume_binary()?))
+}
 
-        // Make the binary executable
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            let mut perms = fs::metadata(&lume_bin_path)?.permissions();
-            perms.set_mode(0o755);
-            fs::set_permissions(&lume_bin_path, perms)?;
+/// The lume binary to run: the locally provided one, or the agent-managed installation
+/// (downloaded first if missing)
+fn resolve_lume_binary() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    // Use a locally provided binary when given and never download anything
+    if let Ok(local_binary) = std::env::var("LUME_BINARY") {
+        let lume_binary = PathBuf::from(local_binary);
+        if !lume_binary.is_file() {
+            return Err(format!("LUME_BINARY {:?} does not exist", lume_binary).into());
         }
+        info!("Using locally provided lume binary at {:?}", lume_binary);
+        return Ok(lume_binary);
+    }
+
+    let arch = supported_arch()?;
+    let lume_version = lume_version();
+    let lume_bin_path = lume_bin_path()?;
 
-        // Clean up the temporary directory
-        fs::remove_dir_all(&temp_dir)?;
+    // Check if lume is already downloaded
+    if !lume_bin_path.exists() {
+        info!("Lume not found");
+        let (downloaded, _download_dir) = download_lume(&lume_version, &arch)?;
+        install_lume_binary(&downloaded, &lume_bin_path)?;
 
         info!(
             "Lume v{} installed successfully at {:?}",
@@ -215,6 +306,112 @@ fn download_and_run_lume_internal() -> Result<(), Box<dyn std::error::Error + Se
         info!("Lume is already installed at {:?}", lume_bin_path);
     }
 
+    Ok(lume_bin_path)
+}
+
+/// Version of the lume binary in use, without installing one
+pub fn installed_lume_version() -> Option<String> {
+    let binary = match std::env::var("LUME_BINARY") {
+        Ok(local_binary) => PathBuf::from(local_binary),
+        Err(_) => lume_bin_path().ok()?,
+    };
+    installed_version(&binary)
+}
+
+/// Upgrade lume in place when the installed version differs from the pinned one,
+/// returning whether an upgrade happened. The new release is downloaded before
+/// `lume serve` is stopped, so the provider is only down while the binary is swapped.
+pub fn upgrade_lume() -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    // A locally provided binary is managed by whoever provided it
+    if std::env::var("LUME_BINARY").is_ok() {
+        return Ok(false);
+    }
+    let lume_bin_path = lume_bin_path()?;
+    if !lume_bin_path.exists() {
+        return Ok(false);
+    }
+    let wanted = lume_version();
+    let Some(installed) = installed_version(&lume_bin_path) else {
+        warn!(
+            "Could not determine the version of {:?}, skipping upgrade check",
+            lume_bin_path
+        );
+        return Ok(false);
+    };
+    if installed == wanted {
+        return Ok(false);
+    }
+
+    info!("Upgrading lume {} -> {}", installed, wanted);
+    let (downloaded, download_dir) = download_lume(&wanted, &supported_arch()?)?;
+    // Stop the service or supervisor first so the old server isn't restarted while the
+    // binary is swapped
+    if agent_config().provider.service {
+        stop_provider_service();
+    } else {
+        supervisor::release();
+    }
+    stop_serve("lume serve");
+    let swapped = install_lume_binary(&downloaded, &lume_bin_path);
+    drop(download_dir);
+    if let Err(e) = swapped {
+        warn!(
+            "Lume upgrade failed, restarting the installed version: {}",
+            e
+        );
+        start_lume_serve(&lume_bin_path)?;
+        return Err(e);
+    }
+    start_lume_serve(&lume_bin_path)?;
+    Ok(true)
+}
+
+/// How `lume serve` is run, whether supervised by the agent or as its own service
+fn lume_serve_spec(lume_binary: &Path) -> ServeSpec {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    let log_dir = PathBuf::from(&home_dir).join(".lume/logs");
+    fs::create_dir_all(&log_dir).unwrap_or_else(|e| {
+        warn!("Could not create log directory: {}", e);
+    });
+    ServeSpec {
+        name: "lume serve",
+        binary: lume_binary.to_path_buf(),
+        args: std::iter::once("serve".to_string())
+            .chain(provider_auth::serve_tls_args())
+            .collect(),
+        env: provider_auth::serve_env(LUME_TOKEN_ENV),
+        stdout_log: log_dir.join("lume-stdout.log"),
+        stderr_log: log_dir.join("lume-stderr.log"),
+    }
+}
+
+/// Start `lume serve` in the background unless it is already running
+fn start_lume_serve(lume_bin_path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if agent_config().provider.service {
+        ensure_provider_service(&lume_serve_spec(lume_bin_path))?;
+        // Give lume some time to start
+        thread::sleep(Duration::from_secs(2));
+        return Ok(());
+    }
+
+    if agent_config().provider.supervise {
+        if is_lume_running() && !supervisor::is_supervised() {
+            info!("Lume server is already running outside the agent, leaving it as is");
+            return Ok(());
+        }
+
+        let spec = lume_serve_spec(lume_bin_path);
+        info!(
+            "Lume logs available at {:?}",
+            spec.stderr_log.parent().unwrap_or(Path::new("."))
+        );
+        supervisor::supervise(spec);
+
+        // Give lume some time to start
+        thread::sleep(Duration::from_secs(2));
+        return Ok(());
+    }
+
     // Check if lume is already running
     if is_lume_running() {
         info!("Lume is already running");
@@ -242,8 +439,10 @@ fn download_and_run_lume_internal() -> Result<(), Box<dyn std::error::Error + Se
             fs::File::create("/dev/null").expect("Failed to open /dev/null")
         });
 
-        let child = Command::new(&lume_bin_path)
+        let mut child = Command::new(lume_bin_path)
             .arg("serve")
+            .args(provider_auth::serve_tls_args())
+            .envs(provider_auth::serve_env(LUME_TOKEN_ENV))
             .stdout(Stdio::from(stdout_file))
             .stderr(Stdio::from(stderr_file))
             .spawn()?;
@@ -258,13 +457,7 @@ fn download_and_run_lume_internal() -> Result<(), Box<dyn std::error::Error + Se
         thread::sleep(Duration::from_secs(2));
 
         // Check if the process is still running
-        let is_running = Command::new("ps")
-            .arg("-p")
-            .arg(child.id().to_string())
-            .stdout(Stdio::null())
-            .status()
-            .map(|status| status.success())
-            .unwrap_or(false);
+        let is_running = matches!(child.try_wait(), Ok(None));
 
         if !is_running {
             warn!(
@@ -275,3 +468,18 @@ fn download_and_run_lume_internal() -> Result<(), Box<dyn std::error::Error + Se
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_release_asset_urls() {
+        let urls = release_asset_urls("0.2.22", "arm64");
+        assert_eq!(
+            urls[0],
+            "https://github.com/trycua/cua/releases/download/lume-v0.2.22/lume-0.2.22-darwin-arm64.tar.gz"
+        );
+        assert!(urls.last().unwrap().ends_with("/lume-v0.2.22/lume.tar.gz"));
+    }
+}
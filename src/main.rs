@@ -1,30 +1,134 @@
+mod address_check;
+mod backpressure;
+mod bench;
+mod boot_ramp;
+mod build_info;
+mod cadence;
+mod cancel;
+mod capacity;
+mod chaos;
+mod commands;
+mod config;
+mod connectivity;
+mod console;
+mod cpu_pinning;
+mod crash;
+mod deletion_queue;
+mod diagnostics;
+mod disk;
+mod endpoints;
+mod events;
+mod fair_share;
+mod guest_files;
+mod guest_metrics;
+mod health;
+mod host_keys;
+mod host_tools;
+mod identity;
+mod ip_discovery;
+mod leases;
+mod lifecycle;
+mod locks;
+mod log_stream;
+mod log_target;
 mod lume;
 mod meda;
+mod migration;
+mod naming;
+mod notifiers;
+mod offline;
+mod os_detect;
+mod pool;
+mod preflight;
+mod provider_auth;
+mod provider_health;
+mod provider_service;
+mod readiness;
+mod retry;
+mod retry_budget;
+mod runner_cache;
+mod runner_logs;
+mod schedule;
+mod script_monitor;
+mod ssh;
+mod state;
+mod supervisor;
+mod temp_guard;
+mod template;
+mod template_failure;
+mod timing;
+mod tool_cache;
+mod tunnels;
+mod units;
+mod upgrade;
+mod usage;
+mod validation;
 mod vm_provision;
 
+use crate::backpressure::{Backpressure, Load};
+use crate::bench::{phase_stats, run_benchmark, BenchmarkResult, ProvisionRun};
+use crate::cadence::Cadence;
+use crate::capacity::Capacity;
+use crate::commands::{CommandRequest, CommandResponse};
+use crate::config::{
+    agent_config, parse_timeout_override, set_agent_config, AgentConfig, QosLimits,
+};
+use crate::console::chatty;
+use crate::deletion_queue::{clear_deletion, due_deletions, is_pending_deletion, queue_deletion};
+use crate::disk::{available_bytes, storage_usage, vm_storage_dir, DiskUsage};
+use crate::events::{AgentEvent, EventRecord};
+use crate::guest_metrics::{sample_runners, RunnerMetrics};
+use crate::health::{check_runners, RunnerHealth};
+use crate::ip_discovery::wait_for_meda_ip;
+use crate::leases::{HeldLeases, LeaseOutcome, LeaseResponse};
+use crate::lifecycle::{interrupted, is_deleted, runner_state, transition, RunnerState, Stage};
+use crate::locks::{lock_runner, lock_template, try_lock_runner, use_template};
+use crate::log_stream::{drain_log_lines, init_log_stream, stream_output, LogLine};
+use crate::log_target::LogTarget;
 use crate::lume::client::LumeClient;
+use crate::lume::errors::LumeError;
+use crate::lume::freshness::TemplateRebuildRequest;
 use crate::lume::setup::cleanup_log_files as cleanup_lume_logs;
 use crate::lume::{
     check_template_exists, create_template, find_matching_template, generate_template_name,
+    validate_template,
 };
 use crate::meda::client::MedaClient;
+use crate::meda::errors::MedaError;
+use crate::meda::images::prepare_image;
 use crate::meda::setup::cleanup_log_files as cleanup_meda_logs;
-use crate::vm_provision::run_script_on_vm;
-use clap::Parser;
+use crate::offline::enqueue_report;
+use crate::os_detect::resolve_runner_os;
+use crate::pool::PoolSpec;
+use crate::retry_budget::RetryDecision;
+use crate::runner_logs::{cleanup_runner_logs, save_result, save_script};
+use crate::schedule::{current_quiet_window, parse_quiet_window, QuietWindow};
+use crate::script_monitor::ScriptStatus;
+use crate::state::{script_hash, StateStore, TemplateFallback};
+use crate::template::render;
+use crate::template_failure::{TemplateError, TemplateStage};
+use crate::timing::{measure_phases, record_phase, Phase, PhaseTimings};
+use crate::tool_cache::preseed_tool_cache;
+use crate::tunnels::{Tunnel, TunnelRequest};
+use crate::units::{DiskSize, Memory};
+use crate::usage::summarize;
+use crate::validation::validate_runner;
+use crate::vm_provision::{run_script_on_vm, upload_script, RemoteScript};
+use clap::{Parser, Subcommand, ValueEnum};
 use log::{debug, error, info, warn};
 use reqwest::{Client, Error};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
 use std::env;
-use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::Command as StdCommand;
 use std::sync::Arc;
 use std::time::SystemTime;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
 use tokio::sync::Semaphore;
 use tokio::task::JoinSet;
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, sleep_until, Duration};
 use uuid::Uuid;
 
 const CIRUN_BANNER: &str = r#"
@@ -38,24 +142,48 @@ const CIRUN_BANNER: &str = r#"
 
 // Command line arguments
 #[derive(Parser, Debug)]
-#[command(version, about = "Cirun Agent", long_about = None)]
+#[command(version, about = "Cirun Agent", long_about = None, subcommand_negates_reqs = true)]
 struct Args {
     /// API token for authentication
-    #[arg(short, long, required_unless_present = "uninstall_service")]
+    #[arg(short, long, required_unless_present_any = ["uninstall_service", "show_usage"])]
     api_token: Option<String>,
 
     /// Polling interval in seconds
     #[arg(short, long, default_value_t = 5)]
     interval: u64,
 
+    /// Interval in seconds between reports of the agent's VMs to the API. VM changes are
+    /// also reported as they happen.
+    #[arg(long, default_value_t = DEFAULT_REPORT_INTERVAL_SECS, value_parser = clap::value_parser!(u64).range(1..))]
+    report_interval: u64,
+
     /// Agent ID file path (optional)
     #[arg(short = 'f', long, default_value = ".agent_id")]
     id_file: String,
 
+    /// Label describing this agent, reported to the API and kept in the agent state file.
+    /// Repeatable; when given, replaces the stored labels.
+    #[arg(long = "label", value_name = "LABEL")]
+    labels: Vec<String>,
+
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
 
+    /// Skip the banner and log routine per-request lines (request IDs, response status)
+    /// at debug level only
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Plain console output for journald and log pipelines: no banner, emoji or colors
+    #[arg(long, visible_alias = "no-emoji")]
+    plain: bool,
+
+    /// Where logs are written. `syslog` and `journald` send each record with its priority
+    /// (and, for the journal, structured fields) instead of relying on stderr capture.
+    #[arg(long, value_enum, default_value_t = LogTarget::Stderr)]
+    log_target: LogTarget,
+
     /// Install cirun-agent as a system service (systemd on Linux, launchd on macOS)
     #[arg(long)]
     install_service: bool,
@@ -64,12 +192,240 @@ struct Args {
     #[arg(long)]
     uninstall_service: bool,
 
+    /// Run meda/lume as their own systemd/launchd service instead of a child of the agent,
+    /// so the VM manager survives agent restarts. Installed together with --install-service.
+    #[arg(long)]
+    provider_as_service: bool,
+
     /// Maximum number of concurrent VMs (required on macOS due to Apple Virtualization Framework limit of 2)
     #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
     max_vms: Option<u32>,
+
+    /// Interval in seconds between runner health checks (0 disables health monitoring)
+    #[arg(long, default_value_t = 300)]
+    health_check_interval: u64,
+
+    /// Restart (or re-provision) runner VMs that stop unexpectedly, up to this many times per runner
+    #[arg(long)]
+    remediation_budget: Option<u32>,
+
+    /// Maximum lifetime of a runner VM in seconds; older VMs are force-deleted
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_vm_lifetime: Option<u64>,
+
+    /// Daily window (HH:MM-HH:MM, local time) during which no new runners are provisioned.
+    /// Can be given multiple times.
+    #[arg(long = "quiet-hours", value_parser = parse_quiet_window)]
+    quiet_hours: Vec<QuietWindow>,
+
+    /// Print runner usage (VM hours and resources) for the last N days and exit
+    #[arg(long, value_name = "DAYS", num_args = 0..=1, default_missing_value = "30")]
+    show_usage: Option<i64>,
+
+    /// Interval in seconds between usage reports to the API
+    #[arg(long, default_value_t = 3600)]
+    usage_report_interval: u64,
+
+    /// Path to the agent configuration file (defaults to ~/.cirun-agent/config.toml if present)
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Override a timeout from the config file, e.g. `--timeout ssh_ready=600`. Repeatable.
+    #[arg(long = "timeout", value_name = "NAME=SECS", value_parser = parse_timeout_override)]
+    timeouts: Vec<(String, u64)>,
+
+    /// Pull lume images without reusing cached layers, overriding `[lume] pull_cache`
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Let the API delete VMs the agent did not create: VMs outside `[naming] prefix` that
+    /// belong to no tracked runner
+    #[arg(long)]
+    allow_unmanaged_delete: bool,
+
+    /// Inject provider API failures, SSH timeouts and slow pulls, for testing retries and
+    /// recovery. PROFILE is e.g. `seed=7,provider_error=0.1,ssh_timeout=0.05,slow_pull=0.2`.
+    #[arg(long, hide = true, value_name = "PROFILE", value_parser = chaos::parse_profile)]
+    chaos: Option<chaos::Profile>,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Measure clone time, boot-to-SSH time and disk throughput, and report a performance score
+    Bench {
+        /// Reference image: a meda image on Linux, an existing Lume VM or template on macOS
+        #[arg(long)]
+        image: String,
+
+        /// SSH username for the reference image
+        #[arg(long, default_value = "admin")]
+        username: String,
+
+        /// SSH password for the reference image (macOS only)
+        #[arg(long, default_value = "admin")]
+        password: String,
+    },
+    /// Run the full provisioning pipeline (image, clone, boot, SSH, script, delete) several
+    /// times and print latency percentiles per phase
+    BenchProvision {
+        /// Image to provision, as the Cirun API would send it
+        #[arg(long)]
+        image: String,
+
+        /// Number of runners to provision one after another
+        #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+        runs: u32,
+
+        /// vCPUs of each runner
+        #[arg(long, default_value_t = 2)]
+        cpu: u32,
+
+        /// Memory of each runner, e.g. 4 (GB) or 512MB
+        #[arg(long, default_value = "4")]
+        memory: Memory,
+
+        /// Disk of each runner, e.g. 20 (GB)
+        #[arg(long, default_value = "20")]
+        disk: DiskSize,
+
+        /// Script run on each runner
+        #[arg(long, default_value = "echo cirun-bench-ok")]
+        script: String,
+
+        /// SSH username for the image
+        #[arg(long, default_value = "admin")]
+        username: String,
+
+        /// SSH password for the image (macOS only)
+        #[arg(long, default_value = "admin")]
+        password: String,
+    },
+    /// Forward a local port to a runner VM's SSH port until the tunnel expires
+    Tunnel {
+        /// Name of the runner to connect to
+        #[arg(long)]
+        runner: String,
+
+        /// Tunnel lifetime in seconds (defaults to `[tunnels] default_ttl_secs`)
+        #[arg(long)]
+        ttl: Option<u64>,
+
+        /// Local port to listen on (any free port when unset)
+        #[arg(long)]
+        port: Option<u16>,
+    },
+    /// Manage lume templates (macOS)
+    Template {
+        #[command(subcommand)]
+        action: TemplateAction,
+    },
+    /// Probe every host the agent connects to (Cirun API, GitHub releases, registries,
+    /// image mirrors) and print the required egress as JSON. Exits non-zero if a required
+    /// one is unreachable.
+    ConnectivityCheck,
+    /// Check whether the VM provider is running and answering, and show the provider
+    /// health the agent last recorded. Exits non-zero if the provider does not answer.
+    Status,
+    /// Archive the agent ID file and generate a new identity, registered on the next start
+    ResetIdentity,
+    /// Write the agent identity, template manifest and runner records to a bundle, to
+    /// move the agent to a rebuilt or new host
+    ExportState {
+        /// Bundle file to write
+        file: PathBuf,
+    },
+    /// Restore a bundle written by `export-state` on this host. Stop the agent first.
+    ImportState {
+        /// Bundle file to read
+        file: PathBuf,
+
+        /// Replace an existing agent identity with a different ID (it is archived)
+        #[arg(long)]
+        force: bool,
+    },
+    /// Validate a configuration file and print the effective configuration, secrets
+    /// redacted. Exits non-zero if it has errors.
+    CheckConfig {
+        /// Configuration file (defaults to --config, then ~/.cirun-agent/config.toml)
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TemplateAction {
+    /// Recreate templates from a fresh pull of their image (pull, configure, validate,
+    /// swap in). TARGET is a template name or the image templates were built from.
+    Rebuild {
+        target: String,
+
+        /// SSH username for the template
+        #[arg(long, default_value = "admin")]
+        username: String,
+
+        /// SSH password for the template
+        #[arg(long, default_value = "admin")]
+        password: String,
+    },
 }
 
+const DEFAULT_API_URL: &str = "https://api.cirun.io/api/v1";
 const MACOS_DEFAULT_MAX_VMS: u32 = 2;
+const DEFAULT_REPORT_INTERVAL_SECS: u64 = 30;
+// VM changes within this window are sent to the API as one report
+const REPORT_DEBOUNCE: Duration = Duration::from_secs(2);
+// Poll intervals and delays the API asks for are kept within these bounds
+const MIN_SERVER_POLL_SECS: u64 = 1;
+const MAX_SERVER_POLL_SECS: u64 = 3600;
+
+// Shutdown is not held up longer than this by an unresponsive API
+const DEREGISTER_TIMEOUT_SECS: u64 = 5;
+
+// Cancelled provisioning tasks get this long to stop on shutdown
+const SHUTDOWN_GRACE_SECS: u64 = 10;
+
+const PROVISION_CANCELLED: &str = "Provisioning cancelled";
+
+/// Signals that stop the agent: SIGINT and SIGTERM on Unix, Ctrl-C elsewhere
+struct ShutdownSignals {
+    #[cfg(unix)]
+    interrupt: Signal,
+    #[cfg(unix)]
+    terminate: Signal,
+}
+
+impl ShutdownSignals {
+    fn install() -> Self {
+        ShutdownSignals {
+            #[cfg(unix)]
+            interrupt: signal(SignalKind::interrupt()).expect("Failed to install SIGINT handler"),
+            #[cfg(unix)]
+            terminate: signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler"),
+        }
+    }
+
+    /// Wait for the next shutdown signal and return its name
+    async fn recv(&mut self) -> &'static str {
+        #[cfg(unix)]
+        {
+            tokio::select! {
+                _ = self.interrupt.recv() => "SIGINT",
+                _ = self.terminate.recv() => "SIGTERM",
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+            "Ctrl-C"
+        }
+    }
+}
+
+// Retention for agent, provider and per-runner logs
+const LOG_RETENTION_DAYS: u64 = 7;
+const LOG_ROTATE_SIZE_MB: u64 = 100;
 
 // Structs for agent and API data
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -78,13 +434,55 @@ struct AgentInfo {
     hostname: String,
     os: String,
     arch: String,
+    #[serde(default)]
+    version: String,
+    #[serde(default)]
+    labels: Vec<String>,
+    #[serde(skip_deserializing)]
+    build: build_info::BuildInfo,
+    #[serde(skip_deserializing)]
+    uptime_secs: build_info::Uptime,
+    #[serde(skip_deserializing)]
+    provider: build_info::Provider,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 struct ApiResponse {
     #[serde(default)]
     runners_to_provision: Vec<RunnerToProvision>,
     runners_to_delete: Vec<RunnerToDelete>,
+    /// Debug tunnels developers asked for; repeated until the agent reports them open
+    #[serde(default)]
+    tunnels_to_open: Vec<TunnelRequest>,
+    /// IDs of tunnels to close before they expire
+    #[serde(default)]
+    tunnels_to_close: Vec<String>,
+    /// Seconds between polls the API asks for from now on; unset uses `--interval`
+    #[serde(default)]
+    poll_interval: Option<u64>,
+    /// Seconds to wait before the next poll only
+    #[serde(default)]
+    next_poll_after: Option<u64>,
+    /// Ad-hoc commands to run; repeated until their results are reported
+    #[serde(default)]
+    commands_to_run: Vec<CommandRequest>,
+    /// Templates to recreate from their source image (lume only)
+    #[serde(default)]
+    templates_to_rebuild: Vec<TemplateRebuildRequest>,
+}
+
+impl ApiResponse {
+    /// Fill poll hints the body left out from the `X-Poll-Interval` and `Retry-After` headers
+    fn apply_poll_headers(&mut self, headers: &reqwest::header::HeaderMap) {
+        let seconds = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+        };
+        self.poll_interval = self.poll_interval.or_else(|| seconds("x-poll-interval"));
+        self.next_poll_after = self.next_poll_after.or_else(|| seconds("retry-after"));
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -93,26 +491,31 @@ struct TemplateConfig {
     registry: Option<String>,
     organization: Option<String>,
     cpu: u32,
-    memory: u32,
-    disk: u32,
+    memory: Memory,
+    disk: DiskSize,
     os: String,
+    /// Download every layer again instead of reusing lume's cache
+    #[serde(default)]
+    no_cache: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct RunnerLogin {
     username: String,
     password: String,
+    #[serde(default)]
+    ssh: ssh::SshOverrides,
+    #[serde(default)]
+    script: vm_provision::ScriptExecution,
 }
 
 #[derive(Debug, Clone)]
 struct RunnerResources {
     cpu: u32,
-    memory: u32,
-    disk: u32,
-}
-
-fn default_max_retries() -> u32 {
-    3
+    memory: Memory,
+    disk: DiskSize,
+    dedicated_cpus: bool,
+    qos: QosLimits,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -120,14 +523,30 @@ struct RunnerToProvision {
     name: String,
     provision_script: String,
     image: String, // The container/VM image to use
-    os: String,    // The OS platform: "linux", "macos", or "windows"
+    #[serde(default)]
+    os: String, // The OS platform: "linux", "macos", or "windows" (detected from the image if empty)
     cpu: u32,
-    memory: u32,
+    memory: Memory,
     #[serde(default)]
-    disk: u32,
+    disk: DiskSize,
     login: RunnerLogin,
-    #[serde(default = "default_max_retries")]
-    max_retries: u32,
+    /// Files placed on the guest before the provision script runs
+    #[serde(default)]
+    files: Vec<guest_files::GuestFile>,
+    /// Consecutive failures before the agent gives up; unset uses `[retry] budget`
+    #[serde(default)]
+    max_retries: Option<u32>,
+    #[serde(default)]
+    labels: Vec<String>,
+    /// Pin the runner's vCPUs to dedicated host cores (meda only)
+    #[serde(default)]
+    dedicated_cpus: bool,
+    /// Disk and network limits, over those configured for the image (meda only)
+    #[serde(default)]
+    qos: QosLimits,
+    /// Pull the image without lume's layer cache, when a template has to be created
+    #[serde(default)]
+    no_cache: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -135,20 +554,16 @@ struct RunnerToDelete {
     name: String,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Serialize, Deserialize)]
-struct CommandResponse {
-    command: String,
-    output: String,
-    error: String,
-    agent: AgentInfo,
-}
-
 // Helper function to determine if we should use meda (Linux host) or lume (macOS host)
 fn use_meda() -> bool {
     env::consts::OS == "linux"
 }
 
+/// Run a provider process check (pgrep) off the async runtime
+async fn provider_running(check: fn() -> bool) -> bool {
+    tokio::task::spawn_blocking(check).await.unwrap_or(false)
+}
+
 /// Get the count of currently running VMs
 async fn get_running_vm_count() -> Result<usize, Box<dyn std::error::Error>> {
     if use_meda() {
@@ -162,38 +577,254 @@ async fn get_running_vm_count() -> Result<usize, Box<dyn std::error::Error>> {
     }
 }
 
+/// Disk of a meda VM. The host space comes from its files; the size the guest sees is what
+/// meda reports, or else the apparent size of those files. Zero when neither is known,
+/// e.g. for VMs on remote endpoints whose meda does not report sizes.
+fn meda_disk_usage(vm: &meda::models::VmInfo) -> DiskUsage {
+    let stored = storage_usage(&vm_storage_dir(&vm.name)).unwrap_or_default();
+    DiskUsage {
+        allocated: stored.allocated,
+        total: vm.disk.unwrap_or(stored.total),
+    }
+}
+
+/// Check whether a VM with the given name exists on the local provider
+async fn runner_vm_exists(vm_name: &str) -> bool {
+    if use_meda() {
+        match MedaClient::new() {
+            Ok(meda) => meda.get_vm(vm_name).await.is_ok(),
+            Err(_) => false,
+        }
+    } else {
+        match LumeClient::new() {
+            Ok(lume) => lume.get_vm(vm_name).await.is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Start a stopped runner VM again on the local provider
+async fn restart_runner_vm(runner_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let runner_name = &pool::vm_name(runner_name);
+    boot_ramp::wait_for_slot(runner_name).await;
+    if use_meda() {
+        let meda = MedaClient::new()?;
+        meda.start_vm(runner_name).await?;
+    } else {
+        let lume = LumeClient::new()?;
+        let run_config = lume::RunConfig {
+            no_display: Some(true),
+            shared_directories: None,
+            recovery_mode: None,
+        };
+        lume.run_vm(runner_name, Some(run_config)).await?;
+    }
+    Ok(())
+}
+
 /// Result of a single runner provisioning attempt
 struct ProvisionResult {
     runner_name: String,
     outcome: Result<(), String>,
+    timings: PhaseTimings,
+    /// Lifecycle stage the runner failed in, if it failed
+    failed_stage: Option<Stage>,
+    /// Diagnostics bundle written for a failure
+    diagnostics: Option<PathBuf>,
+    /// Stopped by a delete or shutdown rather than failed; not reported as a failure
+    cancelled: bool,
+}
+
+/// Variables substituted into `{{ name }}` placeholders of the provision script.
+/// Built-in runner values take precedence over host-provided config variables.
+fn script_variables(runner: &RunnerToProvision, agent: &AgentInfo) -> HashMap<String, String> {
+    let mut variables = agent_config().variables.clone();
+    variables.extend([
+        ("runner_name".to_string(), runner.name.clone()),
+        ("runner_labels".to_string(), runner.labels.join(",")),
+        ("image".to_string(), runner.image.clone()),
+        ("cpu".to_string(), runner.cpu.to_string()),
+        ("memory".to_string(), runner.memory.as_gb().to_string()),
+        ("disk".to_string(), runner.disk.as_gb().to_string()),
+        ("agent_id".to_string(), agent.id.clone()),
+        ("agent_hostname".to_string(), agent.hostname.clone()),
+    ]);
+    variables
 }
 
 /// Provision a single runner in its own task (standalone, no &self needed).
 /// Acquires a semaphore permit to enforce concurrency bounds.
 async fn provision_single_runner(
     runner: RunnerToProvision,
+    agent: AgentInfo,
     semaphore: Arc<Semaphore>,
 ) -> ProvisionResult {
-    let _permit = semaphore.acquire().await.expect("semaphore closed");
+    let runner_name = runner.name.clone();
+    // Registered before waiting for a permit, so a delete also cancels a queued runner
+    let cancellation = cancel::runner(&runner_name);
+    let Some((_permit, _runner_lock)) = cancellation
+        .run(async {
+            let permit = semaphore.acquire().await.expect("semaphore closed");
+            // Hold the runner lock for the whole provisioning so no delete or restart races with it
+            (permit, lock_runner(&runner_name).await)
+        })
+        .await
+    else {
+        info!(
+            "Provisioning of '{}' cancelled before it started",
+            runner_name
+        );
+        return ProvisionResult {
+            runner_name,
+            outcome: Err(PROVISION_CANCELLED.to_string()),
+            timings: PhaseTimings::default(),
+            failed_stage: None,
+            diagnostics: None,
+            cancelled: true,
+        };
+    };
+
+    let agent_hostname = agent.hostname.clone();
+    events::publish(AgentEvent::ProvisionStarted {
+        runner_name: runner_name.clone(),
+    });
+    let endpoint = endpoints::endpoint_for_runner(&runner_name);
+    let (outcome, timings) = measure_phases(endpoints::on_endpoint(
+        endpoint,
+        cancellation.run(provision_runner(runner, agent)),
+    ))
+    .await;
+    info!("Provisioning phases for '{}': {}", runner_name, timings);
+    StateStore::new().record_phase_timings(&runner_name, &timings);
+
+    let Some(outcome) = outcome else {
+        let outcome = Err(PROVISION_CANCELLED.to_string());
+        save_result(&runner_name, &outcome, &timings);
+        if cancel::shutting_down() {
+            // Left as it is; recovery on the next start cleans up interrupted runners
+            info!("Provisioning of '{}' interrupted by shutdown", runner_name);
+        } else {
+            info!(
+                "Provisioning of '{}' cancelled, removing its VM",
+                runner_name
+            );
+            if let Err(e) =
+                endpoints::on_endpoint(endpoint, CirunClient::cleanup_failed_runner(&runner_name))
+                    .await
+            {
+                warn!(
+                    "Failed to clean up cancelled runner '{}': {}",
+                    runner_name, e
+                );
+            }
+        }
+        return ProvisionResult {
+            runner_name,
+            outcome,
+            timings,
+            failed_stage: None,
+            diagnostics: None,
+            cancelled: true,
+        };
+    };
+    save_result(&runner_name, &outcome, &timings);
+
+    let mut diagnostics = None;
+    let failed_stage = match &outcome {
+        Ok(()) => {
+            let _ = transition(&runner_name, RunnerState::Ready);
+            events::publish(AgentEvent::VmReady {
+                runner_name: runner_name.clone(),
+            });
+            None
+        }
+        Err(error) => {
+            let stage = lifecycle::fail(&runner_name);
+            events::publish(AgentEvent::ProvisionFailed {
+                runner_name: runner_name.clone(),
+                stage,
+                error: error.clone(),
+            });
+            diagnostics =
+                diagnostics::bundle_failure(&runner_name, error, stage, &timings, &agent_hostname);
+            Some(stage)
+        }
+    };
 
+    ProvisionResult {
+        runner_name,
+        outcome,
+        timings,
+        failed_stage,
+        diagnostics,
+        cancelled: false,
+    }
+}
+
+async fn provision_runner(mut runner: RunnerToProvision, agent: AgentInfo) -> Result<(), String> {
+    transition(&runner.name, RunnerState::Requested)?;
     info!(
-        "Processing runner: {} (image: {}, os: {}, cpu: {}, mem: {}GB, disk: {}GB)",
+        "Processing runner: {} (image: {}, os: {}, cpu: {}, mem: {}, disk: {})",
         runner.name, runner.image, runner.os, runner.cpu, runner.memory, runner.disk
     );
 
+    let mut variables = script_variables(&runner, &agent);
+    if let Some(cached) = runner_cache::runner_for(&runner.os).await {
+        variables.extend(cached.variables());
+        runner.files.push(cached.file);
+    }
+    runner.provision_script = render(&runner.provision_script, &variables);
+    save_script(&runner.name, &runner.provision_script);
+
+    // Skip re-running the script if it already completed for this runner (e.g. the agent
+    // restarted after provisioning but before reporting the VM)
+    let state = StateStore::new();
+    let provision_hash = script_hash(&runner.provision_script);
+    if state.is_provisioned(&runner.name, &provision_hash) {
+        if runner_vm_exists(&pool::vm_name(&runner.name)).await {
+            info!(
+                "Runner '{}' was already provisioned with this script. Skipping re-execution.",
+                runner.name
+            );
+            return Ok(());
+        }
+        info!(
+            "Runner '{}' has a stale provisioned marker but no VM. Provisioning again.",
+            runner.name
+        );
+        state.clear_runner(&runner.name);
+    }
+    state.record_runner_created(&runner.name, runner.cpu, runner.memory, runner.disk);
+    let provision_start = std::time::Instant::now();
+
+    let lookup_start = std::time::Instant::now();
+
+    // Map the API image name to a locally configured image, if an alias exists
+    let source_image = agent_config().resolve_image(&runner.image, use_meda());
+
     // Parse registry from image name
     let (registry, image) =
-        if runner.image.contains('.') && runner.image.split('/').next().unwrap().contains('.') {
-            let parts: Vec<&str> = runner.image.splitn(2, '/').collect();
+        if source_image.contains('.') && source_image.split('/').next().unwrap().contains('.') {
+            let parts: Vec<&str> = source_image.splitn(2, '/').collect();
             if parts.len() == 2 {
                 (Some(parts[0].to_string()), parts[1].to_string())
             } else {
-                (Some("ghcr.io".to_string()), runner.image.clone())
+                (Some("ghcr.io".to_string()), source_image.clone())
             }
         } else {
-            (Some("ghcr.io".to_string()), runner.image.clone())
+            (Some("ghcr.io".to_string()), source_image.clone())
         };
 
+    // Prefer the API-provided OS; otherwise detect it from the image, reporting ambiguous cases
+    let os = match resolve_runner_os(&runner.os, &source_image).await {
+        Ok(detected) if runner.os.trim().is_empty() => detected.to_string(),
+        Ok(_) => runner.os.clone(),
+        Err(e) => {
+            error!("{}", e);
+            return Err(e);
+        }
+    };
+
     let template_config = TemplateConfig {
         image,
         registry,
@@ -201,24 +832,51 @@ async fn provision_single_runner(
         cpu: runner.cpu,
         memory: runner.memory,
         disk: runner.disk,
-        os: runner.os.clone(),
+        os,
+        no_cache: runner.no_cache,
     };
 
     // Resolve template: meda uses image directly, lume uses template matching
-    let template_name = if use_meda() {
-        info!(
-            "Using meda on Linux - using image name directly: {}",
-            runner.image
-        );
-        Some(runner.image.clone())
+    let template_name: Result<String, String> = if use_meda() {
+        if let Some(image_source) = agent_config().image_source(&runner.image) {
+            // Concurrent runners share one download/import of the same image
+            let _template_lock = lock_template(&source_image).await;
+            let _pull = fair_share::pulling(&runner.image);
+            match prepare_image(&source_image, image_source).await {
+                Ok(prepared) => {
+                    info!("Using meda image '{}' from configured source", prepared);
+                    Ok(prepared)
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to prepare image {} ({}): {}",
+                        source_image, e.stage, e
+                    );
+                    template_failure::report(&runner.name, &runner.image, &source_image, &e);
+                    Err(format!("Image preparation failed: {}", e))
+                }
+            }
+        } else {
+            info!(
+                "Using meda on Linux - using image name directly: {}",
+                source_image
+            );
+            Ok(source_image.clone())
+        }
+    } else if source_image != runner.image && check_template_exists(&source_image).await {
+        // An alias may point straight at a local VM or template to clone
+        info!("Using aliased template: {}", source_image);
+        Ok(source_image.clone())
     } else if let Some(existing_template) = find_matching_template(&template_config).await {
         info!(
             "Found existing template with matching configuration: {}",
             existing_template
         );
-        Some(existing_template)
+        Ok(existing_template)
     } else {
         let generated_name = generate_template_name(&template_config);
+        // Late arrivals wait here for the first creation to finish and then reuse its template
+        let _template_lock = lock_template(&generated_name).await;
         let template_exists = check_template_exists(&generated_name).await;
 
         if !template_exists {
@@ -226,35 +884,61 @@ async fn provision_single_runner(
                 "No matching template found. Creating new template '{}' from image '{}'",
                 generated_name, template_config.image
             );
-            match create_template(&template_config, &generated_name).await {
+            let _pull = fair_share::pulling(&runner.image);
+            match create_template(&template_config, &generated_name, &runner.login).await {
                 Ok(_) => {
                     info!("Successfully created template: {}", generated_name);
-                    Some(generated_name)
+                    Ok(generated_name)
                 }
                 Err(e) => {
-                    error!("Failed to create template {}: {}", generated_name, e);
-                    return ProvisionResult {
-                        runner_name: runner.name.clone(),
-                        outcome: Err(format!("Template creation failed: {}", e)),
-                    };
+                    error!(
+                        "Failed to create template {} ({}): {}",
+                        generated_name, e.stage, e
+                    );
+                    template_failure::report(&runner.name, &runner.image, &generated_name, &e);
+                    Err(format!("Template creation failed: {}", e))
                 }
             }
         } else {
             info!("Using existing template: {}", generated_name);
-            Some(generated_name)
+            Ok(generated_name)
         }
     };
 
-    let template_name = match template_name {
-        Some(t) => t,
-        None => {
-            return ProvisionResult {
-                runner_name: runner.name.clone(),
-                outcome: Err("No template available".to_string()),
-            };
-        }
+    let (template_name, fell_back) = match template_name {
+        Ok(template_name) => (template_name, false),
+        Err(e) => (
+            fallback_template(&runner.name, &runner.image, &template_config.os, e).await?,
+            true,
+        ),
     };
 
+    // Unvalidated templates are boot tested once before any runner is cloned from them
+    if !use_meda() && !state.is_template_validated(&template_name) {
+        let _template_lock = lock_template(&template_name).await;
+        // Another runner may have validated it while we waited for the lock
+        if state.is_template_validated(&template_name) {
+            info!(
+                "Template '{}' was validated by another runner",
+                template_name
+            );
+        } else if let Err(e) = validate_template(&template_name, &runner.login).await {
+            error!("Template {} failed validation: {}", template_name, e);
+            template_failure::report(
+                &runner.name,
+                &runner.image,
+                &template_name,
+                &TemplateError::new(TemplateStage::Validate, &e),
+            );
+            return Err(format!("Template validation failed: {}", e));
+        }
+    }
+    record_phase(Phase::TemplateLookup, lookup_start.elapsed());
+    // A fallback template was not built from this runner's image, so it is not tracked
+    if !use_meda() && !fell_back {
+        lume::freshness::template_used(&template_name, &template_config, &runner.login);
+    }
+
     info!(
         "Provisioning runner '{}' with template '{}'",
         runner.name, template_name
@@ -264,26 +948,59 @@ async fn provision_single_runner(
         cpu: runner.cpu,
         memory: runner.memory,
         disk: runner.disk,
+        dedicated_cpus: runner.dedicated_cpus,
+        qos: agent_config().qos_limits(&runner.image, runner.qos),
+    };
+
+    // A pooled VM cloned earlier from the same template skips the clone entirely. Pooled
+    // VMs run unthrottled, so meda runners with QoS limits always get a VM of their own.
+    let pooled_vm = if pool::enabled() && (!use_meda() || resources.qos.is_unlimited()) {
+        pool::claim(
+            &runner.name,
+            PoolSpec {
+                template: template_name.clone(),
+                cpu: runner.cpu,
+                memory: runner.memory,
+                disk: runner.disk,
+            },
+        )
+    } else {
+        None
     };
+    if let Some(vm_name) = &pooled_vm {
+        info!(
+            "Reusing pooled VM '{}' for runner '{}'",
+            vm_name, runner.name
+        );
+    }
+    let vm_name = pooled_vm.unwrap_or_else(|| naming::runner_vm_name(&runner.name));
 
     // Dispatch to meda or lume provisioning
     let result = if use_meda() {
         do_provision_meda(
             &runner.name,
+            &vm_name,
             &runner.provision_script,
+            &runner.files,
             &template_name,
             &runner.login,
             &resources,
         )
         .await
     } else {
-        do_provision_lume(
-            &runner.name,
-            &runner.provision_script,
-            &template_name,
-            &runner.login,
-        )
-        .await
+        if runner.dedicated_cpus {
+            warn!(
+                "Runner '{}' asked for dedicated CPUs, which lume cannot pin; provisioning unpinned",
+                runner.name
+            );
+        }
+        if !resources.qos.is_unlimited() {
+            warn!(
+                "Runner '{}' has QoS limits, which lume cannot enforce; provisioning unthrottled",
+                runner.name
+            );
+        }
+        do_provision_lume(&runner, &vm_name, &template_name).await
     };
 
     match result {
@@ -292,10 +1009,9 @@ async fn provision_single_runner(
                 "Successfully provisioned runner: {} using template {}",
                 runner.name, template_name
             );
-            ProvisionResult {
-                runner_name: runner.name.clone(),
-                outcome: Ok(()),
-            }
+            state.mark_provisioned(&runner.name, &provision_hash);
+            state.record_provision_duration(&runner.name, provision_start.elapsed().as_secs());
+            Ok(())
         }
         Err(e) => {
             let error_msg = e.to_string();
@@ -303,18 +1019,57 @@ async fn provision_single_runner(
                 "Failed to provision runner {} using template {}: {}",
                 runner.name, template_name, error_msg
             );
-            ProvisionResult {
-                runner_name: runner.name.clone(),
-                outcome: Err(error_msg),
-            }
+            Err(error_msg)
         }
     }
 }
 
+/// The `[fallback_templates]` template for `os`, used when the runner's own template or
+/// image could not be prepared (`error`). Its use is recorded and published, so the runner
+/// is not mistaken for what was requested. Without one, `error` is returned.
+async fn fallback_template(
+    runner_name: &str,
+    image: &str,
+    os: &str,
+    error: String,
+) -> Result<String, String> {
+    let Some(template) = agent_config().fallback_templates.for_os(os) else {
+        return Err(error);
+    };
+    if !use_meda() && !check_template_exists(template).await {
+        error!(
+            "Fallback template '{}' for {} runners does not exist",
+            template, os
+        );
+        return Err(error);
+    }
+    warn!(
+        "Provisioning runner '{}' from fallback template '{}' instead of image '{}': {}",
+        runner_name, template, image, error
+    );
+    StateStore::new().record_template_fallback(
+        runner_name,
+        TemplateFallback {
+            template: template.to_string(),
+            image: image.to_string(),
+            error: error.clone(),
+        },
+    );
+    events::publish(AgentEvent::TemplateFallback {
+        runner_name: runner_name.to_string(),
+        image: image.to_string(),
+        template: template.to_string(),
+        error,
+    });
+    Ok(template.to_string())
+}
+
 /// Free-function version of meda provisioning (no &self needed)
 async fn do_provision_meda(
     runner_name: &str,
+    vm_name: &str,
     provision_script: &str,
+    files: &[guest_files::GuestFile],
     image: &str,
     runner_login: &RunnerLogin,
     resources: &RunnerResources,
@@ -323,8 +1078,9 @@ async fn do_provision_meda(
 
     let meda = MedaClient::new().map_err(|e| format!("Failed to initialize Meda client: {e}"))?;
 
-    match meda.get_vm(runner_name).await {
+    match meda.get_vm(vm_name).await {
         Ok(vm_info) => {
+            let _ = transition(runner_name, RunnerState::Booting);
             if vm_info.state == "running" {
                 info!(
                     "VM '{}' already exists and is running. Skipping creation.",
@@ -335,9 +1091,12 @@ async fn do_provision_meda(
                     "VM '{}' exists but is not running. Starting it...",
                     runner_name
                 );
-                meda.start_vm(runner_name)
+                boot_ramp::wait_for_slot(vm_name).await;
+                let boot_start = std::time::Instant::now();
+                meda.start_vm(vm_name)
                     .await
                     .map_err(|e| format!("Failed to start VM '{}': {e}", runner_name))?;
+                record_phase(Phase::Boot, boot_start.elapsed());
             }
         }
         Err(_) => {
@@ -345,15 +1104,26 @@ async fn do_provision_meda(
                 "VM '{}' does not exist. Creating from image '{}'...",
                 runner_name, image
             );
+            let pinning =
+                cpu_pinning::allocate(runner_name, resources.cpu, resources.dedicated_cpus)?;
             let run_request = VmRunRequest {
                 image: image.to_string(),
-                name: Some(runner_name.to_string()),
-                memory: Some(format!("{}G", resources.memory)),
+                name: Some(vm_name.to_string()),
+                memory: Some(resources.memory.to_meda()),
                 cpus: Some(resources.cpu),
-                disk_size: Some(format!("{}G", resources.disk)),
+                disk_size: Some(resources.disk.to_meda()),
+                cpu_affinity: pinning.as_ref().map(|pinning| pinning.cores.clone()),
+                numa_node: pinning.and_then(|pinning| pinning.numa_node),
+                qos: (!resources.qos.is_unlimited()).then_some(resources.qos),
             };
 
-            if let Err(err_msg) = meda.run_vm(run_request).await.map_err(|e| {
+            // meda creates and boots the VM in one call, so this is recorded as the clone phase
+            let _ = transition(runner_name, RunnerState::Cloning);
+            boot_ramp::wait_for_slot(vm_name).await;
+            let clone_start = std::time::Instant::now();
+            let run_result = meda.run_vm(run_request).await;
+            record_phase(Phase::Clone, clone_start.elapsed());
+            if let Err(err_msg) = run_result.map_err(|e| {
                 format!(
                     "Failed to create and run VM from image '{}': {:?}",
                     image, e
@@ -364,15 +1134,15 @@ async fn do_provision_meda(
                 return Err(err_msg);
             }
             info!("VM '{}' created and started successfully", runner_name);
+            let _ = transition(runner_name, RunnerState::Booting);
         }
     }
 
     info!("Waiting for VM '{}' to get an IP address...", runner_name);
-    let ip_address = match meda
-        .wait_for_vm_ip(runner_name, 300)
-        .await
-        .map_err(|e| format!("Failed to get VM IP address: {:?}", e))
-    {
+    let ip_wait_start = std::time::Instant::now();
+    let ip_result = wait_for_meda_ip(&meda, vm_name, agent_config().timeouts.ip_wait_secs).await;
+    record_phase(Phase::IpWait, ip_wait_start.elapsed());
+    let ip_address = match ip_result.map_err(|e| format!("Failed to get VM IP address: {:?}", e)) {
         Ok(ip) => ip,
         Err(err_msg) => {
             error!("{}", err_msg);
@@ -386,9 +1156,10 @@ async fn do_provision_meda(
 
     match run_script_on_vm_meda(
         &meda,
-        runner_name,
+        vm_name,
         &ip_address,
         provision_script,
+        files,
         runner_login,
         true,
     )
@@ -408,16 +1179,47 @@ async fn do_provision_meda(
     }
 }
 
+/// Lume states a VM leaves on its own, e.g. while it is cloned or booted
+const TRANSITIONAL_VM_STATES: &[&str] = &["provisioning", "starting", "stopping", "pulling"];
+/// Pause between checks of a VM that is changing state
+const VM_SETTLE_POLL_SECS: u64 = 5;
+
+/// What to do with a runner VM that already exists when the runner is provisioned
+#[derive(Debug, PartialEq)]
+enum ExistingVm {
+    /// Stopped, e.g. a fresh clone; boot and provision it
+    Provision,
+    /// Changing state on its own; look again once it settles
+    Settling,
+    /// Running a provision script this agent launched earlier, which is followed instead
+    Attach,
+    /// Running, or in an unknown state, without a provision script from this agent
+    Conflict,
+}
+
+fn existing_vm_action(vm_state: &str, script_status: Option<ScriptStatus>) -> ExistingVm {
+    match vm_state {
+        "stopped" => ExistingVm::Provision,
+        state if TRANSITIONAL_VM_STATES.contains(&state) => ExistingVm::Settling,
+        "running" => match script_status {
+            Some(ScriptStatus::Running | ScriptStatus::Succeeded) => ExistingVm::Attach,
+            _ => ExistingVm::Conflict,
+        },
+        _ => ExistingVm::Conflict,
+    }
+}
+
 /// Free-function version of lume provisioning (no &self needed)
 async fn do_provision_lume(
-    runner_name: &str,
-    provision_script: &str,
+    runner: &RunnerToProvision,
+    vm_name: &str,
     template_name: &str,
-    runner_login: &RunnerLogin,
 ) -> Result<(), String> {
+    let runner_name = runner.name.as_str();
+    let disk = runner.disk;
     let lume = LumeClient::new().map_err(|e| format!("Failed to initialize Lume client: {e}"))?;
 
-    let vm_result = lume.get_vm(runner_name).await;
+    let vm_result = lume.get_vm(vm_name).await;
     let vm_exists = vm_result.is_ok();
 
     let vm = if vm_exists {
@@ -428,6 +1230,8 @@ async fn do_provision_lume(
             runner_name, template_name
         );
 
+        // Held until the clone is done, so a template rebuild can't swap it out mid-copy
+        let _template_use = use_template(template_name).await;
         let template_check = lume.get_vm(template_name).await.map_err(|e| {
             format!(
                 "Template '{}' not found: {:?}. Cannot provision runner.",
@@ -436,22 +1240,23 @@ async fn do_provision_lume(
         });
         template_check?;
 
-        let clone_result = lume
-            .clone_vm(template_name, runner_name)
-            .await
-            .map_err(|e| {
-                format!(
-                    "Failed to clone VM from template '{}': {:?}",
-                    template_name, e
-                )
-            });
+        let _ = transition(runner_name, RunnerState::Cloning);
+        let clone_start = std::time::Instant::now();
+        let clone_result = lume.clone_vm(template_name, vm_name).await;
+        record_phase(Phase::Clone, clone_start.elapsed());
+        let clone_result = clone_result.map_err(|e| {
+            format!(
+                "Failed to clone VM from template '{}': {:?}",
+                template_name, e
+            )
+        });
         match clone_result {
             Ok(_) => {
                 info!(
                     "VM '{}' cloned successfully from template '{}'",
                     runner_name, template_name
                 );
-                lume.get_vm(runner_name)
+                lume.get_vm(vm_name)
                     .await
                     .map_err(|e| format!("Failed to get VM after clone: {:?}", e))?
             }
@@ -465,26 +1270,84 @@ async fn do_provision_lume(
 
     info!("VM '{}' is now available", runner_name);
 
-    if vm.state != "stopped" {
+    let script_status = StateStore::new()
+        .script_statuses()
+        .get(runner_name)
+        .copied();
+    let settle_secs = agent_config().timeouts.vm_settle_secs;
+    let settle_deadline = std::time::Instant::now() + Duration::from_secs(settle_secs);
+    let mut vm = vm;
+    loop {
+        match existing_vm_action(&vm.state, script_status) {
+            ExistingVm::Provision => break,
+            ExistingVm::Attach => {
+                info!(
+                    "VM '{}' is already running its provision script ({:?}); following it instead of starting over",
+                    runner_name, script_status
+                );
+                if script_status == Some(ScriptStatus::Succeeded) {
+                    return Ok(());
+                }
+                return script_monitor::resume(
+                    runner_name,
+                    vm.ip_address.as_deref(),
+                    &runner.login,
+                );
+            }
+            ExistingVm::Conflict => {
+                let err_msg = format!(
+                    "VM '{}' is already {} but has no provision script from this agent; not provisioning over it",
+                    runner_name, vm.state
+                );
+                error!("{}", err_msg);
+                return Err(err_msg);
+            }
+            ExistingVm::Settling if std::time::Instant::now() >= settle_deadline => {
+                return Err(format!(
+                    "VM '{}' is still {} after {}s",
+                    runner_name, vm.state, settle_secs
+                ));
+            }
+            ExistingVm::Settling => {
+                info!(
+                    "VM '{}' is {}; waiting for it to settle before provisioning",
+                    runner_name, vm.state
+                );
+                sleep(Duration::from_secs(VM_SETTLE_POLL_SECS)).await;
+                vm = lume
+                    .get_vm(vm_name)
+                    .await
+                    .map_err(|e| format!("Failed to get VM '{}': {:?}", runner_name, e))?;
+            }
+        }
+    }
+
+    // Clones get the template's disk; grow it to what the runner asked for
+    if vm.disk_size.total < disk {
         info!(
-            "VM '{}' exists and is not stopped. Skipping provisioning.",
-            runner_name
+            "Growing disk of VM '{}' from {} to {}",
+            runner_name, vm.disk_size.total, disk
         );
-        return Ok(());
+        if let Err(e) = lume.resize_disk(vm_name, disk).await {
+            let err_msg = format!("Failed to resize disk to {}: {}", disk, e);
+            error!("{}", err_msg);
+            let _ = CirunClient::cleanup_failed_runner(runner_name).await;
+            return Err(err_msg);
+        }
+        disk::grow_filesystem_on_boot(vm_name);
     }
 
-    let username = runner_login.username.clone();
-    let password = runner_login.password.clone();
-
     info!("Provisioning runner: {}", runner_name);
+    let _ = transition(runner_name, RunnerState::Booting);
 
     match run_script_on_vm(
         &lume,
-        runner_name,
-        provision_script,
-        &username,
-        &password,
-        20,
+        vm_name,
+        &runner.provision_script,
+        &runner.files,
+        agent_config().shared_directories(&runner.image),
+        &runner.login,
+        agent_config().timeouts.lume_runner_ip_wait_secs,
         true,
     )
     .await
@@ -503,82 +1366,66 @@ async fn do_provision_lume(
     }
 }
 
+/// Cirun API base URL, `CIRUN_API_URL` unless unset
+fn cirun_api_url() -> String {
+    env::var("CIRUN_API_URL").unwrap_or_else(|_| DEFAULT_API_URL.to_string())
+}
+
 // Get system hostname
 fn get_hostname() -> String {
     if let Ok(hostname) = env::var("HOSTNAME") {
         return hostname;
     }
 
-    if let Ok(output) = StdCommand::new("hostname").output() {
-        if let Ok(hostname) = String::from_utf8(output.stdout) {
-            return hostname.trim().to_string();
-        }
-    }
-
-    "unknown-host".to_string()
+    host_tools::hostname().unwrap_or_else(|| "unknown-host".to_string())
 }
 
 // Generate or retrieve a persistent agent information
 fn check_sshpass_installed() -> bool {
-    match StdCommand::new("which").arg("sshpass").output() {
-        Ok(output) => {
-            if output.status.success() {
-                info!("✅ sshpass is installed");
-                true
-            } else {
-                error!("❌ sshpass is not installed");
-                error!("VM provisioning requires sshpass for SSH authentication");
-                error!("Install it using: brew install sshpass");
-                false
-            }
-        }
-        Err(e) => {
-            warn!("Failed to check for sshpass: {}", e);
-            false
-        }
+    if host_tools::find_program("sshpass").is_some() {
+        info!("✅ sshpass is installed");
+        true
+    } else {
+        error!("❌ sshpass is not installed");
+        error!("VM provisioning requires sshpass for SSH authentication");
+        error!("Install it using: brew install sshpass");
+        false
     }
 }
 
-fn get_agent_info(id_file: &str) -> AgentInfo {
-    let id = if Path::new(id_file).exists() {
-        match fs::read_to_string(id_file) {
-            Ok(id) => {
-                let id = id.trim().to_string();
-                info!("Using existing agent ID: {}", id);
-                id
-            }
-            Err(e) => {
-                error!("Failed to read agent ID file: {}", e);
-                // Generate a new UUID v4
-                let new_id = Uuid::new_v4().to_string();
-                info!("Generated new agent ID: {}", new_id);
-
-                // Save the ID to file for persistence
-                if let Err(e) = fs::write(id_file, &new_id) {
-                    error!("Failed to write agent ID to file: {}", e);
-                }
-
-                new_id
-            }
-        }
+/// Resolve the agent ID file path, relative paths being taken from the home directory
+fn resolve_id_file(id_file: &str) -> String {
+    if Path::new(id_file).is_absolute() {
+        id_file.to_string()
     } else {
-        // Generate a new UUID v4
-        let new_id = Uuid::new_v4().to_string();
-        info!("Generated new agent ID: {}", new_id);
+        let home_dir = env::var("HOME").unwrap_or_else(|_| ".".to_string());
+        PathBuf::from(&home_dir)
+            .join(id_file)
+            .to_string_lossy()
+            .to_string()
+    }
+}
 
-        // Save the ID to file for persistence
-        if let Err(e) = fs::write(id_file, &new_id) {
-            error!("Failed to write agent ID to file: {}", e);
+async fn get_agent_info(id_file: &str, labels: &[String]) -> AgentInfo {
+    let identity = match identity::load_or_create(Path::new(id_file), labels) {
+        Ok(identity) => identity,
+        Err(e) => {
+            // Running under a throwaway ID would register a second agent with the API
+            error!("Failed to load agent state file {}: {}", id_file, e);
+            std::process::exit(1);
         }
-
-        new_id
     };
 
     AgentInfo {
-        id,
+        id: identity.id,
         hostname: get_hostname(),
         os: env::consts::OS.to_string(),
         arch: env::consts::ARCH.to_string(),
+        version: build_info::VERSION.to_string(),
+        labels: identity.labels,
+        build: build_info::BuildInfo::current(),
+        uptime_secs: build_info::Uptime,
+        provider: build_info::Provider,
     }
 }
 
@@ -588,13 +1435,54 @@ struct CirunClient {
     base_url: String,
     api_token: String,
     agent: AgentInfo,
-    retry_tracker: HashMap<String, u32>,
     /// None means no limit, Some(n) means max n concurrent VMs
     max_vms: Option<u32>,
+    /// Runners provisioned by this process, used for SSH health checks and re-provisioning
+    provisioned_runners: HashMap<String, RunnerToProvision>,
+    /// None disables automatic remediation, Some(n) allows n remediation attempts per runner
+    remediation_budget: Option<u32>,
+    remediation_tracker: HashMap<String, u32>,
+    /// Crashed runners to provision again; later polls admit them like new requests
+    reprovision_queue: HashMap<String, RunnerToProvision>,
+    /// Runners the last poll asked to delete, which are never remediated
+    last_deletions: std::collections::HashSet<String>,
+    /// None means runner VMs live until the API deletes them
+    max_vm_lifetime: Option<Duration>,
+    /// Windows during which provisioning is deferred
+    quiet_windows: Vec<QuietWindow>,
+    /// Runners already reported as deferred during the current quiet window
+    deferred_runners: std::collections::HashSet<String>,
+    /// Invalid runner requests already reported to the API
+    rejected_runners: std::collections::HashSet<String>,
+    /// Runners already reported as queued because they exceed the overcommit policy
+    capacity_queued: std::collections::HashSet<String>,
+    /// Runner leases held while this agent provisions them
+    held_leases: HeldLeases,
+    /// IDs of API commands already started, so repeats are not run twice
+    started_commands: commands::StartedCommands,
+    /// Results of finished API commands the API has not accepted yet
+    unacked_command_results: Vec<CommandResponse>,
+    /// Delete VMs the agent did not create when the API asks to
+    allow_unmanaged_delete: bool,
+    /// Runner VMs in the agent's namespace that the last status report listed, so runners
+    /// left by an agent version that did not track them can still be deleted
+    reported_runners: std::collections::HashSet<String>,
+    /// IDs of template rebuilds already started, so repeats are not run twice
+    started_rebuilds: std::collections::HashSet<String>,
+    /// Whether the last poll left the agent overloaded, sent with the next one
+    backpressure: Backpressure,
 }
 
 impl CirunClient {
-    fn new(base_url: &str, api_token: &str, agent: AgentInfo, max_vms: Option<u32>) -> Self {
+    fn new(
+        base_url: &str,
+        api_token: &str,
+        agent: AgentInfo,
+        max_vms: Option<u32>,
+        remediation_budget: Option<u32>,
+        max_vm_lifetime: Option<Duration>,
+        quiet_windows: Vec<QuietWindow>,
+    ) -> Self {
         let client = Client::builder()
             .timeout(Duration::from_secs(15))
             .connect_timeout(Duration::from_secs(10))
@@ -606,15 +1494,32 @@ impl CirunClient {
             base_url: base_url.to_string(),
             api_token: api_token.to_string(),
             agent,
-            retry_tracker: HashMap::new(),
             max_vms,
+            provisioned_runners: HashMap::new(),
+            remediation_budget,
+            remediation_tracker: HashMap::new(),
+            reprovision_queue: HashMap::new(),
+            last_deletions: std::collections::HashSet::new(),
+            max_vm_lifetime,
+            quiet_windows,
+            deferred_runners: std::collections::HashSet::new(),
+            rejected_runners: std::collections::HashSet::new(),
+            capacity_queued: std::collections::HashSet::new(),
+            held_leases: HeldLeases::default(),
+            started_commands: commands::StartedCommands::default(),
+            unacked_command_results: Vec::new(),
+            allow_unmanaged_delete: false,
+            reported_runners: std::collections::HashSet::new(),
+            started_rebuilds: std::collections::HashSet::new(),
+            backpressure: Backpressure::default(),
         }
     }
 
     // Helper method to create a request builder with common headers
     fn create_request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
         let request_id = Uuid::new_v4().to_string();
-        info!("Creating request with ID: {}", request_id);
+        chatty!("Creating request with ID: {}", request_id);
+        crash::record_request_id(&request_id);
 
         self.client
             .request(method, url)
@@ -653,13 +1558,30 @@ impl CirunClient {
         }
     }
 
-    async fn report_running_vms(&self) {
+    /// Report every runner VM to the API. Returns whether the API accepted the report.
+    async fn report_running_vms(&mut self) -> bool {
         info!("Reporting running VMs to API");
+        let mut reported = false;
+        let provision_phases = StateStore::new().provision_phases();
+        let script_statuses = StateStore::new().script_statuses();
+        let cpu_pinnings = StateStore::new().cpu_pinnings();
+        let template_fallbacks = StateStore::new().template_fallbacks();
+        let capacity = capacity::current_capacity();
+        let lifecycle_states = StateStore::new().read(|state| {
+            state
+                .lifecycle
+                .iter()
+                .map(|(name, record)| (name.clone(), record.state.to_string()))
+                .collect::<HashMap<_, _>>()
+        });
+        let placements = StateStore::new().read(|state| state.placements.clone());
+        let event_counts = events::counts();
 
         if use_meda() {
             // Use meda for Linux
             // Check if meda is running, restart if needed
-            if !meda::setup::is_meda_running() {
+            if !supervisor::is_supervised() && !provider_running(meda::setup::is_meda_running).await
+            {
                 warn!("Meda process is not running. Restarting...");
                 meda::download_and_run_meda().await;
             }
@@ -668,26 +1590,53 @@ impl CirunClient {
                 Ok(meda) => {
                     match meda.list_vms().await {
                         Ok(vms) => {
+                            let remote_vms = endpoints::list_remote(|| async {
+                                MedaClient::new()?.list_vms().await
+                            })
+                            .await;
                             // Report all cirun VMs (running or stopped) so API can sync deletion state
                             let cirun_vms: Vec<_> = vms
                                 .into_iter()
-                                .filter(|vm| vm.name.starts_with("cirun-"))
+                                .chain(remote_vms)
+                                .filter(|vm| {
+                                    naming::is_managed(&vm.name) && !pool::is_pool_vm(&vm.name)
+                                })
+                                .map(|mut vm| {
+                                    // Read from the VM's own files, so before it is renamed
+                                    let disk = meda_disk_usage(&vm);
+                                    // Claimed pooled VMs are reported under their runner's name
+                                    vm.name = pool::runner_name(&vm.name);
+                                    (vm, disk)
+                                })
                                 .collect();
+                            self.reported_runners = naming::reported_runners(
+                                cirun_vms.iter().map(|(vm, _)| vm.name.as_str()),
+                            );
                             let url = format!("{}/agent", self.base_url);
 
                             let res = self
                                 .create_request(reqwest::Method::POST, &url)
                                 .json(&json!({
                                     "agent": self.agent,
-                                    "vms": cirun_vms.iter().map(|vm| {
+                                    "vms": cirun_vms.iter().map(|(vm, disk)| {
                                         json!({
                                             "name": vm.name,
                                             "os": "linux",
                                             "cpu": vm.cpus.unwrap_or(2),
-                                            "memory": vm.memory.as_ref().and_then(|m| m.trim_end_matches("GB").trim_end_matches("G").parse::<u64>().ok()).unwrap_or(2048),
-                                            "disk_size": 0  // Meda doesn't report disk size in list
+                                            // Sizes are reported in MB; 0 when they are unknown
+                                            "memory": vm.memory.map(Memory::as_mb).unwrap_or(0),
+                                            "disk_size": disk.total.as_mb(),
+                                            "disk_allocated": disk.allocated.as_mb(),
+                                            "provision_phases": provision_phases.get(&vm.name),
+                                            "lifecycle_state": lifecycle_states.get(&vm.name),
+                                            "script_status": script_statuses.get(&vm.name),
+                                            "cpu_pinning": cpu_pinnings.get(&vm.name),
+                                            "template_fallback": template_fallbacks.get(&vm.name),
+                                            "endpoint": placements.get(&vm.name),
                                         })
-                                    }).collect::<Vec<_>>()
+                                    }).collect::<Vec<_>>(),
+                                    "capacity": capacity.as_ref().map(Capacity::report),
+                                    "event_counts": &event_counts,
                                 }))
                                 .send()
                                 .await;
@@ -695,10 +1644,11 @@ impl CirunClient {
                             match res {
                                 Ok(response) => {
                                     let status = response.status();
-                                    info!("API response status: {}", status);
+                                    chatty!("API response status: {}", status);
+                                    reported = status.is_success();
                                     if let Some(req_id) = response.headers().get("X-Request-ID") {
                                         if let Ok(id) = req_id.to_str() {
-                                            info!("Response received with request ID: {}", id);
+                                            chatty!("Response received with request ID: {}", id);
                                         }
                                     }
                                     self.handle_orphaned_runners(response).await;
@@ -714,7 +1664,8 @@ impl CirunClient {
         } else {
             // Use lume for macOS
             // Check if lume is running, restart if needed
-            if !lume::setup::is_lume_running() {
+            if !supervisor::is_supervised() && !provider_running(lume::setup::is_lume_running).await
+            {
                 warn!("Lume process is not running. Restarting...");
                 lume::download_and_run_lume().await;
             }
@@ -723,11 +1674,26 @@ impl CirunClient {
                 Ok(lume) => {
                     match lume.list_vms().await {
                         Ok(vms) => {
+                            let remote_vms = endpoints::list_remote(|| async {
+                                LumeClient::new()?.list_vms().await
+                            })
+                            .await;
                             // Report all cirun VMs (running or stopped) so API can sync deletion state
                             let cirun_vms: Vec<_> = vms
                                 .into_iter()
-                                .filter(|vm| vm.name.starts_with("cirun-"))
+                                .chain(remote_vms)
+                                .filter(|vm| {
+                                    naming::is_managed(&vm.name) && !pool::is_pool_vm(&vm.name)
+                                })
+                                .map(|mut vm| {
+                                    // Claimed pooled VMs are reported under their runner's name
+                                    vm.name = pool::runner_name(&vm.name);
+                                    vm
+                                })
                                 .collect();
+                            self.reported_runners = naming::reported_runners(
+                                cirun_vms.iter().map(|vm| vm.name.as_str()),
+                            );
                             let url = format!("{}/agent", self.base_url);
 
                             // Use the helper method instead of direct client access
@@ -740,10 +1706,19 @@ impl CirunClient {
                                             "name": vm.name,
                                             "os": vm.os,
                                             "cpu": vm.cpu,
-                                            "memory": vm.memory,
-                                            "disk_size": vm.disk_size.total
+                                            "memory": vm.memory.as_mb(),
+                                            "disk_size": vm.disk_size.total.as_mb(),
+                                            "disk_allocated": vm.disk_size.allocated.as_mb(),
+                                            "provision_phases": provision_phases.get(&vm.name),
+                                            "lifecycle_state": lifecycle_states.get(&vm.name),
+                                            "script_status": script_statuses.get(&vm.name),
+                                            "template_fallback": template_fallbacks.get(&vm.name),
+                                            "endpoint": placements.get(&vm.name),
                                         })
-                                    }).collect::<Vec<_>>()
+                                    }).collect::<Vec<_>>(),
+                                    "templates": lume::freshness::report(),
+                                    "capacity": capacity.as_ref().map(Capacity::report),
+                                    "event_counts": &event_counts,
                                 }))
                                 .send()
                                 .await;
@@ -751,10 +1726,11 @@ impl CirunClient {
                             match res {
                                 Ok(response) => {
                                     let status = response.status();
-                                    info!("API response status: {}", status);
+                                    chatty!("API response status: {}", status);
+                                    reported = status.is_success();
                                     if let Some(req_id) = response.headers().get("X-Request-ID") {
                                         if let Ok(id) = req_id.to_str() {
-                                            info!("Response received with request ID: {}", id);
+                                            chatty!("Response received with request ID: {}", id);
                                         }
                                     }
                                     self.handle_orphaned_runners(response).await;
@@ -768,24 +1744,39 @@ impl CirunClient {
                 Err(e) => error!("Failed to initialize Lume client: {:?}", e),
             }
         }
+        reported
     }
 
     /// Helper function to cleanup a failed runner VM
     async fn cleanup_failed_runner(runner_name: &str) -> Result<(), Box<dyn std::error::Error>> {
         info!("Cleaning up failed runner: {}", runner_name);
+        StateStore::new().clear_runner(runner_name);
+        // A claimed pooled VM is deleted like any other; the pool refills on later deletions
+        let vm_name = pool::vm_name(runner_name);
+        pool::forget(runner_name);
+        host_keys::forget(&vm_name);
+        ssh::close_connections(&vm_name).await;
+        let original_name = runner_name;
+        let runner_name = vm_name.as_str();
 
         if use_meda() {
             match MedaClient::new() {
-                Ok(meda) => match meda.delete_vm(runner_name).await {
-                    Ok(_) => {
-                        info!("Successfully deleted failed runner VM: {}", runner_name);
-                        Ok(())
+                Ok(meda) => {
+                    // Keep the VM's last details for the diagnostics bundle
+                    if let Ok(vm) = meda.get_vm(runner_name).await {
+                        runner_logs::save_vm_info(original_name, &json!(vm));
                     }
-                    Err(e) => {
-                        error!("Failed to delete runner VM {}: {:?}", runner_name, e);
-                        Err(e.into())
+                    match meda.delete_vm(runner_name).await {
+                        Ok(_) => {
+                            info!("Successfully deleted failed runner VM: {}", runner_name);
+                            Ok(())
+                        }
+                        Err(e) => {
+                            error!("Failed to delete runner VM {}: {:?}", runner_name, e);
+                            Err(e.into())
+                        }
                     }
-                },
+                }
                 Err(e) => {
                     error!("Failed to initialize Meda client for cleanup: {:?}", e);
                     Err(e.into())
@@ -793,16 +1784,21 @@ impl CirunClient {
             }
         } else {
             match LumeClient::new() {
-                Ok(lume) => match lume.delete_vm(runner_name).await {
-                    Ok(_) => {
-                        info!("Successfully deleted failed runner VM: {}", runner_name);
-                        Ok(())
+                Ok(lume) => {
+                    if let Ok(vm) = lume.get_vm(runner_name).await {
+                        runner_logs::save_vm_info(original_name, &json!(vm));
                     }
-                    Err(e) => {
-                        error!("Failed to delete runner VM {}: {:?}", runner_name, e);
-                        Err(e.into())
+                    match lume.delete_vm(runner_name).await {
+                        Ok(_) => {
+                            info!("Successfully deleted failed runner VM: {}", runner_name);
+                            Ok(())
+                        }
+                        Err(e) => {
+                            error!("Failed to delete runner VM {}: {:?}", runner_name, e);
+                            Err(e.into())
+                        }
                     }
-                },
+                }
                 Err(e) => {
                     error!("Failed to initialize Lume client for cleanup: {:?}", e);
                     Err(e.into())
@@ -812,6 +1808,90 @@ impl CirunClient {
     }
 
     async fn delete_runner(&self, runner_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if is_deleted(runner_name) {
+            debug!(
+                "Runner '{}' was already deleted, acknowledging without provider calls",
+                runner_name
+            );
+            return Ok(());
+        }
+
+        // A misconfigured API must not wipe VMs that other tools manage on this host
+        let vm_name = pool::vm_name(runner_name);
+        if !self.allow_unmanaged_delete
+            && !naming::is_agent_runner(runner_name)
+            && !self.reported_runners.contains(runner_name)
+        {
+            warn!(
+                "Refusing to delete VM '{}': it was not created by this agent (see --allow-unmanaged-delete)",
+                vm_name
+            );
+            return Err(format!(
+                "VM '{}' was not created by this agent; not deleting it",
+                vm_name
+            )
+            .into());
+        }
+
+        // Never delete a VM while it is being provisioned or restarted; the API will
+        // ask again on the next poll
+        let Some(_runner_lock) = try_lock_runner(runner_name) else {
+            // Stop an in-flight provisioning instead of waiting for it to finish
+            cancel::cancel_runner(runner_name);
+            return Err(format!(
+                "Runner '{}' is busy with another lifecycle operation, retrying later",
+                runner_name
+            )
+            .into());
+        };
+        let _ = transition(runner_name, RunnerState::Deleting);
+        let endpoint = endpoints::endpoint_for_runner(runner_name);
+        let free_before = match endpoint {
+            Some(_) => None,
+            None => available_bytes(&vm_storage_dir(&vm_name)).await,
+        };
+        let result = endpoints::on_endpoint(endpoint, async {
+            match self.delete_runner_vm(&vm_name).await {
+                Ok(()) => verify_vm_deleted(&vm_name, free_before)
+                    .await
+                    .map_err(Into::into),
+                Err(e) => Err(e),
+            }
+        })
+        .await;
+        match &result {
+            Ok(()) => {
+                // Instead of keeping the used VM, a fresh clone goes back into the pool
+                if let Some(spec) = pool::refill_spec(runner_name) {
+                    cancel::spawn_provider_task(pool::refill(spec, self.max_vms));
+                }
+                pool::forget(runner_name);
+                host_keys::forget(&vm_name);
+                ssh::close_connections(&vm_name).await;
+                tunnels::close_for_runner(runner_name);
+                guest_metrics::forget(runner_name);
+                StateStore::new().clear_runner(runner_name);
+                endpoints::clear_placement(runner_name);
+                clear_deletion(runner_name);
+                let _ = transition(runner_name, RunnerState::Deleted);
+                events::publish(AgentEvent::VmDeleted {
+                    runner_name: runner_name.to_string(),
+                });
+            }
+            Err(e) => {
+                // Keep trying in the background rather than waiting for the API to resend
+                queue_deletion(runner_name, &e.to_string());
+                lifecycle::fail(runner_name);
+                events::publish(AgentEvent::DeleteFailed {
+                    runner_name: runner_name.to_string(),
+                    error: e.to_string(),
+                });
+            }
+        }
+        result
+    }
+
+    async fn delete_runner_vm(&self, runner_name: &str) -> Result<(), Box<dyn std::error::Error>> {
         if use_meda() {
             match MedaClient::new() {
                 Ok(meda) => {
@@ -827,6 +1907,14 @@ impl CirunClient {
                                 Err(format!("Failed to delete VM: {:?}", e).into())
                             }
                         },
+                        // Meda being unreachable says nothing about whether the VM exists
+                        Err(MedaError::RequestError(e)) => {
+                            error!(
+                                "Meda is unreachable, cannot delete VM '{}': {}",
+                                runner_name, e
+                            );
+                            Err(format!("Meda is unreachable: {}", e).into())
+                        }
                         Err(e) => {
                             warn!(
                                 "VM '{}' not found or error retrieving VM details: {:?}",
@@ -865,6 +1953,14 @@ impl CirunClient {
                                 }
                             }
                         }
+                        // Lume being unreachable says nothing about whether the VM exists
+                        Err(LumeError::RequestError(e)) => {
+                            error!(
+                                "Lume is unreachable, cannot delete VM '{}': {}",
+                                runner_name, e
+                            );
+                            Err(format!("Lume is unreachable: {}", e).into())
+                        }
                         Err(e) => {
                             warn!(
                                 "VM '{}' not found or error retrieving VM details: {:?}",
@@ -884,33 +1980,15 @@ impl CirunClient {
         }
     }
 
-    /// Get the current retry count for a runner
-    fn get_retry_count(&self, runner_name: &str) -> u32 {
-        *self.retry_tracker.get(runner_name).unwrap_or(&0)
-    }
-
-    /// Increment the retry count for a runner and return the new count
-    fn increment_retry(&mut self, runner_name: &str) -> u32 {
-        let count = self
-            .retry_tracker
-            .entry(runner_name.to_string())
-            .or_insert(0);
-        *count += 1;
-        *count
-    }
-
-    /// Clear the retry count for a runner
-    fn clear_retry(&mut self, runner_name: &str) {
-        self.retry_tracker.remove(runner_name);
-    }
-
-    /// Check if a runner should be retried based on max_retries
-    fn should_retry(&self, runner_name: &str, max_retries: u32) -> bool {
-        self.get_retry_count(runner_name) < max_retries
-    }
-
     /// Notify the API that a runner provisioning attempt failed
-    async fn notify_provision_failure(&self, runner_name: &str, error: String, attempt: u32) {
+    async fn notify_provision_failure(
+        &self,
+        runner_name: &str,
+        error: String,
+        attempt: u32,
+        phases: Option<&PhaseTimings>,
+        stage: Option<Stage>,
+    ) {
         let url = format!("{}/agent", self.base_url);
 
         info!(
@@ -924,6 +2002,8 @@ impl CirunClient {
                 "runner_name": runner_name,
                 "error": error,
                 "attempt": attempt,
+                "phases": phases,
+                "stage": stage,
             }
         });
 
@@ -945,174 +2025,1520 @@ impl CirunClient {
             }
             Err(e) => {
                 warn!("Failed to notify API of provisioning failure: {}", e);
+                enqueue_report(request_data);
             }
         }
     }
 
-    async fn manage_runner_lifecycle(
-        &mut self,
-        provision_set: &mut JoinSet<ProvisionResult>,
-        in_flight: &mut std::collections::HashSet<String>,
-    ) -> Result<ApiResponse, Error> {
+    /// Report a template or image that could not be made available, so the user learns
+    /// the image they asked for is broken
+    async fn notify_template_creation_failed(&self, failure: &EventRecord) {
         let url = format!("{}/agent", self.base_url);
-        info!("Fetching runner provision/deletion data from: {}", url);
-
         let request_data = json!({
             "agent": self.agent,
+            "template_creation_failed": failure,
         });
 
-        // Use the helper method instead of direct client access
-        let response = self
-            .create_request(reqwest::Method::GET, &url)
+        match self
+            .create_request(reqwest::Method::POST, &url)
             .json(&request_data)
             .send()
-            .await?;
+            .await
+        {
+            Ok(response) => {
+                if response.status().is_success() {
+                    debug!("Successfully notified API of template creation failure");
+                } else {
+                    warn!(
+                        "API returned non-success status for template failure notification: {}",
+                        response.status()
+                    );
+                }
+            }
+            Err(e) => {
+                warn!("Failed to notify API of template creation failure: {}", e);
+                enqueue_report(request_data);
+            }
+        }
+    }
+
+    /// Collect the runners that should be health checked: everything with a provisioned
+    /// marker that is not currently being provisioned, paired with its login if known
+    fn runners_for_health_check(
+        &mut self,
+        in_flight: &std::collections::HashSet<String>,
+    ) -> HashMap<String, Option<RunnerLogin>> {
+        let provisioned: Vec<String> =
+            StateStore::new().read(|state| state.provisioned.keys().cloned().collect());
+        self.provisioned_runners
+            .retain(|name, _| provisioned.contains(name) || in_flight.contains(name));
+        self.remediation_tracker
+            .retain(|name, _| provisioned.contains(name) || in_flight.contains(name));
+
+        provisioned
+            .into_iter()
+            .filter(|name| !in_flight.contains(name))
+            .map(|name| {
+                let login = self
+                    .provisioned_runners
+                    .get(&name)
+                    .map(|runner| runner.login.clone());
+                (name, login)
+            })
+            .collect()
+    }
 
-        info!("Response status: {}", response.status());
-        let json: ApiResponse = response.json().await?;
+    /// Runners whose guest usage can be sampled: provisioned ones whose request, and so
+    /// login and requested size, is known
+    fn runners_for_metrics(
+        &self,
+        in_flight: &std::collections::HashSet<String>,
+    ) -> Vec<RunnerToProvision> {
+        let provisioned: Vec<String> =
+            StateStore::new().read(|state| state.provisioned.keys().cloned().collect());
+        self.provisioned_runners
+            .values()
+            .filter(|runner| {
+                provisioned.contains(&runner.name) && !in_flight.contains(&runner.name)
+            })
+            .cloned()
+            .collect()
+    }
 
-        // Handle any runners that need deletion
-        if !json.runners_to_delete.is_empty() {
-            info!(
-                "Received {} runners to delete",
-                json.runners_to_delete.len()
-            );
+    /// Whether the API still wants `runner_name`: it was not in the last poll's deletions and
+    /// is neither being deleted nor gone
+    fn still_desired(&self, runner_name: &str) -> bool {
+        !self.last_deletions.contains(runner_name)
+            && !is_pending_deletion(runner_name)
+            && !runner_state(runner_name).is_some_and(|state| state.blocks_provisioning())
+    }
 
-            for runner in &json.runners_to_delete {
-                match self.delete_runner(&runner.name).await {
-                    Ok(_) => {
-                        info!("✅ Successfully deleted runner: {}", runner.name);
-                        self.report_running_vms().await;
-                    }
+    /// Restart or re-provision runner VMs that stopped unexpectedly while the API still wants
+    /// them, within the remediation budget. Re-provisioning waits for the next poll, so it
+    /// takes a slot like any other request. Runners that exhaust the budget are reported to
+    /// the API as failed.
+    async fn remediate_crashed_runners(
+        &mut self,
+        results: &[RunnerHealth],
+        in_flight: &std::collections::HashSet<String>,
+    ) {
+        let Some(budget) = self.remediation_budget else {
+            return;
+        };
 
-                    Err(e) => error!("❌ Failed to delete runner {}: {}", runner.name, e),
-                }
+        for health in results {
+            if !matches!(health.vm_state.as_str(), "stopped" | "error") {
+                continue;
             }
-        }
-
-        // Handle runners that need provisioning
-        if !json.runners_to_provision.is_empty() {
-            info!(
-                "Received {} runners to provision",
-                json.runners_to_provision.len()
-            );
 
-            // First, handle retry-exhausted runners (notify API, skip them)
-            for runner in &json.runners_to_provision {
-                let current_attempts = self.get_retry_count(&runner.name);
-                if !self.should_retry(&runner.name, runner.max_retries) {
+            let name = &health.runner_name;
+            if in_flight.contains(name) || self.reprovision_queue.contains_key(name) {
+                continue;
+            }
+            if !self.still_desired(name) {
+                debug!(
+                    "Runner '{}' is {} but no longer wanted by the API. Not remediating.",
+                    name, health.vm_state
+                );
+                continue;
+            }
+            let Some(runner_lock) = try_lock_runner(name) else {
+                info!(
+                    "Runner '{}' is busy with another lifecycle operation. Skipping remediation.",
+                    name
+                );
+                continue;
+            };
+            let attempts = self.remediation_tracker.get(name).copied().unwrap_or(0);
+            if attempts >= budget {
+                if attempts == budget {
                     warn!(
-                        "Runner '{}' has exceeded max retries ({}/{}). Skipping provisioning.",
-                        runner.name, current_attempts, runner.max_retries
+                        "Runner '{}' exhausted its remediation budget ({}). Reporting it as failed.",
+                        name, budget
                     );
                     self.notify_provision_failure(
-                        &runner.name,
-                        format!("Exceeded max retries ({})", runner.max_retries),
-                        current_attempts,
+                        name,
+                        format!(
+                            "VM stopped unexpectedly (state: {}); remediation budget of {} exhausted",
+                            health.vm_state, budget
+                        ),
+                        attempts,
+                        None,
+                        None,
                     )
                     .await;
+                    // Bump past the budget so the failure is only reported once
+                    self.remediation_tracker.insert(name.clone(), attempts + 1);
                 }
+                continue;
             }
+            self.remediation_tracker.insert(name.clone(), attempts + 1);
 
-            // Collect eligible runners (not retry-exhausted, not already in-flight)
-            let eligible_runners: Vec<RunnerToProvision> = json
-                .runners_to_provision
-                .iter()
-                .filter(|r| self.should_retry(&r.name, r.max_retries))
-                .filter(|r| {
-                    if in_flight.contains(&r.name) {
-                        info!("Skipping runner '{}' — already in-flight", r.name);
-                        false
-                    } else {
-                        true
-                    }
-                })
-                .cloned()
-                .collect();
-
-            if !eligible_runners.is_empty() {
-                // Calculate available slots based on VM capacity
-                let available_slots = if let Some(max_vms) = self.max_vms {
-                    match get_running_vm_count().await {
-                        Ok(running_count) => {
-                            let slots = (max_vms as usize).saturating_sub(running_count);
-                            info!(
-                                "VM capacity: {}/{} running, {} slots available, {} runners requested",
-                                running_count, max_vms, slots, eligible_runners.len()
-                            );
-                            if slots == 0 {
-                                info!("No VM slots available. Runners will be picked up on next poll.");
-                            }
-                            slots
-                        }
-                        Err(e) => {
-                            warn!(
-                                "Failed to check VM capacity: {}. Using runner count as limit.",
-                                e
-                            );
-                            eligible_runners.len()
-                        }
-                    }
-                } else {
-                    eligible_runners.len()
-                };
+            info!(
+                "Remediating crashed runner '{}' (state: {}, attempt {}/{})",
+                name,
+                health.vm_state,
+                attempts + 1,
+                budget
+            );
 
-                if available_slots > 0 {
-                    // Cap runners to available slots
-                    let runners_to_spawn: Vec<RunnerToProvision> =
-                        eligible_runners.into_iter().take(available_slots).collect();
+            let endpoint = endpoints::endpoint_for_runner(name);
+            match endpoints::on_endpoint(endpoint, restart_runner_vm(name)).await {
+                Ok(()) => {
+                    info!("Restarted runner VM '{}'", name);
+                    continue;
+                }
+                Err(e) => warn!("Failed to restart runner VM '{}': {}", name, e),
+            }
 
+            // Restart didn't work: re-clone and re-provision from scratch if we know the spec
+            match self.provisioned_runners.get(name).cloned() {
+                Some(runner) => {
                     info!(
-                        "Spawning {} runners in parallel (max concurrency: {})",
-                        runners_to_spawn.len(),
-                        available_slots
+                        "Re-provisioning runner '{}' from scratch on the next poll",
+                        name
                     );
+                    let _ = CirunClient::cleanup_failed_runner(name).await;
+                    drop(runner_lock);
+                    self.reprovision_queue.insert(name.clone(), runner);
+                }
+                None => warn!(
+                    "No provisioning details known for runner '{}'. Cannot re-provision.",
+                    name
+                ),
+            }
+        }
+    }
 
-                    let semaphore = Arc::new(Semaphore::new(available_slots));
+    /// Report unhealthy runners to the API along with a suggested action
+    async fn report_runner_health(&self, results: &[RunnerHealth]) {
+        let unhealthy: Vec<&RunnerHealth> = results.iter().filter(|h| !h.is_healthy()).collect();
+        if unhealthy.is_empty() {
+            debug!("All {} checked runners are healthy", results.len());
+            return;
+        }
 
-                    for runner in runners_to_spawn {
-                        in_flight.insert(runner.name.clone());
-                        let sem = semaphore.clone();
-                        provision_set.spawn(provision_single_runner(runner, sem));
-                    }
+        let url = format!("{}/agent", self.base_url);
+        info!("Reporting {} unhealthy runners to API", unhealthy.len());
 
-                    info!(
-                        "Spawned provisioning tasks. Total in-flight: {}",
-                        provision_set.len()
+        let request_data = json!({
+            "agent": self.agent,
+            "runner_health": unhealthy,
+        });
+
+        match self
+            .create_request(reqwest::Method::POST, &url)
+            .json(&request_data)
+            .send()
+            .await
+        {
+            Ok(response) => {
+                if response.status().is_success() {
+                    debug!("Successfully reported runner health");
+                } else {
+                    warn!(
+                        "API returned non-success status for health report: {}",
+                        response.status()
                     );
                 }
             }
+            Err(e) => {
+                warn!("Failed to report runner health: {}", e);
+                enqueue_report(request_data);
+            }
         }
-
-        Ok(json)
     }
-}
 
-fn install_service(args: &Args) {
-    use std::fs;
+    /// API commands not started yet, each with its runner's login when it targets a runner
+    fn new_commands(
+        &mut self,
+        requests: Vec<CommandRequest>,
+    ) -> Vec<(CommandRequest, Option<RunnerLogin>)> {
+        requests
+            .into_iter()
+            .filter(|request| self.started_commands.insert(&request.id))
+            .map(|request| {
+                let login = request
+                    .runner
+                    .as_ref()
+                    .and_then(|name| self.provisioned_runners.get(name))
+                    .map(|runner| runner.login.clone());
+                (request, login)
+            })
+            .collect()
+    }
 
-    println!("Installing cirun-agent as a system service...");
+    /// Send the results of finished API commands, with any the API has not accepted yet.
+    /// Results the API turns down are sent again on the next call.
+    async fn report_command_results(&mut self, results: Vec<CommandResponse>) {
+        self.unacked_command_results.extend(results);
+        let excess = self
+            .unacked_command_results
+            .len()
+            .saturating_sub(commands::MAX_UNACKED_RESULTS);
+        if excess > 0 {
+            warn!("Dropping {} unreported command results", excess);
+            self.unacked_command_results.drain(..excess);
+        }
+        if self.unacked_command_results.is_empty() {
+            return;
+        }
 
-    // Get the current executable path
-    let exe_path = std::env::current_exe().expect("Failed to get current executable path");
-    let exe_path_str = exe_path.to_str().expect("Failed to convert path to string");
+        let url = format!("{}/agent", self.base_url);
+        let request_data = json!({
+            "agent": self.agent,
+            "command_results": self.unacked_command_results,
+        });
 
-    // Build the command line
-    let api_token = args
-        .api_token
-        .as_ref()
-        .expect("API token is required for service installation");
-    let mut cmd = format!("{} --api-token {}", exe_path_str, api_token);
-    if args.interval != 5 {
-        cmd.push_str(&format!(" --interval {}", args.interval));
-    }
-    if args.verbose {
-        cmd.push_str(" --verbose");
+        match self
+            .create_request(reqwest::Method::POST, &url)
+            .json(&request_data)
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => {
+                debug!(
+                    "Reported {} command results",
+                    self.unacked_command_results.len()
+                );
+                self.unacked_command_results.clear();
+            }
+            Ok(response) => warn!(
+                "API returned non-success status for command results: {}, retrying",
+                response.status()
+            ),
+            Err(e) => {
+                warn!("Failed to report command results: {}", e);
+                enqueue_report(request_data);
+                self.unacked_command_results.clear();
+            }
+        }
     }
 
-    if cfg!(target_os = "linux") {
-        // Check if service already exists and stop it first
-        let service_path = "/etc/systemd/system/cirun-agent.service";
+    /// Report each sampled runner's usage so far, with a smaller size where it used clearly
+    /// less than it asked for
+    async fn report_guest_metrics(&self, metrics: &[RunnerMetrics]) {
+        if metrics.is_empty() {
+            return;
+        }
+        for runner in metrics {
+            if let Some(recommendation) = &runner.recommendation {
+                debug!(
+                    "Runner '{}' requested {} CPUs and {}, peak usage fits in {} CPUs and {}",
+                    runner.runner_name,
+                    runner.requested_cpu,
+                    runner.requested_memory,
+                    recommendation.cpu,
+                    recommendation.memory
+                );
+            }
+        }
+
+        let url = format!("{}/agent", self.base_url);
+        let request_data = json!({
+            "agent": self.agent,
+            "guest_metrics": metrics,
+        });
+
+        match self
+            .create_request(reqwest::Method::POST, &url)
+            .json(&request_data)
+            .send()
+            .await
+        {
+            Ok(response) => {
+                if response.status().is_success() {
+                    debug!("Reported guest metrics for {} runners", metrics.len());
+                } else {
+                    warn!(
+                        "API returned non-success status for guest metrics: {}",
+                        response.status()
+                    );
+                }
+            }
+            // Later passes report the accumulated figures again, so nothing is queued
+            Err(e) => warn!("Failed to report guest metrics: {}", e),
+        }
+    }
+
+    /// Force-delete runner VMs that outlived the configured maximum lifetime, reporting each
+    /// one to the API. Protects the host from zombie runners whose delete request never came.
+    async fn enforce_vm_lifetime(&self, in_flight: &std::collections::HashSet<String>) {
+        let Some(max_lifetime) = self.max_vm_lifetime else {
+            return;
+        };
+        let max_lifetime =
+            chrono::Duration::from_std(max_lifetime).unwrap_or(chrono::Duration::MAX);
+        let now = chrono::Utc::now();
+
+        let expired: Vec<(String, chrono::DateTime<chrono::Utc>)> =
+            StateStore::new().read(|state| {
+                state
+                    .runners
+                    .iter()
+                    .filter(|(name, record)| {
+                        !in_flight.contains(*name) && now - record.created_at > max_lifetime
+                    })
+                    .map(|(name, record)| (name.clone(), record.created_at))
+                    .collect()
+            });
+
+        for (runner_name, created_at) in expired {
+            let lifetime_secs = (now - created_at).num_seconds();
+            warn!(
+                "Runner '{}' exceeded its maximum lifetime ({}s > {}s). Force-deleting.",
+                runner_name,
+                lifetime_secs,
+                max_lifetime.num_seconds()
+            );
+            events::publish(AgentEvent::RunnerExpired {
+                runner_name: runner_name.clone(),
+                lifetime_secs,
+            });
+
+            let url = format!("{}/agent", self.base_url);
+            let request_data = json!({
+                "agent": self.agent,
+                "runner_expired": {
+                    "runner_name": runner_name,
+                    "created_at": created_at.to_rfc3339(),
+                    "lifetime_seconds": lifetime_secs,
+                }
+            });
+            if let Err(e) = self
+                .create_request(reqwest::Method::POST, &url)
+                .json(&request_data)
+                .send()
+                .await
+            {
+                warn!("Failed to report expired runner {}: {}", runner_name, e);
+                enqueue_report(request_data);
+            }
+
+            match self.delete_runner(&runner_name).await {
+                Ok(_) => info!("✅ Deleted expired runner: {}", runner_name),
+                Err(e) => error!("❌ Failed to delete expired runner {}: {}", runner_name, e),
+            }
+        }
+    }
+
+    /// Tell the API a provisioning request was received but deferred until the window closes
+    async fn notify_provision_deferred(
+        &self,
+        runner_name: &str,
+        reason: &str,
+        resumes_at: Option<String>,
+    ) {
+        let url = format!("{}/agent", self.base_url);
+
+        let request_data = json!({
+            "agent": self.agent,
+            "provision_deferred": {
+                "runner_name": runner_name,
+                "reason": reason,
+                "resumes_at": resumes_at,
+            }
+        });
+
+        match self
+            .create_request(reqwest::Method::POST, &url)
+            .json(&request_data)
+            .send()
+            .await
+        {
+            Ok(response) => {
+                if !response.status().is_success() {
+                    warn!(
+                        "API returned non-success status for deferral notification: {}",
+                        response.status()
+                    );
+                }
+            }
+            Err(e) => {
+                warn!("Failed to notify API of deferred provisioning: {}", e);
+                enqueue_report(request_data);
+            }
+        }
+    }
+
+    /// Tell the API the agent stopped retrying a runner whose failure budget is spent
+    async fn notify_provision_abandoned(&self, runner_name: &str, failures: u32, last_error: &str) {
+        let url = format!("{}/agent", self.base_url);
+
+        let request_data = json!({
+            "agent": self.agent,
+            "provision_abandoned": {
+                "runner_name": runner_name,
+                "consecutive_failures": failures,
+                "last_error": last_error,
+            }
+        });
+
+        match self
+            .create_request(reqwest::Method::POST, &url)
+            .json(&request_data)
+            .send()
+            .await
+        {
+            Ok(response) => {
+                if !response.status().is_success() {
+                    warn!(
+                        "API returned non-success status for give-up notification: {}",
+                        response.status()
+                    );
+                }
+            }
+            Err(e) => {
+                warn!("Failed to notify API of abandoned runner: {}", e);
+                enqueue_report(request_data);
+            }
+        }
+    }
+
+    /// Send a diagnostics bundle written for a failed runner to the API. The bundle stays
+    /// on disk either way.
+    async fn upload_diagnostics(&self, runner_name: &str, bundle: &Path) {
+        let url = format!("{}/agent/diagnostics", self.base_url);
+        let contents = match tokio::fs::read(bundle).await {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("Failed to read diagnostics bundle {:?}: {}", bundle, e);
+                return;
+            }
+        };
+
+        match self
+            .create_request(reqwest::Method::POST, &url)
+            .header("Content-Type", "application/gzip")
+            .header("X-Runner-Name", runner_name)
+            .body(contents)
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => {
+                info!("Uploaded diagnostics bundle for '{}'", runner_name);
+            }
+            Ok(response) => {
+                warn!(
+                    "API returned non-success status for diagnostics upload: {}",
+                    response.status()
+                );
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to upload diagnostics bundle for '{}': {}",
+                    runner_name, e
+                );
+            }
+        }
+    }
+
+    /// Ask the API for a lease on a runner name so only one agent provisions it
+    async fn request_lease(&self, runner_name: &str) -> LeaseOutcome {
+        let ttl_secs = agent_config().coordination.lease_ttl_secs;
+        let url = format!("{}/agent/lease", self.base_url);
+        let request_data = json!({
+            "agent": self.agent,
+            "runner_name": runner_name,
+            "ttl_secs": ttl_secs,
+        });
+
+        match self
+            .create_request(reqwest::Method::POST, &url)
+            .json(&request_data)
+            .send()
+            .await
+        {
+            Ok(response) => {
+                let status = response.status().as_u16();
+                let body = response.json::<LeaseResponse>().await.ok();
+                LeaseOutcome::from_response(status, body, ttl_secs)
+            }
+            Err(e) => LeaseOutcome::Unavailable(e.to_string()),
+        }
+    }
+
+    /// With `[coordination] leases` enabled, keep only the runners this agent obtained a
+    /// lease for. Runners leased by another agent, or whose lease could not be requested,
+    /// are left to the API and a later poll.
+    async fn acquire_leases(&mut self, runners: Vec<RunnerToProvision>) -> Vec<RunnerToProvision> {
+        if !agent_config().coordination.leases {
+            return runners;
+        }
+        let mut leased = Vec::new();
+        for runner in runners {
+            match self.request_lease(&runner.name).await {
+                LeaseOutcome::Granted(expires_at) => {
+                    debug!(
+                        "Leased runner '{}' until {}",
+                        runner.name,
+                        expires_at.to_rfc3339()
+                    );
+                    self.held_leases.insert(&runner.name, expires_at);
+                    leased.push(runner);
+                    continue;
+                }
+                LeaseOutcome::Unsupported => {
+                    warn!(
+                        "API does not issue runner leases; provisioning '{}' without coordination",
+                        runner.name
+                    );
+                    leased.push(runner);
+                    continue;
+                }
+                LeaseOutcome::HeldElsewhere(holder) => info!(
+                    "Skipping runner '{}' — leased by another agent ({})",
+                    runner.name,
+                    holder.as_deref().unwrap_or("unknown")
+                ),
+                LeaseOutcome::Unavailable(e) => warn!(
+                    "Could not obtain a lease for runner '{}': {}. Will retry on next poll.",
+                    runner.name, e
+                ),
+            }
+            endpoints::clear_placement(&runner.name);
+        }
+        leased
+    }
+
+    /// Renew the leases of runners still being provisioned before they expire
+    async fn renew_leases(&mut self, in_flight: &std::collections::HashSet<String>) {
+        let ttl_secs = agent_config().coordination.lease_ttl_secs;
+        for runner_name in self
+            .held_leases
+            .due_for_renewal(chrono::Utc::now(), ttl_secs)
+        {
+            if !in_flight.contains(&runner_name) {
+                self.release_lease(&runner_name).await;
+                continue;
+            }
+            match self.request_lease(&runner_name).await {
+                LeaseOutcome::Granted(expires_at) => {
+                    self.held_leases.insert(&runner_name, expires_at)
+                }
+                outcome => error!(
+                    "Failed to renew the lease on runner '{}' ({:?}); another agent may provision it",
+                    runner_name, outcome
+                ),
+            }
+        }
+    }
+
+    /// Give up the lease on a runner once this agent is done provisioning it
+    async fn release_lease(&mut self, runner_name: &str) {
+        if !self.held_leases.remove(runner_name) {
+            return;
+        }
+        let url = format!("{}/agent/lease", self.base_url);
+        let request_data = json!({
+            "agent": self.agent,
+            "runner_name": runner_name,
+        });
+        match self
+            .create_request(reqwest::Method::DELETE, &url)
+            .json(&request_data)
+            .send()
+            .await
+        {
+            Ok(response) if !response.status().is_success() => warn!(
+                "API returned non-success status releasing lease on '{}': {}",
+                runner_name,
+                response.status()
+            ),
+            Ok(_) => debug!("Released lease on runner '{}'", runner_name),
+            // The lease simply expires
+            Err(e) => warn!("Failed to release lease on '{}': {}", runner_name, e),
+        }
+    }
+
+    /// Keep the runners that fit the `[capacity]` overcommit policy. The rest stay queued
+    /// on the API and are picked up by a later poll once running VMs free up capacity.
+    async fn admit_within_capacity(
+        &mut self,
+        runners: Vec<RunnerToProvision>,
+    ) -> Vec<RunnerToProvision> {
+        let Some(mut capacity) = capacity::current_capacity() else {
+            return runners;
+        };
+        let mut admitted = Vec::new();
+        let mut queued = std::collections::HashSet::new();
+        for runner in runners {
+            // The overcommit policy describes this host; remote endpoints have their own limits
+            if endpoints::endpoint_for_runner(&runner.name).is_some() {
+                admitted.push(runner);
+                continue;
+            }
+            if capacity.fits(runner.cpu, runner.memory) {
+                capacity.reserve(runner.cpu, runner.memory);
+                admitted.push(runner);
+                continue;
+            }
+            let reason = if capacity.could_fit(runner.cpu, runner.memory) {
+                "capacity"
+            } else {
+                "exceeds_host_capacity"
+            };
+            if !self.capacity_queued.contains(&runner.name) {
+                warn!(
+                    "Queueing runner '{}' ({} vCPU, {}): overcommit headroom is {:?} vCPU, {:?} MB ({})",
+                    runner.name,
+                    runner.cpu,
+                    runner.memory,
+                    capacity.cpu_headroom(),
+                    capacity.memory_headroom_mb(),
+                    reason
+                );
+                self.notify_provision_deferred(&runner.name, reason, None)
+                    .await;
+            }
+            queued.insert(runner.name);
+        }
+        self.capacity_queued = queued;
+        admitted
+    }
+
+    /// Report a runner request that failed validation and will not be provisioned
+    async fn notify_provision_rejected(&self, runner_name: &str, errors: &[String]) {
+        let url = format!("{}/agent", self.base_url);
+
+        error!(
+            "Rejecting runner request '{}': {}",
+            runner_name,
+            errors.join("; ")
+        );
+
+        let request_data = json!({
+            "agent": self.agent,
+            "provision_rejected": {
+                "runner_name": runner_name,
+                "errors": errors,
+            }
+        });
+
+        match self
+            .create_request(reqwest::Method::POST, &url)
+            .json(&request_data)
+            .send()
+            .await
+        {
+            Ok(response) => {
+                if !response.status().is_success() {
+                    warn!(
+                        "API returned non-success status for rejection notification: {}",
+                        response.status()
+                    );
+                }
+            }
+            Err(e) => {
+                warn!("Failed to notify API of rejected runner request: {}", e);
+                enqueue_report(request_data);
+            }
+        }
+    }
+
+    /// Tell the API this agent is going offline, so it stops assigning runners to it
+    /// straight away instead of waiting for polls to stop. Not queued when it fails:
+    /// delivering it on the next start would mark the agent offline again.
+    async fn deregister(&self, reason: &str, in_flight: &std::collections::HashSet<String>) {
+        let url = format!("{}/agent", self.base_url);
+        let request_data = json!({
+            "agent": self.agent,
+            "deregister": {
+                "reason": reason,
+                "in_flight_runners": in_flight,
+            }
+        });
+
+        match self
+            .create_request(reqwest::Method::POST, &url)
+            .timeout(Duration::from_secs(DEREGISTER_TIMEOUT_SECS))
+            .json(&request_data)
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => {
+                info!("Deregistered agent from Cirun API")
+            }
+            Ok(response) => warn!(
+                "API returned non-success status for deregistration: {}",
+                response.status()
+            ),
+            Err(e) => warn!("Failed to deregister agent: {}", e),
+        }
+    }
+
+    /// Start over with a new agent ID after the API said it no longer knows ours. The old
+    /// ID file is archived; the next poll registers the new identity.
+    fn reset_identity(&mut self) {
+        warn!(
+            "Cirun API does not recognise agent ID {}, generating a new identity",
+            self.agent.id
+        );
+        match identity::reset_current() {
+            Ok((archived, new_id)) => {
+                if let Some(archived) = archived {
+                    info!("Archived old agent ID file to {:?}", archived);
+                }
+                info!("New agent ID: {}", new_id);
+                log_target::set_agent_id(&new_id);
+                self.agent.id = new_id;
+                offline::forget_identity();
+                self.held_leases = HeldLeases::default();
+            }
+            Err(e) => error!("Failed to reset agent identity: {}", e),
+        }
+    }
+
+    /// Start the template rebuilds the API asked for; progress and failures are reported
+    /// with each template
+    fn start_template_rebuilds(&mut self, requests: &[TemplateRebuildRequest]) {
+        for request in requests {
+            if !self.started_rebuilds.insert(request.id.clone()) {
+                continue;
+            }
+            if use_meda() {
+                warn!(
+                    "Rebuild {}: meda has no templates; pull '{}' again instead",
+                    request.id, request.template
+                );
+                continue;
+            }
+            lume::freshness::request_rebuild(request.clone());
+        }
+    }
+
+    /// Open and close debug tunnels the API asked for. A request the API repeats on later
+    /// polls is only acted on once.
+    async fn handle_tunnel_requests(&self, json: &ApiResponse) {
+        for id in &json.tunnels_to_close {
+            if !tunnels::close(id, "closed by API") {
+                debug!("Tunnel {} is not open, nothing to close", id);
+            }
+        }
+        for request in &json.tunnels_to_open {
+            if request.id.as_deref().is_some_and(tunnels::already_opened) {
+                continue;
+            }
+            let result = tunnels::open(request.clone()).await;
+            if let Err(e) = &result {
+                warn!(
+                    "Failed to open tunnel to runner '{}': {}",
+                    request.runner_name, e
+                );
+            }
+            self.report_tunnel(request, result).await;
+        }
+    }
+
+    /// Tell the API where an opened tunnel listens, or why it could not be opened
+    async fn report_tunnel(&self, request: &TunnelRequest, result: Result<Tunnel, String>) {
+        let url = format!("{}/agent", self.base_url);
+        let tunnel = match result {
+            Ok(tunnel) => json!(tunnel),
+            Err(error) => json!({
+                "id": request.id,
+                "runner_name": request.runner_name,
+                "error": error,
+            }),
+        };
+        let request_data = json!({
+            "agent": self.agent,
+            "tunnel": tunnel,
+        });
+
+        match self
+            .create_request(reqwest::Method::POST, &url)
+            .json(&request_data)
+            .send()
+            .await
+        {
+            Ok(response) => {
+                if !response.status().is_success() {
+                    warn!(
+                        "API returned non-success status for tunnel report: {}",
+                        response.status()
+                    );
+                }
+            }
+            Err(e) => {
+                warn!("Failed to report tunnel to API: {}", e);
+                enqueue_report(request_data);
+            }
+        }
+    }
+
+    /// Report aggregated runner usage since the previous report to the API
+    async fn report_usage(&self) {
+        let store = StateStore::new();
+        let now = chrono::Utc::now();
+        let (summary, since) = store.read(|state| {
+            let since = state
+                .last_usage_report
+                .unwrap_or_else(|| now - chrono::Duration::hours(1));
+            let active: Vec<_> = state
+                .runners
+                .iter()
+                .map(|(name, record)| (name.clone(), record.clone()))
+                .collect();
+            (summarize(&state.usage_history, &active, since, now), since)
+        });
+
+        let url = format!("{}/agent", self.base_url);
+        info!(
+            "Reporting usage since {}: {:.2} VM hours across {} runners",
+            since.to_rfc3339(),
+            summary.vm_hours,
+            summary.runner_count
+        );
+
+        let request_data = json!({
+            "agent": self.agent,
+            "usage": summary,
+        });
+
+        match self
+            .create_request(reqwest::Method::POST, &url)
+            .json(&request_data)
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => {
+                store.update(|state| state.last_usage_report = Some(now));
+            }
+            Ok(response) => warn!(
+                "API returned non-success status for usage report: {}",
+                response.status()
+            ),
+            Err(e) => warn!("Failed to report usage: {}", e),
+        }
+    }
+
+    /// Report the result of a host benchmark so scheduling can prefer faster hosts
+    async fn report_benchmark(&self, result: &BenchmarkResult) {
+        let url = format!("{}/agent", self.base_url);
+
+        let request_data = json!({
+            "agent": self.agent,
+            "benchmark": result,
+        });
+
+        match self
+            .create_request(reqwest::Method::POST, &url)
+            .json(&request_data)
+            .send()
+            .await
+        {
+            Ok(response) => {
+                if response.status().is_success() {
+                    info!("Reported benchmark score {} to API", result.score);
+                } else {
+                    warn!(
+                        "API returned non-success status for benchmark report: {}",
+                        response.status()
+                    );
+                }
+            }
+            Err(e) => {
+                warn!("Failed to report benchmark: {}", e);
+                enqueue_report(request_data);
+            }
+        }
+    }
+
+    /// Add crashed runners waiting to be provisioned again to `runners_to_provision`, so they
+    /// go through the same checks and slot accounting. Runners the API no longer wants are
+    /// dropped from the queue.
+    fn queue_reprovisions(&mut self, runners_to_provision: &mut Vec<RunnerToProvision>) {
+        let queued = std::mem::take(&mut self.reprovision_queue);
+        for (name, runner) in queued {
+            if !self.still_desired(&name) {
+                info!(
+                    "Runner '{}' is no longer wanted by the API. Not re-provisioning it.",
+                    name
+                );
+                continue;
+            }
+            if !runners_to_provision.iter().any(|r| r.name == name) {
+                runners_to_provision.push(runner.clone());
+            }
+            self.reprovision_queue.insert(name, runner);
+        }
+    }
+
+    /// Last desired state the API returned, for use while it is unreachable. Deletions are
+    /// never replayed, and runners that were provisioned since are left out.
+    fn offline_desired_state(&self) -> Option<ApiResponse> {
+        let cached = offline::cached_desired_state()?;
+        let mut json: ApiResponse = serde_json::from_value(cached.response).ok()?;
+        json.runners_to_delete.clear();
+        // Poll hints were meant for the time they were sent
+        json.poll_interval = None;
+        json.next_poll_after = None;
+        let provisioned: std::collections::HashSet<String> =
+            StateStore::new().read(|state| state.runners.keys().cloned().collect());
+        json.runners_to_provision.retain(|runner| {
+            !provisioned.contains(&runner.name)
+                && !self.provisioned_runners.contains_key(&runner.name)
+        });
+        info!(
+            "Using desired state cached at {} ({} runners still to provision)",
+            cached.fetched_at.to_rfc3339(),
+            json.runners_to_provision.len()
+        );
+        Some(json)
+    }
+
+    /// Send reports queued while the API was unreachable, oldest first, stopping at the
+    /// first one that still cannot be delivered
+    async fn flush_queued_reports(&self) {
+        let queued = offline::queued_reports();
+        if queued.is_empty() {
+            return;
+        }
+        info!("Sending {} reports queued while offline", queued.len());
+        let url = format!("{}/agent", self.base_url);
+        let mut delivered = Vec::new();
+        for report in queued {
+            let mut payload = report.payload.clone();
+            payload["queued_at"] = json!(report.queued_at.to_rfc3339());
+            match self
+                .create_request(reqwest::Method::POST, &url)
+                .json(&payload)
+                .send()
+                .await
+            {
+                Ok(response) if retry::retryable_status(response.status()) => {
+                    warn!(
+                        "API returned {} for a queued report, keeping the rest queued",
+                        response.status()
+                    );
+                    break;
+                }
+                // Other client errors won't go away by retrying, so those reports are dropped too
+                Ok(_) => delivered.push(report),
+                Err(e) => {
+                    warn!("Failed to send queued reports: {}", e);
+                    break;
+                }
+            }
+        }
+        offline::remove_reports(&delivered);
+    }
+
+    /// Finish what a previous run of the agent left half done, e.g. after a crash: runners it
+    /// was provisioning are failed and their VMs removed, so the API's next request starts
+    /// them afresh, and deletions it was running are queued for retry
+    async fn recover_interrupted_runners(&self) {
+        for (runner_name, state) in interrupted() {
+            if state == RunnerState::Deleting {
+                if !is_pending_deletion(&runner_name) {
+                    warn!("Deletion of '{}' was interrupted; queueing it", runner_name);
+                    queue_deletion(&runner_name, "Interrupted by an agent restart");
+                }
+                continue;
+            }
+            warn!(
+                "Provisioning of '{}' was interrupted while {}; cleaning it up",
+                runner_name, state
+            );
+            lifecycle::fail(&runner_name);
+            let endpoint = endpoints::endpoint_for_runner(&runner_name);
+            let cleanup =
+                endpoints::on_endpoint(endpoint, Self::cleanup_failed_runner(&runner_name)).await;
+            if let Err(e) = cleanup {
+                warn!(
+                    "Failed to clean up interrupted runner '{}': {}",
+                    runner_name, e
+                );
+            }
+        }
+    }
+
+    /// Retry queued deletions that are due and report whatever is still pending
+    async fn retry_pending_deletions(&self) {
+        for runner_name in due_deletions() {
+            info!("Retrying queued deletion of runner: {}", runner_name);
+            match self.delete_runner(&runner_name).await {
+                Ok(_) => info!("✅ Deleted queued runner: {}", runner_name),
+                Err(e) => warn!("Queued deletion of {} failed again: {}", runner_name, e),
+            }
+        }
+
+        let pending = StateStore::new().read(|state| state.pending_deletions.clone());
+        if pending.is_empty() {
+            return;
+        }
+
+        let url = format!("{}/agent", self.base_url);
+        let request_data = json!({
+            "agent": self.agent,
+            "pending_deletions": pending,
+        });
+
+        match self
+            .create_request(reqwest::Method::POST, &url)
+            .json(&request_data)
+            .send()
+            .await
+        {
+            Ok(response) => {
+                if response.status().is_success() {
+                    debug!("Reported {} pending deletions to API", pending.len());
+                } else {
+                    warn!(
+                        "API returned non-success status for pending deletions: {}",
+                        response.status()
+                    );
+                }
+            }
+            Err(e) => {
+                warn!("Failed to report pending deletions: {}", e);
+            }
+        }
+    }
+
+    /// Forward streamed provision output lines to the API
+    async fn stream_runner_logs(&self, lines: &[LogLine]) {
+        let url = format!("{}/agent", self.base_url);
+
+        let request_data = json!({
+            "agent": self.agent,
+            "runner_logs": lines,
+        });
+
+        match self
+            .create_request(reqwest::Method::POST, &url)
+            .json(&request_data)
+            .send()
+            .await
+        {
+            Ok(response) => {
+                if response.status().is_success() {
+                    debug!("Streamed {} runner log lines to API", lines.len());
+                } else {
+                    warn!(
+                        "API returned non-success status for runner logs: {}",
+                        response.status()
+                    );
+                }
+            }
+            Err(e) => {
+                // Live output is not worth queueing; the outbox is for state reports
+                warn!("Failed to stream runner logs: {}", e);
+            }
+        }
+    }
+
+    async fn manage_runner_lifecycle(
+        &mut self,
+        provision_set: &mut JoinSet<ProvisionResult>,
+        in_flight: &mut std::collections::HashSet<String>,
+    ) -> Result<ApiResponse, Error> {
+        let url = format!("{}/agent", self.base_url);
+        chatty!("Fetching runner provision/deletion data from: {}", url);
+
+        let request_data = json!({
+            "agent": self.agent,
+            "backpressure": self.backpressure,
+        });
+
+        // Use the helper method instead of direct client access
+        let fetch = || async {
+            let response = self
+                .create_request(reqwest::Method::GET, &url)
+                .json(&request_data)
+                .send()
+                .await?;
+            chatty!("Response status: {}", response.status());
+            let status = response.status();
+            // Server errors and rate limits are retried; other statuses are judged below
+            if retry::retryable_status(status) {
+                response.error_for_status_ref()?;
+            }
+            let headers = response.headers().clone();
+            Ok::<_, Error>((status, headers, response.text().await?))
+        };
+        let fetched = retry::with_retries("API poll", agent_config().retry.cirun_api, fetch).await;
+
+        if let Ok((status, _, body)) = &fetched {
+            if identity::is_unknown_agent(*status, body) {
+                self.reset_identity();
+                return Ok(ApiResponse::default());
+            }
+        }
+        let fetched = fetched
+            .map(|(_, headers, body)| (headers, serde_json::from_str::<serde_json::Value>(&body)));
+
+        let mut json: ApiResponse = match fetched {
+            Ok((_, Err(e))) => match self.offline_desired_state() {
+                Some(json) => {
+                    warn!(
+                        "Cirun API returned an invalid response ({}). Serving cached desired state.",
+                        e
+                    );
+                    json
+                }
+                None => {
+                    error!("Unexpected response from API: {}", e);
+                    return Ok(ApiResponse::default());
+                }
+            },
+            Ok((headers, Ok(value))) => {
+                match serde_json::from_value::<ApiResponse>(value.clone()) {
+                    Ok(mut json) => {
+                        json.apply_poll_headers(&headers);
+                        identity::record_api_contact();
+                        offline::cache_desired_state(value);
+                        self.flush_queued_reports().await;
+                        json
+                    }
+                    Err(e) => {
+                        error!("Unexpected response from API: {}", e);
+                        return Ok(ApiResponse::default());
+                    }
+                }
+            }
+            Err(e) => match self.offline_desired_state() {
+                Some(json) => {
+                    warn!(
+                        "Cirun API is unreachable ({}). Serving cached desired state.",
+                        e
+                    );
+                    json
+                }
+                None => return Err(e),
+            },
+        };
+
+        // Handle any runners that need deletion
+        if !json.runners_to_delete.is_empty() {
+            info!(
+                "Received {} runners to delete",
+                json.runners_to_delete.len()
+            );
+
+            // The API keeps resending deletions until the VM report catches up
+            let mut seen = std::collections::HashSet::new();
+            for runner in &json.runners_to_delete {
+                if !seen.insert(runner.name.as_str()) {
+                    continue;
+                }
+                if is_deleted(&runner.name) {
+                    debug!(
+                        "Runner {} already deleted, ignoring repeat request",
+                        runner.name
+                    );
+                    continue;
+                }
+                if is_pending_deletion(&runner.name) {
+                    debug!(
+                        "Deletion of runner {} is already queued for retry",
+                        runner.name
+                    );
+                    continue;
+                }
+                match self.delete_runner(&runner.name).await {
+                    Ok(_) => info!("✅ Successfully deleted runner: {}", runner.name),
+                    Err(e) => error!("❌ Failed to delete runner {}: {}", runner.name, e),
+                }
+            }
+        }
+
+        self.last_deletions = json
+            .runners_to_delete
+            .iter()
+            .map(|runner| runner.name.clone())
+            .collect();
+        self.queue_reprovisions(&mut json.runners_to_provision);
+
+        self.handle_tunnel_requests(&json).await;
+        self.start_template_rebuilds(&json.templates_to_rebuild);
+
+        // Handle runners that need provisioning
+        let mut waiting_for_slot = 0;
+        if !json.runners_to_provision.is_empty() {
+            info!(
+                "Received {} runners to provision",
+                json.runners_to_provision.len()
+            );
+
+            // Inside a quiet window: report the requests as deferred and pick them up
+            // on a later poll once the window has closed
+            if let Some(window) = current_quiet_window(&self.quiet_windows) {
+                for runner in &json.runners_to_provision {
+                    if !self.deferred_runners.contains(&runner.name) {
+                        info!(
+                            "Deferring provisioning of {} until quiet window {}-{} ends",
+                            runner.name,
+                            window.start.format("%H:%M"),
+                            window.end.format("%H:%M")
+                        );
+                        self.notify_provision_deferred(
+                            &runner.name,
+                            "quiet_hours",
+                            Some(window.end.format("%H:%M").to_string()),
+                        )
+                        .await;
+                        self.deferred_runners.insert(runner.name.clone());
+                    }
+                }
+                return Ok(json);
+            }
+            self.deferred_runners.clear();
+
+            // Reject invalid requests before they reach the provider or a shell
+            let mut invalid = std::collections::HashSet::new();
+            for runner in &json.runners_to_provision {
+                if let Err(errors) = validate_runner(runner) {
+                    invalid.insert(runner.name.clone());
+                    if self.rejected_runners.insert(runner.name.clone()) {
+                        self.notify_provision_rejected(&runner.name, &errors).await;
+                    }
+                }
+            }
+            self.rejected_runners.retain(|name| invalid.contains(name));
+
+            // Hold back runners that failed recently, and give up on those whose failure
+            // budget is spent, telling the API once
+            let requested: Vec<String> = json
+                .runners_to_provision
+                .iter()
+                .map(|r| r.name.clone())
+                .collect();
+            retry_budget::retain_requested(&requested);
+            let mut held_back = std::collections::HashSet::new();
+            for runner in &json.runners_to_provision {
+                let budget = retry_budget::budget(runner.max_retries);
+                match retry_budget::decision(&runner.name, budget) {
+                    RetryDecision::Attempt => continue,
+                    RetryDecision::BackOff(until) => debug!(
+                        "Runner '{}' failed recently; next attempt after {}",
+                        runner.name,
+                        until.format("%H:%M:%S")
+                    ),
+                    RetryDecision::GiveUp {
+                        failures,
+                        last_error,
+                        report,
+                    } => {
+                        if report {
+                            warn!(
+                                "Giving up on runner '{}' after {} consecutive failures: {}",
+                                runner.name, failures, last_error
+                            );
+                            self.notify_provision_abandoned(&runner.name, failures, &last_error)
+                                .await;
+                            retry_budget::mark_gave_up(&runner.name);
+                        }
+                    }
+                }
+                held_back.insert(runner.name.clone());
+            }
+
+            // Collect eligible runners (not held back, not already in-flight)
+            let eligible_runners: Vec<RunnerToProvision> = json
+                .runners_to_provision
+                .iter()
+                .filter(|r| !invalid.contains(&r.name))
+                .filter(|r| !held_back.contains(&r.name))
+                .filter(|r| match runner_state(&r.name) {
+                    Some(state) if state.blocks_provisioning() => {
+                        info!(
+                            "Skipping runner '{}' — lifecycle state is {}",
+                            r.name, state
+                        );
+                        false
+                    }
+                    _ => true,
+                })
+                .filter(|r| {
+                    if in_flight.contains(&r.name) {
+                        info!("Skipping runner '{}' — already in-flight", r.name);
+                        false
+                    } else {
+                        true
+                    }
+                })
+                .cloned()
+                .collect();
+
+            // Take turns between images, and hold back runners of images being pulled so
+            // runners that can clone right away are not stuck behind a long pull
+            let pulls = fair_share::pulls();
+            let pulling = pulls.keys().cloned().collect();
+            let (eligible_runners, held_for_pull) =
+                fair_share::schedule(eligible_runners, &pulling, |runner| runner.image.clone());
+            for (image, elapsed) in &pulls {
+                let held = held_for_pull
+                    .iter()
+                    .filter(|runner| runner.image == *image)
+                    .count();
+                if held > 0 {
+                    info!(
+                        "Holding {} runners of image '{}' until its pull finishes ({}s so far)",
+                        held,
+                        image,
+                        elapsed.as_secs()
+                    );
+                }
+            }
+
+            if !eligible_runners.is_empty() {
+                // Calculate available slots based on VM capacity
+                let available_slots = if let Some(max_vms) = self.max_vms {
+                    match get_running_vm_count().await {
+                        Ok(running_count) => {
+                            let slots = (max_vms as usize).saturating_sub(running_count)
+                                + endpoints::remote_free_slots(eligible_runners.len()).await;
+                            info!(
+                                "VM capacity: {}/{} running, {} slots available, {} runners requested",
+                                running_count, max_vms, slots, eligible_runners.len()
+                            );
+                            if slots == 0 {
+                                info!("No VM slots available. Runners will be picked up on next poll.");
+                            }
+                            waiting_for_slot = eligible_runners.len().saturating_sub(slots);
+                            slots
+                        }
+                        Err(e) => {
+                            warn!(
+                                "Failed to check VM capacity: {}. Using runner count as limit.",
+                                e
+                            );
+                            eligible_runners.len()
+                        }
+                    }
+                } else {
+                    eligible_runners.len()
+                };
+
+                if available_slots > 0 {
+                    // Cap runners to available slots
+                    let runners_to_spawn: Vec<RunnerToProvision> =
+                        eligible_runners.into_iter().take(available_slots).collect();
+                    let runners_to_spawn = endpoints::place(runners_to_spawn, self.max_vms).await;
+                    let runners_to_spawn = self.admit_within_capacity(runners_to_spawn).await;
+                    let runners_to_spawn = self.acquire_leases(runners_to_spawn).await;
+
+                    info!(
+                        "Spawning {} runners in parallel (max concurrency: {})",
+                        runners_to_spawn.len(),
+                        available_slots
+                    );
+
+                    let semaphore = Arc::new(Semaphore::new(available_slots));
+
+                    for runner in runners_to_spawn {
+                        self.reprovision_queue.remove(&runner.name);
+                        in_flight.insert(runner.name.clone());
+                        self.provisioned_runners
+                            .insert(runner.name.clone(), runner.clone());
+                        let sem = semaphore.clone();
+                        provision_set.spawn(provision_single_runner(
+                            runner,
+                            self.agent.clone(),
+                            sem,
+                        ));
+                    }
+
+                    info!(
+                        "Spawned provisioning tasks. Total in-flight: {}",
+                        provision_set.len()
+                    );
+                }
+            }
+        }
+
+        let waiting_for_capacity = json
+            .runners_to_provision
+            .iter()
+            .filter(|runner| self.capacity_queued.contains(&runner.name))
+            .count();
+        self.update_backpressure(Load {
+            provisioning: in_flight.len(),
+            waiting_for_slot,
+            waiting_for_capacity,
+        });
+
+        Ok(json)
+    }
+
+    /// Decide from the load after a poll whether the next poll asks the API to slow down
+    fn update_backpressure(&mut self, load: Load) {
+        let pressure = backpressure::assess(&load, &agent_config().backpressure);
+        if pressure.active && pressure.reasons != self.backpressure.reasons {
+            warn!(
+                "Asking the API to slow down runner assignment ({:?}, {} runners queued)",
+                pressure.reasons, pressure.queue_depth
+            );
+        } else if !pressure.active && self.backpressure.active {
+            info!("No longer asking the API to slow down runner assignment");
+        }
+        self.backpressure = pressure;
+    }
+}
+
+fn install_service(args: &Args) {
+    use std::fs;
+
+    println!("Installing cirun-agent as a system service...");
+
+    // Get the current executable path
+    let exe_path = std::env::current_exe().expect("Failed to get current executable path");
+    let exe_path_str = exe_path.to_str().expect("Failed to convert path to string");
+
+    // Build the command line
+    let api_token = args
+        .api_token
+        .as_ref()
+        .expect("API token is required for service installation");
+    let mut cmd = format!("{} --api-token {}", exe_path_str, api_token);
+    if args.interval != 5 {
+        cmd.push_str(&format!(" --interval {}", args.interval));
+    }
+    if args.report_interval != DEFAULT_REPORT_INTERVAL_SECS {
+        cmd.push_str(&format!(" --report-interval {}", args.report_interval));
+    }
+    if args.verbose {
+        cmd.push_str(" --verbose");
+    }
+    if args.quiet {
+        cmd.push_str(" --quiet");
+    }
+    if args.plain {
+        cmd.push_str(" --plain");
+    }
+    if args.log_target != LogTarget::Stderr {
+        cmd.push_str(&format!(
+            " --log-target {}",
+            args.log_target.to_possible_value().unwrap().get_name()
+        ));
+    }
+    if let Some(config) = &args.config {
+        let config = fs::canonicalize(config).unwrap_or_else(|_| config.clone());
+        cmd.push_str(&format!(" --config {}", config.display()));
+    }
+    if args.provider_as_service {
+        cmd.push_str(" --provider-as-service");
+
+        println!("Installing the VM manager as its own service...");
+        let result = if use_meda() {
+            meda::setup::install_meda_service()
+        } else {
+            lume::setup::install_lume_service()
+        };
+        match result {
+            Ok(()) => println!("✅ VM manager service installed and running"),
+            Err(e) => {
+                eprintln!("Failed to install the VM manager service: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if cfg!(target_os = "linux") {
+        // Check if service already exists and stop it first
+        let service_path = "/etc/systemd/system/cirun-agent.service";
         if std::path::Path::new(service_path).exists() {
             println!("Found existing cirun-agent service, stopping it...");
             let _ = std::process::Command::new("systemctl")
@@ -1130,7 +3556,7 @@ fn install_service(args: &Args) {
         let service_content = format!(
             r#"[Unit]
 Description=Cirun Agent for On-Prem Runner Management
-After=network.target
+After=network.target{}
 
 [Service]
 Type=simple
@@ -1138,13 +3564,21 @@ ExecStart={}
 Environment="HOME={}"
 Restart=always
 RestartSec=10
+# Only the agent is stopped; a supervised provider server and its VMs keep running
+KillMode=process
 StandardOutput=journal
 StandardError=journal
 
 [Install]
 WantedBy=multi-user.target
 "#,
-            cmd, home_dir
+            if args.provider_as_service {
+                format!(" {0}\nWants={0}", provider_service::systemd_unit_name())
+            } else {
+                String::new()
+            },
+            cmd,
+            home_dir
         );
 
         let service_path = "/etc/systemd/system/cirun-agent.service";
@@ -1204,6 +3638,8 @@ WantedBy=multi-user.target
         <string>{}</string>
         <string>--interval</string>
         <string>{}</string>
+        <string>--report-interval</string>
+        <string>{}</string>
 {}    </array>
     <key>EnvironmentVariables</key>
     <dict>
@@ -1214,6 +3650,8 @@ WantedBy=multi-user.target
     <true/>
     <key>KeepAlive</key>
     <true/>
+    <key>AbandonProcessGroup</key>
+    <true/>
     <key>StandardOutPath</key>
     <string>{}/Library/Logs/cirun-agent.log</string>
     <key>StandardErrorPath</key>
@@ -1224,11 +3662,39 @@ WantedBy=multi-user.target
             exe_path_str,
             api_token,
             args.interval,
-            if args.verbose {
-                "        <string>--verbose</string>\n"
-            } else {
-                ""
-            },
+            args.report_interval,
+            [
+                (args.verbose, "        <string>--verbose</string>\n"),
+                (args.quiet, "        <string>--quiet</string>\n"),
+                (args.plain, "        <string>--plain</string>\n"),
+                (
+                    args.provider_as_service,
+                    "        <string>--provider-as-service</string>\n"
+                ),
+            ]
+            .iter()
+            .filter(|(enabled, _)| *enabled)
+            .map(|(_, arg)| arg.to_string())
+            .chain((args.log_target != LogTarget::Stderr).then(|| {
+                format!(
+                    "        <string>--log-target</string>\n        <string>{}</string>\n",
+                    args.log_target.to_possible_value().unwrap().get_name()
+                )
+            }))
+            .chain(args.config.as_ref().map(|config| {
+                let config = fs::canonicalize(config).unwrap_or_else(|_| config.clone());
+                let config = config
+                    .display()
+                    .to_string()
+                    .replace('&', "&amp;")
+                    .replace('<', "&lt;")
+                    .replace('>', "&gt;");
+                format!(
+                    "        <string>--config</string>\n        <string>{}</string>\n",
+                    config
+                )
+            }))
+            .collect::<String>(),
             home_dir,
             home_dir
         );
@@ -1256,6 +3722,15 @@ WantedBy=multi-user.target
     }
 }
 
+/// Remove the VM manager service installed with --provider-as-service, if any
+fn uninstall_provider_service_if_present() {
+    match provider_service::uninstall_provider_service() {
+        Ok(true) => println!("[OK] Removed VM manager service"),
+        Ok(false) => {}
+        Err(e) => eprintln!("[ERROR] Failed to remove VM manager service: {}", e),
+    }
+}
+
 fn uninstall_service() {
     println!("Uninstalling cirun-agent system service...");
 
@@ -1296,6 +3771,8 @@ fn uninstall_service() {
             .expect("Failed to reload systemd");
         println!("[OK] Reloaded systemd");
 
+        uninstall_provider_service_if_present();
+
         println!("\n[OK] Service uninstalled successfully!");
     } else if cfg!(target_os = "macos") {
         let home_dir = std::env::var("HOME").expect("Failed to get HOME directory");
@@ -1327,6 +3804,8 @@ fn uninstall_service() {
         }
         println!("[OK] Removed plist file: {}", plist_path);
 
+        uninstall_provider_service_if_present();
+
         println!("\n[OK] Service uninstalled successfully!");
     } else {
         eprintln!("Unsupported operating system");
@@ -1334,54 +3813,180 @@ fn uninstall_service() {
     }
 }
 
+/// Upgrade the provider (meda or lume) in place when its installed version differs
+/// from the pinned one, then check it is reachable again. Returns false when the
+/// check has to wait because lume is still running VMs.
+async fn upgrade_provider() -> bool {
+    // lume VMs live inside the `lume serve` process, so restarting it would kill them
+    if !use_meda() {
+        if let Ok(lume) = LumeClient::new() {
+            if let Ok(vms) = lume.list_vms().await {
+                if vms.iter().any(|vm| vm.state == "running") {
+                    debug!("Deferring lume upgrade check while VMs are running");
+                    return false;
+                }
+            }
+        }
+    }
+
+    let upgrade = if use_meda() {
+        meda::setup::upgrade_meda
+    } else {
+        lume::setup::upgrade_lume
+    };
+    match tokio::task::spawn_blocking(upgrade).await {
+        Ok(Ok(true)) => {
+            verify_provider_connectivity().await;
+            let _ = tokio::task::spawn_blocking(build_info::refresh_provider_version).await;
+        }
+        Ok(Ok(false)) => {}
+        Ok(Err(e)) => error!("Provider upgrade failed: {}", e),
+        Err(e) => error!("Provider upgrade task failed: {}", e),
+    }
+    true
+}
+
+/// Wait for a freshly restarted provider to answer API calls
+async fn verify_provider_connectivity() {
+    for attempt in 1..=10 {
+        let reachable = if use_meda() {
+            match MedaClient::new() {
+                Ok(meda) => meda.list_vms().await.is_ok(),
+                Err(_) => false,
+            }
+        } else {
+            match LumeClient::new() {
+                Ok(lume) => lume.list_vms().await.is_ok(),
+                Err(_) => false,
+            }
+        };
+        if reachable {
+            info!("✅ Provider is reachable after upgrade");
+            return;
+        }
+        debug!(
+            "Provider not reachable yet after upgrade (attempt {}/10)",
+            attempt
+        );
+        sleep(Duration::from_secs(3)).await;
+    }
+    error!("❌ Provider is not reachable after upgrade");
+}
+
+/// Whether the provider still knows about a VM. Unreachable providers count as "still
+/// present" so a deletion is never confirmed without evidence.
+async fn vm_still_listed(vm_name: &str) -> bool {
+    if use_meda() {
+        match MedaClient::new() {
+            Ok(meda) => !matches!(
+                meda.get_vm(vm_name).await,
+                Err(MedaError::ApiError(_) | MedaError::Status { .. })
+            ),
+            Err(_) => true,
+        }
+    } else {
+        match LumeClient::new() {
+            Ok(lume) => !matches!(
+                lume.get_vm(vm_name).await,
+                Err(LumeError::ApiError(_) | LumeError::Status { .. })
+            ),
+            Err(_) => true,
+        }
+    }
+}
+
+/// Poll until a deleted VM is gone from the provider and its storage directory was
+/// removed, then log how much disk space was reclaimed. Providers accept a delete
+/// before the disk is actually freed, so a deletion only counts once this passes.
+async fn verify_vm_deleted(vm_name: &str, free_before: Option<u64>) -> Result<(), String> {
+    let storage_dir = vm_storage_dir(vm_name);
+    let timeout = Duration::from_secs(agent_config().timeouts.vm_delete_secs);
+    let start = std::time::Instant::now();
+
+    loop {
+        let listed = vm_still_listed(vm_name).await;
+        // A remote endpoint's storage is not visible from this host
+        let storage_present = endpoints::current().is_none() && storage_dir.exists();
+        if !listed && !storage_present {
+            break;
+        }
+        if start.elapsed() >= timeout {
+            return Err(format!(
+                "VM '{}' still present after {}s (listed by provider: {}, storage at {:?}: {})",
+                vm_name,
+                timeout.as_secs(),
+                listed,
+                storage_dir,
+                storage_present
+            ));
+        }
+        sleep(Duration::from_secs(2)).await;
+    }
+
+    match (free_before, available_bytes(&storage_dir).await) {
+        (Some(before), Some(after)) => info!(
+            "VM '{}' deletion verified in {:.1}s, reclaimed {:.2} GB",
+            vm_name,
+            start.elapsed().as_secs_f64(),
+            after.saturating_sub(before) as f64 / 1024.0 / 1024.0 / 1024.0
+        ),
+        _ => info!(
+            "VM '{}' deletion verified in {:.1}s",
+            vm_name,
+            start.elapsed().as_secs_f64()
+        ),
+    }
+    Ok(())
+}
+
 // Helper function for running scripts on VMs using meda (simpler version without lume client)
 async fn run_script_on_vm_meda(
     _meda: &MedaClient,
     vm_name: &str,
     ip_address: &str,
     script_content: &str,
+    files: &[guest_files::GuestFile],
     login: &RunnerLogin,
     run_detached: bool,
 ) -> Result<String, Box<dyn std::error::Error>> {
-    use std::io::Write;
     use std::time::Instant;
-    use tempfile::NamedTempFile;
     use tokio::process::Command;
 
     info!("VM '{}' is ready with IP: {}", vm_name, ip_address);
 
-    // Step 1: Create a temporary file for the script
-    info!("Creating temporary script file");
-    let mut temp_file = NamedTempFile::new()?;
-    temp_file.write_all(script_content.as_bytes())?;
-    let temp_file_path = temp_file
-        .path()
-        .to_str()
-        .ok_or("Failed to get temporary file path")?;
-
-    // Step 2: Resolve SSH private key path
+    // Step 1: Resolve SSH private key path
     let home_dir = std::env::var("HOME").unwrap_or_else(|_| "/root".to_string());
     let ssh_key_path = format!("{}/.meda/ssh/id_ed25519", home_dir);
     info!("Using SSH key authentication: {}", ssh_key_path);
 
-    // Step 3: Setup SSH options
-    let ssh_options = vec![
-        "-o",
-        "StrictHostKeyChecking=no",
-        "-o",
-        "UserKnownHostsFile=/dev/null",
-        "-o",
-        "ConnectTimeout=10",
-    ];
-
-    // Step 4: Test SSH connection with retries (SSH may not be ready immediately after VM boot)
-    info!("Waiting for SSH to be ready on VM (max 30 seconds)...");
-    let max_ssh_retries = 6; // 6 retries * 5 seconds = 30 seconds max
+    // Step 2: Setup SSH options
+    let ssh_settings = ssh::settings(login, vm_name);
+    let ssh_options = ssh_settings.args();
+
+    // Step 3: Test SSH connection with retries (SSH may not be ready immediately after VM boot)
+    let timeouts = &agent_config().timeouts;
+    info!(
+        "Waiting for SSH to be ready on VM (max {} seconds)...",
+        timeouts.ssh_ready_secs
+    );
+    let boot_wait_start = Instant::now();
+    readiness::wait_until_booted(vm_name, timeouts.ssh_ready_secs).await;
+    record_phase(Phase::Boot, boot_wait_start.elapsed());
+    address_check::verify(vm_name, ip_address, &ssh_settings).await?;
+    host_keys::ensure_trusted(vm_name, timeouts.ssh_ready_secs).await?;
+    let ssh_wait_start = Instant::now();
+    let ssh_ready_window = tokio::time::Duration::from_secs(timeouts.ssh_ready_secs);
     let mut ssh_ready = false;
+    let mut attempt = 0;
 
-    for attempt in 1..=max_ssh_retries {
+    while ssh_wait_start.elapsed() < ssh_ready_window
+        && ssh_settings
+            .retries
+            .is_none_or(|retries| attempt <= retries)
+    {
+        attempt += 1;
         let output = match tokio::time::timeout(
-            tokio::time::Duration::from_secs(30),
+            tokio::time::Duration::from_secs(timeouts.ssh_attempt_secs),
             Command::new("ssh")
                 .arg("-i")
                 .arg(&ssh_key_path)
@@ -1390,6 +3995,7 @@ async fn run_script_on_vm_meda(
                 .arg("echo 'SSH connection test successful'")
                 .stdout(std::process::Stdio::piped())
                 .stderr(std::process::Stdio::piped())
+                .kill_on_drop(true)
                 .output(),
         )
         .await
@@ -1397,117 +4003,106 @@ async fn run_script_on_vm_meda(
             Ok(result) => result?,
             Err(_) => {
                 warn!(
-                    "SSH connection test timed out after 30s (attempt {}/{})",
-                    attempt, max_ssh_retries
+                    "SSH connection test timed out after {}s (attempt {})",
+                    timeouts.ssh_attempt_secs, attempt
                 );
-                if attempt < max_ssh_retries {
-                    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-                }
+                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
                 continue;
             }
         };
 
         if output.status.success() {
-            info!(
-                "✔ SSH connection successful (attempt {}/{})",
-                attempt, max_ssh_retries
-            );
+            info!("✔ SSH connection successful (attempt {})", attempt);
             ssh_ready = true;
             break;
         } else {
             let error_msg = String::from_utf8_lossy(&output.stderr);
             info!(
-                "SSH not ready yet (attempt {}/{}): {}",
+                "SSH not ready yet (attempt {}): {}",
                 attempt,
-                max_ssh_retries,
                 error_msg.trim()
             );
-            if attempt < max_ssh_retries {
-                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-            }
+            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
         }
     }
 
+    record_phase(Phase::SshWait, ssh_wait_start.elapsed());
     if !ssh_ready {
         return Err(
             "SSH connection failed after multiple retries - VM may not be fully booted".into(),
         );
     }
 
-    // Step 5: Copy the script to the VM
-    let remote_script_path = format!("/tmp/script_{}.sh", Instant::now().elapsed().as_secs());
-    info!("Copying script to VM at {}", remote_script_path);
+    preseed_tool_cache(vm_name, ip_address, login).await;
+    guest_files::push_files(vm_name, ip_address, login, files).await?;
 
-    let output = tokio::time::timeout(
-        tokio::time::Duration::from_secs(60),
-        Command::new("scp")
-            .arg("-i")
-            .arg(&ssh_key_path)
-            .args(&ssh_options)
-            .arg(temp_file_path)
-            .arg(format!(
-                "{}@{}:{}",
-                login.username, ip_address, remote_script_path
-            ))
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .output(),
+    // Step 4: Upload the script to the VM
+    let _ = transition(&pool::runner_name(vm_name), RunnerState::Provisioning);
+    let script_start = Instant::now();
+    let remote_script_path = format!(
+        "/tmp/script_{}.{}",
+        Instant::now().elapsed().as_secs(),
+        login.script.extension()
+    );
+    let program = login.script.program(&remote_script_path, Some("bash"))?;
+    let remote_script = RemoteScript::new(vm_name, remote_script_path.clone(), script_content);
+    info!("Uploading script to VM at {}", remote_script_path);
+    upload_script(
+        vm_name,
+        ip_address,
+        login,
+        script_content,
+        &remote_script_path,
+        timeouts.transfer_secs,
     )
-    .await
-    .map_err(|_| "SCP transfer timed out after 60s")??;
-
-    if !output.status.success() {
-        let error_msg = String::from_utf8_lossy(&output.stderr);
-        return Err(format!("SCP failed: {}", error_msg).into());
-    }
-
-    info!("✔ SCP transfer successful");
+    .await?;
 
-    // Step 6: Execute the script on the VM with sudo (provision scripts need root privileges)
+    // Step 5: Execute the script on the VM, as root unless the runner says otherwise.
     // Detached mode gets a short timeout (just needs to launch); blocking mode gets longer.
-    let (script_timeout_secs, script_future) = if run_detached {
-        info!("Executing script on VM in detached mode with sudo");
-        (
-            60u64,
-            Command::new("ssh")
-                .arg("-i")
-                .arg(&ssh_key_path)
-                .args(&ssh_options)
-                .arg(format!("{}@{}", login.username, ip_address))
-                .arg(format!(
-                    "chmod +x {} && sudo nohup bash {} > /tmp/script_stdout.log 2> /tmp/script_stderr.log & echo $!",
-                    remote_script_path, remote_script_path
-                ))
-                .stdout(std::process::Stdio::piped())
-                .stderr(std::process::Stdio::piped())
-                .output(),
-        )
+    let remote_command = login
+        .script
+        .command(&login.username, &program, true, run_detached);
+    let script_timeout_secs = if run_detached {
+        info!("Executing script on VM in detached mode");
+        timeouts.script_launch_secs
     } else {
-        info!("Executing script on VM and waiting for completion with sudo");
-        (
-            600u64,
-            Command::new("ssh")
-                .arg("-i")
-                .arg(&ssh_key_path)
-                .args(&ssh_options)
-                .arg(format!("{}@{}", login.username, ip_address))
-                .arg(format!(
-                    "chmod +x {} && sudo bash {}",
-                    remote_script_path, remote_script_path
-                ))
-                .stdout(std::process::Stdio::piped())
-                .stderr(std::process::Stdio::piped())
-                .output(),
-        )
+        info!("Executing script on VM and waiting for completion");
+        timeouts.script_secs
+    };
+
+    let mut command = Command::new("ssh");
+    command
+        .arg("-i")
+        .arg(&ssh_key_path)
+        .args(&ssh_options)
+        .arg(format!("{}@{}", login.username, ip_address))
+        .arg(remote_command)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+
+    // Blocking runs stream their output as it arrives so long provisions don't look hung
+    let script_future = async {
+        if run_detached {
+            command.output().await
+        } else {
+            stream_output(&mut command, &pool::runner_name(vm_name)).await
+        }
     };
 
     let output = tokio::time::timeout(
         tokio::time::Duration::from_secs(script_timeout_secs),
         script_future,
     )
-    .await
-    .map_err(|_| format!("Script execution timed out after {}s", script_timeout_secs))??;
-
+    .await;
+    record_phase(Phase::Script, script_start.elapsed());
+    let output = output
+        .map_err(|_| format!("Script execution timed out after {}s", script_timeout_secs))??;
+
+    // A detached script still reads its file; the monitor removes it once it exits
+    if !run_detached || !output.status.success() {
+        remote_script.clean_up(ip_address, login).await;
+    }
     if !output.status.success() {
         let error_msg = String::from_utf8_lossy(&output.stderr);
         return Err(format!("Script execution failed: {}", error_msg).into());
@@ -1515,13 +4110,274 @@ async fn run_script_on_vm_meda(
 
     let script_output = String::from_utf8_lossy(&output.stdout).to_string();
     info!("Script execution completed successfully.");
+    if run_detached {
+        script_monitor::watch_detached(
+            &pool::runner_name(vm_name),
+            ip_address,
+            login,
+            &script_output,
+            remote_script,
+        );
+    }
     Ok(script_output)
 }
 
+/// Print the provider's health, checked now and as the agent last recorded it. Returns the
+/// exit code: 1 when the provider does not answer.
+async fn show_status() -> i32 {
+    // Read first, since the check below records its own outcome
+    let recorded = provider_health::persisted();
+    let running = provider_running(if use_meda() {
+        meda::setup::is_meda_running
+    } else {
+        lume::setup::is_lume_running
+    })
+    .await;
+    let version = tokio::task::spawn_blocking(build_info::installed_provider_version)
+        .await
+        .ok()
+        .flatten();
+    let listed = if use_meda() {
+        match MedaClient::new() {
+            Ok(meda) => meda.list_vms().await.map(|vms| vms.len()),
+            Err(e) => Err(e),
+        }
+        .map_err(|e| e.to_string())
+    } else {
+        match LumeClient::new() {
+            Ok(lume) => lume.list_vms().await.map(|vms| vms.len()),
+            Err(e) => Err(e),
+        }
+        .map_err(|e| e.to_string())
+    };
+
+    println!(
+        "Provider:        {} {}",
+        build_info::provider_name(),
+        version.as_deref().unwrap_or("(version unknown)")
+    );
+    println!(
+        "Process:         {}",
+        if running { "running" } else { "not running" }
+    );
+    match &listed {
+        Ok(count) => println!("API:             reachable ({} VMs)", count),
+        Err(e) => println!("API:             not reachable: {}", e),
+    }
+    match recorded {
+        Some(health) => {
+            let at = |time: Option<chrono::DateTime<chrono::Utc>>| {
+                time.map_or("never".to_string(), |time| time.to_rfc3339())
+            };
+            println!(
+                "Agent recorded:  {} at {}",
+                if health.reachable {
+                    "reachable"
+                } else {
+                    "not reachable"
+                },
+                health.checked_at.to_rfc3339()
+            );
+            println!("Last success:    {}", at(health.last_success_at));
+            if let Some(error) = &health.last_error {
+                println!("Last error:      {} ({})", error, at(health.last_error_at));
+            }
+        }
+        None => println!("Agent recorded:  nothing yet"),
+    }
+    i32::from(listed.is_err())
+}
+
+/// Print usage accounting for the last `days` days from the local state store
+fn show_usage(days: i64) {
+    let now = chrono::Utc::now();
+    let since = now - chrono::Duration::days(days);
+
+    StateStore::new().read(|state| {
+        let active: Vec<_> = state
+            .runners
+            .iter()
+            .map(|(name, record)| (name.clone(), record.clone()))
+            .collect();
+
+        println!("Runner usage for the last {} days", days);
+        println!(
+            "{:<40} {:>10} {:>5} {:>8} {:>8} {:>12}",
+            "RUNNER", "HOURS", "CPU", "MEM(GB)", "DISK(GB)", "PROVISION(s)"
+        );
+        for record in &state.usage_history {
+            let summary = summarize(std::slice::from_ref(record), &[], since, now);
+            if summary.runner_count == 0 {
+                continue;
+            }
+            println!(
+                "{:<40} {:>10.2} {:>5} {:>8} {:>8} {:>12}",
+                record.runner_name,
+                summary.vm_hours,
+                record.cpu,
+                record.memory.as_gb(),
+                record.disk.as_gb(),
+                record
+                    .provision_duration_secs
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|| "-".to_string())
+            );
+        }
+        for (name, record) in &active {
+            let summary = summarize(&[], &[(name.clone(), record.clone())], since, now);
+            println!(
+                "{:<40} {:>10.2} {:>5} {:>8} {:>8} {:>12} (running)",
+                name,
+                summary.vm_hours,
+                record.cpu,
+                record.memory.as_gb(),
+                record.disk.as_gb(),
+                record
+                    .provision_duration_secs
+                    .map(|s| s.to_string())
+                    .unwrap_or_else(|| "-".to_string())
+            );
+        }
+
+        let total = summarize(&state.usage_history, &active, since, now);
+        println!();
+        println!("Runners:         {}", total.runner_count);
+        println!("VM hours:        {:.2}", total.vm_hours);
+        println!("CPU hours:       {:.2}", total.cpu_hours);
+        println!("Memory GB-hours: {:.2}", total.memory_gb_hours);
+        println!("Disk GB-hours:   {:.2}", total.disk_gb_hours);
+        if let Some(avg) = total.avg_provision_secs {
+            println!("Avg provision:   {:.1}s", avg);
+        }
+    });
+}
+
+/// Provision and delete `runs` runners based on `runner`, one after another, timing every
+/// phase. The first run includes preparing the image or template; later runs reuse it.
+async fn run_provision_benchmark(
+    client: &CirunClient,
+    runner: RunnerToProvision,
+    runs: u32,
+) -> Vec<ProvisionRun> {
+    let prefix = naming::vm_name("bench-", &Uuid::new_v4().simple().to_string()[..8]);
+    let mut results = Vec::new();
+    for run in 1..=runs {
+        let runner = RunnerToProvision {
+            name: format!("{}-{}", prefix, run),
+            ..runner.clone()
+        };
+        let runner_name = runner.name.clone();
+        info!(
+            "Benchmark run {}/{}: provisioning '{}'",
+            run, runs, runner_name
+        );
+
+        let started = std::time::Instant::now();
+        let (outcome, timings) =
+            measure_phases(provision_runner(runner, client.agent.clone())).await;
+        let mut error = outcome.err();
+        if error.is_some() {
+            let _ = CirunClient::cleanup_failed_runner(&runner_name).await;
+        }
+
+        let delete_started = std::time::Instant::now();
+        let delete_secs = match error {
+            Some(_) => None,
+            None => match client.delete_runner(&runner_name).await {
+                Ok(()) => Some(delete_started.elapsed().as_secs_f64()),
+                Err(e) => {
+                    error = Some(format!("delete failed: {}", e));
+                    None
+                }
+            },
+        };
+        if let Some(e) = &error {
+            warn!("Benchmark run {}/{} failed: {}", run, runs, e);
+        }
+        results.push(ProvisionRun {
+            timings,
+            delete_secs,
+            total_secs: started.elapsed().as_secs_f64(),
+            error,
+        });
+    }
+    results
+}
+
+/// The configuration file at `path` (or the default one) with command line overrides applied
+fn load_config(
+    args: &Args,
+    path: Option<&Path>,
+) -> Result<AgentConfig, Box<dyn std::error::Error>> {
+    let mut config = AgentConfig::load(path)?;
+    for (name, secs) in &args.timeouts {
+        // Names were validated by the argument parser
+        let _ = config.timeouts.set(name, *secs);
+    }
+    if args.provider_as_service {
+        config.provider.service = true;
+    }
+    if args.no_cache {
+        config.lume.pull_cache = false;
+    }
+    Ok(config)
+}
+
+/// Validate a configuration file and print the effective configuration. Returns the exit
+/// code: 0 when valid, 1 when it has errors.
+fn check_config(args: &Args, path: Option<&Path>) -> i32 {
+    let config = match load_config(args, path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return 1;
+        }
+    };
+    let shown_path = path
+        .map(Path::to_path_buf)
+        .unwrap_or_else(config::default_config_path);
+    println!("# Effective configuration from {}", shown_path.display());
+    if !args.timeouts.is_empty() || args.provider_as_service || args.no_cache {
+        println!("# with command line overrides applied");
+    }
+    for (setting, var, set) in config.env_references() {
+        println!(
+            "# {} reads ${} ({})",
+            setting,
+            var,
+            if set { "set" } else { "not set" }
+        );
+    }
+    match toml::to_string_pretty(&config.redacted()) {
+        Ok(toml) => println!("\n{}", toml),
+        Err(e) => eprintln!("warning: cannot print the configuration: {}", e),
+    }
+
+    let errors = config.validate();
+    if errors.is_empty() {
+        println!("Configuration is valid");
+        return 0;
+    }
+    for error in &errors {
+        eprintln!("error: {}", error);
+    }
+    eprintln!("Configuration has {} error(s)", errors.len());
+    1
+}
+
 #[tokio::main]
 async fn main() {
-    println!("{}", CIRUN_BANNER);
     let args = Args::parse();
+    console::configure(args.quiet, args.plain);
+    // check-config and connectivity-check output is meant to be piped or diffed
+    if console::show_banner()
+        && !matches!(
+            args.command,
+            Some(Commands::CheckConfig { .. } | Commands::ConnectivityCheck)
+        )
+    {
+        println!("{}", CIRUN_BANNER);
+    }
 
     // Handle install service flag
     if args.install_service {
@@ -1535,16 +4391,124 @@ async fn main() {
         return;
     }
 
+    if let Some(days) = args.show_usage {
+        show_usage(days);
+        return;
+    }
+
+    if let Some(Commands::CheckConfig { file }) = &args.command {
+        let path = file.as_deref().or(args.config.as_deref());
+        std::process::exit(check_config(&args, path));
+    }
+
+    if let Some(Commands::ResetIdentity) = &args.command {
+        let id_file_path = resolve_id_file(&args.id_file);
+        match identity::reset(Path::new(&id_file_path)) {
+            Ok((archived, new_id)) => {
+                if let Some(archived) = archived {
+                    println!("Archived old agent ID file to {}", archived.display());
+                }
+                println!("New agent ID: {}", new_id);
+                println!("Restart the agent to register with the new identity");
+            }
+            Err(e) => {
+                eprintln!("Failed to reset agent identity: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(Commands::ExportState { file }) = &args.command {
+        let id_file_path = resolve_id_file(&args.id_file);
+        match migration::export(Path::new(&id_file_path), get_hostname(), file) {
+            Ok(bundle) => println!(
+                "Exported agent {} with {} templates and {} runners to {}",
+                bundle.identity.id,
+                bundle.templates.len(),
+                bundle.runners.len(),
+                file.display()
+            ),
+            Err(e) => {
+                eprintln!("Failed to export agent state: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(Commands::ImportState { file, force }) = &args.command {
+        let id_file_path = resolve_id_file(&args.id_file);
+        match migration::import(Path::new(&id_file_path), file, *force) {
+            Ok(summary) => {
+                if let Some(archived) = summary.archived {
+                    println!("Archived old agent ID file to {}", archived.display());
+                }
+                println!(
+                    "Imported {} templates and {} runners",
+                    summary.templates, summary.runners
+                );
+                println!("Start the agent to continue with the imported identity");
+            }
+            Err(e) => {
+                eprintln!("Failed to import agent state: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     // Initialize logger with the appropriate level
     if args.verbose {
         env::set_var("RUST_LOG", "debug");
     } else {
         env::set_var("RUST_LOG", "info");
     }
-    env_logger::init();
+    if let Err(e) = args.log_target.check_supported() {
+        eprintln!("error: {}", e);
+        std::process::exit(2);
+    }
+    diagnostics::init_logging(args.log_target);
     let version = env!("CARGO_PKG_VERSION");
     info!("Cirun Agent version: {}", version);
 
+    match load_config(&args, args.config.as_deref()) {
+        Ok(config) => set_agent_config(config),
+        Err(e) => {
+            error!("Exiting: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(profile) = &args.chaos {
+        chaos::enable(profile.clone());
+    }
+
+    if let Some(Commands::Status) = &args.command {
+        std::process::exit(show_status().await);
+    }
+
+    if let Some(Commands::ConnectivityCheck) = &args.command {
+        let report = connectivity::check(&cirun_api_url()).await;
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Failed to encode the connectivity report: {}", e),
+        }
+        for blocked in report
+            .endpoints
+            .iter()
+            .filter(|result| result.required && !result.reachable)
+        {
+            error!(
+                "Cannot reach {} ({}): {}",
+                blocked.url,
+                blocked.purpose,
+                blocked.error.as_deref().unwrap_or("no answer")
+            );
+        }
+        std::process::exit(i32::from(!report.ok));
+    }
+
     // Check if sshpass is installed (only required on macOS)
     if cfg!(target_os = "macos") && !check_sshpass_installed() {
         error!("Exiting: sshpass is required for VM provisioning on macOS");
@@ -1553,24 +4517,36 @@ async fn main() {
 
     // Get or generate a persistent agent information
     // Resolve id_file path to use HOME directory if it's relative
-    let id_file_path = if Path::new(&args.id_file).is_absolute() {
-        args.id_file.clone()
-    } else {
-        let home_dir = env::var("HOME").unwrap_or_else(|_| ".".to_string());
-        PathBuf::from(&home_dir)
-            .join(&args.id_file)
-            .to_string_lossy()
-            .to_string()
-    };
-    let agent_info = get_agent_info(&id_file_path);
+    let id_file_path = resolve_id_file(&args.id_file);
+    identity::set_id_file(Path::new(&id_file_path));
+    build_info::mark_started();
+    let agent_info = get_agent_info(&id_file_path, &args.labels).await;
+    info!(
+        "cirun-agent {} ({})",
+        build_info::VERSION,
+        agent_info.build.git_sha.unwrap_or("unknown commit")
+    );
     info!("Agent ID: {}", agent_info.id);
+    log_target::set_agent_id(&agent_info.id);
     info!("Hostname: {}", agent_info.hostname);
     info!("OS: {} ({})", agent_info.os, agent_info.arch);
 
-    let default_api_url = "https://api.cirun.io/api/v1";
-    let cirun_api_url = env::var("CIRUN_API_URL").unwrap_or_else(|_| default_api_url.to_string());
+    let cirun_api_url = cirun_api_url();
     info!("Cirun API URL: {}", cirun_api_url);
 
+    // Local subcommands (bench, tunnel, template rebuild) run without the API, so only the
+    // agent itself needs the startup checks and an API token
+    if args.command.is_none() {
+        let problems = preflight::run(&cirun_api_url).await;
+        if problems
+            .iter()
+            .any(|problem| problem.severity == preflight::Severity::Fatal)
+        {
+            error!("Exiting: fix the failed startup checks above");
+            std::process::exit(1);
+        }
+    }
+
     // Determine effective max_vms:
     // - If explicitly provided, use that value
     // - On macOS: default to 2 (Apple Virtualization Framework limit)
@@ -1586,12 +4562,83 @@ async fn main() {
         Some(limit) => info!("Max concurrent VMs: {}", limit),
         None => info!("Max concurrent VMs: unlimited"),
     }
+    for window in &args.quiet_hours {
+        info!(
+            "Quiet hours: no provisioning between {} and {}",
+            window.start.format("%H:%M"),
+            window.end.format("%H:%M")
+        );
+    }
 
-    let api_token = args
-        .api_token
-        .as_ref()
-        .expect("API token is required when not installing or uninstalling service");
-    let mut client = CirunClient::new(&cirun_api_url, api_token, agent_info, max_vms);
+    let api_token = match (&args.api_token, &args.command) {
+        (Some(api_token), _) => api_token.clone(),
+        (None, Some(_)) => String::new(),
+        (None, None) => {
+            error!("Exiting: --api-token is required");
+            std::process::exit(1);
+        }
+    };
+    let mut client = CirunClient::new(
+        &cirun_api_url,
+        &api_token,
+        agent_info,
+        max_vms,
+        args.remediation_budget,
+        args.max_vm_lifetime.map(Duration::from_secs),
+        args.quiet_hours.clone(),
+    );
+    client.allow_unmanaged_delete = args.allow_unmanaged_delete;
+    if !api_token.is_empty() {
+        let crash_agent = client.agent.clone();
+        crash::install(crash::CrashUpload {
+            url: format!("{}/agent", client.base_url),
+            api_token: client.api_token.clone(),
+            agent_id: client.agent.id.clone(),
+            agent: Box::new(move || json!(crash_agent)),
+        });
+    }
+
+    // The agent and a template rebuild both create and delete templates, so only one of
+    // them may run at a time
+    let agent_lock = match &args.command {
+        None => {
+            match state::try_lock_agent() {
+                Ok(Some(lock)) => Some(lock),
+                Ok(None) => {
+                    info!("Waiting for another cirun-agent command (e.g. a template rebuild) to finish");
+                    let locked = tokio::task::spawn_blocking(state::lock_agent)
+                        .await
+                        .map_err(|e| e.to_string())
+                        .and_then(|locked| locked.map_err(|e| e.to_string()));
+                    match locked {
+                        Ok(lock) => Some(lock),
+                        Err(e) => {
+                            error!("Failed to take the agent lock: {}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to take the agent lock: {}", e);
+                    None
+                }
+            }
+        }
+        Some(Commands::Template { .. }) => match state::try_lock_agent() {
+            Ok(Some(lock)) => Some(lock),
+            Ok(None) => {
+                eprintln!(
+                    "cirun-agent is running on this host; stop it first, or let it rebuild the template (templates_to_rebuild)"
+                );
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("Failed to take the agent lock: {}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
 
     // Set up log cleanup parameters based on platform
     let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
@@ -1626,6 +4673,10 @@ async fn main() {
         info!("Detected macOS platform - using Lume for VM management");
         lume::download_and_run_lume().await;
         log_dir = PathBuf::from(&home_dir).join(".lume/logs");
+        // Half-built templates may belong to a running agent unless this process holds the lock
+        if agent_lock.is_some() {
+            lume::cleanup_incomplete_templates().await;
+        }
 
         info!("Checking Lume connectivity...");
         match LumeClient::new() {
@@ -1651,32 +4702,252 @@ async fn main() {
         }
     }
 
+    let _ = tokio::task::spawn_blocking(build_info::refresh_provider_version).await;
+
+    if let Some(Commands::Bench {
+        image,
+        username,
+        password,
+    }) = &args.command
+    {
+        let login = RunnerLogin {
+            username: username.clone(),
+            password: password.clone(),
+            ssh: ssh::SshOverrides::default(),
+            script: vm_provision::ScriptExecution::default(),
+        };
+        match run_benchmark(image, &login).await {
+            Ok(result) => {
+                println!("Clone time:       {:.1}s", result.clone_secs);
+                println!("Boot to SSH:      {:.1}s", result.boot_to_ssh_secs);
+                println!("Disk throughput:  {:.1} MB/s", result.disk_mb_per_sec);
+                println!("Performance score: {}", result.score);
+                if !client.api_token.is_empty() {
+                    client.report_benchmark(&result).await;
+                }
+            }
+            Err(e) => {
+                error!("Benchmark failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(Commands::Template {
+        action:
+            TemplateAction::Rebuild {
+                target,
+                username,
+                password,
+            },
+    }) = &args.command
+    {
+        if use_meda() {
+            eprintln!("Templates are only used on macOS (lume)");
+            std::process::exit(1);
+        }
+        let templates = lume::freshness::resolve(target);
+        if templates.is_empty() {
+            eprintln!("No tracked template is named or built from '{}'", target);
+            std::process::exit(1);
+        }
+        let login = RunnerLogin {
+            username: username.clone(),
+            password: password.clone(),
+            ssh: ssh::SshOverrides::default(),
+            script: vm_provision::ScriptExecution::default(),
+        };
+        let mut failed = false;
+        for template in templates {
+            match lume::freshness::rebuild(&template, &login).await {
+                Ok(()) => println!("Rebuilt template '{}'", template),
+                Err(e) => {
+                    eprintln!("Failed to rebuild template '{}': {}", template, e);
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Commands::BenchProvision {
+        image,
+        runs,
+        cpu,
+        memory,
+        disk,
+        script,
+        username,
+        password,
+    }) = &args.command
+    {
+        let runner = RunnerToProvision {
+            name: String::new(),
+            provision_script: script.clone(),
+            image: image.clone(),
+            os: String::new(),
+            cpu: *cpu,
+            memory: *memory,
+            disk: *disk,
+            login: RunnerLogin {
+                username: username.clone(),
+                password: password.clone(),
+                ssh: ssh::SshOverrides::default(),
+                script: vm_provision::ScriptExecution::default(),
+            },
+            files: Vec::new(),
+            max_retries: None,
+            labels: Vec::new(),
+            dedicated_cpus: false,
+            qos: QosLimits::default(),
+            no_cache: false,
+        };
+        let results = run_provision_benchmark(&client, runner, *runs).await;
+        let failures: Vec<&String> = results.iter().filter_map(|r| r.error.as_ref()).collect();
+
+        println!(
+            "{:<16} {:>5} {:>9} {:>9} {:>9}",
+            "Phase", "runs", "p50", "p90", "max"
+        );
+        for stats in phase_stats(&results) {
+            println!(
+                "{:<16} {:>5} {:>8.1}s {:>8.1}s {:>8.1}s",
+                stats.phase, stats.runs, stats.p50, stats.p90, stats.max
+            );
+        }
+        println!("Failed runs: {}/{}", failures.len(), results.len());
+        if let Some(last) = failures.last() {
+            println!("Last error: {}", last);
+        }
+        if failures.len() == results.len() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(Commands::Tunnel { runner, ttl, port }) = &args.command {
+        let request = TunnelRequest {
+            id: None,
+            runner_name: runner.clone(),
+            ttl_secs: *ttl,
+            local_port: *port,
+            requested_by: Some(format!(
+                "cli:{}",
+                env::var("USER").unwrap_or_else(|_| "unknown".to_string())
+            )),
+        };
+        let tunnel = match tunnels::open(request).await {
+            Ok(tunnel) => tunnel,
+            Err(e) => {
+                error!("Failed to open tunnel: {}", e);
+                std::process::exit(1);
+            }
+        };
+        println!(
+            "Tunnel to '{}' listening on {}:{} until {}",
+            tunnel.runner_name,
+            tunnel.address,
+            tunnel.port,
+            tunnel.expires_at.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+        println!(
+            "Connect with: ssh -p {} <user>@{}",
+            tunnel.port, tunnel.address
+        );
+        let remaining = (tunnel.expires_at - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        tokio::select! {
+            _ = sleep(remaining) => {}
+            _ = tokio::signal::ctrl_c() => {
+                tunnels::close(&tunnel.id, "interrupted");
+            }
+        }
+        return;
+    }
+
+    // Handle SIGINT/SIGTERM ourselves so a supervised provider server can be stopped cleanly
+    let mut shutdown = ShutdownSignals::install();
+
+    // Guests report boot completion to the agent when readiness uses boot beacons
+    readiness::start_beacon_listener();
+    client.recover_interrupted_runners().await;
+    script_monitor::resume_all();
+    crash::queue_unsent_reports();
+    events::start_subscribers(&client.agent.hostname);
+
     let mut last_cleanup = SystemTime::now();
+    let mut last_upgrade_check: Option<SystemTime> = None;
     let cleanup_interval = Duration::from_secs(24 * 60 * 60); // Daily log cleanup
 
+    // Templates are checked against the registry once at startup, then every check_hours
+    let mut last_freshness_check: Option<SystemTime> = None;
+    let freshness_interval =
+        Duration::from_secs(agent_config().lume.freshness.check_hours * 60 * 60);
+
+    let mut last_usage_report = SystemTime::now();
+    let usage_report_interval = Duration::from_secs(args.usage_report_interval);
+
+    // Health checks run in the background so slow SSH probes never delay polling
+    let mut health_set: JoinSet<Vec<RunnerHealth>> = JoinSet::new();
+    let mut command_set: JoinSet<CommandResponse> = JoinSet::new();
+    let mut last_health_check = SystemTime::now();
+    let health_check_interval = Duration::from_secs(args.health_check_interval);
+
+    // Guest metrics are sampled in the background for the same reason
+    let mut metrics_set: JoinSet<Vec<RunnerMetrics>> = JoinSet::new();
+    let mut last_metrics_sample = SystemTime::now();
+    let metrics_interval = Duration::from_secs(agent_config().metrics.interval_secs);
+
     // Persistent JoinSet for provisioning tasks — lives across loop iterations
     // so in-flight tasks don't block polling.
     let mut provision_set: JoinSet<ProvisionResult> = JoinSet::new();
+    // Provision output streamed by running tasks, forwarded to the API each iteration
+    let mut log_receiver = init_log_stream();
     // Track runner names currently being provisioned to avoid spawning duplicates.
     let mut in_flight: std::collections::HashSet<String> = std::collections::HashSet::new();
 
+    // Status reports subscribe to runner events so VM changes reach the API promptly
+    let mut report_events = events::subscribe();
+    let mut template_failure_events = events::subscribe();
+
+    // Polls and VM reports each run on their own schedule
+    let mut poll_cadence = Cadence::new(Duration::from_secs(args.interval), &agent_config().poll);
+    let mut report_cadence = Cadence::new(
+        Duration::from_secs(args.report_interval),
+        &agent_config().report,
+    );
+
     // Main loop
     loop {
         // Drain completed provisioning results (non-blocking)
-        let mut any_provision_succeeded = false;
         while let Some(result) = provision_set.try_join_next() {
             match result {
                 Ok(pr) => {
                     in_flight.remove(&pr.runner_name);
+                    client.release_lease(&pr.runner_name).await;
                     match pr.outcome {
-                        Ok(()) => {
-                            client.clear_retry(&pr.runner_name);
-                            any_provision_succeeded = true;
-                        }
+                        Ok(()) => retry_budget::clear(&pr.runner_name),
+                        Err(_) if pr.cancelled => {}
                         Err(error_msg) => {
-                            let attempt = client.increment_retry(&pr.runner_name);
+                            if let Some(bundle) = &pr.diagnostics {
+                                if agent_config().diagnostics.upload {
+                                    client.upload_diagnostics(&pr.runner_name, bundle).await;
+                                }
+                            }
+                            let attempt = retry_budget::record_failure(&pr.runner_name, &error_msg);
                             client
-                                .notify_provision_failure(&pr.runner_name, error_msg, attempt)
+                                .notify_provision_failure(
+                                    &pr.runner_name,
+                                    error_msg,
+                                    attempt,
+                                    Some(&pr.timings),
+                                    pr.failed_stage,
+                                )
                                 .await;
                         }
                     }
@@ -1687,39 +4958,107 @@ async fn main() {
             }
         }
 
-        if any_provision_succeeded {
-            client.report_running_vms().await;
+        for failure in events::template_failures(&mut template_failure_events) {
+            client.notify_template_creation_failed(&failure).await;
         }
 
-        match client
-            .manage_runner_lifecycle(&mut provision_set, &mut in_flight)
-            .await
+        if events::vms_changed(&mut report_events) {
+            report_cadence.trigger(REPORT_DEBOUNCE);
+        }
+
+        // Upgrading restarts the provider, so only check while no operation is in flight
+        let upgrade_due = last_upgrade_check.is_none_or(|checked| {
+            SystemTime::now()
+                .duration_since(checked)
+                .is_ok_and(|duration| duration >= cleanup_interval)
+        });
+        if upgrade_due && in_flight.is_empty() && health_set.is_empty() && upgrade_provider().await
         {
-            Ok(response) => {
-                info!(
-                    "Attempted runners to provision: {}",
-                    response.runners_to_provision.len()
-                );
-                info!(
-                    "Attempted runners to delete: {}",
-                    response.runners_to_delete.len()
-                );
+            last_upgrade_check = Some(SystemTime::now());
+        }
+
+        let log_lines = drain_log_lines(&mut log_receiver);
+        if !log_lines.is_empty() {
+            client.stream_runner_logs(&log_lines).await;
+        }
+
+        if poll_cadence.is_due() {
+            match client
+                .manage_runner_lifecycle(&mut provision_set, &mut in_flight)
+                .await
+            {
+                Ok(response) => {
+                    let idle = response.runners_to_provision.is_empty()
+                        && response.runners_to_delete.is_empty()
+                        && provision_set.is_empty();
+                    if idle {
+                        poll_cadence.idle();
+                    } else {
+                        poll_cadence.succeeded();
+                    }
+                    let server_secs = |secs: u64| {
+                        Duration::from_secs(secs.clamp(MIN_SERVER_POLL_SECS, MAX_SERVER_POLL_SECS))
+                    };
+                    poll_cadence.server_interval(response.poll_interval.map(server_secs));
+                    if let Some(delay) = response.next_poll_after.map(server_secs) {
+                        debug!("API asked for the next poll in {:?}", delay);
+                        poll_cadence.defer(delay);
+                    }
+                    for (request, login) in client.new_commands(response.commands_to_run) {
+                        command_set.spawn(commands::execute(request, login));
+                    }
+                    chatty!(
+                        "Attempted runners to provision: {}",
+                        response.runners_to_provision.len()
+                    );
+                    chatty!(
+                        "Attempted runners to delete: {}",
+                        response.runners_to_delete.len()
+                    );
+                }
+                Err(e) => {
+                    poll_cadence.failed();
+                    error!("Error fetching command: {}", e);
+                }
+            }
+
+            client.renew_leases(&in_flight).await;
+
+            client.retry_pending_deletions().await;
+
+            if agent_config().pool.enabled {
+                pool::trim().await;
+            }
+
+            client.enforce_vm_lifetime(&in_flight).await;
+
+            if events::vms_changed(&mut report_events) {
+                report_cadence.trigger(REPORT_DEBOUNCE);
             }
-            Err(e) => error!("Error fetching command: {}", e),
         }
 
-        // Report running VMs after all operations
-        client.report_running_vms().await;
+        // One report covers every VM change so far
+        if report_cadence.is_due() {
+            if client.report_running_vms().await {
+                report_cadence.succeeded();
+            } else {
+                report_cadence.failed();
+            }
+            events::vms_changed(&mut report_events);
+        }
 
         // Check if it's time to clean up logs
         if let Ok(duration) = SystemTime::now().duration_since(last_cleanup) {
             if duration >= cleanup_interval {
                 let cleanup_result = if use_meda() {
-                    cleanup_meda_logs(&log_dir, 7, 100)
+                    cleanup_meda_logs(&log_dir, LOG_RETENTION_DAYS, LOG_ROTATE_SIZE_MB)
                 } else {
-                    cleanup_lume_logs(&log_dir, 7, 100)
+                    cleanup_lume_logs(&log_dir, LOG_RETENTION_DAYS, LOG_ROTATE_SIZE_MB)
                 };
 
+                let cleanup_result = cleanup_result
+                    .and_then(|_| cleanup_runner_logs(LOG_RETENTION_DAYS, LOG_ROTATE_SIZE_MB));
+
                 match cleanup_result {
                     // Keep logs for 7 days, rotate at 100MB
                     Ok(_) => {
@@ -1731,7 +5070,107 @@ async fn main() {
             }
         }
 
-        sleep(Duration::from_secs(args.interval)).await;
+        let freshness_due = last_freshness_check.is_none_or(|checked| {
+            SystemTime::now()
+                .duration_since(checked)
+                .is_ok_and(|duration| duration >= freshness_interval)
+        });
+        if !use_meda() && agent_config().lume.freshness.check_hours > 0 && freshness_due {
+            cancel::spawn_provider_task(lume::freshness::check_all());
+            last_freshness_check = Some(SystemTime::now());
+        }
+
+        if args.usage_report_interval > 0 {
+            if let Ok(duration) = SystemTime::now().duration_since(last_usage_report) {
+                if duration >= usage_report_interval {
+                    client.report_usage().await;
+                    last_usage_report = SystemTime::now();
+                }
+            }
+        }
+
+        let mut command_results = Vec::new();
+        while let Some(result) = command_set.try_join_next() {
+            match result {
+                Ok(response) => command_results.push(response),
+                Err(e) => error!("Command task panicked: {}", e),
+            }
+        }
+        client.report_command_results(command_results).await;
+
+        // Report results of a finished health check pass
+        while let Some(result) = health_set.try_join_next() {
+            match result {
+                Ok(results) => {
+                    client.report_runner_health(&results).await;
+                    client.remediate_crashed_runners(&results, &in_flight).await;
+                }
+                Err(e) => error!("Health check task panicked: {}", e),
+            }
+        }
+
+        // Start a new health check pass if one is due and none is running
+        if args.health_check_interval > 0 && health_set.is_empty() {
+            if let Ok(duration) = SystemTime::now().duration_since(last_health_check) {
+                if duration >= health_check_interval {
+                    let runners = client.runners_for_health_check(&in_flight);
+                    if !runners.is_empty() {
+                        health_set.spawn(check_runners(runners));
+                    }
+                    last_health_check = SystemTime::now();
+                }
+            }
+        }
+
+        while let Some(result) = metrics_set.try_join_next() {
+            match result {
+                Ok(metrics) => client.report_guest_metrics(&metrics).await,
+                Err(e) => error!("Guest metrics task panicked: {}", e),
+            }
+        }
+
+        if agent_config().metrics.enabled && metrics_set.is_empty() {
+            if let Ok(duration) = SystemTime::now().duration_since(last_metrics_sample) {
+                if duration >= metrics_interval {
+                    let runners = client.runners_for_metrics(&in_flight);
+                    if !runners.is_empty() {
+                        metrics_set.spawn(sample_runners(runners));
+                    }
+                    last_metrics_sample = SystemTime::now();
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = sleep_until(poll_cadence.due_at().min(report_cadence.due_at())) => {}
+            _ = events::next_vm_change(&mut report_events) => {
+                report_cadence.trigger(REPORT_DEBOUNCE);
+            }
+            signal = shutdown.recv() => {
+                info!("Received {}, shutting down", signal);
+                break;
+            }
+        }
+    }
+
+    // Stop provisioning, image pulls and other provider work, and give the tasks a moment
+    // to unwind before the process exits
+    cancel::agent().cancel();
+    let drained = tokio::time::timeout(Duration::from_secs(SHUTDOWN_GRACE_SECS), async {
+        while provision_set.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "Provisioning tasks did not stop within {}s of shutdown",
+            SHUTDOWN_GRACE_SECS
+        );
+    }
+
+    client.deregister("shutdown", &in_flight).await;
+
+    if agent_config().provider.stop_on_exit {
+        supervisor::shutdown().await;
     }
 }
 
@@ -1748,9 +5187,10 @@ mod tests {
             registry: Some("ghcr.io".to_string()),
             organization: Some("cirunlabs".to_string()),
             cpu: 4,
-            memory: 8,
-            disk: 100,
+            memory: Memory::from_gb(8),
+            disk: DiskSize::from_gb(100),
             os: "macOS".to_string(),
+            no_cache: false,
         };
 
         let config2 = TemplateConfig {
@@ -1758,9 +5198,10 @@ mod tests {
             registry: Some("ghcr.io".to_string()),
             organization: Some("cirunlabs".to_string()),
             cpu: 4,
-            memory: 8,
-            disk: 100,
+            memory: Memory::from_gb(8),
+            disk: DiskSize::from_gb(100),
             os: "macOS".to_string(),
+            no_cache: false,
         };
 
         let config3 = TemplateConfig {
@@ -1768,9 +5209,10 @@ mod tests {
             registry: Some("ghcr.io".to_string()),
             organization: Some("cirunlabs".to_string()),
             cpu: 8, // Different CPU
-            memory: 8,
-            disk: 100,
+            memory: Memory::from_gb(8),
+            disk: DiskSize::from_gb(100),
             os: "macOS".to_string(),
+            no_cache: false,
         };
 
         // Same configs should produce same template names
@@ -1890,23 +5332,111 @@ mod tests {
         assert_eq!(hash1, hash2);
     }
 
-    // Mock tests that would require integration testing
     #[test]
-    fn test_agent_info_creation() {
+    fn test_provision_marker_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = StateStore::with_path(dir.path().join("state.json"));
+        let hash = script_hash("#!/bin/bash\necho hello");
+
+        assert!(!store.is_provisioned("cirun-runner-1", &hash));
+
+        store.mark_provisioned("cirun-runner-1", &hash);
+        assert!(store.is_provisioned("cirun-runner-1", &hash));
+        // A changed script should not be treated as already provisioned
+        assert!(!store.is_provisioned("cirun-runner-1", &script_hash("echo changed")));
+
+        store.clear_runner("cirun-runner-1");
+        assert!(!store.is_provisioned("cirun-runner-1", &hash));
+    }
+
+    #[test]
+    fn test_existing_vm_action() {
+        assert_eq!(existing_vm_action("stopped", None), ExistingVm::Provision);
+        assert_eq!(existing_vm_action("starting", None), ExistingVm::Settling);
+        assert_eq!(
+            existing_vm_action("running", Some(ScriptStatus::Running)),
+            ExistingVm::Attach
+        );
+        // A running VM whose script failed, or never ran, is not a provisioned runner
+        assert_eq!(
+            existing_vm_action("running", Some(ScriptStatus::Failed { exit_code: Some(1) })),
+            ExistingVm::Conflict
+        );
+        assert_eq!(existing_vm_action("running", None), ExistingVm::Conflict);
+        assert_eq!(existing_vm_action("error", None), ExistingVm::Conflict);
+    }
+
+    #[test]
+    fn test_reprovision_only_while_desired() {
+        let agent: AgentInfo = serde_json::from_value(json!({
+            "id": "agent-1",
+            "hostname": "host",
+            "os": "linux",
+            "arch": "x86_64",
+        }))
+        .unwrap();
+        let mut client = CirunClient::new(
+            "http://localhost",
+            "token",
+            agent,
+            None,
+            Some(2),
+            None,
+            vec![],
+        );
+        let runner = |name: &str| -> RunnerToProvision {
+            serde_json::from_value(json!({
+                "name": name,
+                "image": "ubuntu-24",
+                "provision_script": "",
+                "login": {"username": "runner", "password": "secret"},
+                "cpu": 2,
+                "memory": 4096,
+                "disk": 20,
+            }))
+            .unwrap()
+        };
+        for name in ["remediate-kept-7f3a", "remediate-deleted-7f3a"] {
+            client
+                .reprovision_queue
+                .insert(name.to_string(), runner(name));
+        }
+        client.last_deletions = ["remediate-deleted-7f3a".to_string()].into();
+
+        // A runner the API also requests is not added twice
+        let mut runners_to_provision = vec![runner("remediate-kept-7f3a")];
+        client.queue_reprovisions(&mut runners_to_provision);
+        assert_eq!(runners_to_provision.len(), 1);
+        let mut runners_to_provision = Vec::new();
+        client.queue_reprovisions(&mut runners_to_provision);
+        let names: Vec<&str> = runners_to_provision
+            .iter()
+            .map(|r| r.name.as_str())
+            .collect();
+        assert_eq!(names, ["remediate-kept-7f3a"]);
+        assert!(!client
+            .reprovision_queue
+            .contains_key("remediate-deleted-7f3a"));
+    }
+
+    // Mock tests that would require integration testing
+    #[tokio::test]
+    async fn test_agent_info_creation() {
         let id_file = ".test_agent_id";
 
         // Cleanup in case file exists
         let _ = std::fs::remove_file(id_file);
 
         // First call should generate a new ID
-        let agent_info1 = get_agent_info(id_file);
+        let agent_info1 = get_agent_info(id_file, &[]).await;
         assert!(!agent_info1.id.is_empty());
 
         // Second call should use the same ID
-        let agent_info2 = get_agent_info(id_file);
+        let agent_info2 = get_agent_info(id_file, &[]).await;
         assert_eq!(agent_info1.id, agent_info2.id);
 
         // Clean up
         let _ = std::fs::remove_file(id_file);
+        let _ = std::fs::remove_file(format!("{}.lock", id_file));
     }
 }
@@ -1,12 +1,17 @@
-use backon::{ExponentialBuilder, Retryable};
 use log::{info, warn};
 use reqwest::Client;
 use std::time::Duration;
 
+use crate::chaos;
+use crate::config::agent_config;
 use crate::meda::errors::MedaError;
 use crate::meda::models::{
-    VmCreateRequest, VmDetailResponse, VmInfo, VmListResponse, VmRunRequest,
+    ImageImportRequest, ImagePullRequest, VmCreateRequest, VmDetailResponse, VmInfo,
+    VmListResponse, VmRunRequest,
 };
+use crate::provider_auth;
+use crate::provider_health;
+use crate::retry::with_retries;
 
 const DEFAULT_API_URL: &str = "http://127.0.0.1:7777/api/v1";
 const CONNECT_TIMEOUT: u64 = 10; // 10 seconds
@@ -19,7 +24,7 @@ pub struct MedaClient {
 
 impl MedaClient {
     pub fn new() -> Result<Self, MedaError> {
-        Self::with_base_url(DEFAULT_API_URL)
+        Self::with_base_url(&provider_auth::api_url(DEFAULT_API_URL))
     }
 
     #[allow(dead_code)]
@@ -28,12 +33,14 @@ impl MedaClient {
     }
 
     pub fn with_base_url(base_url: &str) -> Result<Self, MedaError> {
-        let client = Client::builder()
+        let builder = Client::builder()
             .timeout(Duration::from_secs(MAX_TIMEOUT))
             .connect_timeout(Duration::from_secs(CONNECT_TIMEOUT))
             .pool_idle_timeout(Duration::from_secs(90))
             .pool_max_idle_per_host(10)
-            .tcp_keepalive(Duration::from_secs(60))
+            .tcp_keepalive(Duration::from_secs(60));
+        let client = provider_auth::configure_client(builder)
+            .map_err(MedaError::ApiError)?
             .build()
             .map_err(MedaError::from)?;
 
@@ -48,6 +55,7 @@ impl MedaClient {
     pub async fn create_vm(&self, config: VmCreateRequest) -> Result<(), MedaError> {
         let url = format!("{}/vms", self.base_url);
 
+        chaos::provider_call(&url)?;
         let response = self.client.post(&url).json(&config).send().await?;
 
         if !response.status().is_success() {
@@ -71,6 +79,7 @@ impl MedaClient {
 
         info!("Running VM from image: {}", config.image);
 
+        chaos::provider_call(&url)?;
         let response = self.client.post(&url).json(&config).send().await?;
         let status = response.status();
         let response_text = response
@@ -94,12 +103,61 @@ impl MedaClient {
         Ok(())
     }
 
+    /// Pull an image from a registry, optionally with credentials
+    pub async fn pull_image(&self, request: ImagePullRequest) -> Result<(), MedaError> {
+        let url = format!("{}/images/pull", self.base_url);
+
+        info!("Pulling image: {}", request.image);
+
+        chaos::provider_call(&url)?;
+        let response = self.client.post(&url).json(&request).send().await?;
+
+        if !response.status().is_success() {
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(MedaError::ApiError(format!(
+                "Failed to pull image: {}",
+                error_text
+            )));
+        }
+
+        info!("Successfully pulled image: {}", request.image);
+        Ok(())
+    }
+
+    /// Import a local disk image file under the given image name
+    pub async fn import_image(&self, request: ImageImportRequest) -> Result<(), MedaError> {
+        let url = format!("{}/images/import", self.base_url);
+
+        info!("Importing image '{}' from {}", request.name, request.path);
+
+        chaos::provider_call(&url)?;
+        let response = self.client.post(&url).json(&request).send().await?;
+
+        if !response.status().is_success() {
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(MedaError::ApiError(format!(
+                "Failed to import image: {}",
+                error_text
+            )));
+        }
+
+        info!("Successfully imported image: {}", request.name);
+        Ok(())
+    }
+
     /// Start an existing VM
     pub async fn start_vm(&self, name: &str) -> Result<(), MedaError> {
         let url = format!("{}/vms/{}/start", self.base_url, name);
 
         info!("Starting VM: {}", name);
 
+        chaos::provider_call(&url)?;
         let response = self.client.post(&url).send().await?;
         let status = response.status();
         let response_text = response
@@ -130,6 +188,7 @@ impl MedaClient {
 
         info!("Stopping VM: {}", name);
 
+        chaos::provider_call(&url)?;
         let response = self.client.post(&url).send().await?;
 
         if !response.status().is_success() {
@@ -153,63 +212,54 @@ impl MedaClient {
 
         info!("Deleting VM {}", name);
 
-        let send_delete_request =
-            || async {
-                let response =
-                    self.client.delete(&url).send().await.map_err(|e| {
-                        MedaError::ApiError(format!("HTTP request failed: {:?}", e))
-                    })?;
+        let send_delete_request = || async {
+            chaos::provider_call(&url)?;
+            let response = self.client.delete(&url).send().await?;
 
-                let status = response.status();
-                let response_text = response
-                    .text()
-                    .await
-                    .unwrap_or_else(|_| "Unknown error".to_string());
-
-                info!("Delete operation response status: {}", status);
-                info!("Delete operation response body: {}", response_text);
+            let status = response.status();
+            info!("Delete operation response status: {}", status);
+            if !status.is_success() {
+                return Err(MedaError::from_response("Failed to delete VM", response).await);
+            }
+            Ok(())
+        };
 
-                if !status.is_success() {
-                    return Err(MedaError::ApiError(format!(
-                        "Failed to delete VM: {}",
-                        response_text
-                    )));
-                }
-                Ok(())
-            };
-
-        // Retry logic with proper error conversion
-        send_delete_request
-            .retry(ExponentialBuilder::default().with_max_times(5))
-            .sleep(tokio::time::sleep)
-            .when(|e| matches!(e, MedaError::ApiError(_)))
-            .notify(|err, dur| warn!("Retrying VM deletion after {:?}: {:?}", dur, err))
-            .await
-            .map_err(|e| MedaError::ApiError(format!("Retry exhausted: {:?}", e)))?;
+        with_retries(
+            "VM deletion",
+            agent_config().retry.provider_api,
+            send_delete_request,
+        )
+        .await?;
 
         info!("VM {} successfully deleted", name);
         Ok(())
     }
 
-    /// List all VMs
+    /// List all VMs. The outcome is recorded as the provider's health.
     pub async fn list_vms(&self) -> Result<Vec<VmInfo>, MedaError> {
-        let url = format!("{}/vms", self.base_url);
+        let list = async {
+            let url = format!("{}/vms", self.base_url);
 
-        let response = self.client.get(&url).send().await?;
+            chaos::provider_call(&url)?;
+            let response = self.client.get(&url).send().await?;
 
-        if !response.status().is_success() {
-            let error_text = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(MedaError::ApiError(format!(
-                "Failed to list VMs: {}",
-                error_text
-            )));
-        }
+            if !response.status().is_success() {
+                let error_text = response
+                    .text()
+                    .await
+                    .unwrap_or_else(|_| "Unknown error".to_string());
+                return Err(MedaError::ApiError(format!(
+                    "Failed to list VMs: {}",
+                    error_text
+                )));
+            }
 
-        let vm_list = response.json::<VmListResponse>().await?;
-        Ok(vm_list.vms)
+            let vm_list = response.json::<VmListResponse>().await?;
+            Ok(vm_list.vms)
+        };
+        let result = list.await;
+        provider_health::record(&result);
+        result
     }
 
     /// Get details of a specific VM
@@ -217,53 +267,16 @@ impl MedaClient {
         info!("Getting VM details for {}", name);
         let url = format!("{}/vms/{}", self.base_url, name);
 
-        let max_retries = 3;
-        let mut attempts = 0;
-        let retry_delay = Duration::from_millis(300);
-
-        loop {
-            attempts += 1;
-            match self.client.get(&url).send().await {
-                Ok(response) => {
-                    if response.status().is_success() {
-                        match response.json::<VmDetailResponse>().await {
-                            Ok(vm_info) => return Ok(vm_info),
-                            Err(e) => {
-                                warn!(
-                                    "Failed to parse VM details JSON (attempt {}/{}): {:?}",
-                                    attempts, max_retries, e
-                                );
-                                if attempts >= max_retries {
-                                    return Err(MedaError::RequestError(e));
-                                }
-                            }
-                        }
-                    } else {
-                        let error_text = response
-                            .text()
-                            .await
-                            .unwrap_or_else(|_| "Unknown error".to_string());
-                        if attempts >= max_retries {
-                            return Err(MedaError::ApiError(format!(
-                                "Failed to get VM details: {}",
-                                error_text
-                            )));
-                        }
-                    }
-                }
-                Err(e) => {
-                    warn!(
-                        "Failed to get VM details (attempt {}/{}): {:?}",
-                        attempts, max_retries, e
-                    );
-                    if attempts >= max_retries {
-                        return Err(MedaError::RequestError(e));
-                    }
-                }
+        let fetch = || async {
+            chaos::provider_call(&url)?;
+            let response = self.client.get(&url).send().await?;
+            if !response.status().is_success() {
+                return Err(MedaError::from_response("Failed to get VM details", response).await);
             }
+            Ok(response.json::<VmDetailResponse>().await?)
+        };
 
-            tokio::time::sleep(retry_delay).await;
-        }
+        with_retries("VM details", agent_config().retry.provider_api, fetch).await
     }
 
     /// Wait for a VM to have an IP address
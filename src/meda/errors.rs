@@ -1,11 +1,38 @@
+use crate::chaos::Fault;
+use crate::retry::{retry_after, retryable_status, Classify};
 use reqwest::Error as ReqwestError;
+use reqwest::{Response, StatusCode};
 use serde::de::StdError;
 use std::fmt;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum MedaError {
     RequestError(ReqwestError),
     ApiError(String),
+    /// The provider answered with an error status
+    Status {
+        status: StatusCode,
+        message: String,
+        retry_after: Option<Duration>,
+    },
+}
+
+impl MedaError {
+    /// Error for a response with a failure status, e.g. "Failed to clone VM (503): busy"
+    pub async fn from_response(action: &str, response: Response) -> Self {
+        let status = response.status();
+        let retry_after = retry_after(response.headers());
+        let body = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unknown error".to_string());
+        MedaError::Status {
+            status,
+            message: format!("{} ({}): {}", action, status.as_u16(), body),
+            retry_after,
+        }
+    }
 }
 
 impl fmt::Display for MedaError {
@@ -13,6 +40,7 @@ impl fmt::Display for MedaError {
         match self {
             MedaError::RequestError(err) => write!(f, "Request error: {}", err),
             MedaError::ApiError(msg) => write!(f, "API error: {}", msg),
+            MedaError::Status { message, .. } => write!(f, "API error: {}", message),
         }
     }
 }
@@ -21,7 +49,25 @@ impl StdError for MedaError {
     fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match self {
             MedaError::RequestError(err) => Some(err),
-            MedaError::ApiError(_) => None,
+            MedaError::ApiError(_) | MedaError::Status { .. } => None,
+        }
+    }
+}
+
+impl Classify for MedaError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            MedaError::RequestError(err) => err.is_retryable(),
+            // Errors without a status, e.g. injected faults, have always been retried
+            MedaError::ApiError(_) => true,
+            MedaError::Status { status, .. } => retryable_status(*status),
+        }
+    }
+
+    fn retry_hint(&self) -> Option<Duration> {
+        match self {
+            MedaError::Status { retry_after, .. } => *retry_after,
+            _ => None,
         }
     }
 }
@@ -31,3 +77,9 @@ impl From<ReqwestError> for MedaError {
         MedaError::RequestError(error)
     }
 }
+
+impl From<Fault> for MedaError {
+    fn from(fault: Fault) -> Self {
+        MedaError::ApiError(fault.to_string())
+    }
+}
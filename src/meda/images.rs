@@ -0,0 +1,148 @@
+use log::info;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+use crate::chaos;
+use crate::config::ImageSource;
+use crate::meda::client::MedaClient;
+use crate::meda::models::{ImageImportRequest, ImagePullRequest};
+use crate::template_failure::{AtStage, TemplateError, TemplateStage};
+
+/// Directory where images downloaded from HTTP mirrors are cached
+fn image_cache_dir() -> PathBuf {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home_dir).join(".cirun-agent/images")
+}
+
+/// File name used to cache a downloaded image, keeping the extension from the URL
+fn cache_file_name(image: &str, url: &str) -> String {
+    let base: String = image
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let extension = path
+        .rsplit('/')
+        .next()
+        .and_then(|file| file.rsplit_once('.'))
+        .map(|(_, ext)| ext)
+        .filter(|ext| !ext.is_empty())
+        .unwrap_or("img");
+    format!("{}.{}", base, extension)
+}
+
+/// Download `url` to `dest`, writing to a temporary file first so partial downloads are never used
+async fn download_image(url: &str, dest: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = dest.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let partial = dest.with_extension("part");
+
+    info!("Downloading image from {} to {:?}", url, dest);
+    let mut response = reqwest::get(url).await?.error_for_status()?;
+    let mut file = tokio::fs::File::create(&partial).await?;
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    drop(file);
+
+    tokio::fs::rename(&partial, dest).await?;
+    info!("Downloaded image to {:?}", dest);
+    Ok(())
+}
+
+async fn import_file(
+    meda: &MedaClient,
+    image: &str,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !path.is_file() {
+        return Err(format!("Image file {:?} for '{}' does not exist", path, image).into());
+    }
+    meda.import_image(ImageImportRequest {
+        name: image.to_string(),
+        path: path.to_string_lossy().to_string(),
+    })
+    .await?;
+    Ok(())
+}
+
+/// Make `image` available to meda from its configured private or offline source.
+/// Returns the image reference to run the VM from.
+pub async fn prepare_image(image: &str, source: &ImageSource) -> Result<String, TemplateError> {
+    let meda = MedaClient::new().at(TemplateStage::Pull)?;
+    chaos::slow_pull(image).await;
+
+    match source {
+        ImageSource::Http { url } => {
+            let cached = image_cache_dir().join(cache_file_name(image, url));
+            if cached.is_file() {
+                info!("Using cached image {:?} for '{}'", cached, image);
+            } else {
+                download_image(url, &cached)
+                    .await
+                    .at(TemplateStage::Download)?;
+            }
+            import_file(&meda, image, &cached)
+                .await
+                .at(TemplateStage::Import)?;
+            Ok(image.to_string())
+        }
+        ImageSource::File { path } => {
+            import_file(&meda, image, path)
+                .await
+                .at(TemplateStage::Import)?;
+            Ok(image.to_string())
+        }
+        ImageSource::Registry {
+            registry,
+            image: remote_image,
+            username,
+            password_env,
+        } => {
+            let remote_image = remote_image.clone().unwrap_or_else(|| image.to_string());
+            let password = match password_env {
+                Some(var) => Some(std::env::var(var).map_err(|_| {
+                    TemplateError::new(
+                        TemplateStage::Pull,
+                        format!("Registry password variable '{}' is not set", var),
+                    )
+                })?),
+                None => None,
+            };
+            meda.pull_image(ImagePullRequest {
+                image: remote_image.clone(),
+                registry: Some(registry.clone()),
+                username: username.clone(),
+                password,
+            })
+            .await
+            .at(TemplateStage::Pull)?;
+            Ok(format!("{}/{}", registry, remote_image))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_file_name() {
+        assert_eq!(
+            cache_file_name("ubuntu:24.04", "http://mirror.local/ubuntu-24.04.qcow2"),
+            "ubuntu_24_04.qcow2"
+        );
+        assert_eq!(
+            cache_file_name("ubuntu", "http://mirror.local/images/ubuntu?token=x"),
+            "ubuntu.img"
+        );
+    }
+}
@@ -1,6 +1,7 @@
 // Re-export all public items from submodules
 pub mod client;
 pub mod errors;
+pub mod images;
 pub mod models;
 pub mod setup;
 
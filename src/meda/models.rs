@@ -1,3 +1,5 @@
+use crate::config::QosLimits;
+use crate::units::{self, DiskSize, Memory};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -25,6 +27,15 @@ pub struct VmRunRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "disk")]
     pub disk_size: Option<String>,
+    /// Host cores to pin the vCPUs to, one per vCPU
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_affinity: Option<Vec<u32>>,
+    /// NUMA node to take the VM's memory from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numa_node: Option<u32>,
+    /// Disk and network throttling
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qos: Option<QosLimits>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -33,10 +44,17 @@ pub struct VmInfo {
     pub state: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ip: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub memory: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<Memory>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cpus: Option<u32>,
+    /// Size of the disk the guest sees, when meda reports it in a form we understand
+    #[serde(
+        default,
+        deserialize_with = "units::lenient::deserialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub disk: Option<DiskSize>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -50,8 +68,57 @@ pub struct VmDetailResponse {
     pub state: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ip: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub memory: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<Memory>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cpus: Option<u32>,
+    /// Size of the disk the guest sees, when meda reports it in a form we understand
+    #[serde(
+        default,
+        deserialize_with = "units::lenient::deserialize",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub disk: Option<DiskSize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImagePullRequest {
+    pub image: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageImportRequest {
+    pub name: String,
+    pub path: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unexpected_disk_size_is_ignored() {
+        let list: VmListResponse = serde_json::from_str(
+            r#"{"vms": [
+                {"name": "a", "state": "running", "disk": "20G"},
+                {"name": "b", "state": "running", "disk": "unknown"},
+                {"name": "c", "state": "stopped", "disk": null},
+                {"name": "d", "state": "stopped"}
+            ]}"#,
+        )
+        .unwrap();
+        let disks: Vec<Option<DiskSize>> = list.vms.iter().map(|vm| vm.disk).collect();
+        assert_eq!(disks, vec![Some(DiskSize::from_gb(20)), None, None, None]);
+
+        let detail: VmDetailResponse =
+            serde_json::from_str(r#"{"name": "b", "state": "running", "disk": {"size": 1}}"#)
+                .unwrap();
+        assert_eq!(detail.disk, None);
+    }
 }
@@ -4,18 +4,25 @@ use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use std::{thread, time::Duration, time::SystemTime};
 
+use crate::config::agent_config;
+use crate::host_tools;
+use crate::provider_auth;
+use crate::provider_service::{ensure_provider_service, stop_provider_service};
+use crate::supervisor::{self, ServeSpec};
+use crate::temp_guard::TempGuard;
+use crate::upgrade::{installed_version, stop_serve};
 use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
 use std::path::Path;
 
+/// Meda release installed when `MEDA_VERSION` is not set
+const DEFAULT_MEDA_VERSION: &str = "0.3.0";
+/// Environment variable `serve` reads the API token from
+const MEDA_TOKEN_ENV: &str = "MEDA_API_TOKEN";
+
 /// Check if meda serve process is currently running
 pub fn is_meda_running() -> bool {
-    Command::new("pgrep")
-        .arg("-f")
-        .arg("meda serve")
-        .stdout(Stdio::null())
-        .status()
-        .map(|status| status.success())
-        .unwrap_or(false)
+    host_tools::process_running("meda serve")
 }
 
 pub async fn download_and_run_meda() {
@@ -124,7 +131,64 @@ pub fn cleanup_log_files(
     Ok(())
 }
 
-fn download_and_run_meda_internal() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// SHA-256 of a file as a lowercase hex string
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Find the checksum of `file_name` in `sha256sum`-style output ("<hash>  <name>")
+fn find_checksum(checksums: &str, file_name: &str) -> Option<String> {
+    checksums.lines().find_map(|line| {
+        let (hash, name) = line.trim().split_once(char::is_whitespace)?;
+        let name = name.trim().trim_start_matches('*');
+        let base = name.rsplit('/').next().unwrap_or(name);
+        (base == file_name).then(|| hash.to_lowercase())
+    })
+}
+
+/// Expected checksum for a file, preferring an explicit override from `env_var` over the
+/// checksums published with the release
+fn expected_checksum(env_var: &str, published: Option<&str>, file_name: &str) -> Option<String> {
+    std::env::var(env_var)
+        .ok()
+        .map(|hash| hash.trim().to_lowercase())
+        .or_else(|| published.and_then(|sums| find_checksum(sums, file_name)))
+}
+
+/// Refuse to use a file whose checksum is unknown or doesn't match
+fn verify_checksum(
+    path: &Path,
+    expected: Option<&str>,
+    what: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let expected = expected.ok_or_else(|| format!("No checksum available for {}", what))?;
+    let actual = sha256_file(path)?;
+    if actual != expected {
+        return Err(format!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            what, expected, actual
+        )
+        .into());
+    }
+    info!("Verified checksum of {} ({})", what, actual);
+    Ok(())
+}
+
+/// Download `url` to `dest`, failing on HTTP errors
+fn download(url: &str, dest: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    Ok(host_tools::download(url, dest)?)
+}
+
+/// Meda version to install, pinned unless `MEDA_VERSION` overrides it
+fn meda_version() -> String {
+    std::env::var("MEDA_VERSION").unwrap_or_else(|_| String::from(DEFAULT_MEDA_VERSION))
+}
+
+/// Locate an existing meda installation in the usual places or on PATH
+fn find_meda_binary() -> Result<Option<PathBuf>, Box<dyn std::error::Error + Send + Sync>> {
     let install_dir = PathBuf::from(format!("{}/.meda", std::env::var("HOME")?));
     let meda_bin_path = install_dir.join("meda");
 
@@ -153,93 +217,287 @@ fn download_and_run_meda_internal() -> Result<(), Box<dyn std::error::Error + Se
 
     // Also check if meda is in PATH
     if found_meda.is_none() {
-        if let Ok(output) = Command::new("which").arg("meda").output() {
-            if output.status.success() {
-                let path_str = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                if !path_str.is_empty() {
-                    let path = PathBuf::from(path_str);
-                    found_meda = Some(path.clone());
-                    info!("Found meda in PATH at {:?}", path);
-                }
-            }
+        if let Some(path) = host_tools::find_program("meda") {
+            info!("Found meda in PATH at {:?}", path);
+            found_meda = Some(path);
         }
     }
 
-    // If meda is not found anywhere, install it
-    if found_meda.is_none() {
-        info!("Meda not found, installing...");
+    Ok(found_meda)
+}
 
-        // Download and run the installation script
-        info!("Running meda installation script...");
+/// Install `meda_version` with its checksum-verified release install script,
+/// returning the path of the installed binary
+fn install_meda(meda_version: &str) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    info!("Installing meda version {}...", meda_version);
+
+    // Temporary directory for the installation, removed on every return path
+    let install_dir = TempGuard::dir("meda_install")?;
+    let temp_dir = install_dir.path();
+
+    let install_script = temp_dir.join("install-release.sh");
+
+    // Pin the installation script to the release tag instead of main
+    download(
+        &format!(
+            "https://raw.githubusercontent.com/cirunlabs/meda/v{}/scripts/install-release.sh",
+            meda_version
+        ),
+        &install_script,
+    )?;
+
+    // Checksums published with the release; explicit env overrides win
+    let checksums_path = temp_dir.join("SHA256SUMS");
+    let published = match download(
+        &format!(
+            "https://github.com/cirunlabs/meda/releases/download/v{}/SHA256SUMS",
+            meda_version
+        ),
+        &checksums_path,
+    ) {
+        Ok(()) => fs::read_to_string(&checksums_path).ok(),
+        Err(e) => {
+            warn!("Could not fetch published meda checksums: {}", e);
+            None
+        }
+    };
+
+    verify_checksum(
+        &install_script,
+        expected_checksum(
+            "MEDA_INSTALL_SHA256",
+            published.as_deref(),
+            "install-release.sh",
+        )
+        .as_deref(),
+        "meda installation script",
+    )?;
+
+    // Make the script executable
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mut perms = fs::metadata(&install_script)?.permissions();
+        perms.set_mode(0o755);
+        fs::set_permissions(&install_script, perms)?;
+    }
+
+    // Run the verified installation script
+    info!("Running meda installation script...");
+    let status = Command::new("bash")
+        .arg(&install_script)
+        .env("HOME", std::env::var("HOME")?)
+        .env("MEDA_VERSION", meda_version)
+        .status()?;
+
+    if !status.success() {
+        return Err("Failed to install meda".into());
+    }
 
-        // Create a temporary directory for the installation
-        let temp_dir = std::env::temp_dir().join("meda_install");
-        if temp_dir.exists() {
-            fs::remove_dir_all(&temp_dir)?;
+    // Verify the binary was installed - check multiple possible locations
+    let home_dir = std::env::var("HOME")?;
+    let possible_install_locations = vec![
+        PathBuf::from(&home_dir).join(".local/bin/meda"),
+        PathBuf::from(&home_dir).join(".cargo/bin/meda"),
+        PathBuf::from("/usr/local/bin/meda"),
+    ];
+
+    let mut installed_meda = None;
+    for location in &possible_install_locations {
+        if location.exists() {
+            installed_meda = Some(location.clone());
+            break;
         }
-        fs::create_dir_all(&temp_dir)?;
+    }
 
-        let install_script = temp_dir.join("install-meda.sh");
+    let installed_meda = installed_meda
+        .ok_or("Meda binary not found after installation in any expected location")?;
+
+    if let Err(e) = verify_checksum(
+        &installed_meda,
+        expected_checksum("MEDA_BINARY_SHA256", published.as_deref(), "meda").as_deref(),
+        "installed meda binary",
+    ) {
+        // Never leave an unverified binary where later runs would pick it up
+        let _ = fs::remove_file(&installed_meda);
+        return Err(e);
+    }
+
+    info!("Meda installed successfully at {:?}", installed_meda);
+    Ok(installed_meda)
+}
 
-        // Download the installation script
-        let status = Command::new("curl")
-            .arg("-fsSL")
-            .arg("https://raw.githubusercontent.com/cirunlabs/meda/main/scripts/install-release.sh")
-            .arg("-o")
-            .arg(&install_script)
-            .status()?;
+fn download_and_run_meda_internal() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    start_meda_serve(&resolve_meda_binary()?)
+}
 
-        if !status.success() {
-            return Err("Failed to download meda installation script".into());
+/// Install meda (if needed) and run `meda serve` as its own system service instead of
+/// a process owned by the agent
+pub fn install_meda_service() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    ensure_provider_service(&meda_serve_spec(&resolve_meda_binary()?))
+}
+
+/// The meda binary to run: the locally provided one, an existing installation, or a
+/// freshly installed pinned release
+fn resolve_meda_binary() -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    // Offline mode: use a locally provided binary and never download anything
+    if let Ok(local_binary) = std::env::var("MEDA_BINARY") {
+        let meda_binary = PathBuf::from(local_binary);
+        if !meda_binary.is_file() {
+            return Err(format!("MEDA_BINARY {:?} does not exist", meda_binary).into());
         }
+        if let Ok(expected) = std::env::var("MEDA_BINARY_SHA256") {
+            verify_checksum(
+                &meda_binary,
+                Some(expected.trim().to_lowercase().as_str()),
+                "meda binary",
+            )?;
+        }
+        info!("Using locally provided meda binary at {:?}", meda_binary);
+        return Ok(meda_binary);
+    }
 
-        // Make the script executable
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            let mut perms = fs::metadata(&install_script)?.permissions();
-            perms.set_mode(0o755);
-            fs::set_permissions(&install_script, perms)?;
+    match find_meda_binary()? {
+        Some(path) => Ok(path),
+        None => {
+            info!("Meda not found");
+            install_meda(&meda_version())
         }
+    }
+}
 
-        // Run the installation script
-        let status = Command::new("bash")
-            .arg(&install_script)
-            .env("HOME", std::env::var("HOME")?)
-            .status()?;
+/// Version of the meda binary in use, without installing one
+pub fn installed_meda_version() -> Option<String> {
+    let binary = match std::env::var("MEDA_BINARY") {
+        Ok(local_binary) => PathBuf::from(local_binary),
+        Err(_) => find_meda_binary().ok()??,
+    };
+    installed_version(&binary)
+}
 
-        if !status.success() {
-            return Err("Failed to install meda".into());
-        }
+/// Upgrade meda in place when the installed version differs from the pinned one,
+/// returning whether an upgrade happened. `meda serve` is down while the binary is
+/// swapped, so callers must make sure no provider operation is in flight.
+pub fn upgrade_meda() -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    // A locally provided binary is managed by whoever provided it
+    if std::env::var("MEDA_BINARY").is_ok() {
+        return Ok(false);
+    }
+    let Some(current_binary) = find_meda_binary()? else {
+        return Ok(false);
+    };
+    let wanted = meda_version();
+    let Some(installed) = installed_version(&current_binary) else {
+        warn!(
+            "Could not determine the version of {:?}, skipping upgrade check",
+            current_binary
+        );
+        return Ok(false);
+    };
+    if installed == wanted {
+        return Ok(false);
+    }
 
-        // Verify the binary was installed - check multiple possible locations
-        let home_dir = std::env::var("HOME")?;
-        let possible_install_locations = vec![
-            PathBuf::from(&home_dir).join(".local/bin/meda"),
-            PathBuf::from(&home_dir).join(".cargo/bin/meda"),
-            PathBuf::from("/usr/local/bin/meda"),
-        ];
-
-        let mut installed_meda = None;
-        for location in &possible_install_locations {
-            if location.exists() {
-                installed_meda = Some(location.clone());
-                break;
-            }
+    info!("Upgrading meda {} -> {}", installed, wanted);
+    // The install script writes over the running binary, and a failed install deletes
+    // what it wrote, so keep the working version to fall back to
+    let backup = keep_copy(&current_binary).map_err(|e| {
+        format!(
+            "Not upgrading meda, could not keep a copy of {:?}: {}",
+            current_binary, e
+        )
+    })?;
+    // Stop the service or supervisor first so the old server isn't restarted while the
+    // binary is swapped
+    if agent_config().provider.service {
+        stop_provider_service();
+    } else {
+        supervisor::release();
+    }
+    stop_serve("meda serve");
+    match install_meda(&wanted) {
+        Ok(new_binary) => {
+            let _ = fs::remove_file(&backup);
+            start_meda_serve(&new_binary)?;
+            Ok(true)
         }
+        Err(e) => {
+            warn!(
+                "Meda upgrade failed, restoring and restarting meda {}: {}",
+                installed, e
+            );
+            restore_copy(&backup, &current_binary)?;
+            start_meda_serve(&current_binary)?;
+            Err(e)
+        }
+    }
+}
 
-        let installed_meda = installed_meda
-            .ok_or("Meda binary not found after installation in any expected location")?;
+/// Copy `binary` to `<binary>.previous`, keeping its permissions
+fn keep_copy(binary: &Path) -> std::io::Result<PathBuf> {
+    let mut name = binary.file_name().unwrap_or_default().to_os_string();
+    name.push(".previous");
+    let backup = binary.with_file_name(name);
+    fs::copy(binary, &backup)?;
+    Ok(backup)
+}
+
+/// Put the copy made by `keep_copy` back in place of whatever is at `binary` now
+fn restore_copy(backup: &Path, binary: &Path) -> std::io::Result<()> {
+    fs::rename(backup, binary)
+        .inspect_err(|e| error!("Failed to restore {:?} from {:?}: {}", binary, backup, e))
+}
 
-        info!("Meda installed successfully at {:?}", installed_meda);
-        found_meda = Some(installed_meda);
+/// How `meda serve` is run, whether supervised by the agent or as its own service
+fn meda_serve_spec(meda_binary: &Path) -> ServeSpec {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    let log_dir = PathBuf::from(&home_dir).join(".meda/logs");
+    fs::create_dir_all(&log_dir).unwrap_or_else(|e| {
+        warn!("Could not create log directory: {}", e);
+    });
+    ServeSpec {
+        name: "meda serve",
+        binary: meda_binary.to_path_buf(),
+        args: vec![
+            "serve".to_string(),
+            "--port".to_string(),
+            "7777".to_string(),
+        ]
+        .into_iter()
+        .chain(provider_auth::serve_tls_args())
+        .collect(),
+        env: provider_auth::serve_env(MEDA_TOKEN_ENV),
+        stdout_log: log_dir.join("meda-stdout.log"),
+        stderr_log: log_dir.join("meda-stderr.log"),
+    }
+}
 
-        // Clean up the temporary directory
-        fs::remove_dir_all(&temp_dir)?;
+/// Start `meda serve` in the background unless it is already running
+fn start_meda_serve(meda_binary: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if agent_config().provider.service {
+        ensure_provider_service(&meda_serve_spec(meda_binary))?;
+        // Give meda some time to start
+        thread::sleep(Duration::from_secs(5));
+        return Ok(());
     }
 
-    // Use the found meda binary path
-    let meda_binary = found_meda.ok_or("Meda binary not found")?;
+    if agent_config().provider.supervise {
+        if is_meda_running() && !supervisor::is_supervised() {
+            info!("Meda server is already running outside the agent, leaving it as is");
+            return Ok(());
+        }
+
+        let spec = meda_serve_spec(meda_binary);
+        info!(
+            "Meda logs available at {:?}",
+            spec.stderr_log.parent().unwrap_or(Path::new("."))
+        );
+        supervisor::supervise(spec);
+
+        // Give meda some time to start
+        thread::sleep(Duration::from_secs(5));
+        return Ok(());
+    }
 
     // Check if meda serve is already running
     if is_meda_running() {
@@ -268,10 +526,12 @@ fn download_and_run_meda_internal() -> Result<(), Box<dyn std::error::Error + Se
             fs::File::create("/dev/null").expect("Failed to open /dev/null")
         });
 
-        let child = Command::new(&meda_binary)
+        let mut child = Command::new(meda_binary)
             .arg("serve")
             .arg("--port")
             .arg("7777")
+            .args(provider_auth::serve_tls_args())
+            .envs(provider_auth::serve_env(MEDA_TOKEN_ENV))
             .stdout(Stdio::from(stdout_file))
             .stderr(Stdio::from(stderr_file))
             .spawn()?;
@@ -286,13 +546,7 @@ fn download_and_run_meda_internal() -> Result<(), Box<dyn std::error::Error + Se
         thread::sleep(Duration::from_secs(5));
 
         // Check if the process is still running
-        let is_running = Command::new("ps")
-            .arg("-p")
-            .arg(child.id().to_string())
-            .stdout(Stdio::null())
-            .status()
-            .map(|status| status.success())
-            .unwrap_or(false);
+        let is_running = matches!(child.try_wait(), Ok(None));
 
         if !is_running {
             warn!(
@@ -303,3 +557,40 @@ fn download_and_run_meda_internal() -> Result<(), Box<dyn std::error::Error + Se
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_checksum() {
+        let sums = "ABC123  install-release.sh\n\
+                    def456 *dist/meda\n\
+                    0000  meda.tar.gz\n";
+        assert_eq!(
+            find_checksum(sums, "install-release.sh").as_deref(),
+            Some("abc123")
+        );
+        assert_eq!(find_checksum(sums, "meda").as_deref(), Some("def456"));
+        assert_eq!(find_checksum(sums, "missing"), None);
+    }
+
+    #[test]
+    fn test_failed_upgrade_restores_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("meda");
+        fs::write(&binary, b"meda 0.2.0").unwrap();
+
+        let backup = keep_copy(&binary).unwrap();
+        // A failed install leaves a half written binary, or none at all
+        fs::write(&binary, b"partial").unwrap();
+        restore_copy(&backup, &binary).unwrap();
+        assert_eq!(fs::read(&binary).unwrap(), b"meda 0.2.0");
+        assert!(!backup.exists());
+
+        let backup = keep_copy(&binary).unwrap();
+        fs::remove_file(&binary).unwrap();
+        restore_copy(&backup, &binary).unwrap();
+        assert_eq!(fs::read(&binary).unwrap(), b"meda 0.2.0");
+    }
+}
@@ -0,0 +1,165 @@
+use crate::identity::{self, AgentIdentity};
+use crate::lume::freshness::TemplateSource;
+use crate::state::{AgentState, RunnerRecord, StateStore};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Format version of state bundles
+const BUNDLE_FORMAT: u32 = 1;
+
+/// What an agent knows about itself, moved to a rebuilt or new host with `export-state`
+/// and `import-state`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateBundle {
+    pub format: u32,
+    pub exported_at: DateTime<Utc>,
+    /// Host the bundle was exported on
+    pub hostname: String,
+    pub identity: AgentIdentity,
+    /// Templates and the images they were built from
+    #[serde(default)]
+    pub templates: HashMap<String, TemplateSource>,
+    /// When each of `templates` passed its boot test
+    #[serde(default)]
+    pub validated_templates: HashMap<String, DateTime<Utc>>,
+    #[serde(default)]
+    pub runners: HashMap<String, RunnerRecord>,
+}
+
+/// What `import-state` changed
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    /// Where a different identity that was replaced was moved to
+    pub archived: Option<PathBuf>,
+    pub templates: usize,
+    pub runners: usize,
+}
+
+/// Write the identity in `id_file` and the template manifest and runner records of the
+/// state file to `out`. Half-built templates are left out.
+pub fn export(id_file: &Path, hostname: String, out: &Path) -> Result<StateBundle, String> {
+    let identity = identity::load(id_file)
+        .map_err(|e| format!("Failed to read agent ID file {:?}: {}", id_file, e))?
+        .ok_or_else(|| format!("No agent identity in {:?} to export", id_file))?;
+    let bundle = StateStore::new().read(|state| {
+        let templates: HashMap<String, TemplateSource> = state
+            .templates
+            .iter()
+            .filter(|(name, _)| !state.incomplete_templates.contains_key(*name))
+            .map(|(name, source)| (name.clone(), source.clone()))
+            .collect();
+        StateBundle {
+            format: BUNDLE_FORMAT,
+            exported_at: Utc::now(),
+            hostname,
+            identity,
+            validated_templates: state
+                .validated_templates
+                .iter()
+                .filter(|(name, _)| templates.contains_key(*name))
+                .map(|(name, at)| (name.clone(), *at))
+                .collect(),
+            templates,
+            runners: state.runners.clone(),
+        }
+    });
+    let json = serde_json::to_string_pretty(&bundle).map_err(|e| e.to_string())?;
+    fs::write(out, json).map_err(|e| format!("Failed to write {:?}: {}", out, e))?;
+    Ok(bundle)
+}
+
+/// Add the bundle's templates and runners to `state`. What the state already knows is
+/// kept. Runners lose their CPU pinning, since the cores belonged to the old host.
+fn merge(state: &mut AgentState, bundle: &StateBundle) -> ImportSummary {
+    let mut summary = ImportSummary::default();
+    for (name, source) in &bundle.templates {
+        if state.templates.contains_key(name) {
+            continue;
+        }
+        state.templates.insert(name.clone(), source.clone());
+        if let Some(at) = bundle.validated_templates.get(name) {
+            state.validated_templates.entry(name.clone()).or_insert(*at);
+        }
+        summary.templates += 1;
+    }
+    for (name, record) in &bundle.runners {
+        if state.runners.contains_key(name) {
+            continue;
+        }
+        let mut record = record.clone();
+        record.cpu_pinning = None;
+        state.runners.insert(name.clone(), record);
+        summary.runners += 1;
+    }
+    summary
+}
+
+/// Restore a bundle written by `export`: its identity goes to `id_file`, replacing a
+/// different one only with `force`, and its templates and runners are added to the state
+/// file
+pub fn import(id_file: &Path, bundle_path: &Path, force: bool) -> Result<ImportSummary, String> {
+    let contents = fs::read_to_string(bundle_path)
+        .map_err(|e| format!("Failed to read {:?}: {}", bundle_path, e))?;
+    let bundle: StateBundle = serde_json::from_str(&contents)
+        .map_err(|e| format!("{:?} is not a state bundle: {}", bundle_path, e))?;
+    if bundle.format > BUNDLE_FORMAT {
+        return Err(format!(
+            "{:?} has bundle format {}, newer than this agent supports ({})",
+            bundle_path, bundle.format, BUNDLE_FORMAT
+        ));
+    }
+    let archived = identity::import(id_file, &bundle.identity, force)
+        .map_err(|e| format!("Failed to import the agent identity: {}", e))?;
+    let summary = StateStore::new().update(|state| merge(state, &bundle));
+    Ok(ImportSummary {
+        archived,
+        ..summary
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_keeps_local_state() {
+        let record = |cpu| RunnerRecord {
+            created_at: Utc::now(),
+            cpu,
+            memory: Default::default(),
+            disk: Default::default(),
+            provision_duration_secs: None,
+            provision_phases: None,
+            script_status: None,
+            detached_script: None,
+            cpu_pinning: Some(crate::cpu_pinning::CpuPinning {
+                cores: vec![0, 1],
+                numa_node: None,
+            }),
+            template_fallback: None,
+        };
+        let bundle = StateBundle {
+            format: BUNDLE_FORMAT,
+            exported_at: Utc::now(),
+            hostname: "old-host".to_string(),
+            identity: AgentIdentity::generate(),
+            templates: HashMap::new(),
+            validated_templates: HashMap::new(),
+            runners: HashMap::from([
+                ("cirun-a".to_string(), record(2)),
+                ("cirun-b".to_string(), record(4)),
+            ]),
+        };
+        let mut state = AgentState::default();
+        state.runners.insert("cirun-a".to_string(), record(8));
+
+        let summary = merge(&mut state, &bundle);
+        assert_eq!(summary.runners, 1);
+        assert_eq!(state.runners["cirun-a"].cpu, 8);
+        assert_eq!(state.runners["cirun-b"].cpu, 4);
+        assert!(state.runners["cirun-b"].cpu_pinning.is_none());
+    }
+}
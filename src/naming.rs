@@ -0,0 +1,133 @@
+use crate::config::agent_config;
+use crate::state::{AgentState, StateStore};
+use std::collections::HashSet;
+
+/// Start of every VM and template name the agent creates
+pub fn prefix() -> &'static str {
+    &agent_config().naming.prefix
+}
+
+/// Whether a VM or template was created by this agent
+pub fn is_managed(vm_name: &str) -> bool {
+    vm_name.starts_with(prefix())
+}
+
+/// VM kinds the agent creates for itself rather than for a runner
+const INTERNAL_KINDS: &[&str] = &["template-", "pool-", "bench-"];
+
+/// Whether the agent provisioned `runner_name` and so may delete its VM. The VM name
+/// can't tell: every runner VM name is built under the prefix.
+pub fn is_agent_runner(runner_name: &str) -> bool {
+    StateStore::new().read(|state| tracks_runner(state, runner_name))
+}
+
+fn tracks_runner(state: &AgentState, runner_name: &str) -> bool {
+    state.lifecycle.contains_key(runner_name)
+        || state.runners.contains_key(runner_name)
+        || state.provisioned.contains_key(runner_name)
+        || state.pending_deletions.contains_key(runner_name)
+        || state.pool.assigned.contains_key(runner_name)
+}
+
+/// Runners among the VM names a status report listed: those in the agent's namespace that
+/// are not templates, pool VMs or benchmarks. Runner VMs created before the agent tracked
+/// its runners are only known this way.
+pub fn reported_runners<'a>(reported: impl Iterator<Item = &'a str>) -> HashSet<String> {
+    reported
+        .filter(|name| is_runner_vm(name, prefix()))
+        .map(str::to_string)
+        .collect()
+}
+
+fn is_runner_vm(vm_name: &str, prefix: &str) -> bool {
+    vm_name.starts_with(prefix)
+        && !INTERNAL_KINDS
+            .iter()
+            .any(|kind| vm_name[prefix.len()..].starts_with(kind))
+}
+
+/// Name for a new agent VM of some `kind`, e.g. "pool-" or "template-"
+pub fn vm_name(kind: &str, suffix: &str) -> String {
+    format!("{}{}{}", prefix(), kind, suffix)
+}
+
+/// VM named after `runner_name` with `prefix`; runner names that already carry the prefix
+/// (Cirun's own "cirun-" names, by default) are used as they are
+fn prefixed(runner_name: &str, prefix: &str) -> String {
+    if runner_name.starts_with(prefix) {
+        runner_name.to_string()
+    } else {
+        format!("{}{}", prefix, runner_name)
+    }
+}
+
+/// Runner a prefixed VM belongs to, the inverse of `prefixed`; `is_runner` tells which
+/// names are runners this agent tracks
+fn unprefixed(vm_name: &str, prefix: &str, is_runner: impl Fn(&str) -> bool) -> String {
+    match vm_name.strip_prefix(prefix) {
+        Some(runner_name) if !is_runner(vm_name) && is_runner(runner_name) => {
+            runner_name.to_string()
+        }
+        _ => vm_name.to_string(),
+    }
+}
+
+/// VM created for a runner
+pub fn runner_vm_name(runner_name: &str) -> String {
+    prefixed(runner_name, prefix())
+}
+
+/// Runner of a VM named by `runner_vm_name`. VMs of runners the agent no longer tracks
+/// keep their VM name.
+pub fn runner_for_vm(vm_name: &str) -> String {
+    let runners = StateStore::new().read(|state| state.runners.keys().cloned().collect::<Vec<_>>());
+    unprefixed(vm_name, prefix(), |name| runners.iter().any(|r| r == name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runner_vm_names() {
+        assert_eq!(prefixed("cirun-abc", "cirun-"), "cirun-abc");
+        assert_eq!(prefixed("cirun-abc", "ci-prod-"), "ci-prod-cirun-abc");
+
+        let tracked = |name: &str| name == "cirun-abc";
+        assert_eq!(unprefixed("cirun-abc", "cirun-", tracked), "cirun-abc");
+        assert_eq!(
+            unprefixed("ci-prod-cirun-abc", "ci-prod-", tracked),
+            "cirun-abc"
+        );
+        // Untracked VMs are reported under their own name
+        assert_eq!(
+            unprefixed("ci-prod-old", "ci-prod-", tracked),
+            "ci-prod-old"
+        );
+    }
+
+    #[test]
+    fn test_untracked_runner_is_not_agent_runner() {
+        let mut state = AgentState::default();
+        // The API asks to delete a runner this agent never provisioned
+        assert!(!tracks_runner(&state, "cirun-unknown"));
+
+        state
+            .pool
+            .assigned
+            .insert("cirun-abc".to_string(), "cirun-pool-1".to_string());
+        assert!(tracks_runner(&state, "cirun-abc"));
+        assert!(!tracks_runner(&state, "cirun-unknown"));
+    }
+
+    #[test]
+    fn test_reported_runner_vms() {
+        // Runner VMs from before the agent tracked its runners are still in its namespace
+        assert!(is_runner_vm("cirun-abc", "cirun-"));
+        assert!(is_runner_vm("ci-prod-cirun-abc", "ci-prod-"));
+        assert!(!is_runner_vm("other-vm", "cirun-"));
+        assert!(!is_runner_vm("cirun-template-ubuntu", "cirun-"));
+        assert!(!is_runner_vm("cirun-pool-1a2b", "cirun-"));
+        assert!(!is_runner_vm("cirun-bench-1a2b", "cirun-"));
+    }
+}
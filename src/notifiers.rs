@@ -0,0 +1,260 @@
+use crate::config::{ChatService, NotifierConfig, NotifyOn};
+use crate::events::{AgentEvent, EventRecord};
+use crate::script_monitor::ScriptStatus;
+use reqwest::Client;
+use serde_json::{json, Value};
+
+/// Longest error text included in a message; chat services cut long messages anyway
+const ERROR_SNIPPET_CHARS: usize = 500;
+
+/// Human-readable form of an event
+struct Message {
+    title: String,
+    failure: bool,
+    facts: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+fn snippet(error: &str) -> String {
+    let error = error.trim();
+    match error.char_indices().nth(ERROR_SNIPPET_CHARS) {
+        Some((end, _)) => format!("{}…", &error[..end]),
+        None => error.to_string(),
+    }
+}
+
+/// Whether the event reports something going wrong
+fn is_failure(event: &AgentEvent) -> bool {
+    match event {
+        // A runner on a fallback template is not what was asked for
+        AgentEvent::ProvisionFailed { .. }
+        | AgentEvent::DeleteFailed { .. }
+        | AgentEvent::TemplateFallback { .. }
+        | AgentEvent::TemplateCreationFailed { .. } => true,
+        AgentEvent::ScriptFinished { status, .. } => {
+            matches!(status, ScriptStatus::Failed { .. } | ScriptStatus::TimedOut)
+        }
+        _ => false,
+    }
+}
+
+/// Whether a notifier posts this event. State changes repeat the other events, so only
+/// the audit log and webhooks receive them.
+pub fn wants(notifier: &NotifierConfig, event: &AgentEvent) -> bool {
+    match notifier.on {
+        NotifyOn::Failures => is_failure(event),
+        NotifyOn::All => !matches!(event, AgentEvent::StateChanged { .. }),
+    }
+}
+
+fn message(record: &EventRecord, hostname: &str) -> Message {
+    let (title, runner_name, mut facts, error) = match &record.event {
+        AgentEvent::ProvisionStarted { runner_name } => {
+            ("Provisioning started", runner_name, vec![], None)
+        }
+        AgentEvent::VmReady { runner_name } => ("Runner ready", runner_name, vec![], None),
+        AgentEvent::ProvisionFailed {
+            runner_name,
+            stage,
+            error,
+        } => (
+            "Provisioning failed",
+            runner_name,
+            vec![("Stage", stage.to_string())],
+            Some(error),
+        ),
+        AgentEvent::VmDeleted { runner_name } => ("Runner deleted", runner_name, vec![], None),
+        AgentEvent::DeleteFailed { runner_name, error } => {
+            ("Deletion failed", runner_name, vec![], Some(error))
+        }
+        AgentEvent::RunnerExpired {
+            runner_name,
+            lifetime_secs,
+        } => (
+            "Runner expired",
+            runner_name,
+            vec![("Lifetime", format!("{}s", lifetime_secs))],
+            None,
+        ),
+        AgentEvent::StateChanged {
+            runner_name,
+            from,
+            to,
+        } => {
+            let from = from.map_or("none".to_string(), |state| state.to_string());
+            (
+                "Runner state changed",
+                runner_name,
+                vec![("State", format!("{} → {}", from, to))],
+                None,
+            )
+        }
+        AgentEvent::ScriptFinished {
+            runner_name,
+            status,
+        } => match status {
+            ScriptStatus::Running | ScriptStatus::Succeeded => {
+                ("Provision script succeeded", runner_name, vec![], None)
+            }
+            ScriptStatus::Failed { exit_code } => (
+                "Provision script failed",
+                runner_name,
+                exit_code
+                    .map(|code| vec![("Exit code", code.to_string())])
+                    .unwrap_or_default(),
+                None,
+            ),
+            ScriptStatus::TimedOut => ("Provision script timed out", runner_name, vec![], None),
+            ScriptStatus::Unknown => (
+                "Provision script outcome unknown",
+                runner_name,
+                vec![],
+                None,
+            ),
+        },
+        AgentEvent::TemplateFallback {
+            runner_name,
+            image,
+            template,
+            error,
+        } => (
+            "Provisioned from fallback template",
+            runner_name,
+            vec![("Image", image.clone()), ("Template", template.clone())],
+            Some(error),
+        ),
+        AgentEvent::TemplateCreationFailed {
+            runner_name,
+            image,
+            template,
+            stage,
+            error,
+        } => (
+            "Template creation failed",
+            runner_name,
+            vec![
+                ("Image", image.clone()),
+                ("Template", template.clone()),
+                ("Stage", stage.to_string()),
+            ],
+            Some(error),
+        ),
+    };
+    facts.insert(0, ("Runner", runner_name.clone()));
+    facts.insert(1, ("Host", hostname.to_string()));
+    facts.push((
+        "Time",
+        record.at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+    ));
+    Message {
+        title: title.to_string(),
+        failure: is_failure(&record.event),
+        facts,
+        error: error.map(|e| snippet(e)),
+    }
+}
+
+/// Slack incoming webhook payload, using mrkdwn
+fn slack_payload(message: &Message) -> Value {
+    let icon = if message.failure { "🔴" } else { "🟢" };
+    let mut text = format!("{} *{}*", icon, message.title);
+    for (name, value) in &message.facts {
+        text.push_str(&format!("\n*{}:* {}", name, value));
+    }
+    if let Some(error) = &message.error {
+        text.push_str(&format!("\n```{}```", error.replace("```", "'''")));
+    }
+    json!({ "text": text })
+}
+
+/// Teams incoming webhook payload, as a message card
+fn teams_payload(message: &Message) -> Value {
+    let mut facts: Vec<Value> = message
+        .facts
+        .iter()
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect();
+    if let Some(error) = &message.error {
+        facts.push(json!({ "name": "Error", "value": error }));
+    }
+    json!({
+        "@type": "MessageCard",
+        "@context": "https://schema.org/extensions",
+        "summary": message.title,
+        "themeColor": if message.failure { "D70000" } else { "2EB886" },
+        "title": message.title,
+        "sections": [{ "facts": facts }],
+    })
+}
+
+/// Post an event to a notifier's webhook
+pub async fn notify(
+    client: &Client,
+    notifier: &NotifierConfig,
+    record: &EventRecord,
+    hostname: &str,
+) -> Result<(), String> {
+    let message = message(record, hostname);
+    let payload = match notifier.service {
+        ChatService::Slack => slack_payload(&message),
+        ChatService::Teams => teams_payload(&message),
+    };
+    let response = client
+        .post(&notifier.url)
+        .json(&payload)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("returned {}", response.status()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lifecycle::Stage;
+    use chrono::Utc;
+
+    #[test]
+    fn test_failure_messages() {
+        let record = EventRecord {
+            at: Utc::now(),
+            event: AgentEvent::ProvisionFailed {
+                runner_name: "cirun-runner-1".to_string(),
+                stage: Stage::Booting,
+                error: "x".repeat(600),
+            },
+        };
+        let notifier = NotifierConfig {
+            service: ChatService::Slack,
+            url: "https://hooks.slack.com/services/T0/B0/X".to_string(),
+            on: NotifyOn::Failures,
+        };
+        assert!(wants(&notifier, &record.event));
+        assert!(!wants(
+            &notifier,
+            &AgentEvent::VmReady {
+                runner_name: "cirun-runner-1".to_string(),
+            }
+        ));
+
+        let message = message(&record, "ci-host-1");
+        assert!(message.failure);
+        assert_eq!(message.error.as_ref().unwrap().chars().count(), 501);
+
+        let text = slack_payload(&message)["text"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(text.starts_with("🔴 *Provisioning failed*\n*Runner:* cirun-runner-1"));
+        assert!(text.contains("*Host:* ci-host-1"));
+        assert!(text.contains("*Stage:* booting"));
+
+        let card = teams_payload(&message);
+        assert_eq!(card["themeColor"], "D70000");
+        assert_eq!(card["sections"][0]["facts"][2]["name"], "Stage");
+    }
+}
@@ -0,0 +1,150 @@
+use crate::config::agent_config;
+use crate::state::{self, StateStore};
+use chrono::{DateTime, Duration, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The last desired state fetched from the API, served while the API is unreachable
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedDesiredState {
+    pub fetched_at: DateTime<Utc>,
+    pub response: serde_json::Value,
+}
+
+/// A report that could not be sent and waits for the API to come back
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct QueuedReport {
+    pub queued_at: DateTime<Utc>,
+    pub payload: serde_json::Value,
+}
+
+/// Remember the desired state the API just returned
+pub fn cache_desired_state(response: serde_json::Value) {
+    StateStore::new().update(|state| {
+        state.desired_state = Some(CachedDesiredState {
+            fetched_at: Utc::now(),
+            response,
+        })
+    });
+}
+
+/// The cached desired state, unless it is older than `[offline] desired_state_max_age_secs`
+pub fn cached_desired_state() -> Option<CachedDesiredState> {
+    let max_age = Duration::seconds(agent_config().offline.desired_state_max_age_secs as i64);
+    StateStore::new()
+        .read(|state| state.desired_state.clone())
+        .filter(|cached| Utc::now() - cached.fetched_at <= max_age)
+}
+
+/// Drop reports older than `retention` and then the oldest ones beyond `max_reports`.
+/// Returns how many were dropped.
+fn prune(
+    queue: &mut Vec<QueuedReport>,
+    now: DateTime<Utc>,
+    max_reports: usize,
+    retention: Duration,
+) -> usize {
+    let before = queue.len();
+    queue.retain(|report| now - report.queued_at <= retention);
+    let excess = queue.len().saturating_sub(max_reports);
+    queue.drain(..excess);
+    before - queue.len()
+}
+
+const OUTBOX_FILE: &str = "outbox.json";
+
+// Serializes outbox updates within this process
+static OUTBOX_LOCK: Mutex<()> = Mutex::new(());
+
+fn load_outbox(path: &Path) -> Vec<QueuedReport> {
+    match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable outbox {}: {}", path.display(), e);
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Run `f` on the queued reports and save them. Reports queued in state.json by older
+/// agents are moved into the outbox file on first use.
+fn update_outbox<T>(f: impl FnOnce(&mut Vec<QueuedReport>) -> T) -> T {
+    let _guard = OUTBOX_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let path = state::agent_file(OUTBOX_FILE);
+    let mut queue = load_outbox(&path);
+    let store = StateStore::new();
+    if store.read(|state| !state.legacy_outbox.is_empty()) {
+        let legacy = store.update(|state| std::mem::take(&mut state.legacy_outbox));
+        queue.splice(0..0, legacy);
+    }
+    let result = f(&mut queue);
+    let written = serde_json::to_vec_pretty(&queue)
+        .map_err(std::io::Error::from)
+        .and_then(|contents| state::write_private(&path, &contents));
+    if let Err(e) = written {
+        warn!("Failed to save outbox {}: {}", path.display(), e);
+    }
+    result
+}
+
+/// Keep a report that could not be sent, to be flushed once the API is reachable
+pub fn enqueue_report(payload: serde_json::Value) {
+    let config = &agent_config().offline;
+    let dropped = update_outbox(|queue| {
+        let now = Utc::now();
+        queue.push(QueuedReport {
+            queued_at: now,
+            payload,
+        });
+        prune(
+            queue,
+            now,
+            config.max_queued_reports,
+            Duration::seconds(config.report_retention_secs as i64),
+        )
+    });
+    if dropped > 0 {
+        warn!(
+            "Dropped {} queued reports past the retention limits",
+            dropped
+        );
+    }
+}
+
+/// Queued reports, oldest first
+pub fn queued_reports() -> Vec<QueuedReport> {
+    update_outbox(|queue| queue.clone())
+}
+
+/// Remove reports that were delivered
+pub fn remove_reports(delivered: &[QueuedReport]) {
+    update_outbox(|queue| queue.retain(|report| !delivered.contains(report)));
+}
+
+/// Drop the cached desired state and queued reports, which belong to an agent identity
+/// the API no longer knows
+pub fn forget_identity() {
+    StateStore::new().update(|state| state.desired_state = None);
+    update_outbox(|queue| queue.clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune_enforces_age_and_count() {
+        let now = Utc::now();
+        let report = |age_secs: i64, n: u32| QueuedReport {
+            queued_at: now - Duration::seconds(age_secs),
+            payload: serde_json::json!({ "n": n }),
+        };
+        let mut queue = vec![report(7200, 0), report(30, 1), report(20, 2), report(10, 3)];
+        let dropped = prune(&mut queue, now, 2, Duration::seconds(3600));
+        assert_eq!(dropped, 2);
+        assert_eq!(queue, vec![report(20, 2), report(10, 3)]);
+    }
+}
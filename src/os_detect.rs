@@ -0,0 +1,248 @@
+use log::{debug, info, warn};
+use reqwest::Client;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+/// Manifest/config keys that may carry the guest OS of an image
+const OS_ANNOTATIONS: [&str; 3] = ["org.opencontainers.image.os", "io.cirun.image.os", "os"];
+
+/// Normalize an OS name to one of "linux", "macos" or "windows"
+pub fn normalize_os(os: &str) -> Option<&'static str> {
+    match os.trim().to_lowercase().as_str() {
+        "linux" | "ubuntu" | "debian" => Some("linux"),
+        "macos" | "darwin" | "osx" | "mac" => Some("macos"),
+        "windows" | "win" => Some("windows"),
+        _ => None,
+    }
+}
+
+/// Guess the OS from substrings of the image name. Returns an error when no hint, or
+/// hints for more than one OS, are found instead of silently picking a default.
+pub fn os_from_image_name(image: &str) -> Result<&'static str, String> {
+    let name = image.to_lowercase();
+    let hints: [(&str, &[&str]); 3] = [
+        (
+            "macos",
+            &["macos", "sequoia", "sonoma", "ventura", "tahoe", "xcode"],
+        ),
+        (
+            "linux",
+            &[
+                "ubuntu", "debian", "linux", "fedora", "centos", "rocky", "alpine",
+            ],
+        ),
+        ("windows", &["windows", "win2022", "win11"]),
+    ];
+
+    let matches: Vec<&'static str> = hints
+        .iter()
+        .filter(|(_, words)| words.iter().any(|w| name.contains(w)))
+        .map(|(os, _)| *os)
+        .collect();
+
+    match matches.as_slice() {
+        [os] => Ok(os),
+        [] => Err(format!("no OS hint found in image name '{}'", image)),
+        many => Err(format!(
+            "image name '{}' is ambiguous (matches {})",
+            image,
+            many.join(", ")
+        )),
+    }
+}
+
+/// Find an OS annotation/label in a manifest, index or image config document
+fn os_from_manifest(doc: &Value) -> Option<&'static str> {
+    for section in ["annotations", "labels"] {
+        if let Some(map) = doc.get(section).and_then(Value::as_object) {
+            for key in OS_ANNOTATIONS {
+                if let Some(os) = map.get(key).and_then(Value::as_str).and_then(normalize_os) {
+                    return Some(os);
+                }
+            }
+        }
+    }
+
+    // Image configs carry "os" at the top level (or labels under "config")
+    if let Some(os) = doc.get("os").and_then(Value::as_str).and_then(normalize_os) {
+        return Some(os);
+    }
+    if let Some(os) = doc.get("config").and_then(os_from_manifest) {
+        return Some(os);
+    }
+
+    // Index manifests list per-platform entries
+    doc.get("manifests")
+        .and_then(Value::as_array)
+        .and_then(|entries| {
+            entries.iter().find_map(|m| {
+                m.pointer("/platform/os")
+                    .and_then(Value::as_str)
+                    .and_then(normalize_os)
+            })
+        })
+}
+
+/// Split `registry/repo:tag` into its parts, defaulting to ghcr.io and `latest`
+pub fn split_image_reference(image: &str) -> (String, String, String) {
+    let (registry, rest) = match image.split_once('/') {
+        Some((first, rest)) if first.contains('.') || first.contains(':') => {
+            (first.to_string(), rest.to_string())
+        }
+        _ => ("ghcr.io".to_string(), image.to_string()),
+    };
+    let (repository, tag) = match rest.rsplit_once(':') {
+        Some((repo, tag)) if !tag.contains('/') => (repo.to_string(), tag.to_string()),
+        _ => (rest, "latest".to_string()),
+    };
+    (registry, repository, tag)
+}
+
+/// Manifest of an image and its digest, from the registry (anonymous pull access only)
+async fn fetch_manifest(image: &str) -> Result<Option<(Value, String)>, reqwest::Error> {
+    let (registry, repository, tag) = split_image_reference(image);
+    let client = Client::builder().timeout(Duration::from_secs(15)).build()?;
+
+    // Registries like ghcr.io hand out anonymous tokens for public repositories
+    let token = client
+        .get(format!(
+            "https://{}/token?scope=repository:{}:pull",
+            registry, repository
+        ))
+        .send()
+        .await
+        .ok()
+        .filter(|r| r.status().is_success());
+    let token = match token {
+        Some(response) => response
+            .json::<Value>()
+            .await
+            .ok()
+            .and_then(|v| v.get("token").and_then(Value::as_str).map(str::to_string)),
+        None => None,
+    };
+
+    let mut request = client
+        .get(format!(
+            "https://{}/v2/{}/manifests/{}",
+            registry, repository, tag
+        ))
+        .header(
+            "Accept",
+            "application/vnd.oci.image.index.v1+json, application/vnd.oci.image.manifest.v1+json, \
+             application/vnd.docker.distribution.manifest.v2+json",
+        );
+    if let Some(token) = &token {
+        request = request.bearer_auth(token);
+    }
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        debug!(
+            "Manifest lookup for '{}' returned status {}",
+            image,
+            response.status()
+        );
+        return Ok(None);
+    }
+    let header_digest = response
+        .headers()
+        .get("Docker-Content-Digest")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let body = response.bytes().await?;
+    // A manifest's digest is the hash of its exact bytes
+    let digest = header_digest.unwrap_or_else(|| format!("sha256:{:x}", Sha256::digest(&body)));
+    match serde_json::from_slice(&body) {
+        Ok(manifest) => Ok(Some((manifest, digest))),
+        Err(e) => {
+            debug!("Manifest of '{}' is not valid JSON: {}", image, e);
+            Ok(None)
+        }
+    }
+}
+
+/// Look up the OS from the registry manifest of an image (anonymous pull access only)
+pub async fn os_from_registry(image: &str) -> Result<Option<&'static str>, reqwest::Error> {
+    Ok(fetch_manifest(image)
+        .await?
+        .and_then(|(manifest, _)| os_from_manifest(&manifest)))
+}
+
+/// Digest the registry currently serves for an image's tag (anonymous pull access only)
+pub async fn registry_digest(image: &str) -> Result<Option<String>, reqwest::Error> {
+    Ok(fetch_manifest(image).await?.map(|(_, digest)| digest))
+}
+
+/// Resolve the guest OS of a runner image: the API-provided value wins, then registry
+/// manifest annotations, with the image name heuristic only as a last resort.
+/// Returns an error for ambiguous cases so they get reported rather than guessed.
+pub async fn resolve_runner_os(api_os: &str, image: &str) -> Result<&'static str, String> {
+    if let Some(os) = normalize_os(api_os) {
+        return Ok(os);
+    }
+    if !api_os.trim().is_empty() {
+        warn!(
+            "API provided unrecognized OS '{}' for image '{}'. Detecting from image.",
+            api_os, image
+        );
+    }
+
+    match os_from_registry(image).await {
+        Ok(Some(os)) => {
+            info!("Detected OS '{}' from registry manifest of '{}'", os, image);
+            return Ok(os);
+        }
+        Ok(None) => debug!("No OS annotation in registry manifest of '{}'", image),
+        Err(e) => debug!("Registry manifest lookup failed for '{}': {}", image, e),
+    }
+
+    let os = os_from_image_name(image)
+        .map_err(|e| format!("Cannot determine OS for image '{}': {}", image, e))?;
+    warn!(
+        "Guessed OS '{}' for image '{}' from its name; set the os field or an \
+         org.opencontainers.image.os annotation to avoid guessing",
+        os, image
+    );
+    Ok(os)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_os_detection_sources() {
+        assert_eq!(normalize_os("macOS"), Some("macos"));
+        assert_eq!(normalize_os("Darwin"), Some("macos"));
+        assert_eq!(normalize_os("plan9"), None);
+
+        assert_eq!(
+            os_from_image_name("cirunlabs/macos-sequoia-xcode:16"),
+            Ok("macos")
+        );
+        assert_eq!(os_from_image_name("cirunlabs/ubuntu:24.04"), Ok("linux"));
+        assert!(os_from_image_name("cirunlabs/runner:latest").is_err());
+        assert!(os_from_image_name("ubuntu-on-macos-host").is_err());
+
+        let manifest = json!({
+            "annotations": {"org.opencontainers.image.os": "darwin"}
+        });
+        assert_eq!(os_from_manifest(&manifest), Some("macos"));
+        let index = json!({
+            "manifests": [{"platform": {"os": "linux", "architecture": "amd64"}}]
+        });
+        assert_eq!(os_from_manifest(&index), Some("linux"));
+
+        assert_eq!(
+            split_image_reference("cirunlabs/ubuntu:24.04"),
+            (
+                "ghcr.io".to_string(),
+                "cirunlabs/ubuntu".to_string(),
+                "24.04".to_string()
+            )
+        );
+    }
+}
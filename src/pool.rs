@@ -0,0 +1,291 @@
+use crate::boot_ramp;
+use crate::capacity;
+use crate::config::agent_config;
+use crate::endpoints;
+use crate::locks::use_template;
+use crate::lume::client::LumeClient;
+use crate::meda::client::MedaClient;
+use crate::meda::models::VmRunRequest;
+use crate::naming;
+use crate::state::StateStore;
+use crate::units::{DiskSize, Memory};
+use crate::use_meda;
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// What a VM was cloned from; only runners asking for the same spec can reuse it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PoolSpec {
+    pub template: String,
+    pub cpu: u32,
+    pub memory: Memory,
+    pub disk: DiskSize,
+}
+
+/// A freshly cloned, stopped VM waiting to be claimed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PooledVm {
+    pub vm_name: String,
+    pub spec: PoolSpec,
+    pub pooled_at: DateTime<Utc>,
+}
+
+/// Reuse pool bookkeeping, persisted in the state file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PoolState {
+    #[serde(default)]
+    pub idle: Vec<PooledVm>,
+    /// VM backing each runner that claimed a pooled VM, by runner name
+    #[serde(default)]
+    pub assigned: HashMap<String, String>,
+    /// Spec of every runner that can be returned to the pool, by runner name
+    #[serde(default)]
+    pub specs: HashMap<String, PoolSpec>,
+}
+
+/// Whether runners are pooled; only VMs on this host are
+pub fn enabled() -> bool {
+    agent_config().pool.enabled && endpoints::current().is_none()
+}
+
+/// VM backing a runner: a claimed pooled VM, or the VM named after the runner
+pub fn vm_name(runner_name: &str) -> String {
+    StateStore::new()
+        .read(|state| state.pool.assigned.get(runner_name).cloned())
+        .unwrap_or_else(|| naming::runner_vm_name(runner_name))
+}
+
+/// Runner a VM belongs to, the inverse of `vm_name`
+pub fn runner_name(vm_name: &str) -> String {
+    StateStore::new()
+        .read(|state| {
+            state
+                .pool
+                .assigned
+                .iter()
+                .find(|(_, vm)| vm.as_str() == vm_name)
+                .map(|(runner, _)| runner.clone())
+        })
+        .unwrap_or_else(|| naming::runner_for_vm(vm_name))
+}
+
+/// Whether a VM belongs to the pool rather than a runner: waiting in it, or still being
+/// created for it. Such VMs are not runners and are never reported.
+pub fn is_pool_vm(vm_name: &str) -> bool {
+    let assigned = StateStore::new().read(|state| state.pool.assigned.clone());
+    unassigned_pool_vm(vm_name, &naming::vm_name("pool-", ""), &assigned)
+}
+
+fn unassigned_pool_vm(
+    vm_name: &str,
+    pool_prefix: &str,
+    assigned: &HashMap<String, String>,
+) -> bool {
+    vm_name.starts_with(pool_prefix) && !assigned.values().any(|vm| vm == vm_name)
+}
+
+/// Whether the host has room for another VM of `spec`, under `max_vms` running VMs and
+/// the overcommit limits
+async fn has_room(spec: &PoolSpec, max_vms: Option<u32>) -> bool {
+    if let Some(max_vms) = max_vms {
+        match crate::get_running_vm_count().await {
+            Ok(running) if running < max_vms as usize => {}
+            Ok(_) => return false,
+            Err(e) => {
+                warn!("Failed to check VM capacity for the reuse pool: {}", e);
+                return false;
+            }
+        }
+    }
+    capacity::current_capacity().is_none_or(|capacity| capacity.fits(spec.cpu, spec.memory))
+}
+
+fn take_matching(idle: &mut Vec<PooledVm>, spec: &PoolSpec) -> Option<PooledVm> {
+    let index = idle.iter().position(|p| &p.spec == spec)?;
+    Some(idle.remove(index))
+}
+
+/// Claim a pooled VM for a runner, if one matches its spec. The runner is remembered
+/// either way so it can be returned to the pool when deleted.
+pub fn claim(runner_name: &str, spec: PoolSpec) -> Option<String> {
+    StateStore::new().update(|state| {
+        let pooled = take_matching(&mut state.pool.idle, &spec);
+        state.pool.specs.insert(runner_name.to_string(), spec);
+        let vm_name = pooled?.vm_name;
+        state
+            .pool
+            .assigned
+            .insert(runner_name.to_string(), vm_name.clone());
+        Some(vm_name)
+    })
+}
+
+/// Spec to refill the pool with once this runner's VM is deleted, if its pool has room
+pub fn refill_spec(runner_name: &str) -> Option<PoolSpec> {
+    if !enabled() {
+        return None;
+    }
+    let max = agent_config().pool.max_per_spec;
+    StateStore::new().read(|state| {
+        let spec = state.pool.specs.get(runner_name)?;
+        let pooled = state.pool.idle.iter().filter(|p| &p.spec == spec).count();
+        (pooled < max).then(|| spec.clone())
+    })
+}
+
+/// Drop a runner's pool bookkeeping once its VM is gone
+pub fn forget(runner_name: &str) {
+    StateStore::new().update(|state| {
+        state.pool.assigned.remove(runner_name);
+        state.pool.specs.remove(runner_name);
+    });
+}
+
+/// Clone a fresh VM for `spec`, stop it and add it to the pool, unless the host is at
+/// `max_vms` or its overcommit limits
+pub async fn refill(spec: PoolSpec, max_vms: Option<u32>) {
+    if !has_room(&spec, max_vms).await {
+        info!(
+            "Not refilling the reuse pool for template '{}': the host is at capacity",
+            spec.template
+        );
+        return;
+    }
+    let vm_name = naming::vm_name("pool-", &uuid::Uuid::new_v4().simple().to_string()[..12]);
+    let created: Result<(), String> = if use_meda() {
+        // meda only creates VMs by running them
+        async {
+            let meda = MedaClient::new().map_err(|e| e.to_string())?;
+            boot_ramp::wait_for_slot(&vm_name).await;
+            meda.run_vm(VmRunRequest {
+                image: spec.template.clone(),
+                name: Some(vm_name.clone()),
+                memory: Some(spec.memory.to_meda()),
+                cpus: Some(spec.cpu),
+                disk_size: Some(spec.disk.to_meda()),
+                cpu_affinity: None,
+                numa_node: None,
+                qos: None,
+            })
+            .await
+            .map_err(|e| format!("{:?}", e))?;
+            meda.stop_vm(&vm_name).await.map_err(|e| format!("{:?}", e))
+        }
+        .await
+    } else {
+        async {
+            let lume = LumeClient::new().map_err(|e| e.to_string())?;
+            let _template_use = use_template(&spec.template).await;
+            lume.clone_vm(&spec.template, &vm_name)
+                .await
+                .map_err(|e| format!("{:?}", e))
+        }
+        .await
+    };
+
+    match created {
+        Ok(()) => {
+            info!(
+                "Added VM '{}' to the reuse pool for template '{}'",
+                vm_name, spec.template
+            );
+            StateStore::new().update(|state| {
+                state.pool.idle.push(PooledVm {
+                    vm_name,
+                    spec,
+                    pooled_at: Utc::now(),
+                })
+            });
+        }
+        Err(e) => {
+            warn!("Failed to refill the reuse pool: {}", e);
+            delete_vm(&vm_name).await;
+        }
+    }
+}
+
+async fn delete_vm(vm_name: &str) {
+    let deleted = if use_meda() {
+        match MedaClient::new() {
+            Ok(meda) => meda
+                .delete_vm(vm_name)
+                .await
+                .map_err(|e| format!("{:?}", e)),
+            Err(e) => Err(e.to_string()),
+        }
+    } else {
+        match LumeClient::new() {
+            Ok(lume) => lume
+                .delete_vm(vm_name)
+                .await
+                .map_err(|e| format!("{:?}", e)),
+            Err(e) => Err(e.to_string()),
+        }
+    };
+    if let Err(e) = deleted {
+        warn!("Failed to delete pooled VM '{}': {}", vm_name, e);
+    }
+}
+
+/// Delete pooled VMs idle for longer than `[pool] max_idle_secs`
+pub async fn trim() {
+    let max_idle = Duration::seconds(agent_config().pool.max_idle_secs as i64);
+    let now = Utc::now();
+    let expired: Vec<PooledVm> = StateStore::new().update(|state| {
+        let (expired, kept) = state
+            .pool
+            .idle
+            .drain(..)
+            .partition(|p| now - p.pooled_at > max_idle);
+        state.pool.idle = kept;
+        expired
+    });
+    for pooled in expired {
+        info!("Deleting pooled VM '{}' after idling", pooled.vm_name);
+        delete_vm(&pooled.vm_name).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_matching_spec() {
+        let spec = |cpu| PoolSpec {
+            template: "ubuntu-24.04".to_string(),
+            cpu,
+            memory: Memory::from_gb(4),
+            disk: DiskSize::from_gb(20),
+        };
+        let pooled = |name: &str, cpu| PooledVm {
+            vm_name: name.to_string(),
+            spec: spec(cpu),
+            pooled_at: Utc::now(),
+        };
+        let mut idle = vec![pooled("cirun-pool-a", 2), pooled("cirun-pool-b", 4)];
+
+        assert_eq!(
+            take_matching(&mut idle, &spec(4)).map(|p| p.vm_name),
+            Some("cirun-pool-b".to_string())
+        );
+        assert_eq!(take_matching(&mut idle, &spec(4)), None);
+        assert_eq!(idle.len(), 1);
+    }
+
+    #[test]
+    fn test_pool_vms_are_not_runners() {
+        let assigned = HashMap::from([("cirun-a".to_string(), "cirun-pool-b".to_string())]);
+        // Still being cloned, so not in the idle list yet
+        assert!(unassigned_pool_vm("cirun-pool-a", "cirun-pool-", &assigned));
+        // Claimed by a runner, which it is reported as
+        assert!(!unassigned_pool_vm(
+            "cirun-pool-b",
+            "cirun-pool-",
+            &assigned
+        ));
+        assert!(!unassigned_pool_vm("cirun-a", "cirun-pool-", &assigned));
+    }
+}
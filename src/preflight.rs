@@ -0,0 +1,248 @@
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use url::Url;
+
+/// Clock difference to the API above which TLS and token checks start failing
+const MAX_CLOCK_SKEW_SECS: i64 = 60;
+const CHECK_TIMEOUT_SECS: u64 = 10;
+
+/// Directories the agent writes state and logs to, relative to HOME
+const WRITABLE_DIRS: &[&str] = &[".cirun-agent", ".cirun-agent/runners"];
+
+/// How bad a failed check is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The agent cannot work; it exits
+    Fatal,
+    /// Operations will likely fail, but the cause may clear up on its own
+    Warning,
+}
+
+/// A failed startup check, with what to do about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Problem {
+    fn fatal(check: &'static str, message: String) -> Self {
+        Problem {
+            check,
+            severity: Severity::Fatal,
+            message,
+        }
+    }
+
+    fn warning(check: &'static str, message: String) -> Self {
+        Problem {
+            check,
+            severity: Severity::Warning,
+            message,
+        }
+    }
+}
+
+/// HOME must be set and point at an existing directory
+fn check_home() -> Result<PathBuf, Problem> {
+    let home = std::env::var("HOME").map_err(|_| {
+        Problem::fatal(
+            "home",
+            "HOME is not set; set it to the home directory of the user running the agent"
+                .to_string(),
+        )
+    })?;
+    let home = PathBuf::from(home);
+    if !home.is_dir() {
+        return Err(Problem::fatal(
+            "home",
+            format!(
+                "HOME ({}) is not a directory; set it to the home directory of the user running the agent",
+                home.display()
+            ),
+        ));
+    }
+    Ok(home)
+}
+
+/// Create `dir` if needed and prove it is writable by writing and removing a file in it
+fn check_writable(dir: &Path) -> Result<(), Problem> {
+    let probe = dir.join(".cirun-agent-write-check");
+    fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&probe, b"ok"))
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|e| {
+            Problem::fatal(
+                "write_access",
+                format!(
+                    "cannot write to {}: {}; check its ownership and permissions",
+                    dir.display(),
+                    e
+                ),
+            )
+        })
+}
+
+/// The API host must resolve, or every request will fail
+async fn check_dns(api_url: &Url) -> Result<(), Problem> {
+    let Some(host) = api_url.host_str() else {
+        return Err(Problem::fatal(
+            "dns",
+            format!("API URL {} has no host; check CIRUN_API_URL", api_url),
+        ));
+    };
+    let port = api_url.port_or_known_default().unwrap_or(443);
+    let lookup = tokio::time::timeout(
+        Duration::from_secs(CHECK_TIMEOUT_SECS),
+        tokio::net::lookup_host((host, port)),
+    )
+    .await;
+    match lookup.map(|result| result.map(|mut addrs| addrs.next().is_some())) {
+        Ok(Ok(true)) => Ok(()),
+        Ok(Ok(false)) => Err(Problem::warning(
+            "dns",
+            format!(
+                "{} resolved to no addresses; check the host's DNS settings",
+                host
+            ),
+        )),
+        Ok(Err(e)) => Err(Problem::warning(
+            "dns",
+            format!(
+                "cannot resolve {}: {}; check /etc/resolv.conf and network access",
+                host, e
+            ),
+        )),
+        Err(_) => Err(Problem::warning(
+            "dns",
+            format!("resolving {} timed out; check the host's DNS servers", host),
+        )),
+    }
+}
+
+/// Seconds the local clock is ahead of (positive) or behind the server's `Date` header
+fn clock_skew(date_header: &str, now: DateTime<Utc>) -> Option<i64> {
+    let server = DateTime::parse_from_rfc2822(date_header).ok()?;
+    Some((now - server.with_timezone(&Utc)).num_seconds())
+}
+
+/// Compare the local clock with the `Date` header of an API response. A skewed clock
+/// otherwise shows up as confusing TLS or token errors.
+async fn check_clock(api_url: &Url) -> Result<(), Problem> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(CHECK_TIMEOUT_SECS))
+        .build()
+        .map_err(|e| Problem::warning("clock", e.to_string()))?;
+    let response = match client.head(api_url.clone()).send().await {
+        Ok(response) => response,
+        Err(e) => {
+            let hint = if format!("{:?}", e).to_lowercase().contains("certificate") {
+                "; TLS failures like this are often caused by a wrong system clock"
+            } else {
+                ""
+            };
+            return Err(Problem::warning(
+                "clock",
+                format!("could not reach the API to check the clock: {}{}", e, hint),
+            ));
+        }
+    };
+    let Some(skew) = response
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|date| date.to_str().ok())
+        .and_then(|date| clock_skew(date, Utc::now()))
+    else {
+        return Ok(());
+    };
+    if skew.abs() > MAX_CLOCK_SKEW_SECS {
+        return Err(Problem::warning(
+            "clock",
+            format!(
+                "system clock is {}s {} the Cirun API; enable time sync (e.g. `timedatectl set-ntp true` \
+                 or `sntp -sS time.apple.com`)",
+                skew.abs(),
+                if skew > 0 { "ahead of" } else { "behind" }
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Run every startup check and log the problems found
+pub async fn run(api_url: &str) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    match check_home() {
+        Ok(home) => {
+            for dir in WRITABLE_DIRS {
+                if let Err(problem) = check_writable(&home.join(dir)) {
+                    problems.push(problem);
+                }
+            }
+        }
+        Err(problem) => problems.push(problem),
+    }
+
+    match Url::parse(api_url) {
+        Ok(url) => {
+            if let Err(problem) = check_dns(&url).await {
+                problems.push(problem);
+            } else if let Err(problem) = check_clock(&url).await {
+                problems.push(problem);
+            }
+        }
+        Err(e) => problems.push(Problem::fatal(
+            "api_url",
+            format!("invalid API URL {}: {}; check CIRUN_API_URL", api_url, e),
+        )),
+    }
+
+    for problem in &problems {
+        match problem.severity {
+            Severity::Fatal => error!(
+                "Startup check '{}' failed: {}",
+                problem.check, problem.message
+            ),
+            Severity::Warning => {
+                warn!(
+                    "Startup check '{}' failed: {}",
+                    problem.check, problem.message
+                )
+            }
+        }
+    }
+    if problems.is_empty() {
+        info!("Startup checks passed");
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_skew() {
+        let now = DateTime::parse_from_rfc3339("2025-03-01T12:00:30Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(clock_skew("Sat, 01 Mar 2025 12:00:00 GMT", now), Some(30));
+        assert_eq!(clock_skew("Sat, 01 Mar 2025 12:05:00 GMT", now), Some(-270));
+        assert_eq!(clock_skew("yesterday", now), None);
+    }
+
+    #[test]
+    fn test_check_writable() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check_writable(&dir.path().join("nested/state")).is_ok());
+        assert!(!dir
+            .path()
+            .join("nested/state/.cirun-agent-write-check")
+            .exists());
+    }
+}
@@ -0,0 +1,165 @@
+use crate::config::{agent_config, EndpointConfig};
+use crate::endpoints;
+use log::info;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use reqwest::{Certificate, ClientBuilder};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+const TOKEN_FILE: &str = ".cirun-agent/provider-token";
+
+// Token shared between the agent and the provider server for this host
+static TOKEN: OnceLock<Option<String>> = OnceLock::new();
+
+fn token_path() -> PathBuf {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home_dir).join(TOKEN_FILE)
+}
+
+/// Read the token at `path`, generating and storing a new one (readable only by the
+/// agent's user) if there is none yet
+fn load_or_create_token(path: &Path) -> std::io::Result<String> {
+    if let Ok(token) = fs::read_to_string(path) {
+        let token = token.trim().to_string();
+        if !token.is_empty() {
+            return Ok(token);
+        }
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let token = format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    );
+    fs::write(path, &token)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    info!("Generated provider API token at {:?}", path);
+    Ok(token)
+}
+
+/// Token the provider API expects, when token authentication is enabled
+pub fn api_token() -> Option<String> {
+    TOKEN
+        .get_or_init(|| {
+            if !agent_config().provider.auth_token {
+                return None;
+            }
+            match load_or_create_token(&token_path()) {
+                Ok(token) => Some(token),
+                Err(e) => {
+                    log::error!("Failed to set up provider API token: {}", e);
+                    None
+                }
+            }
+        })
+        .clone()
+}
+
+/// Provider API URL: the current remote endpoint's, or the local one switched to HTTPS
+/// when TLS is configured
+pub fn api_url(default_url: &str) -> String {
+    if let Some(endpoint) = endpoints::current() {
+        endpoint.url.clone()
+    } else if agent_config().provider.tls.is_some() {
+        default_url.replacen("http://", "https://", 1)
+    } else {
+        default_url.to_string()
+    }
+}
+
+/// Add the auth header and the provider's CA certificate to an HTTP client
+pub fn configure_client(mut builder: ClientBuilder) -> Result<ClientBuilder, String> {
+    let endpoint = endpoints::current();
+    let token = match endpoint {
+        Some(endpoint) => endpoint_token(endpoint)?,
+        None => api_token(),
+    };
+    if let Some(token) = token {
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|e| format!("Invalid provider API token: {}", e))?;
+        value.set_sensitive(true);
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, value);
+        builder = builder.default_headers(headers);
+    }
+    let ca_path = match endpoint {
+        Some(endpoint) => endpoint.ca.as_ref(),
+        None => agent_config()
+            .provider
+            .tls
+            .as_ref()
+            .map(|tls| tls.ca.as_ref().unwrap_or(&tls.cert)),
+    };
+    if let Some(ca_path) = ca_path {
+        let pem = fs::read(ca_path).map_err(|e| {
+            format!(
+                "Failed to read provider CA certificate {:?}: {}",
+                ca_path, e
+            )
+        })?;
+        let certificate = Certificate::from_pem(&pem)
+            .map_err(|e| format!("Invalid provider CA certificate {:?}: {}", ca_path, e))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+    Ok(builder)
+}
+
+fn endpoint_token(endpoint: &EndpointConfig) -> Result<Option<String>, String> {
+    match &endpoint.token_env {
+        Some(var) => std::env::var(var).map(Some).map_err(|_| {
+            format!(
+                "Token variable {} for endpoint '{}' is not set",
+                var, endpoint.name
+            )
+        }),
+        None => Ok(endpoint.token.clone()),
+    }
+}
+
+/// Environment passing the token to `serve` through `token_var`
+pub fn serve_env(token_var: &str) -> Vec<(String, String)> {
+    api_token()
+        .map(|token| vec![(token_var.to_string(), token)])
+        .unwrap_or_default()
+}
+
+/// Extra `serve` arguments enabling TLS
+pub fn serve_tls_args() -> Vec<String> {
+    match &agent_config().provider.tls {
+        Some(tls) => vec![
+            "--tls-cert".to_string(),
+            tls.cert.display().to_string(),
+            "--tls-key".to_string(),
+            tls.key.display().to_string(),
+        ],
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_is_created_once_and_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested/provider-token");
+        let token = load_or_create_token(&path).unwrap();
+        assert_eq!(token.len(), 64);
+        assert_eq!(load_or_create_token(&path).unwrap(), token);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::endpoints;
+use crate::state::StateStore;
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::sync::Mutex;
+
+/// Unless reachability changes, health is written to the state file at most this often
+const PERSIST_SECS: i64 = 60;
+
+// Health of this host's provider as last observed, and when it was last written
+static HEALTH: Mutex<Option<(ProviderHealth, DateTime<Utc>)>> = Mutex::new(None);
+
+/// Whether the VM provider (meda or lume) answers API calls, from the agent's own calls
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProviderHealth {
+    pub reachable: bool,
+    pub checked_at: DateTime<Utc>,
+    pub last_success_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error_at: Option<DateTime<Utc>>,
+}
+
+/// Health after a call that ended with `outcome` at `now`
+fn observe(
+    previous: Option<&ProviderHealth>,
+    outcome: Result<(), String>,
+    now: DateTime<Utc>,
+) -> ProviderHealth {
+    let last_success_at = previous.and_then(|health| health.last_success_at);
+    let (last_error, last_error_at) = previous
+        .map(|health| (health.last_error.clone(), health.last_error_at))
+        .unwrap_or_default();
+    match outcome {
+        Ok(()) => ProviderHealth {
+            reachable: true,
+            checked_at: now,
+            last_success_at: Some(now),
+            last_error,
+            last_error_at,
+        },
+        Err(error) => ProviderHealth {
+            reachable: false,
+            checked_at: now,
+            last_success_at,
+            last_error: Some(error),
+            last_error_at: Some(now),
+        },
+    }
+}
+
+/// Record the outcome of a call to this host's provider. Calls to remote endpoints are
+/// ignored, since their health says nothing about the local provider.
+pub fn record<T, E: Display>(result: &Result<T, E>) {
+    if endpoints::current().is_some() {
+        return;
+    }
+    let outcome = result.as_ref().map(|_| ()).map_err(|e| e.to_string());
+    let now = Utc::now();
+    let (health, persist) = {
+        let mut current = HEALTH.lock().unwrap_or_else(|e| e.into_inner());
+        let previous = current.as_ref();
+        let health = observe(previous.map(|(health, _)| health), outcome, now);
+        let changed = previous.is_none_or(|(previous, _)| previous.reachable != health.reachable);
+        if changed && previous.is_some() {
+            match &health.last_error {
+                Some(error) if !health.reachable => warn!("Provider is not reachable: {}", error),
+                _ => info!("Provider is reachable again"),
+            }
+        }
+        let persisted_at = previous
+            .map(|(_, persisted_at)| *persisted_at)
+            .filter(|at| !changed && now - *at < Duration::seconds(PERSIST_SECS));
+        *current = Some((health.clone(), persisted_at.unwrap_or(now)));
+        (health, persisted_at.is_none())
+    };
+    if persist {
+        StateStore::new().update(|state| state.provider_health = Some(health));
+    }
+}
+
+/// Health as last observed by this process
+pub fn current() -> Option<ProviderHealth> {
+    HEALTH
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .map(|(health, _)| health.clone())
+}
+
+/// Health as last written to the state file, e.g. by a running agent
+pub fn persisted() -> Option<ProviderHealth> {
+    StateStore::new().read(|state| state.provider_health.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_observe() {
+        let start = Utc::now();
+        let up = observe(None, Ok(()), start);
+        assert!(up.reachable);
+        assert_eq!(up.last_success_at, Some(start));
+
+        let later = start + Duration::seconds(30);
+        let down = observe(Some(&up), Err("connection refused".to_string()), later);
+        assert!(!down.reachable);
+        assert_eq!(down.last_success_at, Some(start));
+        assert_eq!(down.last_error.as_deref(), Some("connection refused"));
+        assert_eq!(down.last_error_at, Some(later));
+
+        // The last error is kept after recovery, for the dashboard
+        let back = observe(Some(&down), Ok(()), later + Duration::seconds(30));
+        assert!(back.reachable);
+        assert_eq!(back.last_error_at, Some(later));
+    }
+}
@@ -0,0 +1,238 @@
+use crate::supervisor::ServeSpec;
+use log::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// systemd unit running `meda serve` on Linux
+const SYSTEMD_UNIT: &str = "cirun-meda";
+/// launchd label running `lume serve` on macOS
+const LAUNCHD_LABEL: &str = "io.cirun.lume";
+
+type SetupResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+fn systemd_unit_path() -> PathBuf {
+    PathBuf::from(format!("/etc/systemd/system/{}.service", SYSTEMD_UNIT))
+}
+
+fn launchd_plist_path() -> PathBuf {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home_dir).join(format!("Library/LaunchAgents/{}.plist", LAUNCHD_LABEL))
+}
+
+/// Name of the provider service the agent unit should depend on
+pub fn systemd_unit_name() -> String {
+    format!("{}.service", SYSTEMD_UNIT)
+}
+
+fn systemd_unit(spec: &ServeSpec) -> String {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| "/root".to_string());
+    let env: String = spec
+        .env
+        .iter()
+        .map(|(key, value)| format!("Environment=\"{}={}\"\n", key, value))
+        .collect();
+    format!(
+        r#"[Unit]
+Description=Meda VM manager for Cirun Agent
+After=network.target
+
+[Service]
+Type=simple
+ExecStart={} {}
+Environment="HOME={}"
+{}Restart=always
+RestartSec=5
+StandardOutput=append:{}
+StandardError=append:{}
+
+[Install]
+WantedBy=multi-user.target
+"#,
+        spec.binary.display(),
+        spec.args.join(" "),
+        home_dir,
+        env,
+        spec.stdout_log.display(),
+        spec.stderr_log.display()
+    )
+}
+
+fn launchd_plist(spec: &ServeSpec) -> String {
+    let arguments: String = std::iter::once(spec.binary.display().to_string())
+        .chain(spec.args.iter().cloned())
+        .map(|arg| format!("        <string>{}</string>\n", arg))
+        .collect();
+    let env = if spec.env.is_empty() {
+        String::new()
+    } else {
+        let entries: String = spec
+            .env
+            .iter()
+            .map(|(key, value)| {
+                format!(
+                    "        <key>{}</key>\n        <string>{}</string>\n",
+                    key, value
+                )
+            })
+            .collect();
+        format!(
+            "    <key>EnvironmentVariables</key>\n    <dict>\n{}    </dict>\n",
+            entries
+        )
+    };
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+{}    </array>
+{}    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardOutPath</key>
+    <string>{}</string>
+    <key>StandardErrorPath</key>
+    <string>{}</string>
+</dict>
+</plist>
+"#,
+        LAUNCHD_LABEL,
+        arguments,
+        env,
+        spec.stdout_log.display(),
+        spec.stderr_log.display()
+    )
+}
+
+/// Write a service definition; it can hold the provider API token, so keep it private
+fn write_private(path: &Path, contents: &str) -> SetupResult<()> {
+    fs::write(path, contents)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+fn run(program: &str, args: &[&str]) -> SetupResult<()> {
+    let status = Command::new(program).args(args).status()?;
+    if !status.success() {
+        return Err(format!("`{} {}` failed with {}", program, args.join(" "), status).into());
+    }
+    Ok(())
+}
+
+/// Install (or update) the provider's own service definition for `spec` and make sure
+/// it is running. The service definition is only rewritten when it changed, e.g. after
+/// an upgrade moved the binary.
+pub fn ensure_provider_service(spec: &ServeSpec) -> SetupResult<()> {
+    if cfg!(target_os = "linux") {
+        let unit_path = systemd_unit_path();
+        let unit = systemd_unit(spec);
+        let unit_name = systemd_unit_name();
+        if fs::read_to_string(&unit_path).ok().as_deref() != Some(unit.as_str()) {
+            write_private(&unit_path, &unit)?;
+            info!("Wrote provider service {:?}", unit_path);
+            run("systemctl", &["daemon-reload"])?;
+            run("systemctl", &["enable", &unit_name])?;
+            run("systemctl", &["restart", &unit_name])?;
+        } else {
+            run("systemctl", &["start", &unit_name])?;
+        }
+        info!("Provider service {} is running", unit_name);
+    } else {
+        let plist_path = launchd_plist_path();
+        let plist = launchd_plist(spec);
+        let plist_path_str = plist_path.to_string_lossy().to_string();
+        if fs::read_to_string(&plist_path).ok().as_deref() != Some(plist.as_str()) {
+            if plist_path.exists() {
+                let _ = run("launchctl", &["unload", &plist_path_str]);
+            }
+            if let Some(parent) = plist_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            write_private(&plist_path, &plist)?;
+            info!("Wrote provider service {:?}", plist_path);
+            run("launchctl", &["load", &plist_path_str])?;
+        } else {
+            run("launchctl", &["start", LAUNCHD_LABEL])?;
+        }
+        info!("Provider service {} is running", LAUNCHD_LABEL);
+    }
+    Ok(())
+}
+
+/// Stop the provider service without removing it (e.g. while its binary is swapped)
+pub fn stop_provider_service() {
+    if cfg!(target_os = "linux") {
+        let _ = run("systemctl", &["stop", &systemd_unit_name()]);
+    } else {
+        let _ = run(
+            "launchctl",
+            &["unload", &launchd_plist_path().to_string_lossy()],
+        );
+    }
+}
+
+/// Stop and remove the provider service, if it was installed
+pub fn uninstall_provider_service() -> SetupResult<bool> {
+    if cfg!(target_os = "linux") {
+        let unit_path = systemd_unit_path();
+        if !unit_path.exists() {
+            return Ok(false);
+        }
+        let unit_name = systemd_unit_name();
+        let _ = run("systemctl", &["stop", &unit_name]);
+        let _ = run("systemctl", &["disable", &unit_name]);
+        fs::remove_file(&unit_path)?;
+        run("systemctl", &["daemon-reload"])?;
+    } else {
+        let plist_path = launchd_plist_path();
+        if !plist_path.exists() {
+            return Ok(false);
+        }
+        let _ = run("launchctl", &["unload", &plist_path.to_string_lossy()]);
+        fs::remove_file(&plist_path)?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_service_definitions_run_serve() {
+        let spec = ServeSpec {
+            name: "meda serve",
+            binary: PathBuf::from("/usr/local/bin/meda"),
+            args: vec![
+                "serve".to_string(),
+                "--port".to_string(),
+                "7777".to_string(),
+            ],
+            env: vec![("MEDA_API_TOKEN".to_string(), "secret".to_string())],
+            stdout_log: PathBuf::from("/var/log/meda-stdout.log"),
+            stderr_log: PathBuf::from("/var/log/meda-stderr.log"),
+        };
+        assert!(systemd_unit(&spec).contains("ExecStart=/usr/local/bin/meda serve --port 7777\n"));
+        assert!(systemd_unit(&spec).contains("StandardError=append:/var/log/meda-stderr.log"));
+
+        let plist = launchd_plist(&spec);
+        assert!(plist.contains(
+            "        <string>/usr/local/bin/meda</string>\n        <string>serve</string>\n"
+        ));
+        assert!(plist.contains("<string>io.cirun.lume</string>"));
+        assert!(systemd_unit(&spec).contains("Environment=\"MEDA_API_TOKEN=secret\"\n"));
+        assert!(
+            plist.contains("        <key>MEDA_API_TOKEN</key>\n        <string>secret</string>\n")
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use crate::config::{agent_config, ReadinessMethod};
+use crate::endpoints;
+use crate::timing::record_booted_at;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+/// How often the guest agent or beacon table is checked
+const POLL_SECS: u64 = 1;
+/// A guest agent that doesn't answer a ping within this is not up yet
+const GUEST_PING_SECS: u64 = 2;
+/// Beacons nobody waited for are dropped after this long
+const BEACON_TTL_SECS: i64 = 3600;
+
+// Boot beacons received from guests, by VM name
+static BEACONS: Mutex<Option<HashMap<String, DateTime<Utc>>>> = Mutex::new(None);
+static BEACON_LISTENER: OnceLock<()> = OnceLock::new();
+
+/// Start listening for boot beacons (`GET /ready/<vm-name>`) when readiness uses them.
+/// Must be called from within the tokio runtime; later calls are ignored.
+pub fn start_beacon_listener() {
+    let config = &agent_config().readiness;
+    if config.method != ReadinessMethod::Beacon {
+        return;
+    }
+    let port = config.beacon_port;
+    BEACON_LISTENER.get_or_init(|| {
+        tokio::spawn(async move {
+            let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Failed to listen for boot beacons on port {}: {}", port, e);
+                    return;
+                }
+            };
+            info!("Listening for boot beacons on port {}", port);
+            loop {
+                if let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(handle_beacon(stream));
+                }
+            }
+        });
+    });
+}
+
+async fn handle_beacon(mut stream: tokio::net::TcpStream) {
+    let mut request_line = String::new();
+    let read = timeout(
+        Duration::from_secs(GUEST_PING_SECS),
+        BufReader::new(&mut stream).read_line(&mut request_line),
+    )
+    .await;
+    let status = match read {
+        Ok(Ok(_)) => match beacon_vm_name(&request_line) {
+            Some(vm_name) => {
+                record_beacon(vm_name, Utc::now());
+                "204 No Content"
+            }
+            None => "404 Not Found",
+        },
+        _ => return,
+    };
+    let _ = stream
+        .write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes())
+        .await;
+}
+
+/// VM name from a `GET /ready/<vm-name> HTTP/1.1` request line
+fn beacon_vm_name(request_line: &str) -> Option<&str> {
+    let mut parts = request_line.split_whitespace();
+    if !matches!(parts.next(), Some("GET") | Some("POST")) {
+        return None;
+    }
+    let name = parts.next()?.strip_prefix("/ready/")?;
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    valid.then_some(name)
+}
+
+fn record_beacon(vm_name: &str, at: DateTime<Utc>) {
+    let mut beacons = BEACONS.lock().unwrap_or_else(|e| e.into_inner());
+    let beacons = beacons.get_or_insert_with(HashMap::new);
+    beacons.retain(|_, received| (at - *received).num_seconds() < BEACON_TTL_SECS);
+    beacons.insert(vm_name.to_string(), at);
+}
+
+fn take_beacon(vm_name: &str) -> Option<DateTime<Utc>> {
+    BEACONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_mut()?
+        .remove(vm_name)
+}
+
+pub fn guest_agent_socket(vm_name: &str) -> String {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    let template = &agent_config().readiness.guest_agent_socket;
+    let path = template.replace("{name}", vm_name);
+    match path.strip_prefix("~/") {
+        Some(rest) => format!("{}/{}", home_dir, rest),
+        None => path,
+    }
+}
+
+/// Whether the QEMU guest agent behind `socket` answers a ping. The socket is a Unix
+/// socket, so elsewhere it never does.
+#[cfg(not(unix))]
+async fn guest_agent_responds(_socket: &str) -> bool {
+    false
+}
+
+/// Whether the QEMU guest agent behind `socket` answers a ping
+#[cfg(unix)]
+async fn guest_agent_responds(socket: &str) -> bool {
+    let ping = async {
+        let mut stream = tokio::net::UnixStream::connect(socket).await.ok()?;
+        stream
+            .write_all(b"{\"execute\":\"guest-ping\"}\n")
+            .await
+            .ok()?;
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response).await.ok()?;
+        Some(response.contains("\"return\""))
+    };
+    timeout(Duration::from_secs(GUEST_PING_SECS), ping)
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or(false)
+}
+
+/// Wait until the guest reports that it finished booting, using the configured readiness
+/// method, and record when that happened. Returns None when readiness is detected by SSH
+/// alone or the guest did not report within `timeout_secs`; callers then rely on SSH.
+pub async fn wait_until_booted(vm_name: &str, timeout_secs: u64) -> Option<DateTime<Utc>> {
+    let method = agent_config().readiness.method;
+    // The guest agent socket only exists on the VM's own host
+    let remote_guest_agent =
+        method == ReadinessMethod::GuestAgent && endpoints::current().is_some();
+    if method == ReadinessMethod::Ssh || remote_guest_agent {
+        return None;
+    }
+    info!("Waiting for VM '{}' to report boot ({:?})", vm_name, method);
+    let deadline = Instant::now() + Duration::from_secs(timeout_secs);
+    let socket = guest_agent_socket(vm_name);
+
+    while Instant::now() < deadline {
+        let booted_at = match method {
+            ReadinessMethod::GuestAgent => {
+                if guest_agent_responds(&socket).await {
+                    Some(Utc::now())
+                } else {
+                    None
+                }
+            }
+            ReadinessMethod::Beacon => take_beacon(vm_name),
+            ReadinessMethod::Ssh => None,
+        };
+        if let Some(booted_at) = booted_at {
+            info!("VM '{}' booted at {}", vm_name, booted_at.to_rfc3339());
+            record_booted_at(booted_at);
+            return Some(booted_at);
+        }
+        sleep(Duration::from_secs(POLL_SECS)).await;
+    }
+    warn!(
+        "VM '{}' did not report boot within {}s, falling back to SSH",
+        vm_name, timeout_secs
+    );
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_beacon_requests() {
+        assert_eq!(
+            beacon_vm_name("GET /ready/cirun-runner-1 HTTP/1.1\r\n"),
+            Some("cirun-runner-1")
+        );
+        assert_eq!(beacon_vm_name("GET /ready/ HTTP/1.1"), None);
+        assert_eq!(beacon_vm_name("GET /ready/../etc HTTP/1.1"), None);
+        assert_eq!(beacon_vm_name("DELETE /ready/vm HTTP/1.1"), None);
+
+        let now = Utc::now();
+        record_beacon("vm-a", now);
+        assert_eq!(take_beacon("vm-a"), Some(now));
+        assert_eq!(take_beacon("vm-a"), None);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::config::RetryPolicy;
+use crate::ssh::SshError;
+use backon::{ExponentialBuilder, Retryable};
+use log::warn;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Whether a failed operation is worth another attempt. Retry sites ask the error instead of
+/// matching on its message.
+pub trait Classify {
+    /// Transient failures, e.g. timeouts, dropped connections and server errors
+    fn is_retryable(&self) -> bool;
+
+    /// Delay the other side asked for before the next attempt, e.g. from `Retry-After`
+    fn retry_hint(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Statuses that may turn into a success when the request is repeated
+pub fn retryable_status(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// Delay of a `Retry-After: <seconds>` header
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+impl Classify for reqwest::Error {
+    fn is_retryable(&self) -> bool {
+        match self.status() {
+            Some(status) => retryable_status(status),
+            // A body cut off mid-transfer parses badly, so decode errors are retried too
+            None => {
+                self.is_timeout()
+                    || self.is_connect()
+                    || self.is_request()
+                    || self.is_body()
+                    || self.is_decode()
+            }
+        }
+    }
+}
+
+impl Classify for std::io::Error {
+    fn is_retryable(&self) -> bool {
+        use std::io::ErrorKind::*;
+        matches!(
+            self.kind(),
+            TimedOut | ConnectionReset | ConnectionAborted | Interrupted | UnexpectedEof
+        )
+    }
+}
+
+/// Boxed errors are classified by what they hold; anything unknown is fatal
+impl Classify for Box<dyn std::error::Error + Send + Sync> {
+    fn is_retryable(&self) -> bool {
+        if let Some(e) = self.downcast_ref::<reqwest::Error>() {
+            e.is_retryable()
+        } else if let Some(e) = self.downcast_ref::<std::io::Error>() {
+            e.is_retryable()
+        } else if let Some(e) = self.downcast_ref::<SshError>() {
+            e.is_retryable()
+        } else {
+            false
+        }
+    }
+}
+
+fn backoff(policy: &RetryPolicy) -> ExponentialBuilder {
+    ExponentialBuilder::default()
+        .with_min_delay(Duration::from_millis(policy.base_delay_ms))
+        .with_max_delay(Duration::from_secs(policy.max_delay_secs))
+        .with_max_times(policy.max_retries)
+}
+
+/// Run `operation` with the backoff of `policy`, retrying failures their error classifies as
+/// retryable and waiting at least as long as a failure's `retry_hint` asks for
+pub async fn with_retries<T, E, F, Fut>(
+    what: &str,
+    policy: RetryPolicy,
+    operation: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Classify + Debug,
+{
+    // backon picks the delay before it sleeps, so the hint is handed from `notify` to `sleep`
+    let hint: Arc<Mutex<Option<Duration>>> = Arc::new(Mutex::new(None));
+    let pending = hint.clone();
+    operation
+        .retry(backoff(&policy))
+        .sleep(move |delay: Duration| {
+            let hinted = pending.lock().unwrap_or_else(|e| e.into_inner()).take();
+            tokio::time::sleep(hinted.map_or(delay, |hinted| hinted.max(delay)))
+        })
+        .when(|e: &E| e.is_retryable())
+        .notify(|e: &E, delay: Duration| {
+            let hinted = e.retry_hint();
+            *hint.lock().unwrap_or_else(|e| e.into_inner()) = hinted;
+            warn!(
+                "Retrying {} after {:?}: {:?}",
+                what,
+                hinted.map_or(delay, |hinted| hinted.max(delay)),
+                e
+            );
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Failure {
+        retryable: bool,
+    }
+
+    impl Classify for Failure {
+        fn is_retryable(&self) -> bool {
+            self.retryable
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_retryable_failures_are_retried() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            base_delay_ms: 1,
+            max_delay_secs: 1,
+        };
+        let mut attempts = 0;
+        let result: Result<(), Failure> = with_retries("test", policy, || {
+            attempts += 1;
+            async { Err(Failure { retryable: true }) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 4);
+
+        let mut attempts = 0;
+        let result: Result<(), Failure> = with_retries("test", policy, || {
+            attempts += 1;
+            async { Err(Failure { retryable: false }) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        assert!(retryable_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!retryable_status(StatusCode::NOT_FOUND));
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, "7".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(7)));
+    }
+}
@@ -0,0 +1,162 @@
+use crate::config::{agent_config, RetryConfig};
+use crate::state::StateStore;
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+/// Consecutive provisioning failures of a runner, kept until it provisions successfully or
+/// the API stops asking for it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailureRecord {
+    pub consecutive: u32,
+    pub last_error: String,
+    pub next_attempt_at: DateTime<Utc>,
+    /// Set once the API has been told the agent gave up on the runner
+    #[serde(default)]
+    pub gave_up: bool,
+}
+
+/// What to do with a requested runner given its failure history
+#[derive(Debug, Clone, PartialEq)]
+pub enum RetryDecision {
+    Attempt,
+    /// Failed recently; the next attempt waits until then
+    BackOff(DateTime<Utc>),
+    /// The budget is spent; `report` is set until the API has been told once
+    GiveUp {
+        failures: u32,
+        last_error: String,
+        report: bool,
+    },
+}
+
+/// Exponential backoff after `failures` consecutive failures, capped at `max_delay_secs`
+fn retry_delay(config: &RetryConfig, failures: u32) -> chrono::Duration {
+    let factor = 1u64 << failures.saturating_sub(1).min(20);
+    let secs = config
+        .base_delay_secs
+        .saturating_mul(factor)
+        .min(config.max_delay_secs);
+    chrono::Duration::seconds(secs as i64)
+}
+
+fn decide(record: Option<&FailureRecord>, budget: u32, now: DateTime<Utc>) -> RetryDecision {
+    match record {
+        None => RetryDecision::Attempt,
+        Some(record) if record.consecutive >= budget => RetryDecision::GiveUp {
+            failures: record.consecutive,
+            last_error: record.last_error.clone(),
+            report: !record.gave_up,
+        },
+        Some(record) if record.next_attempt_at > now => {
+            RetryDecision::BackOff(record.next_attempt_at)
+        }
+        Some(_) => RetryDecision::Attempt,
+    }
+}
+
+/// Failure budget of a runner: the API's `max_retries`, else `[retry] budget`
+pub fn budget(max_retries: Option<u32>) -> u32 {
+    max_retries.unwrap_or(agent_config().retry.budget)
+}
+
+/// Whether a requested runner may be provisioned now
+pub fn decision(runner_name: &str, budget: u32) -> RetryDecision {
+    StateStore::new().read(|state| decide(state.failures.get(runner_name), budget, Utc::now()))
+}
+
+/// Count a failed provisioning attempt and schedule the next one; returns the number of
+/// consecutive failures
+pub fn record_failure(runner_name: &str, error: &str) -> u32 {
+    let config = &agent_config().retry;
+    StateStore::new().update(|state| {
+        let now = Utc::now();
+        let record = state
+            .failures
+            .entry(runner_name.to_string())
+            .or_insert_with(|| FailureRecord {
+                consecutive: 0,
+                last_error: String::new(),
+                next_attempt_at: now,
+                gave_up: false,
+            });
+        record.consecutive += 1;
+        record.last_error = error.to_string();
+        record.next_attempt_at = now + retry_delay(config, record.consecutive);
+        info!(
+            "Runner '{}' failed {} times in a row; next attempt after {}",
+            runner_name,
+            record.consecutive,
+            record.next_attempt_at.format("%H:%M:%S")
+        );
+        record.consecutive
+    })
+}
+
+/// Remember that the API was told the agent gave up on a runner
+pub fn mark_gave_up(runner_name: &str) {
+    StateStore::new().update(|state| {
+        if let Some(record) = state.failures.get_mut(runner_name) {
+            record.gave_up = true;
+        }
+    });
+}
+
+/// Reset a runner's failures after it provisioned successfully
+pub fn clear(runner_name: &str) {
+    StateStore::new().update(|state| {
+        state.failures.remove(runner_name);
+    });
+}
+
+/// Drop the failures of runners the API no longer asks for
+pub fn retain_requested(requested: &[String]) {
+    StateStore::new().update(|state| {
+        state.failures.retain(|name, _| requested.contains(name));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_and_budget() {
+        let config = RetryConfig::default();
+        assert_eq!(retry_delay(&config, 1).num_seconds(), 30);
+        assert_eq!(retry_delay(&config, 3).num_seconds(), 120);
+        assert_eq!(retry_delay(&config, 100).num_seconds(), 30 * 60);
+
+        let now = Utc::now();
+        assert_eq!(decide(None, 3, now), RetryDecision::Attempt);
+        let mut record = FailureRecord {
+            consecutive: 1,
+            last_error: "image is corrupt".to_string(),
+            next_attempt_at: now + chrono::Duration::seconds(30),
+            gave_up: false,
+        };
+        assert_eq!(
+            decide(Some(&record), 3, now),
+            RetryDecision::BackOff(record.next_attempt_at)
+        );
+        assert_eq!(
+            decide(Some(&record), 3, now + chrono::Duration::seconds(31)),
+            RetryDecision::Attempt
+        );
+
+        record.consecutive = 3;
+        assert_eq!(
+            decide(Some(&record), 3, now),
+            RetryDecision::GiveUp {
+                failures: 3,
+                last_error: "image is corrupt".to_string(),
+                report: true,
+            }
+        );
+        record.gave_up = true;
+        assert!(matches!(
+            decide(Some(&record), 3, now),
+            RetryDecision::GiveUp { report: false, .. }
+        ));
+    }
+}
@@ -0,0 +1,429 @@
+use crate::config::agent_config;
+use crate::guest_files::GuestFile;
+use crate::retry::with_retries;
+use crate::use_meda;
+use log::{info, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+
+const CACHE_DIR: &str = ".cirun-agent/runner-cache";
+const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/actions/runner/releases/latest";
+const RELEASE_BY_TAG_URL: &str = "https://api.github.com/repos/actions/runner/releases/tags";
+const DOWNLOAD_URL: &str = "https://github.com/actions/runner/releases/download";
+/// Tarballs kept per platform, so runners still being provisioned keep theirs after a refresh
+const KEEP_VERSIONS: usize = 2;
+
+/// Latest release and when it was looked up
+static LATEST: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+/// One download per tarball at a time
+static DOWNLOADS: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    #[serde(default)]
+    body: String,
+    #[serde(default)]
+    assets: Vec<ReleaseAsset>,
+}
+
+#[derive(Deserialize)]
+struct ReleaseAsset {
+    name: String,
+    /// e.g. `sha256:<hex>`
+    #[serde(default)]
+    digest: Option<String>,
+}
+
+impl Release {
+    /// sha256 of the release download `file_name` for `platform`, from the asset digest or
+    /// the `<!-- BEGIN SHA <platform> -->` block in the release notes
+    fn sha256(&self, file_name: &str, platform: &str) -> Option<String> {
+        let from_asset = self
+            .assets
+            .iter()
+            .find(|asset| asset.name == file_name)
+            .and_then(|asset| asset.digest.as_deref()?.strip_prefix("sha256:"));
+        let from_notes = || {
+            let start = format!("<!-- BEGIN SHA {} -->", platform);
+            let end = format!("<!-- END SHA {} -->", platform);
+            let rest = &self.body[self.body.find(&start)? + start.len()..];
+            Some(rest[..rest.find(&end)?].trim())
+        };
+        from_asset
+            .or_else(from_notes)
+            .filter(|sha| sha.len() == 64 && sha.chars().all(|c| c.is_ascii_hexdigit()))
+            .map(|sha| sha.to_lowercase())
+    }
+}
+
+/// The runner tarball placed on a guest, and the script variables describing it
+pub struct CachedRunner {
+    pub version: String,
+    pub file: GuestFile,
+}
+
+impl CachedRunner {
+    pub fn variables(&self) -> HashMap<String, String> {
+        HashMap::from([
+            ("actions_runner_version".to_string(), self.version.clone()),
+            ("actions_runner_tarball".to_string(), self.file.path.clone()),
+        ])
+    }
+}
+
+fn cache_dir() -> PathBuf {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home_dir).join(CACHE_DIR)
+}
+
+/// Release platform of a runner, e.g. `linux-arm64`. Guests share the host's architecture.
+/// Windows runners ship as zip archives and are not cached.
+fn platform(os: &str) -> Option<String> {
+    let os = match os.trim().to_lowercase().as_str() {
+        "" if use_meda() => "linux",
+        "" => "osx",
+        "linux" => "linux",
+        "macos" | "osx" | "darwin" => "osx",
+        _ => return None,
+    };
+    let arch = match std::env::consts::ARCH {
+        "x86_64" => "x64",
+        "aarch64" => "arm64",
+        "arm" => "arm",
+        _ => return None,
+    };
+    Some(format!("{}-{}", os, arch))
+}
+
+fn tarball_name(platform: &str, version: &str) -> String {
+    format!("actions-runner-{}-{}.tar.gz", platform, version)
+}
+
+fn release_url(version: &str, file_name: &str) -> String {
+    format!("{}/v{}/{}", DOWNLOAD_URL, version, file_name)
+}
+
+/// Release platform in a runner tarball name, e.g. `linux-x64`
+fn tarball_platform<'a>(file_name: &'a str, version: &str) -> Option<&'a str> {
+    file_name
+        .strip_prefix("actions-runner-")?
+        .strip_suffix(&format!("-{}.tar.gz", version))
+}
+
+/// Version and tarball name of a GitHub Actions runner release download URL
+fn parse_release_url(url: &str) -> Option<(&str, &str)> {
+    let rest = url.strip_prefix(DOWNLOAD_URL)?.strip_prefix("/v")?;
+    let (version, file_name) = rest.split_once('/')?;
+    let expected_suffix = format!("-{}.tar.gz", version);
+    (file_name.starts_with("actions-runner-")
+        && file_name.ends_with(&expected_suffix)
+        && !file_name.contains('/'))
+    .then_some((version, file_name))
+}
+
+/// Newest version among the cached tarballs of `platform`
+fn newest_cached_version(dir: &Path, platform: &str) -> Option<String> {
+    let prefix = format!("actions-runner-{}-", platform);
+    let mut cached: Vec<(std::time::SystemTime, String)> = std::fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let version = name.strip_prefix(&prefix)?.strip_suffix(".tar.gz")?;
+            Some((entry.metadata().ok()?.modified().ok()?, version.to_string()))
+        })
+        .collect();
+    cached.sort();
+    cached.pop().map(|(_, version)| version)
+}
+
+/// The runner version to use: the configured one, or the latest release, looked up at most
+/// once per `refresh_hours`. If GitHub cannot be reached, the newest cached version is used.
+async fn resolve_version(platform: &str) -> Option<String> {
+    let config = &agent_config().runner_cache;
+    if let Some(version) = &config.version {
+        return Some(version.trim_start_matches('v').to_string());
+    }
+    let max_age = Duration::from_secs(config.refresh_hours * 3600);
+    if let Some((version, fetched)) = LATEST.lock().unwrap_or_else(|e| e.into_inner()).clone() {
+        if fetched.elapsed() < max_age {
+            return Some(version);
+        }
+    }
+    match latest_release().await {
+        Ok(version) => {
+            *LATEST.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some((version.clone(), Instant::now()));
+            Some(version)
+        }
+        Err(e) => {
+            let cached = newest_cached_version(&cache_dir(), platform);
+            warn!(
+                "Failed to look up the latest actions runner release: {}. Using cached version {:?}.",
+                e, cached
+            );
+            cached
+        }
+    }
+}
+
+async fn fetch_release(url: &str) -> Result<Release, reqwest::Error> {
+    let lookup = || async {
+        reqwest::Client::new()
+            .get(url)
+            .header("User-Agent", "cirun-agent")
+            .timeout(Duration::from_secs(30))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Release>()
+            .await
+    };
+    with_retries(
+        "runner release lookup",
+        agent_config().retry.downloads,
+        lookup,
+    )
+    .await
+}
+
+async fn latest_release() -> Result<String, reqwest::Error> {
+    let release = fetch_release(LATEST_RELEASE_URL).await?;
+    Ok(release.tag_name.trim_start_matches('v').to_string())
+}
+
+/// The published sha256 of the tarball `file_name` of `version`
+async fn release_sha256(version: &str, file_name: &str) -> Result<String, String> {
+    let platform = tarball_platform(file_name, version)
+        .ok_or_else(|| format!("{} is not an actions runner tarball", file_name))?;
+    let release = fetch_release(&format!("{}/v{}", RELEASE_BY_TAG_URL, version))
+        .await
+        .map_err(|e| {
+            format!(
+                "Failed to look up actions runner release {}: {}",
+                version, e
+            )
+        })?;
+    release.sha256(file_name, platform).ok_or_else(|| {
+        format!(
+            "Actions runner release {} has no checksum for {}",
+            version, file_name
+        )
+    })
+}
+
+/// Remove all but the newest `KEEP_VERSIONS` tarballs of `platform`
+fn prune(dir: &Path, platform: &str) {
+    let prefix = format!("actions-runner-{}-", platform);
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut tarballs: Vec<(std::time::SystemTime, PathBuf)> = entries
+        .flatten()
+        .filter(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            name.starts_with(&prefix) && name.ends_with(".tar.gz")
+        })
+        .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+        .collect();
+    tarballs.sort();
+    let excess = tarballs.len().saturating_sub(KEEP_VERSIONS);
+    for (_, path) in tarballs.into_iter().take(excess) {
+        info!("Removing old actions runner tarball {:?}", path);
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Path of the cached tarball `file_name` of `version`, downloading it first if needed
+async fn cached_tarball(version: &str, file_name: &str) -> Result<PathBuf, String> {
+    let dir = cache_dir();
+    let path = dir.join(file_name);
+    let _download = DOWNLOADS.lock().await;
+    if path.is_file() {
+        return Ok(path);
+    }
+    let url = release_url(version, file_name);
+    let expected = release_sha256(version, file_name).await?;
+    info!("Caching actions runner {} from {}", version, url);
+    let download = || async {
+        tokio::fs::create_dir_all(&dir).await?;
+        let partial = path.with_extension("part");
+        let mut response = reqwest::Client::new()
+            .get(&url)
+            .timeout(Duration::from_secs(
+                agent_config().timeouts.runner_download_secs,
+            ))
+            .send()
+            .await?
+            .error_for_status()?;
+        let mut file = tokio::fs::File::create(&partial).await?;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = response.chunk().await? {
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        drop(file);
+        let actual = format!("{:x}", hasher.finalize());
+        if actual != expected {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(format!("checksum mismatch: expected {}, got {}", expected, actual).into());
+        }
+        tokio::fs::rename(&partial, &path).await?;
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+    };
+    with_retries("runner download", agent_config().retry.downloads, download)
+        .await
+        .map_err(|e| format!("Failed to download {}: {}", url, e))?;
+    if let Some(platform) = tarball_platform(file_name, version) {
+        prune(&dir, platform);
+    }
+    Ok(path)
+}
+
+/// The cached copy of a runner release download, if caching is enabled and `url` is one
+pub async fn cached_download(url: &str) -> Option<Result<PathBuf, String>> {
+    if !agent_config().runner_cache.enabled {
+        return None;
+    }
+    let (version, file_name) = parse_release_url(url)?;
+    Some(cached_tarball(version, file_name).await)
+}
+
+/// The runner tarball for `os` guests, to place at `guest_path`. With caching enabled it is
+/// downloaded to the host first; otherwise it is fetched from GitHub when pushed.
+pub async fn runner_tarball(os: &str, guest_path: &str) -> Result<CachedRunner, String> {
+    let platform =
+        platform(os).ok_or_else(|| format!("No Actions runner tarball for '{}' guests", os))?;
+    let version = resolve_version(&platform)
+        .await
+        .ok_or("Unknown Actions runner version")?;
+    let file_name = tarball_name(&platform, &version);
+    if agent_config().runner_cache.enabled {
+        cached_tarball(&version, &file_name).await?;
+    }
+    Ok(CachedRunner {
+        file: GuestFile {
+            path: guest_path.to_string(),
+            content: None,
+            url: Some(release_url(&version, &file_name)),
+            sha256: None,
+            mode: "0644".to_string(),
+        },
+        version,
+    })
+}
+
+/// The runner tarball to push into a new `os` runner, cached on the host. `None` when caching
+/// is off or fails; the provision script then downloads the runner itself.
+pub async fn runner_for(os: &str) -> Option<CachedRunner> {
+    let config = &agent_config().runner_cache;
+    if !config.enabled {
+        return None;
+    }
+    runner_tarball(os, &config.guest_path)
+        .await
+        .map_err(|e| warn!("{}", e))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_release_urls() {
+        let name = tarball_name("linux-x64", "2.319.1");
+        let url = release_url("2.319.1", &name);
+        assert_eq!(
+            url,
+            "https://github.com/actions/runner/releases/download/v2.319.1/actions-runner-linux-x64-2.319.1.tar.gz"
+        );
+        assert_eq!(parse_release_url(&url), Some(("2.319.1", name.as_str())));
+        assert_eq!(
+            parse_release_url("https://github.com/actions/runner/releases/download/v2.319.1/actions-runner-win-x64-2.319.1.zip"),
+            None
+        );
+        assert_eq!(
+            parse_release_url("https://example.com/actions-runner-linux-x64-2.319.1.tar.gz"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_release_sha256() {
+        let sha = "a".repeat(64);
+        let name = tarball_name("linux-x64", "2.319.1");
+        let release = Release {
+            tag_name: "v2.319.1".to_string(),
+            body: format!(
+                "- Linux x64\n<!-- BEGIN SHA linux-x64 -->{}<!-- END SHA linux-x64 -->\n<!-- BEGIN SHA osx-arm64 -->not-a-sha<!-- END SHA osx-arm64 -->",
+                sha
+            ),
+            assets: Vec::new(),
+        };
+        assert_eq!(release.sha256(&name, "linux-x64"), Some(sha.clone()));
+        assert_eq!(
+            release.sha256("actions-runner-osx-arm64-2.319.1.tar.gz", "osx-arm64"),
+            None
+        );
+        assert_eq!(
+            release.sha256("actions-runner-linux-arm-2.319.1.tar.gz", "linux-arm"),
+            None
+        );
+
+        let digest = "B".repeat(64);
+        let release = Release {
+            assets: vec![ReleaseAsset {
+                name: name.clone(),
+                digest: Some(format!("sha256:{}", digest)),
+            }],
+            ..release
+        };
+        assert_eq!(
+            release.sha256(&name, "linux-x64"),
+            Some(digest.to_lowercase())
+        );
+    }
+
+    #[test]
+    fn test_prune_keeps_newest() {
+        let dir = tempfile::tempdir().unwrap();
+        for (i, version) in ["2.317.0", "2.318.0", "2.319.1"].iter().enumerate() {
+            let path = dir.path().join(tarball_name("osx-arm64", version));
+            std::fs::write(&path, b"tarball").unwrap();
+            let modified = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1000 + i as u64);
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+        std::fs::write(dir.path().join(tarball_name("linux-x64", "2.300.0")), b"").unwrap();
+
+        assert_eq!(
+            newest_cached_version(dir.path(), "osx-arm64").as_deref(),
+            Some("2.319.1")
+        );
+        prune(dir.path(), "osx-arm64");
+        assert!(!dir
+            .path()
+            .join(tarball_name("osx-arm64", "2.317.0"))
+            .exists());
+        assert!(dir
+            .path()
+            .join(tarball_name("osx-arm64", "2.318.0"))
+            .exists());
+        assert!(dir
+            .path()
+            .join(tarball_name("linux-x64", "2.300.0"))
+            .exists());
+    }
+}
@@ -0,0 +1,167 @@
+use crate::log_stream::OutputStream;
+use crate::timing::PhaseTimings;
+use chrono::Utc;
+use log::{info, warn};
+use serde_json::json;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const RUNNERS_DIR: &str = ".cirun-agent/runners";
+const SCRIPT_FILE: &str = "provision.sh";
+const TRANSCRIPT_FILE: &str = "transcript.log";
+const RESULT_FILE: &str = "result.json";
+const VM_FILE: &str = "vm.json";
+
+/// Root directory holding one subdirectory per runner
+pub fn runners_log_root() -> PathBuf {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home_dir).join(RUNNERS_DIR)
+}
+
+/// Directory for a single runner's provisioning artifacts, created on first use
+fn runner_dir(runner_name: &str) -> std::io::Result<PathBuf> {
+    // Runner names come from the API; never let one escape the runners directory
+    let safe_name: String = runner_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let dir = runners_log_root().join(safe_name);
+    fs::create_dir_all(&dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // Provision scripts carry runner registration tokens
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(dir)
+}
+
+fn write_file(runner_name: &str, file: &str, contents: &[u8]) -> std::io::Result<()> {
+    let path = runner_dir(runner_name)?.join(file);
+    fs::write(path, contents)
+}
+
+/// Keep a copy of the rendered provision script that was sent to the runner
+pub fn save_script(runner_name: &str, script: &str) {
+    if let Err(e) = write_file(runner_name, SCRIPT_FILE, script.as_bytes()) {
+        warn!(
+            "Failed to save provision script for '{}': {}",
+            runner_name, e
+        );
+    }
+}
+
+/// The provision script last saved for a runner
+pub fn saved_script(runner_name: &str) -> Option<String> {
+    fs::read_to_string(runner_dir(runner_name).ok()?.join(SCRIPT_FILE)).ok()
+}
+
+/// Append a line of runner output to its transcript
+pub fn append_transcript(runner_name: &str, stream: OutputStream, line: &str) {
+    let result = runner_dir(runner_name).and_then(|dir| {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(TRANSCRIPT_FILE))?;
+        let tag = match stream {
+            OutputStream::Stdout => "out",
+            OutputStream::Stderr => "err",
+        };
+        writeln!(file, "{} [{}] {}", Utc::now().to_rfc3339(), tag, line)
+    });
+    if let Err(e) = result {
+        warn!("Failed to write transcript for '{}': {}", runner_name, e);
+    }
+}
+
+/// Record the outcome and phase timings of a provisioning attempt
+pub fn save_result(runner_name: &str, outcome: &Result<(), String>, timings: &PhaseTimings) {
+    let result = json!({
+        "runner_name": runner_name,
+        "success": outcome.is_ok(),
+        "error": outcome.as_ref().err(),
+        "finished_at": Utc::now(),
+        "phases": timings,
+    });
+    let contents = serde_json::to_vec_pretty(&result).unwrap_or_default();
+    if let Err(e) = write_file(runner_name, RESULT_FILE, &contents) {
+        warn!(
+            "Failed to save provision result for '{}': {}",
+            runner_name, e
+        );
+    }
+}
+
+/// Keep the provider's last details of a runner VM that is about to be removed
+pub fn save_vm_info(runner_name: &str, details: &serde_json::Value) {
+    let contents = serde_json::to_vec_pretty(details).unwrap_or_default();
+    if let Err(e) = write_file(runner_name, VM_FILE, &contents) {
+        warn!("Failed to save VM details for '{}': {}", runner_name, e);
+    }
+}
+
+/// The transcript, result and VM details saved for a runner, by file name. The provision
+/// script is left out, since it carries the runner registration token.
+pub fn diagnostic_files(runner_name: &str) -> Vec<(&'static str, Vec<u8>)> {
+    let Ok(dir) = runner_dir(runner_name) else {
+        return Vec::new();
+    };
+    [TRANSCRIPT_FILE, RESULT_FILE, VM_FILE]
+        .into_iter()
+        .filter_map(|file| Some((file, fs::read(dir.join(file)).ok()?)))
+        .collect()
+}
+
+/// Newest modification time of any file in a runner directory
+fn last_modified(dir: &Path) -> Option<SystemTime> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.metadata().ok()?.modified().ok())
+        .max()
+}
+
+/// Remove runner directories untouched for longer than `max_age_days` and rotate
+/// transcripts larger than `max_size_mb`, matching the retention of the agent logs
+pub fn cleanup_runner_logs(
+    max_age_days: u64,
+    max_size_mb: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let root = runners_log_root();
+    if !root.exists() {
+        return Ok(());
+    }
+
+    let max_age = Duration::from_secs(max_age_days * 24 * 60 * 60);
+    let now = SystemTime::now();
+    for entry in fs::read_dir(&root)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let expired = last_modified(&path)
+            .and_then(|modified| now.duration_since(modified).ok())
+            .is_none_or(|age| age > max_age);
+        if expired {
+            info!("Removing old runner log directory: {:?}", path);
+            fs::remove_dir_all(&path)?;
+            continue;
+        }
+
+        let transcript = path.join(TRANSCRIPT_FILE);
+        let size = fs::metadata(&transcript).map(|m| m.len()).unwrap_or(0);
+        if size > max_size_mb * 1024 * 1024 {
+            info!("Transcript too large, rotating: {:?}", transcript);
+            fs::rename(&transcript, transcript.with_extension("log.old"))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use chrono::{DateTime, Local, NaiveTime, TimeZone};
+
+/// A daily time window (local time) during which no new runners are provisioned.
+/// Windows may wrap around midnight, e.g. `22:00-06:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietWindow {
+    /// Check whether the given time of day falls inside the window
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Parse a window in `HH:MM-HH:MM` format (used as a clap value parser)
+pub fn parse_quiet_window(value: &str) -> Result<QuietWindow, String> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| format!("Invalid window '{}': expected HH:MM-HH:MM", value))?;
+
+    let parse_time = |t: &str| {
+        NaiveTime::parse_from_str(t.trim(), "%H:%M")
+            .map_err(|e| format!("Invalid time '{}' in window '{}': {}", t, value, e))
+    };
+
+    let window = QuietWindow {
+        start: parse_time(start)?,
+        end: parse_time(end)?,
+    };
+    if window.start == window.end {
+        return Err(format!("Invalid window '{}': start equals end", value));
+    }
+    Ok(window)
+}
+
+/// Return the window active at the given moment, if any
+pub fn active_window<Tz: TimeZone>(
+    windows: &[QuietWindow],
+    now: &DateTime<Tz>,
+) -> Option<QuietWindow> {
+    let time = now.time();
+    windows.iter().copied().find(|w| w.contains(time))
+}
+
+/// Return the quiet window active right now (local time), if any
+pub fn current_quiet_window(windows: &[QuietWindow]) -> Option<QuietWindow> {
+    active_window(windows, &Local::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quiet_window_parsing_and_matching() {
+        let day = parse_quiet_window("09:00-17:30").unwrap();
+        assert!(day.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+        assert!(!day.contains(NaiveTime::from_hms_opt(17, 30, 0).unwrap()));
+        assert!(!day.contains(NaiveTime::from_hms_opt(8, 59, 0).unwrap()));
+
+        // Windows that wrap around midnight
+        let night = parse_quiet_window("22:00-06:00").unwrap();
+        assert!(night.contains(NaiveTime::from_hms_opt(23, 15, 0).unwrap()));
+        assert!(night.contains(NaiveTime::from_hms_opt(3, 0, 0).unwrap()));
+        assert!(!night.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+
+        assert!(parse_quiet_window("22:00").is_err());
+        assert!(parse_quiet_window("25:00-06:00").is_err());
+        assert!(parse_quiet_window("06:00-06:00").is_err());
+    }
+}
@@ -0,0 +1,393 @@
+use crate::cancel;
+use crate::config::agent_config;
+use crate::endpoints;
+use crate::events::{self, AgentEvent};
+use crate::lifecycle::{runner_state, RunnerState};
+use crate::log_stream::{emit, OutputStream};
+use crate::state::StateStore;
+use crate::vm_provision::{
+    run_ssh_command, RemoteScript, SCRIPT_EXIT_FILE, SCRIPT_STDERR_LOG, SCRIPT_STDOUT_LOG,
+};
+use crate::RunnerLogin;
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const POLL_INTERVAL_SECS: u64 = 15;
+/// Lines of each log sent again when a watch is picked up after the agent lost track of it
+const LOG_TAIL_LINES: usize = 100;
+/// Most lines of each log forwarded per poll
+const FOLLOW_BATCH_LINES: usize = 500;
+/// Batches of each log forwarded at most once the script has exited
+const FINAL_BATCHES: usize = 10;
+/// The detached script's logs on the guest, and the stream each is forwarded as
+const SCRIPT_LOGS: [(&str, OutputStream); 2] = [
+    (SCRIPT_STDOUT_LOG, OutputStream::Stdout),
+    (SCRIPT_STDERR_LOG, OutputStream::Stderr),
+];
+
+/// Outcome of a provision script that was launched detached
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ScriptStatus {
+    Running,
+    Succeeded,
+    /// `exit_code` is None when the script died without recording one
+    Failed {
+        exit_code: Option<i32>,
+    },
+    /// Still running after the `script` timeout; the agent stopped watching it
+    TimedOut,
+    /// The agent restarted while the script ran and had nothing to resume watching it with
+    Unknown,
+}
+
+/// PID printed by the detached launch command
+fn parse_pid(launch_output: &str) -> Option<u32> {
+    launch_output.lines().last()?.trim().parse().ok()
+}
+
+/// Shell command printing `running`, `exited <code>` or `gone` for the script's shell
+fn poll_command(pid: u32) -> String {
+    format!(
+        "if kill -0 {pid} 2>/dev/null; then echo running; \
+         elif [ -f {exit} ]; then echo exited $(cat {exit}); else echo gone; fi",
+        pid = pid,
+        exit = SCRIPT_EXIT_FILE
+    )
+}
+
+fn parse_poll(output: &str) -> Option<ScriptStatus> {
+    let output = output.trim();
+    match output {
+        "running" => Some(ScriptStatus::Running),
+        "gone" => Some(ScriptStatus::Failed { exit_code: None }),
+        _ => match output.strip_prefix("exited ")?.trim().parse().ok()? {
+            0 => Some(ScriptStatus::Succeeded),
+            code => Some(ScriptStatus::Failed {
+                exit_code: Some(code),
+            }),
+        },
+    }
+}
+
+/// A detached provision script being watched, kept in the state file so the watch can be
+/// resumed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DetachedScript {
+    pub pid: u32,
+    pub ip_address: String,
+    pub script: RemoteScript,
+    pub started_at: DateTime<Utc>,
+    /// Login the script was started with, for resuming after an agent restart
+    pub login: RunnerLogin,
+}
+
+// Runners whose detached script is being watched by this process
+static WATCHING: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// Removes a runner from `WATCHING` when its watch ends
+struct WatchGuard(String);
+
+impl Drop for WatchGuard {
+    fn drop(&mut self) {
+        if let Some(watching) = WATCHING.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            watching.remove(&self.0);
+        }
+    }
+}
+
+/// Follow a detached provision script until it exits, in the background, then remove it
+/// from the guest. `launch_output` is what the launch command printed, ending with the PID
+/// of the script's shell.
+pub fn watch_detached(
+    runner_name: &str,
+    ip_address: &str,
+    login: &RunnerLogin,
+    launch_output: &str,
+    script: RemoteScript,
+) {
+    let Some(pid) = parse_pid(launch_output) else {
+        warn!(
+            "No PID in detached script output for '{}'; not watching it",
+            runner_name
+        );
+        return;
+    };
+    let detached = DetachedScript {
+        pid,
+        ip_address: ip_address.to_string(),
+        script,
+        started_at: Utc::now(),
+        login: login.clone(),
+    };
+    let state = StateStore::new();
+    state.record_script_status(runner_name, ScriptStatus::Running);
+    state.record_detached_script(runner_name, Some(detached.clone()));
+    spawn_watch(runner_name, detached, true);
+}
+
+/// Pick up watching the detached script of a runner whose VM was found already running
+/// it, e.g. after an agent restart. The VM may have a new `ip_address`.
+pub fn resume(
+    runner_name: &str,
+    ip_address: Option<&str>,
+    login: &RunnerLogin,
+) -> Result<(), String> {
+    let state = StateStore::new();
+    let Some(mut detached) = state.detached_script(runner_name) else {
+        return Err(format!(
+            "No detached provision script is recorded for '{}'",
+            runner_name
+        ));
+    };
+    if let Some(ip_address) = ip_address {
+        detached.ip_address = ip_address.to_string();
+    }
+    detached.login = login.clone();
+    spawn_watch(runner_name, detached, false);
+    Ok(())
+}
+
+/// Resume the watches a previous run of the agent left behind. Scripts it recorded no
+/// watch for are marked `unknown`, since nothing would ever finish them.
+pub fn resume_all() {
+    let state = StateStore::new();
+    for (runner_name, status) in state.script_statuses() {
+        if status != ScriptStatus::Running {
+            continue;
+        }
+        match state.detached_script(&runner_name) {
+            Some(detached) => {
+                info!(
+                    "Resuming watch of the provision script for '{}'",
+                    runner_name
+                );
+                spawn_watch(&runner_name, detached, false);
+            }
+            None => {
+                warn!(
+                    "Provision script for '{}' was running when the agent stopped; its outcome is unknown",
+                    runner_name
+                );
+                state.record_script_status(&runner_name, ScriptStatus::Unknown);
+            }
+        }
+    }
+}
+
+fn spawn_watch(runner_name: &str, detached: DetachedScript, from_start: bool) {
+    let newly_watched = WATCHING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashSet::new)
+        .insert(runner_name.to_string());
+    if !newly_watched {
+        debug!(
+            "Already watching the provision script for '{}'",
+            runner_name
+        );
+        return;
+    }
+    let guard = WatchGuard(runner_name.to_string());
+    cancel::spawn_provider_task(endpoints::on_endpoint(
+        endpoints::current().or_else(|| endpoints::endpoint_for_runner(runner_name)),
+        watch(runner_name.to_string(), detached, from_start, guard),
+    ));
+}
+
+async fn watch(
+    runner_name: String,
+    detached: DetachedScript,
+    from_start: bool,
+    _guard: WatchGuard,
+) {
+    let DetachedScript {
+        pid,
+        ip_address,
+        script,
+        started_at,
+        login,
+    } = detached;
+    let timeouts = &agent_config().timeouts;
+    let deadline = started_at + chrono::Duration::seconds(timeouts.script_secs as i64);
+    let mut logs = LogFollower::new(from_start);
+    let status = loop {
+        if Utc::now() >= deadline {
+            break ScriptStatus::TimedOut;
+        }
+        tokio::time::sleep(Duration::from_secs(POLL_INTERVAL_SECS)).await;
+        if matches!(
+            runner_state(&runner_name),
+            Some(RunnerState::Deleting | RunnerState::Deleted)
+        ) {
+            return;
+        }
+        let polled = run_ssh_command(
+            &script.vm_name,
+            &ip_address,
+            &login,
+            &poll_command(pid),
+            timeouts.ssh_attempt_secs,
+        )
+        .await
+        .map_err(|e| e.to_string());
+        match polled {
+            Ok(output) => match parse_poll(&String::from_utf8_lossy(&output.stdout)) {
+                Some(ScriptStatus::Running) | None => {}
+                Some(status) => break status,
+            },
+            Err(e) => debug!("Failed to poll script for '{}': {}", runner_name, e),
+        }
+        logs.forward(&runner_name, &script.vm_name, &ip_address, &login, false)
+            .await;
+    };
+
+    // Whatever the script wrote since the last poll
+    for _ in 0..FINAL_BATCHES {
+        let forwarded = logs
+            .forward(&runner_name, &script.vm_name, &ip_address, &login, true)
+            .await;
+        if forwarded == 0 {
+            break;
+        }
+    }
+    // A script that is still running must keep its file
+    if status != ScriptStatus::TimedOut {
+        script.clean_up(&ip_address, &login).await;
+    }
+    match status {
+        ScriptStatus::Succeeded => info!("Provision script for '{}' succeeded", runner_name),
+        status => warn!("Provision script for '{}' ended: {:?}", runner_name, status),
+    }
+    let state = StateStore::new();
+    state.record_script_status(&runner_name, status);
+    state.record_detached_script(&runner_name, None);
+    events::publish(AgentEvent::ScriptFinished {
+        runner_name,
+        status,
+    });
+}
+
+/// Forwards the lines a detached script appends to its logs to the transcript and the API
+struct LogFollower {
+    /// Lines of each log already forwarded; None until the watch knows where to start
+    forwarded: [Option<usize>; 2],
+}
+
+impl LogFollower {
+    /// A follower sending each log from its first line, or, for a watch picked up again,
+    /// from its last `LOG_TAIL_LINES` lines
+    fn new(from_start: bool) -> Self {
+        let start = from_start.then_some(0);
+        LogFollower {
+            forwarded: [start, start],
+        }
+    }
+
+    /// Forward the lines added since the last call, at most a batch per log. A last line
+    /// without a newline may still be being written, so it waits until `finished`.
+    /// Returns how many lines were forwarded.
+    async fn forward(
+        &mut self,
+        runner_name: &str,
+        vm_name: &str,
+        ip_address: &str,
+        login: &RunnerLogin,
+        finished: bool,
+    ) -> usize {
+        let timeout = agent_config().timeouts.ssh_attempt_secs;
+        let mut total = 0;
+        for (forwarded, (path, stream)) in self.forwarded.iter_mut().zip(SCRIPT_LOGS) {
+            let offset = match *forwarded {
+                Some(offset) => offset,
+                None => {
+                    let command = format!("wc -l < {} 2>/dev/null || echo 0", path);
+                    let counted = run_ssh_command(vm_name, ip_address, login, &command, timeout)
+                        .await
+                        .map_err(|e| e.to_string());
+                    match counted {
+                        Ok(output) => {
+                            let lines: usize = String::from_utf8_lossy(&output.stdout)
+                                .trim()
+                                .parse()
+                                .unwrap_or(0);
+                            *forwarded.insert(lines.saturating_sub(LOG_TAIL_LINES))
+                        }
+                        Err(e) => {
+                            debug!("Failed to count {} on '{}': {}", path, runner_name, e);
+                            continue;
+                        }
+                    }
+                }
+            };
+            let command = format!(
+                "tail -n +{} {} 2>/dev/null | head -n {}",
+                offset + 1,
+                path,
+                FOLLOW_BATCH_LINES
+            );
+            let output = run_ssh_command(vm_name, ip_address, login, &command, timeout)
+                .await
+                .map_err(|e| e.to_string());
+            match output {
+                Ok(output) => {
+                    let text = String::from_utf8_lossy(&output.stdout);
+                    let lines = complete_lines(&text, finished);
+                    for line in &lines {
+                        emit(runner_name, stream, line);
+                    }
+                    *forwarded = Some(offset + lines.len());
+                    total += lines.len();
+                }
+                Err(e) => debug!("Failed to fetch {} from '{}': {}", path, runner_name, e),
+            }
+        }
+        total
+    }
+}
+
+/// Lines of `text`, leaving out a last line without a newline unless `finished`
+fn complete_lines(text: &str, finished: bool) -> Vec<&str> {
+    let complete = if finished {
+        text
+    } else {
+        text.rfind('\n').map_or("", |end| &text[..=end])
+    };
+    complete.lines().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_launch_and_poll_output() {
+        assert_eq!(parse_pid("4242\n"), Some(4242));
+        assert_eq!(parse_pid(""), None);
+
+        assert_eq!(parse_poll("running\n"), Some(ScriptStatus::Running));
+        assert_eq!(parse_poll("exited 0\n"), Some(ScriptStatus::Succeeded));
+        assert_eq!(
+            parse_poll("exited 2"),
+            Some(ScriptStatus::Failed { exit_code: Some(2) })
+        );
+        assert_eq!(
+            parse_poll("gone"),
+            Some(ScriptStatus::Failed { exit_code: None })
+        );
+        assert_eq!(parse_poll("exited "), None);
+
+        // A line still being written waits for its newline until the script exits
+        assert_eq!(complete_lines("one\ntwo\nthr", false), ["one", "two"]);
+        assert_eq!(complete_lines("one\ntwo\nthr", true), ["one", "two", "thr"]);
+        assert!(complete_lines("partial", false).is_empty());
+
+        let json = serde_json::to_value(ScriptStatus::Failed { exit_code: Some(2) }).unwrap();
+        assert_eq!(json["status"], "failed");
+        assert_eq!(json["exit_code"], 2);
+    }
+}
@@ -0,0 +1,445 @@
+use crate::config::{agent_config, HostKeyMode};
+use crate::endpoints;
+use crate::host_keys;
+use crate::retry::Classify;
+use crate::RunnerLogin;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Output, Stdio};
+use std::time::Duration;
+use tokio::process::Command;
+
+/// Tuning options the API may set with `login.ssh.options`. Anything else, e.g. options that
+/// run local commands, load libraries, forward ports or pick another route to the VM, can
+/// only come from the host's own config.
+const API_OPTIONS: &[&str] = &[
+    "addressfamily",
+    "ciphers",
+    "compression",
+    "connectionattempts",
+    "connecttimeout",
+    "hostkeyalgorithms",
+    "ipqos",
+    "kexalgorithms",
+    "loglevel",
+    "macs",
+    "numberofpasswordprompts",
+    "preferredauthentications",
+    "pubkeyacceptedalgorithms",
+    "rekeylimit",
+    "serveralivecountmax",
+    "serveraliveinterval",
+    "tcpkeepalive",
+];
+
+/// Control sockets live in a short directory, since socket paths are limited to
+/// `SUN_PATH_MAX` bytes and `$HOME` can be long
+const CONTROL_SOCKETS_DIR: &str = "/tmp/cirun-ssh-";
+/// Socket path limit on macOS (Linux allows 108)
+const SUN_PATH_MAX: usize = 104;
+/// Length of the hash ssh substitutes for `%C`
+const CONTROL_HASH_LEN: usize = 40;
+/// ssh first binds the master socket at `<path>.<16 random characters>`
+const CONTROL_TEMP_SUFFIX_LEN: usize = 17;
+const CLOSE_TIMEOUT_SECS: u64 = 5;
+
+/// Options that decide host key checking; the API may not weaken `[ssh] host_keys`
+const HOST_KEY_OPTIONS: &[&str] = &[
+    "stricthostkeychecking",
+    "userknownhostsfile",
+    "globalknownhostsfile",
+    "hostkeyalias",
+];
+
+/// Per-runner SSH settings sent by the API in `login.ssh`, overriding the agent's `[ssh]` config
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SshOverrides {
+    pub port: Option<u16>,
+    pub connect_timeout_secs: Option<u64>,
+    pub retries: Option<u32>,
+    pub ciphers: Option<String>,
+    pub kex_algorithms: Option<String>,
+    /// Extra `-o` options, e.g. "ServerAliveInterval=15"
+    pub options: Vec<String>,
+    /// Bastion to reach this runner through, e.g. "ops@bastion.internal:2222"
+    pub jump_host: Option<String>,
+}
+
+/// SSH settings for one connection, after applying API overrides to the config defaults
+#[derive(Debug, Clone, PartialEq)]
+pub struct SshSettings {
+    pub port: u16,
+    pub connect_timeout_secs: u64,
+    /// Connection attempts after the first while waiting for SSH; `None` keeps the
+    /// provider's default (backoff for lume, the whole ssh_ready window for meda)
+    pub retries: Option<u32>,
+    pub ciphers: Option<String>,
+    pub kex_algorithms: Option<String>,
+    pub options: Vec<String>,
+    pub jump_host: Option<String>,
+    /// Host key checking options for the VM being connected to
+    pub host_key_options: Vec<String>,
+    /// Master connection socket shared by every step for this VM, with how long it stays
+    /// open when idle
+    pub control: Option<(String, u64)>,
+}
+
+/// Why an SSH step failed, telling connection trouble worth retrying from a remote command
+/// that ran and failed
+#[derive(Debug)]
+pub enum SshError {
+    /// No connection, or it dropped; ssh exits with 255 for these
+    Connection(String),
+    /// The step did not finish within its timeout
+    Timeout { action: &'static str, secs: u64 },
+    /// The remote command ran and exited with a failure
+    Remote {
+        action: &'static str,
+        code: Option<i32>,
+        stderr: String,
+    },
+    /// ssh could not be run at all
+    Local(String),
+}
+
+impl SshError {
+    /// Error for a step whose ssh run exited with a failure
+    pub fn failed(action: &'static str, output: &Output) -> Self {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        match output.status.code() {
+            Some(255) => SshError::Connection(format!("{} failed: {}", action, stderr)),
+            code => SshError::Remote {
+                action,
+                code,
+                stderr,
+            },
+        }
+    }
+}
+
+impl fmt::Display for SshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SshError::Connection(msg) | SshError::Local(msg) => write!(f, "{}", msg),
+            SshError::Timeout { action, secs } => {
+                write!(f, "{} timed out after {}s", action, secs)
+            }
+            SshError::Remote {
+                action,
+                code: Some(code),
+                stderr,
+            } => write!(f, "{} failed with exit code {}: {}", action, code, stderr),
+            SshError::Remote { action, stderr, .. } => write!(f, "{} failed: {}", action, stderr),
+        }
+    }
+}
+
+impl std::error::Error for SshError {}
+
+impl Classify for SshError {
+    fn is_retryable(&self) -> bool {
+        matches!(self, SshError::Connection(_) | SshError::Timeout { .. })
+    }
+}
+
+/// A `[user@]host[:port]` jump host, comma-separated for several hops; anything that
+/// could be read as an ssh option is refused
+fn valid_jump_host(jump_host: &str) -> bool {
+    !jump_host.is_empty()
+        && jump_host.split(',').all(|hop| {
+            !hop.is_empty()
+                && !hop.starts_with('-')
+                && hop
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "@:.-_[]".contains(c))
+        })
+}
+
+/// Name of a `-o` option as ssh reads it, which skips leading whitespace and quotes
+fn option_name(option: &str) -> String {
+    option
+        .trim_start()
+        .trim_start_matches(['"', '\''])
+        .split(|c: char| c == '=' || c == '"' || c == '\'' || c.is_whitespace())
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// Settings for connecting to VM `vm_name` with `login`
+pub fn settings(login: &RunnerLogin, vm_name: &str) -> SshSettings {
+    let config = &agent_config().ssh;
+    let overrides = &login.ssh;
+    let verifies_host_keys = config.host_keys != HostKeyMode::Off;
+
+    // ssh uses the first value it sees for an option, so runner options come first
+    let mut options: Vec<String> = overrides
+        .options
+        .iter()
+        .filter(|option| {
+            let name = option_name(option);
+            let allowed = API_OPTIONS.contains(&name.as_str())
+                || (!verifies_host_keys && HOST_KEY_OPTIONS.contains(&name.as_str()));
+            if !allowed {
+                warn!("Ignoring SSH option '{}' from the API", option);
+            }
+            allowed
+        })
+        .cloned()
+        .collect();
+    options.extend(config.options.iter().cloned());
+
+    SshSettings {
+        port: overrides.port.unwrap_or(config.port),
+        connect_timeout_secs: overrides
+            .connect_timeout_secs
+            .unwrap_or(config.connect_timeout_secs),
+        retries: overrides.retries.or(config.retries),
+        ciphers: overrides.ciphers.clone().or_else(|| config.ciphers.clone()),
+        kex_algorithms: overrides
+            .kex_algorithms
+            .clone()
+            .or_else(|| config.kex_algorithms.clone()),
+        options,
+        jump_host: overrides
+            .jump_host
+            .clone()
+            .or_else(|| endpoints::current().and_then(|e| e.ssh_jump_host.clone()))
+            .or_else(|| config.jump_host.clone())
+            .filter(|jump_host| {
+                let valid = valid_jump_host(jump_host);
+                if !valid {
+                    warn!("Ignoring invalid SSH jump host '{}'", jump_host);
+                }
+                valid
+            }),
+        host_key_options: host_keys::ssh_options(vm_name),
+        control: (config.control_persist_secs > 0)
+            .then(|| control_path(vm_name))
+            .flatten()
+            .map(|path| (path, config.control_persist_secs)),
+    }
+}
+
+/// Socket directory of this agent's user, keyed by `$HOME` so agents of different users
+/// never share one
+fn control_dir() -> PathBuf {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    let key = format!("{:x}", Sha256::digest(home_dir.as_bytes()));
+    PathBuf::from(format!("{}{}", CONTROL_SOCKETS_DIR, &key[..8]))
+}
+
+/// Create the socket directory readable only by us. Since it lives in the shared `/tmp`,
+/// a directory someone else created, or opened up, is refused.
+#[cfg(unix)]
+fn ensure_private_dir(dir: &std::path::Path) -> std::io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    let meta = fs::symlink_metadata(dir)?;
+    let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    let owner = fs::metadata(home)?.uid();
+    if !meta.is_dir() || meta.uid() != owner || meta.permissions().mode() & 0o077 != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "not a private directory owned by this user",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn ensure_private_dir(dir: &std::path::Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)
+}
+
+/// Socket name prefix for a VM; hashed, since socket paths are limited to `SUN_PATH_MAX`
+fn control_prefix(vm_name: &str) -> String {
+    format!("{:x}", Sha256::digest(vm_name.as_bytes()))[..8].to_string()
+}
+
+/// Length of the longest socket path ssh binds for `control_path`
+fn socket_path_len(control_path: &str) -> usize {
+    control_path.len() - "%C".len() + CONTROL_HASH_LEN + CONTROL_TEMP_SUFFIX_LEN
+}
+
+/// `ControlPath` for connections to a VM. ssh expands `%C` to a hash of the user, host
+/// and port, so each login gets its own master connection. `None`, and no connection
+/// sharing, when no usable socket directory exists.
+fn control_path(vm_name: &str) -> Option<String> {
+    let dir = control_dir();
+    if let Err(e) = ensure_private_dir(&dir) {
+        warn!(
+            "Not sharing SSH connections: socket directory {:?} is unusable: {}",
+            dir, e
+        );
+        return None;
+    }
+    let path = format!("{}/{}-%C", dir.to_string_lossy(), control_prefix(vm_name));
+    if socket_path_len(&path) > SUN_PATH_MAX {
+        warn!(
+            "Not sharing SSH connections: socket path {} is too long",
+            path
+        );
+        return None;
+    }
+    Some(path)
+}
+
+/// Close the master connections to a deleted VM, so a new VM reusing its IP address never
+/// gets them
+pub async fn close_connections(vm_name: &str) {
+    let prefix = format!("{}-", control_prefix(vm_name));
+    let Ok(entries) = fs::read_dir(control_dir()) else {
+        return;
+    };
+    for entry in entries.filter_map(Result::ok) {
+        if !entry.file_name().to_string_lossy().starts_with(&prefix) {
+            continue;
+        }
+        let socket = entry.path();
+        let closed = tokio::time::timeout(
+            Duration::from_secs(CLOSE_TIMEOUT_SECS),
+            Command::new("ssh")
+                .arg("-o")
+                .arg(format!("ControlPath={}", socket.to_string_lossy()))
+                .args(["-O", "exit", vm_name])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .kill_on_drop(true)
+                .status(),
+        )
+        .await;
+        if !matches!(closed, Ok(Ok(status)) if status.success()) {
+            // The master is gone already; only its socket is left
+            let _ = fs::remove_file(&socket);
+        }
+    }
+}
+
+impl SshSettings {
+    /// Arguments passed to `ssh` before the destination
+    pub fn args(&self) -> Vec<String> {
+        self.args_with_port_flag("-p")
+    }
+
+    /// The same arguments for `sftp`, which takes the port as `-P`
+    pub fn sftp_args(&self) -> Vec<String> {
+        self.args_with_port_flag("-P")
+    }
+
+    fn args_with_port_flag(&self, port_flag: &str) -> Vec<String> {
+        let mut options = self.options.clone();
+        if let Some(ciphers) = &self.ciphers {
+            options.push(format!("Ciphers={}", ciphers));
+        }
+        if let Some(kex) = &self.kex_algorithms {
+            options.push(format!("KexAlgorithms={}", kex));
+        }
+        options.extend(self.host_key_options.iter().cloned());
+        options.push(format!("ConnectTimeout={}", self.connect_timeout_secs));
+        if let Some((path, persist_secs)) = &self.control {
+            options.push("ControlMaster=auto".to_string());
+            options.push(format!("ControlPath={}", path));
+            options.push(format!("ControlPersist={}", persist_secs));
+        }
+
+        let mut args = vec![port_flag.to_string(), self.port.to_string()];
+        if let Some(jump_host) = &self.jump_host {
+            args.push("-J".to_string());
+            args.push(jump_host.clone());
+        }
+        for option in options {
+            args.push("-o".to_string());
+            args.push(option);
+        }
+        args
+    }
+
+    /// The same options as a single command line, for `rsync -e`
+    pub fn transport(&self) -> String {
+        self.args().join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_overrides_and_filtered_options() {
+        let login = RunnerLogin {
+            username: "runner".to_string(),
+            password: "secret".to_string(),
+            script: Default::default(),
+            ssh: SshOverrides {
+                port: Some(2222),
+                kex_algorithms: Some("curve25519-sha256".to_string()),
+                options: [
+                    "ServerAliveInterval=15",
+                    "ProxyCommand nc %h %p",
+                    "ProxyJump=attacker.example",
+                    "PKCS11Provider=/tmp/evil.so",
+                    "LocalForward 8080 localhost:80",
+                    "ForwardAgent=yes",
+                ]
+                .map(String::from)
+                .to_vec(),
+                ..SshOverrides::default()
+            },
+        };
+        let settings = settings(&login, "cirun-runner-1");
+        assert_eq!(settings.port, 2222);
+        assert_eq!(settings.connect_timeout_secs, 10);
+        assert!(settings.transport().starts_with(
+            "-p 2222 -o ServerAliveInterval=15 -o KexAlgorithms=curve25519-sha256 \
+             -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null -o ConnectTimeout=10 \
+             -o ControlMaster=auto -o ControlPath="
+        ));
+        assert!(settings.transport().ends_with("-%C -o ControlPersist=60"));
+    }
+
+    #[test]
+    fn test_option_name_ignores_padding_and_quotes() {
+        assert_eq!(option_name("ServerAliveInterval=15"), "serveraliveinterval");
+        assert_eq!(option_name(" ProxyCommand=nc %h %p"), "proxycommand");
+        assert_eq!(option_name("\t\"ProxyCommand\" nc %h %p"), "proxycommand");
+        assert_eq!(option_name("'LocalCommand'=id"), "localcommand");
+    }
+
+    #[test]
+    fn test_control_path_fits_sun_path() {
+        let path = control_path("cirun-a-runner-name-well-beyond-any-usual-length-0123456789")
+            .expect("socket directory should be usable");
+        assert!(path.starts_with(CONTROL_SOCKETS_DIR));
+        assert!(socket_path_len(&path) <= SUN_PATH_MAX);
+        // The 40-character %C hash, plus ssh's temporary suffix, however long HOME is
+        assert_eq!(socket_path_len(&path), path.len() + 38 + 17);
+    }
+
+    #[test]
+    fn test_jump_host_validation() {
+        assert!(valid_jump_host("ops@bastion.internal:2222"));
+        assert!(valid_jump_host("edge,ops@[fd00::1]:22"));
+        assert!(!valid_jump_host("-oProxyCommand=touch /tmp/x"));
+        assert!(!valid_jump_host("bastion;reboot"));
+        assert!(!valid_jump_host(""));
+
+        let mut login = RunnerLogin {
+            username: "runner".to_string(),
+            password: "secret".to_string(),
+            script: Default::default(),
+            ssh: SshOverrides::default(),
+        };
+        login.ssh.jump_host = Some("bastion".to_string());
+        let args = settings(&login, "cirun-runner-1").args();
+        assert_eq!(&args[2..4], &["-J".to_string(), "bastion".to_string()]);
+    }
+}
@@ -0,0 +1,479 @@
+use crate::cpu_pinning::CpuPinning;
+use crate::deletion_queue::PendingDeletion;
+use crate::lifecycle::LifecycleRecord;
+use crate::lume::freshness::TemplateSource;
+use crate::offline::{CachedDesiredState, QueuedReport};
+use crate::pool::PoolState;
+use crate::provider_health::ProviderHealth;
+use crate::retry_budget::FailureRecord;
+use crate::script_monitor::{DetachedScript, ScriptStatus};
+use crate::timing::PhaseTimings;
+use crate::units::{DiskSize, Memory};
+use crate::usage::{UsageRecord, USAGE_RETENTION_DAYS};
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const STATE_DIR: &str = ".cirun-agent";
+const STATE_FILE: &str = "state.json";
+const AGENT_LOCK_FILE: &str = "agent.lock";
+
+// Serializes load-modify-save cycles across provisioning tasks
+static STATE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Marker written after a provision script finished successfully on a runner
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProvisionMarker {
+    pub script_hash: String,
+    pub provisioned_at: DateTime<Utc>,
+}
+
+/// Bookkeeping for a runner VM created by this agent
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RunnerRecord {
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub cpu: u32,
+    #[serde(default)]
+    pub memory: Memory,
+    #[serde(default)]
+    pub disk: DiskSize,
+    #[serde(default)]
+    pub provision_duration_secs: Option<u64>,
+    /// Per-phase breakdown of the last provisioning attempt
+    #[serde(default)]
+    pub provision_phases: Option<PhaseTimings>,
+    /// Outcome of the provision script when it was launched detached
+    #[serde(default)]
+    pub script_status: Option<ScriptStatus>,
+    /// Detached provision script being watched, so the watch can be resumed
+    #[serde(default)]
+    pub detached_script: Option<DetachedScript>,
+    /// Host cores the runner's VM is pinned to
+    #[serde(default)]
+    pub cpu_pinning: Option<CpuPinning>,
+    /// Set when the runner was provisioned from a fallback template
+    #[serde(default)]
+    pub template_fallback: Option<TemplateFallback>,
+}
+
+/// A runner provisioned from `[fallback_templates]` instead of its own image
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TemplateFallback {
+    pub template: String,
+    /// Image the API asked for
+    pub image: String,
+    /// Why the requested image could not be used
+    pub error: String,
+}
+
+/// Everything the agent persists locally between restarts
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct AgentState {
+    #[serde(default)]
+    pub provisioned: HashMap<String, ProvisionMarker>,
+    #[serde(default)]
+    pub runners: HashMap<String, RunnerRecord>,
+    /// Usage of runners that have since been deleted
+    #[serde(default)]
+    pub usage_history: Vec<UsageRecord>,
+    #[serde(default)]
+    pub last_usage_report: Option<DateTime<Utc>>,
+    /// Templates that passed a boot test, with the time they were validated
+    #[serde(default)]
+    pub validated_templates: HashMap<String, DateTime<Utc>>,
+    /// Templates whose creation started but never completed; these must never be used
+    #[serde(default)]
+    pub incomplete_templates: HashMap<String, DateTime<Utc>>,
+    /// Image each template was built from, for freshness checks
+    #[serde(default)]
+    pub templates: HashMap<String, TemplateSource>,
+    /// Lifecycle state machine position of each runner
+    #[serde(default)]
+    pub lifecycle: HashMap<String, LifecycleRecord>,
+    /// Deletions that failed and are retried in the background
+    #[serde(default)]
+    pub pending_deletions: HashMap<String, PendingDeletion>,
+    /// Remote endpoint each runner was placed on; runners on this host are absent
+    #[serde(default)]
+    pub placements: HashMap<String, String>,
+    /// Last desired state fetched from the API
+    #[serde(default)]
+    pub desired_state: Option<CachedDesiredState>,
+    /// Reports queued by older agents, before the outbox got its own file; moved there
+    /// on first use
+    #[serde(default, rename = "outbox", skip_serializing)]
+    pub legacy_outbox: Vec<QueuedReport>,
+    #[serde(default)]
+    pub pool: PoolState,
+    /// Consecutive provisioning failures of runners that have not provisioned since
+    #[serde(default)]
+    pub failures: HashMap<String, FailureRecord>,
+    /// Whether the VM provider answered the agent's last calls
+    #[serde(default)]
+    pub provider_health: Option<ProviderHealth>,
+}
+
+/// Path of a file in `~/.cirun-agent`
+pub fn agent_file(name: &str) -> PathBuf {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home_dir).join(STATE_DIR).join(name)
+}
+
+/// Write a file readable only by the agent's user. The contents go to a sibling file that
+/// is renamed over `path`, so a crash never leaves a truncated file.
+pub fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // A leftover file keeps its old mode, so set it explicitly
+        if tmp_path.exists() {
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(contents)?;
+    drop(file);
+    fs::rename(&tmp_path, path)
+}
+
+fn open_lock_file(path: &Path) -> std::io::Result<fs::File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+}
+
+/// Take the lock held by the running agent, and by commands that change VMs and templates
+/// and so must not run alongside it. None while another process holds it.
+pub fn try_lock_agent() -> std::io::Result<Option<fs::File>> {
+    let file = open_lock_file(&agent_file(AGENT_LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(fs::TryLockError::WouldBlock) => Ok(None),
+        Err(fs::TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Wait for the agent lock
+pub fn lock_agent() -> std::io::Result<fs::File> {
+    let file = open_lock_file(&agent_file(AGENT_LOCK_FILE))?;
+    file.lock()?;
+    Ok(file)
+}
+
+/// JSON-backed store for agent state, kept under `~/.cirun-agent/state.json`
+pub struct StateStore {
+    path: PathBuf,
+}
+
+impl StateStore {
+    pub fn new() -> Self {
+        Self::with_path(agent_file(STATE_FILE))
+    }
+
+    pub fn with_path(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    fn load(&self) -> AgentState {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!(
+                    "Failed to parse state file {:?}, starting fresh: {}",
+                    self.path, e
+                );
+                AgentState::default()
+            }),
+            Err(_) => AgentState::default(),
+        }
+    }
+
+    fn save(&self, state: &AgentState) -> Result<(), Box<dyn std::error::Error>> {
+        // The cached desired state holds runner logins and provision scripts
+        write_private(&self.path, serde_json::to_string_pretty(state)?.as_bytes())?;
+        Ok(())
+    }
+
+    /// Read a value out of the persisted state
+    pub fn read<R>(&self, f: impl FnOnce(&AgentState) -> R) -> R {
+        let _guard = STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        f(&self.load())
+    }
+
+    /// Read the persisted state without taking the lock, for the panic hook, which may run
+    /// on a thread that holds it. Saves are atomic, so this still sees a whole state.
+    pub fn read_unlocked<R>(&self, f: impl FnOnce(&AgentState) -> R) -> R {
+        f(&self.load())
+    }
+
+    /// Lock on the state file shared with other agent processes, e.g. a CLI command run
+    /// next to the service. Saves are atomic, so only updates need it.
+    fn lock_file(&self) -> Option<fs::File> {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(".lock");
+        let locked = open_lock_file(Path::new(&name)).and_then(|file| file.lock().map(|()| file));
+        match locked {
+            Ok(file) => Some(file),
+            Err(e) => {
+                warn!("Failed to lock state file {:?}: {}", self.path, e);
+                None
+            }
+        }
+    }
+
+    /// Apply a change to the persisted state and write it back to disk
+    pub fn update<R>(&self, f: impl FnOnce(&mut AgentState) -> R) -> R {
+        let _guard = STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let _file_lock = self.lock_file();
+        let mut state = self.load();
+        let result = f(&mut state);
+        if let Err(e) = self.save(&state) {
+            error!("Failed to write state file {:?}: {}", self.path, e);
+        }
+        result
+    }
+
+    /// Check whether a runner was already provisioned with the given script
+    pub fn is_provisioned(&self, runner_name: &str, script_hash: &str) -> bool {
+        self.read(|state| {
+            state
+                .provisioned
+                .get(runner_name)
+                .is_some_and(|marker| marker.script_hash == script_hash)
+        })
+    }
+
+    /// Record that a runner's provision script completed successfully
+    pub fn mark_provisioned(&self, runner_name: &str, script_hash: &str) {
+        self.update(|state| {
+            state.provisioned.insert(
+                runner_name.to_string(),
+                ProvisionMarker {
+                    script_hash: script_hash.to_string(),
+                    provisioned_at: Utc::now(),
+                },
+            );
+        });
+    }
+
+    /// Record when a runner VM was first created and what it was allocated,
+    /// keeping the original creation time on retries
+    pub fn record_runner_created(
+        &self,
+        runner_name: &str,
+        cpu: u32,
+        memory: Memory,
+        disk: DiskSize,
+    ) {
+        self.update(|state| {
+            state
+                .runners
+                .entry(runner_name.to_string())
+                .or_insert_with(|| RunnerRecord {
+                    created_at: Utc::now(),
+                    cpu,
+                    memory,
+                    disk,
+                    provision_duration_secs: None,
+                    provision_phases: None,
+                    script_status: None,
+                    detached_script: None,
+                    cpu_pinning: None,
+                    template_fallback: None,
+                });
+        });
+    }
+
+    /// Record how long provisioning a runner took
+    pub fn record_provision_duration(&self, runner_name: &str, duration_secs: u64) {
+        self.update(|state| {
+            if let Some(record) = state.runners.get_mut(runner_name) {
+                record.provision_duration_secs = Some(duration_secs);
+            }
+        });
+    }
+
+    /// Record the per-phase timing breakdown of a provisioning attempt
+    pub fn record_phase_timings(&self, runner_name: &str, timings: &PhaseTimings) {
+        self.update(|state| {
+            if let Some(record) = state.runners.get_mut(runner_name) {
+                record.provision_phases = Some(timings.clone());
+            }
+        });
+    }
+
+    /// Phase timing breakdowns of all tracked runners, keyed by runner name
+    pub fn provision_phases(&self) -> HashMap<String, PhaseTimings> {
+        self.read(|state| {
+            state
+                .runners
+                .iter()
+                .filter_map(|(name, record)| {
+                    record
+                        .provision_phases
+                        .clone()
+                        .map(|phases| (name.clone(), phases))
+                })
+                .collect()
+        })
+    }
+
+    /// Record how a detached provision script is doing
+    pub fn record_script_status(&self, runner_name: &str, status: ScriptStatus) {
+        self.update(|state| {
+            if let Some(record) = state.runners.get_mut(runner_name) {
+                record.script_status = Some(status);
+            }
+        });
+    }
+
+    /// Remember the detached script being watched for a runner, or forget it once the
+    /// watch is over
+    pub fn record_detached_script(&self, runner_name: &str, script: Option<DetachedScript>) {
+        self.update(|state| {
+            if let Some(record) = state.runners.get_mut(runner_name) {
+                record.detached_script = script;
+            }
+        });
+    }
+
+    /// Detached script being watched for a runner
+    pub fn detached_script(&self, runner_name: &str) -> Option<DetachedScript> {
+        self.read(|state| {
+            state
+                .runners
+                .get(runner_name)
+                .and_then(|record| record.detached_script.clone())
+        })
+    }
+
+    /// Detached script outcomes of all tracked runners, keyed by runner name
+    pub fn script_statuses(&self) -> HashMap<String, ScriptStatus> {
+        self.read(|state| {
+            state
+                .runners
+                .iter()
+                .filter_map(|(name, record)| record.script_status.map(|s| (name.clone(), s)))
+                .collect()
+        })
+    }
+
+    /// Record that a runner was provisioned from a fallback template
+    pub fn record_template_fallback(&self, runner_name: &str, fallback: TemplateFallback) {
+        self.update(|state| {
+            if let Some(record) = state.runners.get_mut(runner_name) {
+                record.template_fallback = Some(fallback);
+            }
+        });
+    }
+
+    /// Fallback templates of runners that were provisioned from one, keyed by runner name
+    pub fn template_fallbacks(&self) -> HashMap<String, TemplateFallback> {
+        self.read(|state| {
+            state
+                .runners
+                .iter()
+                .filter_map(|(name, record)| {
+                    Some((name.clone(), record.template_fallback.clone()?))
+                })
+                .collect()
+        })
+    }
+
+    /// Pinned cores of all tracked runners, keyed by runner name
+    pub fn cpu_pinnings(&self) -> HashMap<String, CpuPinning> {
+        self.read(|state| {
+            state
+                .runners
+                .iter()
+                .filter_map(|(name, record)| Some((name.clone(), record.cpu_pinning.clone()?)))
+                .collect()
+        })
+    }
+
+    /// Check whether a template passed its post-creation boot test
+    pub fn is_template_validated(&self, template_name: &str) -> bool {
+        self.read(|state| state.validated_templates.contains_key(template_name))
+    }
+
+    /// Mark a template as validated so it can be used for runner clones
+    pub fn mark_template_validated(&self, template_name: &str) {
+        self.update(|state| {
+            state
+                .validated_templates
+                .insert(template_name.to_string(), Utc::now());
+        });
+    }
+
+    /// Record that creation of a template has started
+    pub fn mark_template_creating(&self, template_name: &str) {
+        self.update(|state| {
+            state
+                .incomplete_templates
+                .insert(template_name.to_string(), Utc::now());
+            state.validated_templates.remove(template_name);
+        });
+    }
+
+    /// Record that a template was fully created (or its leftovers were removed)
+    pub fn clear_template_creating(&self, template_name: &str) {
+        self.update(|state| {
+            state.incomplete_templates.remove(template_name);
+        });
+    }
+
+    /// Drop everything known about a template that no longer exists
+    pub fn forget_template(&self, template_name: &str) {
+        self.update(|state| {
+            state.validated_templates.remove(template_name);
+            state.incomplete_templates.remove(template_name);
+            state.templates.remove(template_name);
+        });
+    }
+
+    /// Check whether a template is half-built and must not be used
+    pub fn is_template_incomplete(&self, template_name: &str) -> bool {
+        self.read(|state| state.incomplete_templates.contains_key(template_name))
+    }
+
+    /// Forget everything recorded about a runner (called once its VM is gone),
+    /// moving its bookkeeping into the usage history
+    pub fn clear_runner(&self, runner_name: &str) {
+        self.update(|state| {
+            state.provisioned.remove(runner_name);
+            let now = Utc::now();
+            if let Some(record) = state.runners.remove(runner_name) {
+                state
+                    .usage_history
+                    .push(UsageRecord::from_runner(runner_name, &record, now));
+            }
+            let cutoff = now - chrono::Duration::days(USAGE_RETENTION_DAYS);
+            state.usage_history.retain(|r| r.deleted_at > cutoff);
+        });
+    }
+}
+
+/// Stable hash of a provision script, used to detect whether it already ran
+pub fn script_hash(script: &str) -> String {
+    format!("{:x}", Sha256::digest(script.as_bytes()))
+}
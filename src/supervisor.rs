@@ -0,0 +1,229 @@
+use crate::config::{agent_config, RestartPolicy};
+use crate::host_tools;
+use log::{debug, error, info, warn};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration};
+
+/// How long the server gets to exit after SIGTERM before it is killed
+const STOP_GRACE_SECS: u64 = 30;
+/// Longest wait between restarts of a server that keeps crashing
+const MAX_RESTART_DELAY_SECS: u64 = 60;
+/// A server that stayed up this long is considered healthy again and restarts immediately
+const STABLE_UPTIME_SECS: u64 = 60;
+
+/// How to run a provider server (`meda serve` / `lume serve`)
+#[derive(Debug, Clone)]
+pub struct ServeSpec {
+    /// Name used in logs, e.g. "meda serve"
+    pub name: &'static str,
+    pub binary: PathBuf,
+    pub args: Vec<String>,
+    /// Extra environment, e.g. the provider API token
+    pub env: Vec<(String, String)>,
+    pub stdout_log: PathBuf,
+    pub stderr_log: PathBuf,
+}
+
+struct Supervised {
+    stop: watch::Sender<bool>,
+    handle: JoinHandle<()>,
+}
+
+// The one provider server this agent supervises
+static SUPERVISED: Mutex<Option<Supervised>> = Mutex::new(None);
+
+/// Whether a supervised server is currently being kept alive by the agent
+pub fn is_supervised() -> bool {
+    SUPERVISED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+        .is_some_and(|s| !s.handle.is_finished())
+}
+
+/// Start supervising a server, replacing (and stopping) any previously supervised one.
+/// Must be called from within the tokio runtime, including `spawn_blocking` threads.
+pub fn supervise(spec: ServeSpec) {
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let previous = SUPERVISED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .replace(Supervised {
+            stop: stop_tx,
+            handle: tokio::spawn(run(spec, stop_rx)),
+        });
+    if let Some(previous) = previous {
+        let _ = previous.stop.send(true);
+    }
+}
+
+/// Stop the supervised server without restarting it. Returns immediately; the server
+/// gets SIGTERM and a grace period from the supervising task.
+pub fn release() {
+    if let Some(supervised) = SUPERVISED.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        let _ = supervised.stop.send(true);
+    }
+}
+
+/// Stop the supervised server and wait for it to exit (used on agent shutdown)
+pub async fn shutdown() {
+    let supervised = SUPERVISED.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some(supervised) = supervised {
+        let _ = supervised.stop.send(true);
+        let _ = supervised.handle.await;
+    }
+}
+
+async fn open_log(path: &PathBuf) -> Option<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .map_err(|e| warn!("Could not open log file {:?}: {}", path, e))
+        .ok()
+}
+
+/// Copy a server's output line by line into its log file
+fn capture<R: AsyncRead + Unpin + Send + 'static>(
+    name: &'static str,
+    reader: R,
+    log_path: PathBuf,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut log_file = open_log(&log_path).await;
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            debug!("[{}] {}", name, line);
+            if let Some(file) = log_file.as_mut() {
+                let _ = file.write_all(format!("{}\n", line).as_bytes()).await;
+            }
+        }
+    })
+}
+
+/// SIGTERM the server, then SIGKILL it if it hasn't exited after the grace period
+async fn terminate(name: &str, child: &mut Child) {
+    if let Some(pid) = child.id() {
+        info!("Stopping '{}' (PID {})", name, pid);
+        host_tools::terminate(pid);
+    }
+    if timeout(Duration::from_secs(STOP_GRACE_SECS), child.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            "'{}' did not exit within {}s, killing it",
+            name, STOP_GRACE_SECS
+        );
+        let _ = child.kill().await;
+    }
+}
+
+async fn run(spec: ServeSpec, mut stop: watch::Receiver<bool>) {
+    let policy = agent_config().provider.restart;
+    let mut restart_delay = 1;
+
+    loop {
+        let mut child = match Command::new(&spec.binary)
+            .args(&spec.args)
+            .envs(spec.env.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Failed to start '{}': {}", spec.name, e);
+                return;
+            }
+        };
+        info!(
+            "Started '{}' as a supervised process with PID: {}",
+            spec.name,
+            child.id().unwrap_or_default()
+        );
+        if let Some(stdout) = child.stdout.take() {
+            capture(spec.name, stdout, spec.stdout_log.clone());
+        }
+        if let Some(stderr) = child.stderr.take() {
+            capture(spec.name, stderr, spec.stderr_log.clone());
+        }
+        let started = Instant::now();
+
+        let status = tokio::select! {
+            status = child.wait() => status,
+            _ = stop.changed() => {
+                terminate(spec.name, &mut child).await;
+                return;
+            }
+        };
+
+        let succeeded = status.as_ref().is_ok_and(|s| s.success());
+        let restart = match policy {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => !succeeded,
+            RestartPolicy::Never => false,
+        };
+        if !restart {
+            warn!(
+                "'{}' exited ({:?}); restart policy is {:?}, not restarting",
+                spec.name, status, policy
+            );
+            return;
+        }
+
+        if started.elapsed() >= Duration::from_secs(STABLE_UPTIME_SECS) {
+            restart_delay = 1;
+        }
+        warn!(
+            "'{}' exited ({:?}), restarting in {}s. Check logs at {:?}",
+            spec.name, status, restart_delay, spec.stderr_log
+        );
+        tokio::select! {
+            _ = sleep(Duration::from_secs(restart_delay)) => {}
+            _ = stop.changed() => return,
+        }
+        restart_delay = (restart_delay * 2).min(MAX_RESTART_DELAY_SECS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_supervise_captures_output_and_shuts_down() {
+        let dir = tempfile::tempdir().unwrap();
+        let stdout_log = dir.path().join("stdout.log");
+        supervise(ServeSpec {
+            name: "test serve",
+            binary: PathBuf::from("sh"),
+            args: vec!["-c".to_string(), "echo ready; exec sleep 30".to_string()],
+            env: Vec::new(),
+            stdout_log: stdout_log.clone(),
+            stderr_log: dir.path().join("stderr.log"),
+        });
+        assert!(is_supervised());
+
+        for _ in 0..50 {
+            if std::fs::read_to_string(&stdout_log).is_ok_and(|log| log.contains("ready")) {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(std::fs::read_to_string(&stdout_log).unwrap(), "ready\n");
+
+        shutdown().await;
+        assert!(!is_supervised());
+    }
+}
@@ -0,0 +1,122 @@
+use log::warn;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Paths of live guards, removed by the panic hook since the process exits without unwinding
+static LIVE: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+fn register(path: &Path) {
+    LIVE.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashSet::new)
+        .insert(path.to_path_buf());
+}
+
+fn unregister(path: &Path) {
+    if let Some(live) = LIVE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        live.remove(path);
+    }
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    let removed = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    match removed {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// A temporary file or directory holding credentials, scripts or downloads. It is removed
+/// when the guard is dropped, on every return path, and by the panic hook on a crash.
+#[derive(Debug)]
+pub struct TempGuard {
+    path: PathBuf,
+}
+
+impl TempGuard {
+    /// A new file under the system temp directory holding `contents`, readable only by the
+    /// agent user from the moment it is created
+    pub fn secret_file(prefix: &str, contents: &[u8]) -> io::Result<Self> {
+        let (mut file, path) = tempfile::Builder::new()
+            .prefix(prefix)
+            .tempfile()?
+            .into_parts();
+        let guard = Self::adopt(path.keep().map_err(|e| e.error)?);
+        file.write_all(contents)?;
+        Ok(guard)
+    }
+
+    /// A new, empty directory under the system temp directory
+    pub fn dir(prefix: &str) -> io::Result<Self> {
+        let dir = tempfile::Builder::new().prefix(prefix).tempdir()?;
+        Ok(Self::adopt(dir.into_path()))
+    }
+
+    fn adopt(path: PathBuf) -> Self {
+        register(&path);
+        TempGuard { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempGuard {
+    fn drop(&mut self) {
+        if let Err(e) = remove(&self.path) {
+            warn!("Failed to remove temporary {:?}: {}", self.path, e);
+        }
+        unregister(&self.path);
+    }
+}
+
+/// Remove every path still guarded, for the panic hook
+pub fn remove_all() {
+    let live = LIVE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take()
+        .unwrap_or_default();
+    for path in live {
+        let _ = remove(&path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guards_remove_their_paths() {
+        let secret = TempGuard::secret_file("cirun-test-", b"hunter2").unwrap();
+        let path = secret.path().to_path_buf();
+        assert_eq!(fs::read(&path).unwrap(), b"hunter2");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        drop(secret);
+        assert!(!path.exists());
+
+        let dir = TempGuard::dir("cirun-test-").unwrap();
+        fs::write(dir.path().join("download"), b"data").unwrap();
+        let dir_path = dir.path().to_path_buf();
+        // A panic unwinds through the guard's drop
+        let result = std::panic::catch_unwind(move || {
+            let _dir = dir;
+            panic!("provisioning failed");
+        });
+        assert!(result.is_err());
+        assert!(!dir_path.exists());
+    }
+}
@@ -0,0 +1,63 @@
+use log::warn;
+use std::collections::HashMap;
+
+/// Render `{{ name }}` placeholders in a provision script. Unknown placeholders are left
+/// untouched (and logged) so scripts that legitimately contain `{{` keep working.
+pub fn render(script: &str, variables: &HashMap<String, String>) -> String {
+    let mut output = String::with_capacity(script.len());
+    let mut rest = script;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after_open = &rest[start + 2..];
+        let Some(end) = after_open.find("}}") else {
+            output.push_str(&rest[start..]);
+            return output;
+        };
+
+        let name = after_open[..end].trim();
+        match variables.get(name) {
+            Some(value) => output.push_str(value),
+            None => {
+                if is_variable_name(name) {
+                    warn!("Unknown provision script variable '{}'", name);
+                }
+                output.push_str(&rest[start..start + 2 + end + 2]);
+            }
+        }
+        rest = &after_open[end + 2..];
+    }
+
+    output.push_str(rest);
+    output
+}
+
+fn is_variable_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_substitutes_known_variables() {
+        let variables = HashMap::from([
+            ("runner_name".to_string(), "cirun-1".to_string()),
+            ("cache_url".to_string(), "http://cache:8080".to_string()),
+        ]);
+
+        assert_eq!(
+            render(
+                "./config.sh --name {{ runner_name }} --cache {{cache_url}}",
+                &variables
+            ),
+            "./config.sh --name cirun-1 --cache http://cache:8080"
+        );
+        // Unknown and unterminated placeholders are kept verbatim
+        assert_eq!(
+            render("echo ${{ github.ref }} {{ missing }} {{", &variables),
+            "echo ${{ github.ref }} {{ missing }} {{"
+        );
+    }
+}
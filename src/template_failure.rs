@@ -0,0 +1,100 @@
+use crate::events::{self, AgentEvent};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+
+/// Step of making a template or image available that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateStage {
+    /// Downloading an image file from an HTTP source
+    Download,
+    /// Importing an image file into the provider
+    Import,
+    /// Pulling the image from a registry, or cloning a VM that has it
+    Pull,
+    /// Applying the template's CPU, memory and disk size
+    Configure,
+    /// `[lume.template_setup]`
+    Setup,
+    /// The boot test before the first clone
+    Validate,
+}
+
+impl Display for TemplateStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TemplateStage::Download => "download",
+            TemplateStage::Import => "import",
+            TemplateStage::Pull => "pull",
+            TemplateStage::Configure => "configure",
+            TemplateStage::Setup => "setup",
+            TemplateStage::Validate => "validate",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A failed template creation or image preparation, and the step it failed at
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateError {
+    pub stage: TemplateStage,
+    /// The provider's error text
+    pub error: String,
+}
+
+impl TemplateError {
+    pub fn new(stage: TemplateStage, error: impl Display) -> Self {
+        TemplateError {
+            stage,
+            error: error.to_string(),
+        }
+    }
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.error)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Attribute an error to a template stage
+pub trait AtStage<T> {
+    fn at(self, stage: TemplateStage) -> Result<T, TemplateError>;
+}
+
+impl<T, E: Display> AtStage<T> for Result<T, E> {
+    fn at(self, stage: TemplateStage) -> Result<T, TemplateError> {
+        self.map_err(|e| TemplateError::new(stage, e))
+    }
+}
+
+/// Publish that the template or image for `image` could not be made available, so the
+/// API learns the image is broken even when a fallback template lets the runner start
+pub fn report(runner_name: &str, image: &str, template: &str, error: &TemplateError) {
+    events::publish(AgentEvent::TemplateCreationFailed {
+        runner_name: runner_name.to_string(),
+        image: image.to_string(),
+        template: template.to_string(),
+        stage: error.stage,
+        error: error.error.clone(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_at_stage() {
+        let result: Result<(), String> = Err("manifest unknown".to_string());
+        let error = result.at(TemplateStage::Pull).unwrap_err();
+        assert_eq!(error.stage, TemplateStage::Pull);
+        assert_eq!(error.to_string(), "manifest unknown");
+        assert_eq!(
+            serde_json::to_value(TemplateStage::Configure).unwrap(),
+            "configure"
+        );
+    }
+}
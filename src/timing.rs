@@ -0,0 +1,125 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+/// Phases of provisioning a runner, in the order they happen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    TemplateLookup,
+    Clone,
+    Boot,
+    IpWait,
+    SshWait,
+    Script,
+}
+
+/// Seconds spent in each provisioning phase (None if the phase did not run)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PhaseTimings {
+    pub template_lookup: Option<f64>,
+    pub clone: Option<f64>,
+    pub boot: Option<f64>,
+    pub ip_wait: Option<f64>,
+    pub ssh_wait: Option<f64>,
+    pub script: Option<f64>,
+    /// When the guest reported that it finished booting (guest agent or boot beacon)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub booted_at: Option<DateTime<Utc>>,
+}
+
+impl PhaseTimings {
+    fn slot(&mut self, phase: Phase) -> &mut Option<f64> {
+        match phase {
+            Phase::TemplateLookup => &mut self.template_lookup,
+            Phase::Clone => &mut self.clone,
+            Phase::Boot => &mut self.boot,
+            Phase::IpWait => &mut self.ip_wait,
+            Phase::SshWait => &mut self.ssh_wait,
+            Phase::Script => &mut self.script,
+        }
+    }
+
+    /// Add time to a phase; repeated phases (e.g. retries) accumulate
+    pub fn add(&mut self, phase: Phase, elapsed: Duration) {
+        let slot = self.slot(phase);
+        *slot = Some(slot.unwrap_or(0.0) + elapsed.as_secs_f64());
+    }
+
+    /// Seconds of each phase by name, in order
+    pub fn entries(&self) -> [(&'static str, Option<f64>); 6] {
+        [
+            ("template_lookup", self.template_lookup),
+            ("clone", self.clone),
+            ("boot", self.boot),
+            ("ip_wait", self.ip_wait),
+            ("ssh_wait", self.ssh_wait),
+            ("script", self.script),
+        ]
+    }
+}
+
+impl fmt::Display for PhaseTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self
+            .entries()
+            .iter()
+            .filter_map(|(name, secs)| secs.map(|s| format!("{}={:.1}s", name, s)))
+            .collect();
+        if parts.is_empty() {
+            write!(f, "none recorded")
+        } else {
+            write!(f, "{}", parts.join(" "))
+        }
+    }
+}
+
+tokio::task_local! {
+    // Timings of the provisioning running in the current task
+    static TIMINGS: RefCell<PhaseTimings>;
+}
+
+/// Run a provisioning future and collect the phase timings it records
+pub async fn measure_phases<F: Future>(future: F) -> (F::Output, PhaseTimings) {
+    TIMINGS
+        .scope(RefCell::new(PhaseTimings::default()), async {
+            let output = future.await;
+            (output, TIMINGS.with(|t| t.borrow().clone()))
+        })
+        .await
+}
+
+/// Record time spent in a phase. Outside of `measure_phases` this is a no-op.
+pub fn record_phase(phase: Phase, elapsed: Duration) {
+    let _ = TIMINGS.try_with(|t| t.borrow_mut().add(phase, elapsed));
+}
+
+/// Record when the guest finished booting. Outside of `measure_phases` this is a no-op.
+pub fn record_booted_at(at: DateTime<Utc>) {
+    let _ = TIMINGS.try_with(|t| t.borrow_mut().booted_at = Some(at));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_phases_are_collected_per_task() {
+        let ((), timings) = measure_phases(async {
+            record_phase(Phase::Clone, Duration::from_secs(3));
+            record_phase(Phase::SshWait, Duration::from_secs(1));
+            record_phase(Phase::SshWait, Duration::from_secs(2));
+        })
+        .await;
+
+        assert_eq!(timings.clone, Some(3.0));
+        assert_eq!(timings.ssh_wait, Some(3.0));
+        assert_eq!(timings.script, None);
+        assert_eq!(timings.to_string(), "clone=3.0s ssh_wait=3.0s");
+
+        // Recording outside a measured task is ignored
+        record_phase(Phase::Boot, Duration::from_secs(1));
+    }
+}
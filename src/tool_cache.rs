@@ -0,0 +1,234 @@
+use crate::config::{agent_config, SharedDirectoryConfig, ToolCacheConfig, ToolCacheMethod};
+use crate::lume::SharedDirectory;
+use crate::ssh;
+use crate::vm_provision::run_ssh_command;
+use crate::{use_meda, RunnerLogin};
+use log::{info, warn};
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Instant;
+use tokio::process::Command;
+use tokio::time::Duration;
+
+/// Where lume exposes shared directories inside macOS guests
+const LUME_SHARED_MOUNT: &str = "/Volumes/My Shared Files";
+
+/// Upper bound for copying the cache into a runner
+const PRESEED_TIMEOUT_SECS: u64 = 1800;
+/// Upper bound for linking a shared directory into place
+const LINK_TIMEOUT_SECS: u64 = 30;
+
+fn guest_path(config: &ToolCacheConfig) -> String {
+    config.guest_path.clone().unwrap_or_else(|| {
+        if use_meda() {
+            "/opt/hostedtoolcache".to_string()
+        } else {
+            "hostedtoolcache".to_string()
+        }
+    })
+}
+
+/// Host directories lume should share into a runner VM: the tool cache when shared-directory
+/// preseeding is enabled, then the host caches configured for the runner's image
+pub fn lume_shared_directories(image_dirs: &[SharedDirectoryConfig]) -> Vec<SharedDirectory> {
+    let tool_cache = agent_config()
+        .tool_cache
+        .as_ref()
+        .filter(|c| c.method == ToolCacheMethod::SharedDirectory)
+        .map(|c| SharedDirectory {
+            host_path: c.host_path.to_string_lossy().to_string(),
+            read_only: true,
+        });
+    tool_cache
+        .into_iter()
+        .chain(image_dirs.iter().map(|d| SharedDirectory {
+            host_path: d.host_path.to_string_lossy().to_string(),
+            read_only: d.read_only,
+        }))
+        .collect()
+}
+
+/// Where a shared directory appears in the guest. One directory is mounted as the share
+/// itself; with several, each is a subdirectory named after the host directory.
+fn shared_mount(host_path: &Path, shared_count: usize) -> String {
+    match host_path.file_name() {
+        Some(name) if shared_count > 1 => {
+            format!("{}/{}", LUME_SHARED_MOUNT, name.to_string_lossy())
+        }
+        _ => LUME_SHARED_MOUNT.to_string(),
+    }
+}
+
+/// Link the image's shared host caches to their `guest_path`s in a runner that is reachable
+/// over SSH. Failures are logged: jobs still run, just without the cache.
+pub async fn link_shared_directories(
+    vm_name: &str,
+    ip_address: &str,
+    login: &RunnerLogin,
+    image_dirs: &[SharedDirectoryConfig],
+) {
+    let shared_count = lume_shared_directories(image_dirs).len();
+    for dir in image_dirs {
+        let Some(guest_path) = &dir.guest_path else {
+            continue;
+        };
+        // Leave ~ unquoted so the guest shell expands it
+        let target = match guest_path.strip_prefix("~/") {
+            Some(rest) => format!("~/'{}'", rest),
+            None => format!("'{}'", guest_path),
+        };
+        let command = format!(
+            "mkdir -p \"$(dirname {target})\" && rm -rf {target} && ln -s '{mount}' {target}",
+            target = target,
+            mount = shared_mount(&dir.host_path, shared_count)
+        );
+        match run_ssh_command(vm_name, ip_address, login, &command, LINK_TIMEOUT_SECS).await {
+            Ok(output) if output.status.success() => info!(
+                "Linked shared directory {:?} to {} in VM '{}'",
+                dir.host_path, guest_path, vm_name
+            ),
+            Ok(output) => warn!(
+                "Failed to link shared directory {:?} in VM '{}': {}",
+                dir.host_path,
+                vm_name,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => warn!(
+                "Failed to link shared directory {:?} in VM '{}': {}",
+                dir.host_path, vm_name, e
+            ),
+        }
+    }
+}
+
+/// rsync command (wrapped in sshpass on macOS) and the ssh transport it should use
+fn rsync_command(vm_name: &str, login: &RunnerLogin) -> (Command, String) {
+    let ssh_options = ssh::settings(login, vm_name).transport();
+    if use_meda() {
+        let home_dir = std::env::var("HOME").unwrap_or_else(|_| "/root".to_string());
+        (
+            Command::new("rsync"),
+            format!("ssh -i {}/.meda/ssh/id_ed25519 {}", home_dir, ssh_options),
+        )
+    } else {
+        let mut command = Command::new("sshpass");
+        command
+            .arg("-e")
+            .env("SSHPASS", &login.password)
+            .arg("rsync");
+        (command, format!("ssh {}", ssh_options))
+    }
+}
+
+async fn rsync_cache(
+    config: &ToolCacheConfig,
+    vm_name: &str,
+    ip_address: &str,
+    login: &RunnerLogin,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let destination = guest_path(config);
+    // Linux runners keep the cache in a root-owned directory
+    let sudo = if use_meda() { "sudo " } else { "" };
+    let (mut command, ssh) = rsync_command(vm_name, login);
+    command
+        .arg("-a")
+        .arg("--delete")
+        .arg("-e")
+        .arg(ssh)
+        .arg(format!(
+            "--rsync-path={}mkdir -p {} && {}rsync",
+            sudo, destination, sudo
+        ))
+        .arg(format!("{}/", config.host_path.display()))
+        .arg(format!(
+            "{}@{}:{}/",
+            login.username, ip_address, destination
+        ))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let output = tokio::time::timeout(Duration::from_secs(PRESEED_TIMEOUT_SECS), command.output())
+        .await
+        .map_err(|_| format!("rsync timed out after {}s", PRESEED_TIMEOUT_SECS))??;
+    if !output.status.success() {
+        return Err(format!("rsync failed: {}", String::from_utf8_lossy(&output.stderr)).into());
+    }
+    Ok(())
+}
+
+async fn copy_from_shared_directory(
+    config: &ToolCacheConfig,
+    vm_name: &str,
+    ip_address: &str,
+    login: &RunnerLogin,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let destination = guest_path(config);
+    // The cache is in a subdirectory when the image shares host caches as well
+    let command = format!(
+        "src='{nested}'; [ -d \"$src\" ] || src='{mount}'; mkdir -p {dest} && cp -R \"$src/.\" {dest}/",
+        dest = destination,
+        nested = shared_mount(&config.host_path, 2),
+        mount = LUME_SHARED_MOUNT
+    );
+    let output =
+        run_ssh_command(vm_name, ip_address, login, &command, PRESEED_TIMEOUT_SECS).await?;
+    if !output.status.success() {
+        return Err(format!(
+            "Copy from shared directory failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Sync the configured host tool cache into a runner that is reachable over SSH.
+/// Failures are logged and ignored: a missing cache only makes jobs slower.
+pub async fn preseed_tool_cache(vm_name: &str, ip_address: &str, login: &RunnerLogin) {
+    let Some(config) = agent_config().tool_cache.as_ref() else {
+        return;
+    };
+    if !config.host_path.is_dir() {
+        warn!(
+            "Tool cache directory {:?} does not exist. Skipping preseeding.",
+            config.host_path
+        );
+        return;
+    }
+
+    info!(
+        "Preseeding tool cache {:?} into VM '{}'",
+        config.host_path, vm_name
+    );
+    let start = Instant::now();
+    let result = if config.method == ToolCacheMethod::SharedDirectory && !use_meda() {
+        copy_from_shared_directory(config, vm_name, ip_address, login).await
+    } else {
+        if config.method == ToolCacheMethod::SharedDirectory {
+            warn!("Shared directories are not supported with meda. Falling back to rsync.");
+        }
+        rsync_cache(config, vm_name, ip_address, login).await
+    };
+
+    match result {
+        Ok(()) => info!(
+            "✔ Tool cache preseeded into '{}' in {:.1}s",
+            vm_name,
+            start.elapsed().as_secs_f64()
+        ),
+        Err(e) => warn!("Failed to preseed tool cache into '{}': {}", vm_name, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_mount() {
+        let path = Path::new("/Users/ci/Library/Caches/Homebrew");
+        assert_eq!(shared_mount(path, 1), "/Volumes/My Shared Files");
+        assert_eq!(shared_mount(path, 2), "/Volumes/My Shared Files/Homebrew");
+    }
+}
@@ -0,0 +1,264 @@
+use crate::config::agent_config;
+use crate::endpoints;
+use crate::health::get_vm_state_and_ip;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::{sleep_until, timeout_at, Duration, Instant};
+use uuid::Uuid;
+
+const AUDIT_LOG: &str = ".cirun-agent/tunnels.log";
+/// Port tunnels forward to inside the guest
+const GUEST_SSH_PORT: u16 = 22;
+/// Wait after a failed accept, doubled after each further failure
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// A request from the API or the command line for a tunnel to a runner
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelRequest {
+    /// Set by the API so a request it repeats on later polls opens only one tunnel
+    #[serde(default)]
+    pub id: Option<String>,
+    pub runner_name: String,
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// Host port to listen on; any free port when unset
+    #[serde(default)]
+    pub local_port: Option<u16>,
+    /// Who asked for the tunnel, recorded in the audit log
+    #[serde(default)]
+    pub requested_by: Option<String>,
+}
+
+/// An open tunnel, reported back to whoever requested it
+#[derive(Debug, Clone, Serialize)]
+pub struct Tunnel {
+    pub id: String,
+    pub runner_name: String,
+    /// Host address to point `ssh -p` at
+    pub address: String,
+    pub port: u16,
+    pub opened_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub requested_by: Option<String>,
+}
+
+struct OpenTunnel {
+    tunnel: Tunnel,
+    task: AbortHandle,
+}
+
+#[derive(Default)]
+struct Registry {
+    open: HashMap<String, OpenTunnel>,
+    /// IDs of every tunnel opened since the agent started, open or not
+    seen: HashSet<String>,
+}
+
+static TUNNELS: Mutex<Option<Registry>> = Mutex::new(None);
+
+fn with_registry<T>(f: impl FnOnce(&mut Registry) -> T) -> T {
+    f(TUNNELS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(Registry::default))
+}
+
+fn audit_log_path() -> PathBuf {
+    let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home_dir).join(AUDIT_LOG)
+}
+
+/// Append an event to the tunnel audit log, one JSON object per line
+fn audit(event: &str, tunnel: &Tunnel, detail: Option<&str>) {
+    let entry = json!({
+        "at": Utc::now(),
+        "event": event,
+        "tunnel_id": tunnel.id,
+        "runner_name": tunnel.runner_name,
+        "port": tunnel.port,
+        "requested_by": tunnel.requested_by,
+        "detail": detail,
+    });
+    info!(
+        "Tunnel {} to '{}' {}{}",
+        tunnel.id,
+        tunnel.runner_name,
+        event,
+        detail.map(|d| format!(": {}", d)).unwrap_or_default()
+    );
+    let path = audit_log_path();
+    let written = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| OpenOptions::new().create(true).append(true).open(&path))
+        .and_then(|mut file| writeln!(file, "{}", entry));
+    if let Err(e) = written {
+        warn!("Failed to write tunnel audit log {:?}: {}", path, e);
+    }
+}
+
+/// Tunnel lifetime for a request, bounded by the configured maximum
+fn ttl(requested: Option<u64>) -> Result<u64, String> {
+    let config = &agent_config().tunnels;
+    match requested.unwrap_or(config.default_ttl_secs) {
+        0 => Err("tunnel ttl must be at least 1 second".to_string()),
+        secs => Ok(secs.min(config.max_ttl_secs)),
+    }
+}
+
+/// Whether a tunnel with this request ID was already opened
+pub fn already_opened(id: &str) -> bool {
+    with_registry(|registry| registry.seen.contains(id))
+}
+
+/// Listen on a host port and forward every connection to the runner VM's SSH port until
+/// the tunnel expires. Connections still open at expiry are cut.
+pub async fn open(request: TunnelRequest) -> Result<Tunnel, String> {
+    let config = &agent_config().tunnels;
+    if !config.enabled {
+        return Err("debug tunnels are disabled on this agent (see [tunnels] enabled)".to_string());
+    }
+    let ttl_secs = ttl(request.ttl_secs)?;
+    let endpoint = endpoints::endpoint_for_runner(&request.runner_name);
+    let (state, ip) = endpoints::on_endpoint(endpoint, get_vm_state_and_ip(&request.runner_name))
+        .await
+        .map_err(|e| format!("runner '{}' not found: {}", request.runner_name, e))?;
+    let Some(ip) = ip.filter(|ip| !ip.is_empty() && state == "running") else {
+        return Err(format!(
+            "runner '{}' is not running (state: {})",
+            request.runner_name, state
+        ));
+    };
+
+    let listener = TcpListener::bind((
+        config.bind_address.as_str(),
+        request.local_port.unwrap_or(0),
+    ))
+    .await
+    .map_err(|e| format!("failed to listen on {}: {}", config.bind_address, e))?;
+    let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+    let opened_at = Utc::now();
+    let tunnel = Tunnel {
+        id: request.id.unwrap_or_else(|| Uuid::new_v4().to_string()),
+        runner_name: request.runner_name,
+        address: config.bind_address.clone(),
+        port,
+        opened_at,
+        expires_at: opened_at + chrono::Duration::seconds(ttl_secs as i64),
+        requested_by: request.requested_by,
+    };
+    let deadline = Instant::now() + Duration::from_secs(ttl_secs);
+    let task = tokio::spawn(forward(
+        listener,
+        tunnel.clone(),
+        format!("{}:{}", ip, GUEST_SSH_PORT),
+        deadline,
+    ));
+    with_registry(|registry| {
+        registry.seen.insert(tunnel.id.clone());
+        registry.open.insert(
+            tunnel.id.clone(),
+            OpenTunnel {
+                tunnel: tunnel.clone(),
+                task: task.abort_handle(),
+            },
+        );
+    });
+    audit(
+        "opened",
+        &tunnel,
+        Some(&format!("{}:{}", tunnel.address, port)),
+    );
+    Ok(tunnel)
+}
+
+async fn forward(listener: TcpListener, tunnel: Tunnel, target: String, deadline: Instant) {
+    // Dropped with this task, so closing the tunnel also cuts its connections
+    let mut connections = JoinSet::new();
+    let mut accept_backoff = MIN_ACCEPT_BACKOFF;
+    loop {
+        tokio::select! {
+            _ = sleep_until(deadline) => break,
+            accepted = listener.accept() => {
+                let (mut client, peer) = match accepted {
+                    Ok(accepted) => {
+                        accept_backoff = MIN_ACCEPT_BACKOFF;
+                        accepted
+                    }
+                    // e.g. out of file descriptors; retrying at once would spin
+                    Err(e) => {
+                        warn!("Tunnel {} failed to accept a connection: {}", tunnel.id, e);
+                        tokio::time::sleep(accept_backoff).await;
+                        accept_backoff = (accept_backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                        continue;
+                    }
+                };
+                audit("connected", &tunnel, Some(&peer.to_string()));
+                let target = target.clone();
+                connections.spawn(async move {
+                    let Ok(mut guest) = TcpStream::connect(&target).await else {
+                        warn!("Tunnel could not reach {}", target);
+                        return;
+                    };
+                    let _ = timeout_at(
+                        deadline,
+                        tokio::io::copy_bidirectional(&mut client, &mut guest),
+                    )
+                    .await;
+                });
+                while connections.try_join_next().is_some() {}
+            }
+        }
+    }
+    with_registry(|registry| registry.open.remove(&tunnel.id));
+    audit("expired", &tunnel, None);
+}
+
+/// Close a tunnel before it expires
+pub fn close(id: &str, reason: &str) -> bool {
+    let Some(open) = with_registry(|registry| registry.open.remove(id)) else {
+        return false;
+    };
+    open.task.abort();
+    audit("closed", &open.tunnel, Some(reason));
+    true
+}
+
+/// Close every tunnel to a runner, e.g. when it is deleted
+pub fn close_for_runner(runner_name: &str) {
+    let ids: Vec<String> = with_registry(|registry| {
+        registry
+            .open
+            .values()
+            .filter(|open| open.tunnel.runner_name == runner_name)
+            .map(|open| open.tunnel.id.clone())
+            .collect()
+    });
+    for id in ids {
+        close(&id, "runner deleted");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ttl_bounds() {
+        let config = &agent_config().tunnels;
+        assert_eq!(ttl(None), Ok(config.default_ttl_secs));
+        assert_eq!(ttl(Some(60)), Ok(60));
+        assert_eq!(ttl(Some(u64::MAX)), Ok(config.max_ttl_secs));
+        assert!(ttl(Some(0)).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+const MB_PER_GB: u64 = 1024;
+const MB_PER_TB: u64 = 1024 * 1024;
+
+/// Parse a size such as "8", "8G", "8GB", "1.5GiB", "512M" or "1T" into MB.
+/// Sizes without a unit are GB, matching the API's plain integers.
+fn parse_mb(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", value))?;
+    let mb_per_unit = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "g" | "gb" | "gib" => MB_PER_GB,
+        "m" | "mb" | "mib" => 1,
+        "t" | "tb" | "tib" => MB_PER_TB,
+        _ => return Err(format!("unknown unit in size '{}'", value)),
+    };
+    Ok((number * mb_per_unit as f64).round() as u64)
+}
+
+// Memory and disk sizes share their representation but must not be mixed up
+macro_rules! size_type {
+    ($name:ident, $what:literal) => {
+        #[doc = concat!("A ", $what, " size, stored in MB (1 GB = 1024 MB)")]
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(u64);
+
+        impl $name {
+            pub const fn from_gb(gb: u64) -> Self {
+                $name(gb * MB_PER_GB)
+            }
+
+            pub const fn from_mb(mb: u64) -> Self {
+                $name(mb)
+            }
+
+            pub const fn as_mb(self) -> u64 {
+                self.0
+            }
+
+            /// Whole GB, rounded down
+            pub const fn as_gb(self) -> u64 {
+                self.0 / MB_PER_GB
+            }
+
+            pub fn as_gb_f64(self) -> f64 {
+                self.0 as f64 / MB_PER_GB as f64
+            }
+
+            pub const fn is_zero(self) -> bool {
+                self.0 == 0
+            }
+
+            /// Size argument for meda, e.g. "8G" or "512M"
+            pub fn to_meda(self) -> String {
+                if self.0 % MB_PER_GB == 0 {
+                    format!("{}G", self.as_gb())
+                } else {
+                    format!("{}M", self.0)
+                }
+            }
+
+            /// Size argument for lume, e.g. "8GB" or "512MB"
+            pub fn to_lume(self) -> String {
+                self.to_string()
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                if self.0 % MB_PER_GB == 0 {
+                    write!(f, "{}GB", self.as_gb())
+                } else {
+                    write!(f, "{}MB", self.0)
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = String;
+
+            fn from_str(value: &str) -> Result<Self, Self::Err> {
+                parse_mb(value).map($name)
+            }
+        }
+
+        /// Whole GB serialize as a plain integer, as the API and older state files use
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                if self.0 % MB_PER_GB == 0 {
+                    serializer.serialize_u64(self.as_gb())
+                } else {
+                    serializer.serialize_str(&self.to_string())
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserializer.deserialize_any(SizeVisitor).map($name)
+            }
+        }
+    };
+}
+
+size_type!(Memory, "memory");
+size_type!(DiskSize, "disk");
+
+/// Accepts a number of GB or a size string, yielding MB
+struct SizeVisitor;
+
+impl Visitor<'_> for SizeVisitor {
+    type Value = u64;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a number of GB or a size such as \"8G\" or \"512MB\"")
+    }
+
+    fn visit_u64<E: de::Error>(self, gb: u64) -> Result<u64, E> {
+        Ok(gb * MB_PER_GB)
+    }
+
+    fn visit_i64<E: de::Error>(self, gb: i64) -> Result<u64, E> {
+        u64::try_from(gb)
+            .map(|gb| gb * MB_PER_GB)
+            .map_err(|_| E::custom("size cannot be negative"))
+    }
+
+    fn visit_f64<E: de::Error>(self, gb: f64) -> Result<u64, E> {
+        if gb < 0.0 {
+            return Err(E::custom("size cannot be negative"));
+        }
+        Ok((gb * MB_PER_GB as f64).round() as u64)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<u64, E> {
+        parse_mb(value).map_err(E::custom)
+    }
+}
+
+/// (De)serialize a size as a plain number of MB, as lume reports it
+pub mod mb {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T: Copy + Into<u64>, S: Serializer>(
+        size: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64((*size).into())
+    }
+
+    pub fn deserialize<'de, T: From<u64>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        u64::deserialize(deserializer).map(T::from)
+    }
+}
+
+/// Deserialize an optional size reported by a provider, treating a value that is not a size
+/// as unknown instead of failing the whole response
+pub mod lenient {
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<T>, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        Ok(T::deserialize(value).ok())
+    }
+}
+
+impl From<Memory> for u64 {
+    fn from(size: Memory) -> u64 {
+        size.as_mb()
+    }
+}
+
+impl From<u64> for Memory {
+    fn from(mb: u64) -> Memory {
+        Memory::from_mb(mb)
+    }
+}
+
+impl From<DiskSize> for u64 {
+    fn from(size: DiskSize) -> u64 {
+        size.as_mb()
+    }
+}
+
+impl From<u64> for DiskSize {
+    fn from(mb: u64) -> DiskSize {
+        DiskSize::from_mb(mb)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_format_and_serde() {
+        assert_eq!("8".parse::<Memory>(), Ok(Memory::from_gb(8)));
+        assert_eq!("8GB".parse::<Memory>(), Ok(Memory::from_gb(8)));
+        assert_eq!("2048M".parse::<Memory>(), Ok(Memory::from_gb(2)));
+        assert_eq!("1.5GiB".parse::<Memory>(), Ok(Memory::from_mb(1536)));
+        assert_eq!("1T".parse::<DiskSize>(), Ok(DiskSize::from_gb(1024)));
+        assert!("8 parsecs".parse::<Memory>().is_err());
+
+        assert_eq!(Memory::from_gb(8).to_string(), "8GB");
+        assert_eq!(Memory::from_mb(512).to_meda(), "512M");
+        assert_eq!(DiskSize::from_gb(100).to_meda(), "100G");
+
+        assert_eq!(
+            serde_json::from_str::<Memory>("8").unwrap(),
+            Memory::from_gb(8)
+        );
+        assert_eq!(
+            serde_json::from_str::<Memory>("\"4G\"").unwrap(),
+            Memory::from_gb(4)
+        );
+        assert_eq!(serde_json::to_string(&Memory::from_gb(8)).unwrap(), "8");
+        assert_eq!(
+            serde_json::to_string(&Memory::from_mb(512)).unwrap(),
+            "\"512MB\""
+        );
+    }
+}
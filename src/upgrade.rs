@@ -0,0 +1,71 @@
+use crate::host_tools::{process_running, signal_processes};
+use log::{info, warn};
+use std::path::Path;
+use std::process::Command;
+use std::{thread, time::Duration};
+
+/// How long a `serve` process gets to exit after SIGTERM before it is killed
+const SERVE_STOP_GRACE_SECS: u64 = 30;
+
+/// Version reported by a provider binary's `--version`, e.g. "0.2.22"
+pub fn installed_version(binary: &Path) -> Option<String> {
+    let output = Command::new(binary).arg("--version").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_version(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Extract the first version-looking token ("1.2.3" or "v1.2.3") from `--version` output
+fn parse_version(output: &str) -> Option<String> {
+    output
+        .split_whitespace()
+        .map(|token| token.trim_start_matches('v'))
+        .find(|token| {
+            token.contains('.')
+                && token
+                    .split('.')
+                    .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+        })
+        .map(str::to_string)
+}
+
+/// Stop a `serve` process matching `pattern`: SIGTERM first so it can finish what it
+/// is doing, SIGKILL if it is still around after the grace period
+pub fn stop_serve(pattern: &str) {
+    if !process_running(pattern) {
+        return;
+    }
+    info!("Stopping '{}'...", pattern);
+    signal_processes(pattern, false);
+
+    for _ in 0..SERVE_STOP_GRACE_SECS {
+        if !process_running(pattern) {
+            info!("'{}' stopped", pattern);
+            return;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+
+    warn!(
+        "'{}' did not exit within {}s, killing it",
+        pattern, SERVE_STOP_GRACE_SECS
+    );
+    signal_processes(pattern, true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("meda 0.3.0\n").as_deref(), Some("0.3.0"));
+        assert_eq!(parse_version("v0.2.22").as_deref(), Some("0.2.22"));
+        assert_eq!(
+            parse_version("lume version 0.2.22 (build 5)").as_deref(),
+            Some("0.2.22")
+        );
+        assert_eq!(parse_version("unknown"), None);
+    }
+}
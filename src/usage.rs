@@ -0,0 +1,183 @@
+use crate::state::RunnerRecord;
+use crate::units::{DiskSize, Memory};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Completed runners older than this are dropped from the usage history
+pub const USAGE_RETENTION_DAYS: i64 = 90;
+
+/// Resource usage of a runner VM that has been deleted
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UsageRecord {
+    pub runner_name: String,
+    pub created_at: DateTime<Utc>,
+    pub deleted_at: DateTime<Utc>,
+    pub cpu: u32,
+    pub memory: Memory,
+    pub disk: DiskSize,
+    #[serde(default)]
+    pub provision_duration_secs: Option<u64>,
+}
+
+impl UsageRecord {
+    pub fn from_runner(
+        runner_name: &str,
+        record: &RunnerRecord,
+        deleted_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            runner_name: runner_name.to_string(),
+            created_at: record.created_at,
+            deleted_at,
+            cpu: record.cpu,
+            memory: record.memory,
+            disk: record.disk,
+            provision_duration_secs: record.provision_duration_secs,
+        }
+    }
+}
+
+/// Aggregated usage over a reporting period
+#[derive(Debug, Serialize, Default, PartialEq)]
+pub struct UsageSummary {
+    pub period_start: Option<DateTime<Utc>>,
+    pub period_end: Option<DateTime<Utc>>,
+    pub runner_count: usize,
+    pub vm_hours: f64,
+    pub cpu_hours: f64,
+    pub memory_gb_hours: f64,
+    pub disk_gb_hours: f64,
+    pub avg_provision_secs: Option<f64>,
+}
+
+/// Seconds of the `[start, end)` lifetime that overlap the `[since, until)` period
+fn overlap_secs(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> f64 {
+    let from = start.max(since);
+    let to = end.min(until);
+    if to > from {
+        (to - from).num_milliseconds() as f64 / 1000.0
+    } else {
+        0.0
+    }
+}
+
+/// Aggregate completed and still-running runners over the `[since, until)` period
+pub fn summarize(
+    history: &[UsageRecord],
+    active: &[(String, RunnerRecord)],
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> UsageSummary {
+    let mut summary = UsageSummary {
+        period_start: Some(since),
+        period_end: Some(until),
+        ..Default::default()
+    };
+    let mut provision_total = 0u64;
+    let mut provision_count = 0u64;
+
+    let lifetimes = history
+        .iter()
+        .map(|r| {
+            (
+                r.created_at,
+                r.deleted_at,
+                r.cpu,
+                r.memory,
+                r.disk,
+                r.provision_duration_secs,
+            )
+        })
+        .chain(active.iter().map(|(_, r)| {
+            (
+                r.created_at,
+                until,
+                r.cpu,
+                r.memory,
+                r.disk,
+                r.provision_duration_secs,
+            )
+        }));
+
+    for (start, end, cpu, memory, disk, provision_secs) in lifetimes {
+        let secs = overlap_secs(start, end, since, until);
+        if secs <= 0.0 {
+            continue;
+        }
+        let hours = secs / 3600.0;
+        summary.runner_count += 1;
+        summary.vm_hours += hours;
+        summary.cpu_hours += hours * cpu as f64;
+        summary.memory_gb_hours += hours * memory.as_gb_f64();
+        summary.disk_gb_hours += hours * disk.as_gb_f64();
+        if let Some(p) = provision_secs {
+            provision_total += p;
+            provision_count += 1;
+        }
+    }
+
+    if provision_count > 0 {
+        summary.avg_provision_secs = Some(provision_total as f64 / provision_count as f64);
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_summarize_clips_to_period() {
+        let since = Utc::now() - Duration::hours(10);
+        let until = since + Duration::hours(10);
+
+        // Ran 2h entirely inside the period
+        let finished = UsageRecord {
+            runner_name: "cirun-a".to_string(),
+            created_at: since + Duration::hours(1),
+            deleted_at: since + Duration::hours(3),
+            cpu: 4,
+            memory: Memory::from_gb(8),
+            disk: DiskSize::from_gb(50),
+            provision_duration_secs: Some(60),
+        };
+        // Started 1h before the period ended and is still running
+        let active = RunnerRecord {
+            created_at: until - Duration::hours(1),
+            cpu: 2,
+            memory: Memory::from_gb(4),
+            disk: DiskSize::from_gb(20),
+            provision_duration_secs: Some(120),
+            provision_phases: None,
+            script_status: None,
+            detached_script: None,
+            cpu_pinning: None,
+            template_fallback: None,
+        };
+        // Deleted before the period started
+        let old = UsageRecord {
+            created_at: since - Duration::hours(5),
+            deleted_at: since - Duration::hours(4),
+            ..finished.clone()
+        };
+
+        let summary = summarize(
+            &[finished, old],
+            &[("cirun-b".to_string(), active)],
+            since,
+            until,
+        );
+
+        assert_eq!(summary.runner_count, 2);
+        assert!((summary.vm_hours - 3.0).abs() < 1e-6);
+        assert!((summary.cpu_hours - 10.0).abs() < 1e-6);
+        assert!((summary.memory_gb_hours - 20.0).abs() < 1e-6);
+        assert_eq!(summary.avg_provision_secs, Some(90.0));
+    }
+}
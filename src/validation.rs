@@ -0,0 +1,92 @@
+use crate::RunnerToProvision;
+
+/// Longest runner name accepted; names become VM names, hostnames and file names
+const MAX_NAME_LEN: usize = 63;
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with(['-', '.'])
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && !username.starts_with('-')
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Check a runner request before anything is passed to the provider or a shell.
+/// Returns every problem found, so the API can report them all at once.
+pub fn validate_runner(runner: &RunnerToProvision) -> Result<(), Vec<String>> {
+    let mut errors = Vec::new();
+
+    if !valid_name(&runner.name) {
+        errors.push(format!(
+            "invalid runner name '{}': use up to {} letters, digits, '-', '_' or '.', \
+             not starting with '-' or '.'",
+            runner.name, MAX_NAME_LEN
+        ));
+    }
+    if runner.image.trim().is_empty() {
+        errors.push("image is empty".to_string());
+    }
+    if runner.cpu == 0 {
+        errors.push("cpu must be at least 1".to_string());
+    }
+    if runner.memory.is_zero() {
+        errors.push("memory must be greater than 0".to_string());
+    }
+    if runner.disk.is_zero() {
+        errors.push("disk is missing or 0".to_string());
+    }
+    if runner.provision_script.trim().is_empty() {
+        errors.push("provision script is empty".to_string());
+    }
+    if !valid_username(&runner.login.username) {
+        errors.push(format!(
+            "invalid login username '{}'",
+            runner.login.username
+        ));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_runner() {
+        let runner: RunnerToProvision = serde_json::from_value(serde_json::json!({
+            "name": "cirun-runner-1",
+            "provision_script": "#!/bin/sh\necho hi",
+            "image": "ubuntu-24.04",
+            "cpu": 2,
+            "memory": 4,
+            "disk": 20,
+            "login": {"username": "runner", "password": "secret"}
+        }))
+        .unwrap();
+        assert_eq!(validate_runner(&runner), Ok(()));
+
+        let mut bad = runner.clone();
+        bad.name = "../etc/passwd".to_string();
+        bad.cpu = 0;
+        bad.disk = Default::default();
+        bad.provision_script = "  \n".to_string();
+        bad.login.username = "root; reboot".to_string();
+        let errors = validate_runner(&bad).unwrap_err();
+        assert_eq!(errors.len(), 5);
+        assert!(errors[0].starts_with("invalid runner name '../etc/passwd'"));
+    }
+}
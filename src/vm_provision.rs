@@ -1,22 +1,174 @@
+use crate::address_check;
+use crate::boot_ramp;
+use crate::chaos;
+use crate::config::{agent_config, RetryPolicy, SharedDirectoryConfig};
+use crate::disk;
+use crate::guest_files::{push_files, GuestFile};
+use crate::host_keys;
+use crate::lifecycle::{transition, RunnerState};
+use crate::log_stream::stream_output;
 use crate::lume::{LumeClient, RunConfig};
+use crate::pool;
+use crate::readiness;
+use crate::retry::{with_retries, Classify};
+use crate::script_monitor;
+use crate::ssh::{self, SshError};
+use crate::state::script_hash;
+use crate::temp_guard::TempGuard;
+use crate::timing::{record_phase, Phase};
+use crate::tool_cache::{link_shared_directories, lume_shared_directories, preseed_tool_cache};
+use crate::{use_meda, RunnerLogin};
+use base64::prelude::*;
 use log::{error, info, warn};
-use std::fs::{remove_file, File};
-use std::io::Write;
-use std::process::Stdio;
+use serde::{Deserialize, Serialize};
+use std::process::{Output, Stdio};
 use std::time::{Duration, Instant};
-use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
 use tokio::time::sleep;
 
 use anyhow::Result;
-use backon::{ExponentialBuilder, Retryable};
 
+/// Output and exit code of a detached provision script, on the guest
+pub const SCRIPT_STDOUT_LOG: &str = "/tmp/script_stdout.log";
+pub const SCRIPT_STDERR_LOG: &str = "/tmp/script_stderr.log";
+pub const SCRIPT_EXIT_FILE: &str = "/tmp/script_exit.code";
+
+/// Per-runner settings for how the provision script is run, sent by the API in `login.script`
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ScriptExecution {
+    /// User to run the script as; another user than the login user goes through sudo
+    pub user: Option<String>,
+    /// Run the script as root; unset keeps the provider's default (meda: yes, lume: no)
+    pub sudo: Option<bool>,
+    /// Directory to run the script in, instead of the login user's home
+    pub workdir: Option<String>,
+    /// One of `INTERPRETERS`; unset keeps the provider's default (meda: bash, lume: the
+    /// script's shebang)
+    pub interpreter: Option<String>,
+    /// Arguments passed to the interpreter before the script path, replacing its defaults
+    pub interpreter_args: Vec<String>,
+}
+
+/// Interpreters a provision script can be run with, and their default arguments
+const INTERPRETERS: &[(&str, &[&str])] = &[
+    ("bash", &[]),
+    ("sh", &[]),
+    ("zsh", &[]),
+    ("pwsh", &["-NoProfile", "-NonInteractive", "-File"]),
+    ("python3", &[]),
+];
+
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+impl ScriptExecution {
+    /// Whether the script runs through sudo, as root or as another user
+    pub fn elevates(&self, login_user: &str, sudo_by_default: bool) -> bool {
+        self.user.as_deref().is_some_and(|user| user != login_user)
+            || self.sudo.unwrap_or(sudo_by_default)
+    }
+
+    /// File extension for the uploaded script; pwsh only runs `.ps1` files with `-File`
+    pub fn extension(&self) -> &'static str {
+        match self.interpreter().0 {
+            Some("pwsh") => "ps1",
+            Some("python3") => "py",
+            _ => "sh",
+        }
+    }
+
+    /// Interpreter and arguments set for this runner, else those in the `[script]` config
+    fn interpreter(&self) -> (Option<&str>, &[String]) {
+        let config = &agent_config().script;
+        match (&self.interpreter, &config.interpreter) {
+            (None, Some(interpreter)) => (Some(interpreter), &config.interpreter_args),
+            (interpreter, _) => (interpreter.as_deref(), &self.interpreter_args),
+        }
+    }
+
+    /// Command line that runs the script at `script_path`, with `default_interpreter` when
+    /// no interpreter is set, or the script itself when that is `None` too
+    pub fn program(
+        &self,
+        script_path: &str,
+        default_interpreter: Option<&str>,
+    ) -> Result<String, String> {
+        let (interpreter, args) = self.interpreter();
+        let Some(interpreter) = interpreter.or(default_interpreter) else {
+            return Ok(shell_quote(script_path));
+        };
+        let (name, default_args) = INTERPRETERS
+            .iter()
+            .find(|(name, _)| *name == interpreter)
+            .ok_or_else(|| format!("Unsupported script interpreter '{}'", interpreter))?;
+        let mut words = vec![name.to_string()];
+        if args.is_empty() {
+            words.extend(default_args.iter().map(|arg| arg.to_string()));
+        } else {
+            words.extend(args.iter().map(|arg| shell_quote(arg)));
+        }
+        words.push(shell_quote(script_path));
+        Ok(words.join(" "))
+    }
+
+    /// Shell command run over SSH to execute `program`. Detached runs write their output to
+    /// `SCRIPT_STD{OUT,ERR}_LOG` and exit code to `SCRIPT_EXIT_FILE`, and print the PID of
+    /// the shell waiting for the script.
+    pub fn command(
+        &self,
+        login_user: &str,
+        program: &str,
+        sudo_by_default: bool,
+        detached: bool,
+    ) -> String {
+        let elevate = match self.user.as_deref() {
+            Some(user) if user != login_user => format!("sudo -n -H -u {} ", shell_quote(user)),
+            _ if self.elevates(login_user, sudo_by_default) => "sudo -n ".to_string(),
+            _ => String::new(),
+        };
+        let cd = self
+            .workdir
+            .as_deref()
+            .map(|dir| format!("cd {} || exit 1; ", shell_quote(dir)))
+            .unwrap_or_default();
+        if detached {
+            let wrapped = format!("{}{}; echo $? > {}", elevate, program, SCRIPT_EXIT_FILE);
+            format!(
+                "{}rm -f {}; nohup sh -c {} > {} 2> {} & echo $!",
+                cd,
+                SCRIPT_EXIT_FILE,
+                shell_quote(&wrapped),
+                SCRIPT_STDOUT_LOG,
+                SCRIPT_STDERR_LOG
+            )
+        } else {
+            format!("{}{}{}", cd, elevate, program)
+        }
+    }
+}
+
+/// Error of a script run. A run that timed out may have started the script, so unlike
+/// other SSH steps it is not retried.
+#[derive(Debug)]
+struct ScriptRunError(SshError);
+
+impl Classify for ScriptRunError {
+    fn is_retryable(&self) -> bool {
+        matches!(self.0, SshError::Connection(_))
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run_script_on_vm(
     lume: &LumeClient,
     vm_name: &str,
     script_content: &str,
-    username: &str,
-    password: &str,
+    files: &[GuestFile],
+    shared_directories: &[SharedDirectoryConfig],
+    login: &RunnerLogin,
     timeout_seconds: u64,
     run_detached: bool,
 ) -> Result<String, Box<dyn std::error::Error>> {
@@ -35,246 +187,467 @@ pub async fn run_script_on_vm(
         let start_vm = || async {
             let run_config = RunConfig {
                 no_display: Some(true),
-                shared_directories: None,
+                shared_directories: Some(lume_shared_directories(shared_directories))
+                    .filter(|dirs| !dirs.is_empty()),
                 recovery_mode: None,
             };
-            lume.run_vm(vm_name, Some(run_config))
-                .await
-                .map_err(|e| anyhow::anyhow!("Failed to start VM: {:?}", e))
+            lume.run_vm(vm_name, Some(run_config)).await
         };
 
-        start_vm
-            .retry(ExponentialBuilder::default().with_max_times(5))
-            .sleep(tokio::time::sleep)
-            .when(|e| e.to_string().contains("Failed to start VM"))
-            .notify(|err, dur| warn!("Retrying VM start after {:?}: {:?}", dur, err))
-            .await?;
+        boot_ramp::wait_for_slot(vm_name).await;
+        let boot_start = Instant::now();
+        with_retries("VM start", agent_config().retry.provider_api, start_vm)
+            .await
+            .map_err(|e| format!("Failed to start VM: {}", e))?;
+        record_phase(Phase::Boot, boot_start.elapsed());
 
         info!("Start command sent successfully");
     }
 
     // Step 3: Wait for the VM to be running and get its IP
     info!("Waiting for VM to be fully running and get its IP address");
-    let ip_address = wait_for_vm_ip(lume, vm_name, timeout_seconds).await?;
+    let ip_wait_start = Instant::now();
+    let ip_address = wait_for_vm_ip(lume, vm_name, timeout_seconds).await;
+    record_phase(Phase::IpWait, ip_wait_start.elapsed());
+    let ip_address = ip_address?;
     info!("VM is running with IP: {}", ip_address);
 
-    // Step 4: Create a temporary file for the script
-    info!("Creating temporary script file");
-    let mut temp_file = NamedTempFile::new()?;
-    temp_file.write_all(script_content.as_bytes())?;
-    let temp_file_path = temp_file
-        .path()
-        .to_str()
-        .ok_or("Failed to get temporary file path")?;
-
-    // Step 5: Create a temporary password file for sshpass
-    let password_file_path = create_password_file(password)?;
+    // Step 4: Create a temporary password file for sshpass
+    let password_file = TempGuard::secret_file("sshpass_", login.password.as_bytes())?;
+    let username = &login.username;
     info!("Created temporary password file for SSH authentication");
 
-    // Step 6: Setup SSH options
-    let ssh_options = vec![
-        "-o",
-        "StrictHostKeyChecking=no",
-        "-o",
-        "UserKnownHostsFile=/dev/null",
-        "-o",
-        "ConnectTimeout=10",
-    ];
-
-    // Step 7: Test SSH connection with retries (capped at 10 retries, 30s timeout per attempt)
+    // Step 5: Setup SSH options
+    let ssh_settings = ssh::settings(login, vm_name);
+    let ssh_options = ssh_settings.args();
+
+    // Step 6: Test SSH connection with retries (capped by the SSH retry count and ready window)
+    let timeouts = &agent_config().timeouts;
+    let retry_policy = agent_config().retry.ssh;
     info!("Testing SSH connection to VM");
+    let boot_wait_start = Instant::now();
+    readiness::wait_until_booted(vm_name, timeouts.ssh_ready_secs).await;
+    record_phase(Phase::Boot, boot_wait_start.elapsed());
+    address_check::verify(vm_name, &ip_address, &ssh_settings).await?;
+    host_keys::ensure_trusted(vm_name, timeouts.ssh_ready_secs).await?;
+    let ssh_wait_start = Instant::now();
     let ssh_test_result = || async {
         let output = tokio::time::timeout(
-            tokio::time::Duration::from_secs(30),
+            tokio::time::Duration::from_secs(timeouts.ssh_attempt_secs),
             Command::new("sshpass")
                 .arg("-f")
-                .arg(&password_file_path)
+                .arg(password_file.path())
                 .arg("ssh")
                 .args(&ssh_options)
                 .arg(format!("{}@{}", username, ip_address))
                 .arg("echo 'SSH connection test successful'")
                 .stdout(Stdio::piped())
                 .stderr(Stdio::piped())
+                .kill_on_drop(true)
                 .output(),
         )
         .await
-        .map_err(|_| anyhow::anyhow!("SSH connection timed out after 30s"))?
-        .map_err(|e| anyhow::anyhow!("SSH command error: {}", e))?;
+        .map_err(|_| SshError::Timeout {
+            action: "SSH connection",
+            secs: timeouts.ssh_attempt_secs,
+        })?
+        .map_err(|e| SshError::Local(format!("SSH command error: {}", e)))?;
 
+        // The command only echoes, so any failure is the connection's
         if !output.status.success() {
-            Err(anyhow::anyhow!(
+            Err(SshError::Connection(format!(
                 "SSH connection failed: {}",
                 String::from_utf8_lossy(&output.stderr)
-            ))
+            )))
         } else {
             Ok(())
         }
     };
 
-    ssh_test_result
-        .retry(ExponentialBuilder::default().with_max_times(10))
-        .sleep(tokio::time::sleep)
-        .when(|e| {
-            let msg = e.to_string();
-            msg.contains("SSH connection failed") || msg.contains("SSH connection timed out")
-        })
-        .notify(|err, dur| warn!("Retrying SSH connection after {:?}: {:?}", dur, err))
-        .await?;
+    let ssh_ready = tokio::time::timeout(
+        Duration::from_secs(timeouts.ssh_ready_secs),
+        with_retries(
+            "SSH connection",
+            RetryPolicy {
+                max_retries: ssh_settings
+                    .retries
+                    .map_or(retry_policy.max_retries, |retries| retries as usize),
+                ..retry_policy
+            },
+            ssh_test_result,
+        ),
+    )
+    .await;
+    record_phase(Phase::SshWait, ssh_wait_start.elapsed());
+    ssh_ready.map_err(|_| {
+        format!(
+            "SSH did not become ready within {}s",
+            timeouts.ssh_ready_secs
+        )
+    })??;
 
     info!("✔ SSH connection successful");
 
-    // Step 8: Copy the script to the VM using sshpass with retries
-    let remote_script_path = format!("/tmp/script_{}.sh", Instant::now().elapsed().as_secs());
-    info!("Copying script to VM at {}", remote_script_path);
-
-    let scp_transfer = || async {
-        let output = tokio::time::timeout(
-            tokio::time::Duration::from_secs(60),
-            Command::new("sshpass")
-                .arg("-f")
-                .arg(&password_file_path)
-                .arg("scp")
-                .args(&ssh_options)
-                .arg(temp_file_path)
-                .arg(format!(
-                    "{}@{}:{}",
-                    username, ip_address, remote_script_path
-                ))
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .output(),
+    disk::grow_pending_filesystem(vm_name, &ip_address, login).await;
+    preseed_tool_cache(vm_name, &ip_address, login).await;
+    link_shared_directories(vm_name, &ip_address, login, shared_directories).await;
+    push_files(vm_name, &ip_address, login, files).await?;
+
+    // Step 7: Upload the script to the VM with retries
+    let _ = transition(&pool::runner_name(vm_name), RunnerState::Provisioning);
+    let script_start = Instant::now();
+    let remote_script_path = format!(
+        "/tmp/script_{}.{}",
+        Instant::now().elapsed().as_secs(),
+        login.script.extension()
+    );
+    let program = login.script.program(&remote_script_path, None)?;
+    let remote_script = RemoteScript::new(vm_name, remote_script_path.clone(), script_content);
+    info!("Uploading script to VM at {}", remote_script_path);
+
+    let transfer = || async {
+        upload_script(
+            vm_name,
+            &ip_address,
+            login,
+            script_content,
+            &remote_script_path,
+            timeouts.transfer_secs,
         )
         .await
-        .map_err(|_| anyhow::anyhow!("SCP transfer timed out after 60s"))?
-        .map_err(|e| anyhow::anyhow!("SCP command error: {}", e))?;
-
-        if !output.status.success() {
-            Err(anyhow::anyhow!(
-                "SCP failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            ))
-        } else {
-            Ok(())
-        }
     };
 
-    scp_transfer
-        .retry(ExponentialBuilder::default().with_max_times(5))
-        .sleep(tokio::time::sleep)
-        .when(|e| {
-            let msg = e.to_string();
-            msg.contains("SCP failed") || msg.contains("SCP transfer timed out")
-        })
-        .notify(|err, dur| warn!("Retrying SCP transfer after {:?}: {:?}", dur, err))
-        .await?;
-
-    info!("✔ SCP transfer successful");
+    with_retries("script upload", retry_policy, transfer).await?;
 
-    // Step 9: Execute the script on the VM with retries (capped at 3 retries, with timeout)
+    // Step 8: Execute the script on the VM, retried under `[retry.script]`
     let execute_script = || async {
-        let (timeout_secs, cmd_future) = if run_detached {
+        let remote_command = login
+            .script
+            .command(username, &program, false, run_detached);
+        let timeout_secs = if run_detached {
             info!("Executing script on VM in detached mode");
-            (
-                60u64,
-                Command::new("sshpass")
-                    .arg("-f").arg(&password_file_path)
-                    .arg("ssh")
-                    .args(&ssh_options)
-                    .arg(format!("{}@{}", username, ip_address))
-                    .arg(format!("chmod +x {} && nohup {} > /tmp/script_stdout.log 2> /tmp/script_stderr.log & echo $!",
-                                 remote_script_path, remote_script_path))
-                    .stdout(Stdio::piped())
-                    .stderr(Stdio::piped())
-                    .output(),
-            )
+            timeouts.script_launch_secs
         } else {
             info!("Executing script on VM and waiting for completion");
-            (
-                600u64,
-                Command::new("sshpass")
-                    .arg("-f")
-                    .arg(&password_file_path)
-                    .arg("ssh")
-                    .args(&ssh_options)
-                    .arg(format!("{}@{}", username, ip_address))
-                    .arg(format!(
-                        "chmod +x {} && {}",
-                        remote_script_path, remote_script_path
-                    ))
-                    .stdout(Stdio::piped())
-                    .stderr(Stdio::piped())
-                    .output(),
-            )
+            timeouts.script_secs
+        };
+
+        let mut command = Command::new("sshpass");
+        command
+            .arg("-f")
+            .arg(password_file.path())
+            .arg("ssh")
+            .args(&ssh_options)
+            .arg(format!("{}@{}", username, ip_address))
+            .arg(remote_command)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        // Blocking runs stream their output as it arrives so long provisions don't look hung
+        let cmd_future = async {
+            if run_detached {
+                command.output().await
+            } else {
+                stream_output(&mut command, &pool::runner_name(vm_name)).await
+            }
         };
 
         let output =
             tokio::time::timeout(tokio::time::Duration::from_secs(timeout_secs), cmd_future)
                 .await
-                .map_err(|_| anyhow::anyhow!("Script execution timed out after {}s", timeout_secs))?
-                .map_err(|e| anyhow::anyhow!("Script command error: {}", e))?;
+                .map_err(|_| {
+                    ScriptRunError(SshError::Timeout {
+                        action: "Script execution",
+                        secs: timeout_secs,
+                    })
+                })?
+                .map_err(|e| {
+                    ScriptRunError(SshError::Local(format!("Script command error: {}", e)))
+                })?;
 
         if !output.status.success() {
-            Err(anyhow::anyhow!(
-                "Script execution failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            ))
+            Err(ScriptRunError(SshError::failed(
+                "Script execution",
+                &output,
+            )))
         } else {
             Ok(String::from_utf8_lossy(&output.stdout).to_string())
         }
     };
 
-    let script_output = execute_script
-        .retry(ExponentialBuilder::default().with_max_times(3))
-        .sleep(tokio::time::sleep)
-        .when(|e| {
-            let msg = e.to_string();
-            msg.contains("Script execution failed") || msg.contains("Script execution timed out")
-        })
-        .notify(|err, dur| warn!("Retrying script execution after {:?}: {:?}", dur, err))
-        .await?;
-
-    // Step 10: Clean up password file
-    clean_up_password_file(&password_file_path);
+    // A script that ran and failed is not run again; only lost connections are
+    let script_output = with_retries(
+        "script execution",
+        agent_config().retry.script,
+        execute_script,
+    )
+    .await
+    .map_err(|e| e.0);
+    record_phase(Phase::Script, script_start.elapsed());
+
+    // Step 9: Clean up password file and the script, unless a detached script still needs it
+    drop(password_file);
+    if !run_detached || script_output.is_err() {
+        remote_script.clean_up(&ip_address, login).await;
+    }
+    let script_output = script_output?;
+    if run_detached {
+        script_monitor::watch_detached(
+            &pool::runner_name(vm_name),
+            &ip_address,
+            login,
+            &script_output,
+            remote_script,
+        );
+    }
 
-    // Step 11: Return the output
+    // Step 10: Return the output
     info!("Script execution completed successfully.");
     Ok(script_output)
 }
 
-// Helper function to create a temporary file containing the password
-fn create_password_file(password: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let temp_dir = std::env::temp_dir();
-    let password_file_path = temp_dir.join(format!(
-        "sshpass_{}.txt",
-        Instant::now().elapsed().as_millis()
-    ));
-
-    let mut file = File::create(&password_file_path)?;
-    file.write_all(password.as_bytes())?;
-
-    // Restrict permissions on the password file (important for security)
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        let metadata = file.metadata()?;
-        let mut permissions = metadata.permissions();
-        permissions.set_mode(0o600); // Owner read/write only
-        std::fs::set_permissions(&password_file_path, permissions)?;
-    }
+/// Run a single command on a VM over SSH and capture its output.
+/// Uses the meda SSH key on Linux hosts and sshpass with the runner password on macOS.
+pub async fn run_ssh_command(
+    vm_name: &str,
+    ip_address: &str,
+    login: &RunnerLogin,
+    command: &str,
+    timeout_seconds: u64,
+) -> Result<Output, Box<dyn std::error::Error>> {
+    run_ssh_command_with_input(vm_name, ip_address, login, command, None, timeout_seconds).await
+}
+
+/// Like `run_ssh_command`, optionally feeding `input` to the remote command's stdin
+async fn run_ssh_command_with_input(
+    vm_name: &str,
+    ip_address: &str,
+    login: &RunnerLogin,
+    command: &str,
+    input: Option<&[u8]>,
+    timeout_seconds: u64,
+) -> Result<Output, Box<dyn std::error::Error>> {
+    let mut args = ssh::settings(login, vm_name).args();
+    args.push(format!("{}@{}", login.username, ip_address));
+    args.push(command.to_string());
+    run_authenticated("ssh", args, login, input, timeout_seconds).await
+}
+
+/// Run an sftp batch (one command per line) against a VM
+pub async fn run_sftp_batch(
+    vm_name: &str,
+    ip_address: &str,
+    login: &RunnerLogin,
+    batch: &str,
+    timeout_seconds: u64,
+) -> Result<Output, Box<dyn std::error::Error>> {
+    let args = sftp_batch_args(
+        ssh::settings(login, vm_name).sftp_args(),
+        !use_meda(),
+        &format!("{}@{}", login.username, ip_address),
+    );
+    run_authenticated("sftp", args, login, Some(batch.as_bytes()), timeout_seconds).await
+}
 
-    Ok(password_file_path.to_string_lossy().to_string())
+/// Arguments for `sftp -b -`. Batch mode turns on ssh's `BatchMode`, which disables the
+/// password prompt sshpass answers, so with `password_auth` it is turned back off. ssh
+/// keeps the first value it sees, so the option has to come before `-b`.
+fn sftp_batch_args(mut args: Vec<String>, password_auth: bool, target: &str) -> Vec<String> {
+    if password_auth {
+        args.extend(["-o".to_string(), "BatchMode=no".to_string()]);
+    }
+    args.extend(["-b".to_string(), "-".to_string(), target.to_string()]);
+    args
 }
 
-// Helper function to clean up the password file
-fn clean_up_password_file(file_path: &str) {
-    if let Err(e) = remove_file(file_path) {
-        error!("Failed to remove temporary password file: {}", e);
+/// Run `ssh` or `sftp` with the meda SSH key on Linux hosts and sshpass with the runner
+/// password on macOS, optionally feeding `input` to its stdin
+async fn run_authenticated(
+    program: &str,
+    args: Vec<String>,
+    login: &RunnerLogin,
+    input: Option<&[u8]>,
+    timeout_seconds: u64,
+) -> Result<Output, Box<dyn std::error::Error>> {
+    if chaos::ssh_timeout(program) {
+        return Err(format!(
+            "{} command timed out after {}s ({})",
+            program,
+            timeout_seconds,
+            chaos::INJECTED
+        )
+        .into());
+    }
+    let mut password_file = None;
+    let mut ssh = if use_meda() {
+        let home_dir = std::env::var("HOME").unwrap_or_else(|_| "/root".to_string());
+        let ssh_key_path = format!("{}/.meda/ssh/id_ed25519", home_dir);
+        let mut ssh = Command::new(program);
+        ssh.arg("-i").arg(ssh_key_path);
+        ssh
     } else {
-        info!("Temporary password file removed");
+        let file = TempGuard::secret_file("sshpass_", login.password.as_bytes())?;
+        let mut ssh = Command::new("sshpass");
+        ssh.arg("-f").arg(file.path()).arg(program);
+        password_file = Some(file);
+        ssh
+    };
+    ssh.args(args)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let run = async {
+        let mut child = ssh.spawn()?;
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            stdin.write_all(input).await?;
+            // Closing stdin signals end of input to the remote command
+            drop(stdin);
+        }
+        child.wait_with_output().await
+    };
+    let output = tokio::time::timeout(Duration::from_secs(timeout_seconds), run).await;
+    drop(password_file);
+    let output = output
+        .map_err(|_| format!("{} command timed out after {}s", program, timeout_seconds))??;
+    Ok(output)
+}
+
+/// Shell command run on the guest to decode a base64 script from stdin, verify its
+/// SHA-256 and only then move it into place as an executable
+fn upload_command(remote_path: &str, expected_hash: &str) -> String {
+    format!(
+        "tmp='{path}.part'; base64 --decode > \"$tmp\" || exit 1; \
+         sum=$( (sha256sum \"$tmp\" 2>/dev/null || shasum -a 256 \"$tmp\") | cut -d' ' -f1); \
+         if [ \"$sum\" != '{hash}' ]; then \
+         echo \"checksum mismatch: expected {hash}, got $sum\" >&2; rm -f \"$tmp\"; exit 1; fi; \
+         chmod +x \"$tmp\" && mv \"$tmp\" '{path}'",
+        path = remote_path,
+        hash = expected_hash
+    )
+}
+
+/// Normalize line endings so scripts authored on Windows run under a POSIX shell
+fn normalize_script(script: &str) -> String {
+    script.replace("\r\n", "\n")
+}
+
+/// Upload a provision script as base64 over SSH stdin, avoiding shell quoting issues and
+/// argument size limits, and verify its checksum on the guest before it can be executed
+pub async fn upload_script(
+    vm_name: &str,
+    ip_address: &str,
+    login: &RunnerLogin,
+    script: &str,
+    remote_path: &str,
+    timeout_seconds: u64,
+) -> Result<(), SshError> {
+    let script = normalize_script(script);
+    let expected_hash = script_hash(&script);
+    let encoded = BASE64_STANDARD.encode(script.as_bytes());
+
+    let output = run_ssh_command_with_input(
+        vm_name,
+        ip_address,
+        login,
+        &upload_command(remote_path, &expected_hash),
+        Some(encoded.as_bytes()),
+        timeout_seconds,
+    )
+    .await
+    // ssh timing out or not starting is treated like a lost connection and retried
+    .map_err(|e| SshError::Connection(e.to_string()))?;
+
+    if !output.status.success() {
+        return Err(SshError::failed("Script upload", &output));
+    }
+    info!(
+        "✔ Script uploaded to {} (sha256 {})",
+        remote_path,
+        &expected_hash[..12]
+    );
+    Ok(())
+}
+
+/// Words that suggest a script carries credentials, such as a runner registration token
+const CREDENTIAL_MARKERS: &[&str] = &[
+    "token",
+    "password",
+    "passwd",
+    "secret",
+    "api_key",
+    "apikey",
+    "private key",
+];
+
+fn contains_credentials(script: &str) -> bool {
+    let script = script.to_lowercase();
+    CREDENTIAL_MARKERS
+        .iter()
+        .any(|marker| script.contains(marker))
+}
+
+/// A provision script uploaded to a guest, removed again once it has run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteScript {
+    pub vm_name: String,
+    pub path: String,
+    /// The script carries credentials, so it is overwritten before it is removed
+    pub shred: bool,
+}
+
+impl RemoteScript {
+    pub fn new(vm_name: &str, path: String, content: &str) -> Self {
+        RemoteScript {
+            vm_name: vm_name.to_string(),
+            path,
+            shred: contains_credentials(content),
+        }
+    }
+
+    /// Shell command removing the script and any detached output it left behind.
+    /// macOS has no `shred`, but its `rm -P` overwrites files before unlinking them.
+    fn cleanup_command(&self) -> String {
+        let script = shell_quote(&self.path);
+        let remove = if self.shred {
+            format!(
+                "shred -u {s} 2>/dev/null || rm -P {s} 2>/dev/null || rm -f {s}",
+                s = script
+            )
+        } else {
+            format!("rm -f {}", script)
+        };
+        format!(
+            "{}; rm -f {}.part {} {} {}",
+            remove, script, SCRIPT_STDOUT_LOG, SCRIPT_STDERR_LOG, SCRIPT_EXIT_FILE
+        )
+    }
+
+    /// Remove the script from the guest; failures are only logged
+    pub async fn clean_up(&self, ip_address: &str, login: &RunnerLogin) {
+        let timeout = agent_config().timeouts.ssh_attempt_secs;
+        let command = self.cleanup_command();
+        match run_ssh_command(&self.vm_name, ip_address, login, &command, timeout).await {
+            Ok(output) if output.status.success() => {
+                info!("Removed {} from the VM", self.path)
+            }
+            Ok(output) => warn!(
+                "Failed to remove {} from the VM: {}",
+                self.path,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => warn!("Failed to remove {} from the VM: {}", self.path, e),
+        }
     }
 }
 
-async fn wait_for_vm_ip(
+pub async fn wait_for_vm_ip(
     lume: &LumeClient,
     vm_name: &str,
     timeout_seconds: u64,
@@ -307,3 +680,170 @@ async fn wait_for_vm_ip(
 
     Err(format!("Timed out waiting for VM {} to be running with IP", vm_name).into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_run_timeout_is_not_retried() {
+        let timeout = ScriptRunError(SshError::Timeout {
+            action: "Script execution",
+            secs: 60,
+        });
+        assert!(!timeout.is_retryable());
+        assert!(ScriptRunError(SshError::Connection("reset".to_string())).is_retryable());
+    }
+
+    #[test]
+    fn test_sftp_batch_args_keep_password_auth() {
+        let base = vec!["-P".to_string(), "22".to_string()];
+        // lume VMs authenticate through sshpass
+        let args = sftp_batch_args(base.clone(), true, "runner@192.168.64.5");
+        assert_eq!(
+            args,
+            [
+                "-P",
+                "22",
+                "-o",
+                "BatchMode=no",
+                "-b",
+                "-",
+                "runner@192.168.64.5"
+            ]
+        );
+        let batch_mode = args.iter().position(|a| a == "BatchMode=no").unwrap();
+        assert!(batch_mode < args.iter().position(|a| a == "-b").unwrap());
+
+        // meda VMs use a key and keep batch mode
+        let args = sftp_batch_args(base, false, "runner@10.0.0.5");
+        assert!(!args.contains(&"BatchMode=no".to_string()));
+    }
+
+    #[test]
+    fn test_script_execution_command() {
+        let default = ScriptExecution::default();
+        assert_eq!(
+            default.command("runner", "bash /tmp/s.sh", true, false),
+            "sudo -n bash /tmp/s.sh"
+        );
+        assert_eq!(
+            default.command("runner", "/tmp/s.sh", false, false),
+            "/tmp/s.sh"
+        );
+
+        let as_ci = ScriptExecution {
+            user: Some("ci".to_string()),
+            sudo: Some(false),
+            workdir: Some("/home/ci/work dir".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            as_ci.command("runner", "/tmp/s.sh", false, true),
+            "cd '/home/ci/work dir' || exit 1; rm -f /tmp/script_exit.code; \
+             nohup sh -c 'sudo -n -H -u '\\''ci'\\'' /tmp/s.sh; echo $? > /tmp/script_exit.code' \
+             > /tmp/script_stdout.log 2> /tmp/script_stderr.log & echo $!"
+        );
+        // Running as the login user itself never needs sudo
+        let as_login = ScriptExecution {
+            user: Some("runner".to_string()),
+            sudo: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            as_login.command("runner", "/tmp/s.sh", true, false),
+            "/tmp/s.sh"
+        );
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn test_script_interpreters() {
+        let default = ScriptExecution::default();
+        assert_eq!(default.program("/tmp/s.sh", None).unwrap(), "'/tmp/s.sh'");
+        assert_eq!(
+            default.program("/tmp/s.sh", Some("bash")).unwrap(),
+            "bash '/tmp/s.sh'"
+        );
+
+        let pwsh = ScriptExecution {
+            interpreter: Some("pwsh".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(pwsh.extension(), "ps1");
+        assert_eq!(
+            pwsh.program("/tmp/s.ps1", Some("bash")).unwrap(),
+            "pwsh -NoProfile -NonInteractive -File '/tmp/s.ps1'"
+        );
+
+        let python = ScriptExecution {
+            interpreter: Some("python3".to_string()),
+            interpreter_args: vec!["-u".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            python.program("/tmp/s.py", None).unwrap(),
+            "python3 '-u' '/tmp/s.py'"
+        );
+
+        let unknown = ScriptExecution {
+            interpreter: Some("perl".to_string()),
+            ..Default::default()
+        };
+        assert!(unknown.program("/tmp/s.sh", None).is_err());
+    }
+
+    #[test]
+    fn test_remote_script_cleanup() {
+        let plain = RemoteScript::new("cirun-1", "/tmp/script_1.sh".to_string(), "echo hello");
+        assert!(!plain.shred);
+        assert!(plain
+            .cleanup_command()
+            .starts_with("rm -f '/tmp/script_1.sh'; "));
+
+        let secret = RemoteScript::new(
+            "cirun-1",
+            "/tmp/script_1.sh".to_string(),
+            "./config.sh --url https://github.com/org --TOKEN abc",
+        );
+        assert!(secret.shred);
+        let command = secret.cleanup_command();
+        assert!(command.starts_with("shred -u '/tmp/script_1.sh'"));
+        assert!(command.ends_with(
+            "rm -f '/tmp/script_1.sh'.part /tmp/script_stdout.log /tmp/script_stderr.log \
+             /tmp/script_exit.code"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_upload_command_verifies_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote_path = dir.path().join("script.sh");
+        let remote_path = remote_path.to_str().unwrap();
+        let script = normalize_script("#!/bin/sh\r\necho 'quoted \"text\"' $HOME\r\n");
+        assert!(!script.contains('\r'));
+        let encoded = BASE64_STANDARD.encode(script.as_bytes());
+
+        let run = |hash: String| {
+            let mut child = Command::new("sh")
+                .arg("-c")
+                .arg(upload_command(remote_path, &hash))
+                .stdin(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let encoded = encoded.clone();
+            async move {
+                let mut stdin = child.stdin.take().unwrap();
+                stdin.write_all(encoded.as_bytes()).await.unwrap();
+                drop(stdin);
+                child.wait().await.unwrap()
+            }
+        };
+
+        assert!(!run("0".repeat(64)).await.success());
+        assert!(!std::path::Path::new(remote_path).exists());
+
+        assert!(run(script_hash(&script)).await.success());
+        assert_eq!(std::fs::read_to_string(remote_path).unwrap(), script);
+    }
+}